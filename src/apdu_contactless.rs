@@ -26,9 +26,11 @@ pub enum SecureMessaging {
 pub enum Instructions {
     EraseBinary = 0x0E,
     Verify = 0x20,
+    ManageSecurityEnvironment = 0x22,
     ManageChannel = 0x70,
     ExternalAuthenticate = 0x82,
     GetChallenge = 0x84,
+    GeneralAuthenticate = 0x86,
     InternalAuthenticate = 0x88,
     SelectFile = 0xA4,
     ReadBinary = 0xB0,
@@ -44,6 +46,57 @@ pub enum Instructions {
     AppendRecord = 0xE2,
 }
 
+/// 論理チャネルの最大番号
+pub const MAX_LOGICAL_CHANNEL: u8 = 19;
+/// さらなる業界間クラス(01xx xxxx)で表す最初の論理チャネル
+const FIRST_FURTHER_CHANNEL: u8 = 4;
+/// さらなる業界間クラスの印(b7)と、セキュアメッセージング(b6)
+const FURTHER_INTERINDUSTRY: u8 = 0b0100_0000;
+const FURTHER_SECURE_MESSAGING: u8 = 0b0010_0000;
+/// コマンドチェイン(b5)はどちらのクラスでも同じ位置
+const COMMAND_CHAINING: u8 = 0b0001_0000;
+
+fn is_further_interindustry(cla: u8) -> bool {
+    cla != 0xFF && cla & FURTHER_INTERINDUSTRY != 0
+}
+
+/// CLAの論理チャネル番号を書き換える（ISO/IEC 7816-4 5.4.1）
+/// 0～3は b2-b1、4～19は さらなる業界間クラス(01xx xxxx)の b4-b1 に 番号-4 を入れる。
+/// b8(独自クラス)・コマンドチェインはそのまま、セキュアメッセージングは移す先の形式に合わせる
+/// （さらなる業界間クラスでは箇条 6 ヘッダ認証なしだけ）。
+/// CLA=FF(PC/SCの擬似APDU)と範囲外の番号はNone
+pub fn class_with_channel(cla: u8, channel: u8) -> Option<u8> {
+    if cla == 0xFF || channel > MAX_LOGICAL_CHANNEL {
+        return None;
+    }
+    let further = is_further_interindustry(cla);
+    let keep = cla & (0x80 | COMMAND_CHAINING);
+    if channel < FIRST_FURTHER_CHANNEL {
+        let secure = match further {
+            true if cla & FURTHER_SECURE_MESSAGING != 0 => (SecureMessaging::Clause6 as u8) << 2,
+            true => 0,
+            false => cla & 0b0000_1100,
+        };
+        Some(keep | secure | channel)
+    } else {
+        let secure = match further {
+            true => cla & FURTHER_SECURE_MESSAGING,
+            false if cla & 0b0000_1100 != 0 => FURTHER_SECURE_MESSAGING,
+            false => 0,
+        };
+        Some(keep | FURTHER_INTERINDUSTRY | secure | (channel - FIRST_FURTHER_CHANNEL))
+    }
+}
+
+/// CLAが示す論理チャネル番号
+pub fn channel_of_class(cla: u8) -> u8 {
+    if is_further_interindustry(cla) {
+        (cla & 0x0F) + FIRST_FURTHER_CHANNEL
+    } else {
+        cla & 0x03
+    }
+}
+
 /**
  * Apdu
  * APDUビルダ構造体
@@ -72,26 +125,42 @@ impl ApduBuilder {
         self.cla = class_code;
         self
     }
+    /// 論理チャネル(0～19)を設定する。範囲外なら何もしない
+    /// CLAを設定するコマンドより後に呼ぶこと
     pub fn set_vchannel(&mut self, vchanel_no: u8) -> &mut Self {
-        if 0 <= vchanel_no && vchanel_no <= 3 {
-            self.cla &= 0xff ^ 0b0000_0011;
-            self.cla |= vchanel_no;
+        if let Some(cla) = class_with_channel(self.cla, vchanel_no) {
+            self.cla = cla;
         }
         self
     }
     pub fn get_vchannel(&self) -> u8 {
-        self.cla & 0x03
+        channel_of_class(self.cla)
     }
     pub fn is_command_chain(&mut self) -> bool {
         // iso7816では、コマンドチェインは実装されていない。
         false
     }
     pub fn set_secure_mode(&mut self, secure_type: SecureMessaging) -> &mut Self {
+        if is_further_interindustry(self.cla) {
+            // さらなる業界間クラスはb6(箇条 6 ヘッダ認証なし)だけ
+            self.cla &= 0xff ^ FURTHER_SECURE_MESSAGING;
+            if secure_type != SecureMessaging::Plain {
+                self.cla |= FURTHER_SECURE_MESSAGING;
+            }
+            return self;
+        }
         self.cla &= 0xff ^ 0b0000_1100;
         self.cla |= (secure_type as u8) << 2;
         self
     }
     pub fn get_secure_mode(&self) -> SecureMessaging {
+        if is_further_interindustry(self.cla) {
+            return if self.cla & FURTHER_SECURE_MESSAGING != 0 {
+                SecureMessaging::Clause6
+            } else {
+                SecureMessaging::Plain
+            };
+        }
         match ((self.cla & 0b0000_1100) >> 2) & 0b11 {
             0 => SecureMessaging::Plain,
             1 => SecureMessaging::Proprietary,
@@ -125,6 +194,20 @@ impl ApduBuilderExtWithPcsc3V2 for ApduBuilder {
         self.data_field=Some(vec![0]);
         self
     }
+    fn read_binary(&mut self, address: u16, le: u8) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = Instructions::ReadBinary as u8;
+        self.parameter = address.to_be_bytes();
+        self.data_field = Some(vec![le]);
+        self
+    }
+    fn update_binary(&mut self, address: u16, data: &[u8]) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = Instructions::UpdateBinary as u8;
+        self.parameter = address.to_be_bytes();
+        self.data_field = Some([data.len() as u8].iter().chain(data.iter()).cloned().collect());
+        self
+    }
 }
 
 impl ApduBuilderExtWithFelica for ApduBuilder{
@@ -156,6 +239,259 @@ impl ApduBuilderExtWithFelica for ApduBuilder{
         self.data_field=Some(vec![0]);
         self
     }
+    fn select_service(&mut self, service_code: u16) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = Instructions::SelectFile as u8;
+        self.parameter = [0x00, 0x01];
+        // サービスコードはリトルエンディアンで指定する
+        self.data_field = Some(
+            [2u8]
+                .iter()
+                .chain(service_code.to_le_bytes().iter())
+                .cloned()
+                .collect(),
+        );
+        self
+    }
+}
+
+impl ApduBuilderExtWithDirectTransmit for ApduBuilder {
+    fn direct_transmit(&mut self, payload: &[u8]) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0x00;
+        self.parameter = [0, 0];
+        self.data_field = Some(
+            [payload.len() as u8]
+                .iter()
+                .chain(payload.iter())
+                .cloned()
+                .collect(),
+        );
+        self
+    }
+}
+
+impl ApduBuilderExtWithDesfire for ApduBuilder {
+    fn desfire_command(&mut self, command: u8, data: &[u8]) -> &mut Self {
+        self.cla = 0x90;
+        self.ins = command;
+        self.parameter = [0, 0];
+        // データがなければLeだけを付ける
+        let mut field = Vec::with_capacity(data.len() + 2);
+        if !data.is_empty() {
+            field.push(data.len() as u8);
+            field.extend_from_slice(data);
+        }
+        field.push(0x00);
+        self.data_field = Some(field);
+        self
+    }
+}
+
+/// DO 54(オフセット) 3バイト
+fn offset_data_object(offset: u32) -> Vec<u8> {
+    [0x54, 0x03].iter().chain(offset.to_be_bytes()[1..].iter()).cloned().collect()
+}
+
+impl ApduBuilderExtWithIso7816 for ApduBuilder {
+    fn select_file(&mut self, p1: u8, p2: u8, data: &[u8]) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::SelectFile as u8;
+        self.parameter = [p1, p2];
+        let mut field = Vec::with_capacity(data.len() + 2);
+        if !data.is_empty() {
+            field.push(data.len() as u8);
+            field.extend_from_slice(data);
+        }
+        // P2が0Cなら応答データはない
+        if p2 & 0x0C != 0x0C {
+            field.push(0x00);
+        }
+        self.data_field = if field.is_empty() { None } else { Some(field) };
+        self
+    }
+    fn select_by_name(&mut self, name: &[u8]) -> &mut Self {
+        self.select_file(0x04, 0x00, name)
+    }
+    fn select_by_file_id(&mut self, file_id: u16) -> &mut Self {
+        self.select_file(0x00, 0x0C, &file_id.to_be_bytes())
+    }
+    fn read_binary_sfi(&mut self, sfi: u8, offset: u8, le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ReadBinary as u8;
+        self.parameter = [0x80 | (sfi & 0x1F), offset];
+        self.data_field = Some(vec![le]);
+        self
+    }
+    fn manage_channel_open(&mut self, channel: Option<u8>) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ManageChannel as u8;
+        self.parameter = [0x00, channel.unwrap_or(0)];
+        // カードが割り当てるときは番号(1バイト)が返る
+        self.data_field = if channel.is_none() { Some(vec![0x01]) } else { None };
+        self
+    }
+    fn manage_channel_close(&mut self, channel: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ManageChannel as u8;
+        self.parameter = [0x80, channel];
+        self.data_field = None;
+        self
+    }
+    fn read_record(&mut self, record: u8, p2: u8, le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ReadRecords as u8;
+        self.parameter = [record, p2];
+        self.data_field = Some(vec![le]);
+        self
+    }
+    fn iso_read_binary(&mut self, offset: u16, le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ReadBinary as u8;
+        self.parameter = (offset & 0x7FFF).to_be_bytes();
+        self.data_field = Some(vec![le]);
+        self
+    }
+    fn iso_update_binary(&mut self, offset: u16, data: &[u8]) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::UpdateBinary as u8;
+        self.parameter = (offset & 0x7FFF).to_be_bytes();
+        self.data_field = Some([data.len() as u8].iter().chain(data.iter()).cloned().collect());
+        self
+    }
+    fn iso_read_binary_odo(&mut self, offset: u32, le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ReadBinary as u8 | 0x01;
+        self.parameter = [0, 0];
+        let odo = offset_data_object(offset);
+        let mut field = vec![odo.len() as u8];
+        field.extend_from_slice(&odo);
+        field.push(le);
+        self.data_field = Some(field);
+        self
+    }
+    fn iso_update_binary_odo(&mut self, offset: u32, data: &[u8]) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::UpdateBinary as u8 | 0x01;
+        self.parameter = [0, 0];
+        // 54 03 オフセット || 53 長さ データ（128バイト以上は 81 長さ）
+        let mut body = offset_data_object(offset);
+        body.push(0x53);
+        if data.len() >= 0x80 {
+            body.push(0x81);
+        }
+        body.push(data.len() as u8);
+        body.extend_from_slice(data);
+        self.data_field = Some([body.len() as u8].iter().chain(body.iter()).cloned().collect());
+        self
+    }
+    fn get_challenge(&mut self, le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::GetChallenge as u8;
+        self.parameter = [0, 0];
+        self.data_field = Some(vec![le]);
+        self
+    }
+    fn external_authenticate(&mut self, data: &[u8], le: u8) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ExternalAuthenticate as u8;
+        self.parameter = [0, 0];
+        let mut field = vec![data.len() as u8];
+        field.extend_from_slice(data);
+        field.push(le);
+        self.data_field = Some(field);
+        self
+    }
+    fn manage_security_environment(&mut self, p1: u8, p2: u8, data: &[u8]) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        self.ins = Instructions::ManageSecurityEnvironment as u8;
+        self.parameter = [p1, p2];
+        self.data_field = Some([data.len() as u8].iter().chain(data.iter()).cloned().collect());
+        self
+    }
+    fn iso_general_authenticate(&mut self, chaining: bool, data: &[u8]) -> &mut Self {
+        self.cla = Classes::IsoFullAccording as u8;
+        if chaining {
+            self.cla |= COMMAND_CHAINING;
+        }
+        self.ins = Instructions::GeneralAuthenticate as u8;
+        self.parameter = [0, 0];
+        let mut field = vec![data.len() as u8];
+        field.extend_from_slice(data);
+        field.push(0x00);
+        self.data_field = Some(field);
+        self
+    }
+}
+
+impl MifareExt for ApduBuilder {
+    fn load_auth_keys(&mut self, key_structure: u8, key_no: u8, key: &[u8; 6]) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0x82;
+        self.parameter = [key_structure, key_no];
+        self.data_field = Some([6u8].iter().chain(key.iter()).cloned().collect());
+        self
+    }
+    fn general_authenticate(
+        &mut self,
+        block: u8,
+        key_type: MifareKeyType,
+        key_no: u8,
+    ) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0x86;
+        self.parameter = [0, 0];
+        // Lc, バージョン(01), ブロック番号MSB, ブロック番号LSB, キー種別, キー番号
+        self.data_field = Some(vec![5, 0x01, 0x00, block, key_type as u8, key_no]);
+        self
+    }
+    fn read_block(&mut self, block: u8) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = Instructions::ReadBinary as u8;
+        self.parameter = [0, block];
+        self.data_field = Some(vec![16]);
+        self
+    }
+    fn update_block(&mut self, block: u8, data: &[u8; 16]) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = Instructions::UpdateBinary as u8;
+        self.parameter = [0, block];
+        self.data_field = Some([16u8].iter().chain(data.iter()).cloned().collect());
+        self
+    }
+    fn value_block_operation(
+        &mut self,
+        block: u8,
+        operation: ValueBlockOperation,
+        value: i32,
+    ) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0xD7;
+        self.parameter = [0, block];
+        // 値はビッグエンディアンで指定する
+        self.data_field = Some(
+            [5u8, operation as u8]
+                .iter()
+                .chain(value.to_be_bytes().iter())
+                .cloned()
+                .collect(),
+        );
+        self
+    }
+    fn read_value_block(&mut self, block: u8) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0xB1;
+        self.parameter = [0, block];
+        self.data_field = Some(vec![4]);
+        self
+    }
+    fn restore_value_block(&mut self, source: u8, target: u8) -> &mut Self {
+        self.cla = 0xFF;
+        self.ins = 0xD7;
+        self.parameter = [0, source];
+        self.data_field = Some(vec![2, 0x03, target]);
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -262,3 +598,160 @@ fn APDU_secure_cause6_to_clause6_auth() {
     let payload = apdu.build();
     assert_eq!(payload.read8()[0], 0b0000_1100);
 }
+
+#[test]
+fn APDU_mifare_load_keys() {
+    let apdu = ApduBuilder::new()
+        .load_auth_keys(KEY_STRUCTURE_VOLATILE, 0, &[0xFF; 6])
+        .build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0xFF, 0x82, 0x00, 0x00, 0x06, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+    );
+}
+
+#[test]
+fn APDU_mifare_authenticate() {
+    let apdu = ApduBuilder::new()
+        .general_authenticate(0x04, MifareKeyType::KeyB, 1)
+        .build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0xFF, 0x86, 0x00, 0x00, 0x05, 0x01, 0x00, 0x04, 0x61, 0x01]
+    );
+}
+
+#[test]
+fn APDU_mifare_read_update_block() {
+    let apdu = ApduBuilder::new().read_block(0x3F).build();
+    assert_eq!(apdu.read8(), vec![0xFF, 0xB0, 0x00, 0x3F, 0x10]);
+    let apdu = ApduBuilder::new().update_block(0x01, &[0xAB; 16]).build();
+    let bytes = apdu.read8();
+    assert_eq!(&bytes[..5], &[0xFF, 0xD6, 0x00, 0x01, 0x10]);
+    assert_eq!(&bytes[5..], &[0xAB; 16]);
+}
+
+#[test]
+fn APDU_mifare_value_block() {
+    let apdu = ApduBuilder::new()
+        .value_block_operation(0x05, ValueBlockOperation::Decrement, -4)
+        .build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0xFF, 0xD7, 0x00, 0x05, 0x05, 0x02, 0xFF, 0xFF, 0xFF, 0xFC]
+    );
+    let apdu = ApduBuilder::new().read_value_block(0x05).build();
+    assert_eq!(apdu.read8(), vec![0xFF, 0xB1, 0x00, 0x05, 0x04]);
+    let apdu = ApduBuilder::new().restore_value_block(0x05, 0x06).build();
+    assert_eq!(apdu.read8(), vec![0xFF, 0xD7, 0x00, 0x05, 0x02, 0x03, 0x06]);
+}
+
+#[test]
+fn APDU_storage_card_read_update_binary() {
+    let apdu = ApduBuilder::new().read_binary(0x0004, 4).build();
+    assert_eq!(apdu.read8(), vec![0xFF, 0xB0, 0x00, 0x04, 0x04]);
+    let apdu = ApduBuilder::new().update_binary(0x0105, &[1, 2, 3, 4]).build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0xFF, 0xD6, 0x01, 0x05, 0x04, 0x01, 0x02, 0x03, 0x04]
+    );
+}
+
+#[test]
+fn APDU_felica_select_service() {
+    let apdu = ApduBuilder::new().select_service(0x090F).build();
+    assert_eq!(apdu.read8(), vec![0xFF, 0xA4, 0x00, 0x01, 0x02, 0x0F, 0x09]);
+}
+
+#[test]
+fn APDU_direct_transmit() {
+    let apdu = ApduBuilder::new().direct_transmit(&[0xD4, 0x42, 0x60]).build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0xFF, 0x00, 0x00, 0x00, 0x03, 0xD4, 0x42, 0x60]
+    );
+}
+
+#[test]
+fn APDU_desfire_command() {
+    let apdu = ApduBuilder::new().desfire_command(0x60, &[]).build();
+    assert_eq!(apdu.read8(), vec![0x90, 0x60, 0x00, 0x00, 0x00]);
+    let apdu = ApduBuilder::new()
+        .desfire_command(0x5A, &[0x01, 0x00, 0x00])
+        .build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0x90, 0x5A, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn APDU_iso7816_file() {
+    let apdu = ApduBuilder::new()
+        .select_by_name(&[0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01])
+        .build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0x00, 0xA4, 0x04, 0x00, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01, 0x00]
+    );
+    let apdu = ApduBuilder::new().select_by_file_id(0xE103).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xA4, 0x00, 0x0C, 0x02, 0xE1, 0x03]);
+    let apdu = ApduBuilder::new().select_file(0x00, 0x04, &[]).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xA4, 0x00, 0x04, 0x00]);
+    let apdu = ApduBuilder::new().select_file(0x03, 0x0C, &[]).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xA4, 0x03, 0x0C]);
+    let apdu = ApduBuilder::new().read_binary_sfi(0x1E, 0x10, 0x00).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xB0, 0x9E, 0x10, 0x00]);
+    let apdu = ApduBuilder::new().read_record(0x02, 0x0C, 0x00).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xB2, 0x02, 0x0C, 0x00]);
+    let apdu = ApduBuilder::new().iso_read_binary(0x0102, 0x0F).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xB0, 0x01, 0x02, 0x0F]);
+    let apdu = ApduBuilder::new().iso_update_binary(0x0002, &[0xD1, 0x01]).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0xD6, 0x00, 0x02, 0x02, 0xD1, 0x01]);
+    let apdu = ApduBuilder::new().iso_read_binary_odo(0x012345, 0xF0).build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0x00, 0xB1, 0x00, 0x00, 0x05, 0x54, 0x03, 0x01, 0x23, 0x45, 0xF0]
+    );
+    let apdu = ApduBuilder::new().iso_update_binary_odo(0x8000, &[0xAA; 2]).build();
+    assert_eq!(
+        apdu.read8(),
+        vec![0x00, 0xD7, 0x00, 0x00, 0x09, 0x54, 0x03, 0x00, 0x80, 0x00, 0x53, 0x02, 0xAA, 0xAA]
+    );
+    let apdu = ApduBuilder::new().iso_update_binary_odo(0, &[0; 0x80]).build();
+    assert_eq!(apdu.read8()[4..13], [0x88, 0x54, 0x03, 0x00, 0x00, 0x00, 0x53, 0x81, 0x80]);
+}
+
+#[test]
+fn APDU_logical_channel() {
+    let apdu = ApduBuilder::new().manage_channel_open(None).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0x70, 0x00, 0x00, 0x01]);
+    let apdu = ApduBuilder::new().manage_channel_open(Some(5)).build();
+    assert_eq!(apdu.read8(), vec![0x00, 0x70, 0x00, 0x05]);
+    let apdu = ApduBuilder::new().manage_channel_close(5).set_vchannel(5).build();
+    assert_eq!(apdu.read8(), vec![0x41, 0x70, 0x80, 0x05]);
+
+    let mut builder = ApduBuilder::new();
+    builder.select_by_file_id(0x3F00).set_vchannel(3);
+    assert_eq!(builder.build().read8()[0], 0x03);
+    builder.set_vchannel(19);
+    assert_eq!(builder.get_vchannel(), 19);
+    assert_eq!(builder.build().read8()[0], 0x4F);
+    // 範囲外は無視する
+    builder.set_vchannel(20);
+    assert_eq!(builder.get_vchannel(), 19);
+    // セキュアメッセージングはクラスの形式に合わせて移す
+    builder.set_secure_mode(SecureMessaging::Clause6);
+    assert_eq!(builder.build().read8()[0], 0x6F);
+    builder.set_vchannel(1);
+    assert_eq!(builder.build().read8()[0], 0x09);
+    assert_eq!(builder.get_secure_mode(), SecureMessaging::Clause6);
+    builder.set_ext(true).set_vchannel(4);
+    assert_eq!(builder.build().read8()[0], 0xE0);
+
+    assert_eq!(class_with_channel(0x00, 0), Some(0x00));
+    assert_eq!(class_with_channel(0x10, 7), Some(0x53));
+    assert_eq!(class_with_channel(0xFF, 1), None);
+    assert_eq!(channel_of_class(0x80), 0);
+    assert_eq!(channel_of_class(0xC2), 6);
+}
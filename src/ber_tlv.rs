@@ -0,0 +1,805 @@
+// BER-TLV（ISO/IEC 8825-1、ISO/IEC 7816-4 5.2）の符号化と解析
+// タグは最大4バイト（1バイト目の下位5ビットが1Fなら続くバイトがあり、b8が1の間は続く）。
+// 1バイト目のb6が1なら構造化(constructed)で、値はデータオブジェクトの並びになる。
+// 長さは 00～7F の1バイト、または 81～84 の後に1～4バイト。不定長(80)は受け付けない。
+// ISO 7816ではデータオブジェクトの前後の 00・FF は詰め物として読み飛ばす。
+// 構造化の入れ子は MAX_DEPTH 段までとし、それより深いものはスタックを使い切らないよう拒否する。
+// パスは "6F/A5/BF0C/61/4F" のようにタグを / で区切って指定する。
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// 不定長を示す長さのバイト
+const INDEFINITE_LENGTH: u8 = 0x80;
+/// 長さのバイト数の上限
+const MAX_LENGTH_SIZE: usize = 4;
+const MAX_TAG_SIZE: usize = 4;
+/// 構造化のデータオブジェクトの入れ子の上限
+pub const MAX_DEPTH: usize = 16;
+/// 詰め物のバイト
+const PADDING: [u8; 2] = [0x00, 0xFF];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagClass {
+    Universal,
+    Application,
+    ContextSpecific,
+    Private,
+}
+
+/// タグ。符号化したバイト列を上位から詰めた値（5F2D・BF0C など）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag(pub u32);
+
+impl Tag {
+    /// dataの先頭のタグと、そのバイト数
+    pub fn from_bytes(data: &[u8]) -> Result<(Tag, usize), BerTlvError> {
+        let first = *data
+            .first()
+            .ok_or_else(|| BerTlvError::new(BerTlvErrorKind::UnexpectedEnd))?;
+        let mut tag = first as u32;
+        let mut size = 1;
+        if first & 0x1F == 0x1F {
+            loop {
+                let byte = *data
+                    .get(size)
+                    .ok_or_else(|| BerTlvError::new(BerTlvErrorKind::UnexpectedEnd))?;
+                size += 1;
+                if size > MAX_TAG_SIZE {
+                    return Err(BerTlvError::new(BerTlvErrorKind::TagTooLong));
+                }
+                tag = tag << 8 | byte as u32;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+        Ok((Tag(tag), size))
+    }
+    pub fn to_bytes(self) -> Vec<u8> {
+        let bytes = self.0.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count().min(3);
+        bytes[skip..].to_vec()
+    }
+    fn first_byte(self) -> u8 {
+        self.to_bytes()[0]
+    }
+    pub fn class(self) -> TagClass {
+        match self.first_byte() >> 6 {
+            0 => TagClass::Universal,
+            1 => TagClass::Application,
+            2 => TagClass::ContextSpecific,
+            _ => TagClass::Private,
+        }
+    }
+    pub fn is_constructed(self) -> bool {
+        self.first_byte() & 0x20 != 0
+    }
+    /// 符号化が正しいか（複数バイトのタグの続きの有無が合っているか）
+    fn is_valid(self) -> bool {
+        let bytes = self.to_bytes();
+        matches!(Tag::from_bytes(&bytes), Ok((tag, size)) if tag == self && size == bytes.len())
+    }
+}
+
+impl From<u32> for Tag {
+    fn from(tag: u32) -> Self {
+        Tag(tag)
+    }
+}
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02X}", self.0)
+    }
+}
+
+/// タグを / で区切ったパス
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagPath(pub Vec<Tag>);
+
+impl FromStr for TagPath {
+    type Err = BerTlvError;
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = || BerTlvError::new(BerTlvErrorKind::InvalidPath(path.to_owned()));
+        path.split('/')
+            .map(|segment| {
+                let segment = segment.trim();
+                if segment.is_empty()
+                    || segment.len() % 2 != 0
+                    || !segment.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err(invalid());
+                }
+                let tag = Tag(u32::from_str_radix(segment, 16).map_err(|_| invalid())?);
+                if tag.is_valid() && tag.to_bytes().len() * 2 == segment.len() {
+                    Ok(tag)
+                } else {
+                    Err(invalid())
+                }
+            })
+            .collect::<Result<Vec<Tag>, BerTlvError>>()
+            .map(TagPath)
+    }
+}
+
+impl std::fmt::Display for TagPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self
+            .0
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "{}", path.join("/"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlvValue {
+    Primitive(Vec<u8>),
+    Constructed(Vec<Tlv>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tlv {
+    pub tag: Tag,
+    pub value: TlvValue,
+}
+
+impl Tlv {
+    pub fn primitive(tag: u32, value: &[u8]) -> Self {
+        Tlv {
+            tag: Tag(tag),
+            value: TlvValue::Primitive(value.to_vec()),
+        }
+    }
+    pub fn constructed(tag: u32, children: Vec<Tlv>) -> Self {
+        Tlv {
+            tag: Tag(tag),
+            value: TlvValue::Constructed(children),
+        }
+    }
+    /// 先頭のデータオブジェクトを1つ解析する（詰め物は読み飛ばさない）
+    /// 構造化のタグは値も解析する
+    pub fn parse(data: &[u8]) -> Result<(Tlv, usize), BerTlvError> {
+        Tlv::parse_nested(data, 0)
+    }
+    /// データオブジェクトの並びを解析する
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Tlv>, BerTlvError> {
+        Tlv::parse_all_nested(data, 0)
+    }
+    /// depthは外側の構造化のデータオブジェクトの数
+    fn parse_nested(data: &[u8], depth: usize) -> Result<(Tlv, usize), BerTlvError> {
+        let (tag, value, size) = read_object(data)?;
+        let value = if tag.is_constructed() {
+            if depth >= MAX_DEPTH {
+                return Err(BerTlvError::new(BerTlvErrorKind::TooDeep));
+            }
+            TlvValue::Constructed(Tlv::parse_all_nested(value, depth + 1)?)
+        } else {
+            TlvValue::Primitive(value.to_vec())
+        };
+        Ok((Tlv { tag, value }, size))
+    }
+    fn parse_all_nested(data: &[u8], depth: usize) -> Result<Vec<Tlv>, BerTlvError> {
+        let mut tlvs = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            if PADDING.contains(&data[position]) {
+                position += 1;
+                continue;
+            }
+            let (tlv, size) = Tlv::parse_nested(&data[position..], depth)?;
+            tlvs.push(tlv);
+            position += size;
+        }
+        Ok(tlvs)
+    }
+    /// プリミティブの値（構造化ならNone）
+    pub fn value(&self) -> Option<&[u8]> {
+        match &self.value {
+            TlvValue::Primitive(value) => Some(value),
+            TlvValue::Constructed(_) => None,
+        }
+    }
+    /// 構造化の中身（プリミティブなら空）
+    pub fn children(&self) -> &[Tlv] {
+        match &self.value {
+            TlvValue::Primitive(_) => &[],
+            TlvValue::Constructed(children) => children,
+        }
+    }
+    /// 値を符号化したバイト列
+    pub fn value_bytes(&self) -> Vec<u8> {
+        match &self.value {
+            TlvValue::Primitive(value) => value.clone(),
+            TlvValue::Constructed(children) => children.iter().flat_map(|c| c.to_bytes()).collect(),
+        }
+    }
+    /// 長さは最短の形式で符号化する
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let value = self.value_bytes();
+        let mut out = self.tag.to_bytes();
+        out.extend(encode_length(value.len()));
+        out.extend(value);
+        out
+    }
+    /// 子孫からパスで探す（パスの先頭は直下の子のタグ）
+    pub fn get(&self, path: &TagPath) -> Option<&Tlv> {
+        find(self.children(), path)
+    }
+}
+
+impl std::fmt::Display for Tlv {
+    /// 既定の辞書でタグの名前を付けて表示する
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            pretty(std::slice::from_ref(self), &TagDictionary::default())
+        )
+    }
+}
+
+/// 先頭のデータオブジェクトのタグ・値・全体のバイト数
+/// 値は解析しないので、構造化のタグでも値のバイト列がそのまま得られる
+pub fn read_object(data: &[u8]) -> Result<(Tag, &[u8], usize), BerTlvError> {
+    let (tag, tag_size) = Tag::from_bytes(data)?;
+    let (len, length_size) = decode_length(&data[tag_size..])?;
+    let start = tag_size + length_size;
+    if data.len() - start < len {
+        return Err(BerTlvError::new(BerTlvErrorKind::UnexpectedEnd));
+    }
+    Ok((tag, &data[start..start + len], start + len))
+}
+
+/// データオブジェクトの並びを、値を解析せずにタグと値の組にする（詰め物は読み飛ばす）
+pub fn read_objects(mut data: &[u8]) -> Result<Vec<(Tag, &[u8])>, BerTlvError> {
+    let mut objects = Vec::new();
+    while let Some(first) = data.first() {
+        if PADDING.contains(first) {
+            data = &data[1..];
+            continue;
+        }
+        let (tag, value, size) = read_object(data)?;
+        objects.push((tag, value));
+        data = &data[size..];
+    }
+    Ok(objects)
+}
+
+/// 長さと、そのバイト数
+fn decode_length(data: &[u8]) -> Result<(usize, usize), BerTlvError> {
+    let first = *data
+        .first()
+        .ok_or_else(|| BerTlvError::new(BerTlvErrorKind::UnexpectedEnd))?;
+    match first {
+        0x00..=0x7F => Ok((first as usize, 1)),
+        INDEFINITE_LENGTH => Err(BerTlvError::new(BerTlvErrorKind::IndefiniteLength)),
+        _ => {
+            let count = (first & 0x7F) as usize;
+            if count > MAX_LENGTH_SIZE {
+                return Err(BerTlvError::new(BerTlvErrorKind::InvalidLength(first)));
+            }
+            let bytes = data
+                .get(1..1 + count)
+                .ok_or_else(|| BerTlvError::new(BerTlvErrorKind::UnexpectedEnd))?;
+            let len = bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+            Ok((len, 1 + count))
+        }
+    }
+}
+
+pub fn encode_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes = (len as u32).to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    let mut out = vec![0x80 | (4 - skip) as u8];
+    out.extend_from_slice(&bytes[skip..]);
+    out
+}
+
+/// データオブジェクトの並びからパスで探す（同じタグが並ぶときは最初に見つかったもの）
+pub fn find<'a>(tlvs: &'a [Tlv], path: &TagPath) -> Option<&'a Tlv> {
+    find_all(tlvs, path).into_iter().next()
+}
+
+/// パスに当てはまるものをすべて探す（61が複数あるときなど）
+pub fn find_all<'a>(tlvs: &'a [Tlv], path: &TagPath) -> Vec<&'a Tlv> {
+    let (first, rest) = match path.0.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    let matches = tlvs.iter().filter(|tlv| tlv.tag == *first);
+    if rest.is_empty() {
+        return matches.collect();
+    }
+    let rest = TagPath(rest.to_vec());
+    matches
+        .flat_map(|tlv| find_all(tlv.children(), &rest))
+        .collect()
+}
+
+/// タグの名前の辞書
+/// 8x・Ax などの文脈依存のタグは、親のタグごとに名前を登録できる
+#[derive(Debug, Clone)]
+pub struct TagDictionary {
+    names: HashMap<(Option<Tag>, Tag), String>,
+}
+
+/// 既定の辞書（ISO 7816-4・EMVの主なタグ）
+const COMMON_TAGS: &[(u32, &str)] = &[
+    (0x42, "Issuer Identification Number"),
+    (0x4F, "Application Identifier (AID)"),
+    (0x50, "Application Label"),
+    (0x51, "Path"),
+    (0x53, "Discretionary Data"),
+    (0x57, "Track 2 Equivalent Data"),
+    (0x5A, "Application PAN"),
+    (0x5F20, "Cardholder Name"),
+    (0x5F24, "Application Expiration Date"),
+    (0x5F2D, "Language Preference"),
+    (0x61, "Application Template"),
+    (0x62, "File Control Parameters (FCP) Template"),
+    (0x64, "File Management Data (FMD) Template"),
+    (0x6F, "File Control Information (FCI) Template"),
+    (0x70, "Record Template"),
+    (0x73, "Discretionary Template"),
+    (0x77, "Response Message Template Format 2"),
+    (0x87, "Application Priority Indicator"),
+    (0x9F11, "Issuer Code Table Index"),
+    (0x9F12, "Application Preferred Name"),
+    (0x9F38, "PDOL"),
+    (0xBF0C, "FCI Issuer Discretionary Data"),
+];
+/// FCP・FCIの中の文脈依存のタグ
+const FCP_TAGS: &[(u32, &str)] = &[
+    (0x80, "File Size"),
+    (0x81, "Total File Size"),
+    (0x82, "File Descriptor"),
+    (0x83, "File Identifier"),
+    (0x84, "DF Name"),
+    (0x85, "Proprietary Information"),
+    (0x86, "Security Attribute (Proprietary)"),
+    (0x88, "Short EF Identifier"),
+    (0x8A, "Life Cycle Status"),
+    (0x8B, "Security Attribute (Referenced)"),
+    (0x8C, "Security Attribute (Compact)"),
+    (0xA5, "Proprietary Information Template"),
+    (0xAB, "Security Attribute (Expanded)"),
+];
+
+impl TagDictionary {
+    /// 空の辞書
+    pub fn new() -> Self {
+        TagDictionary {
+            names: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, tag: u32, name: &str) -> &mut Self {
+        self.names.insert((None, Tag(tag)), name.to_owned());
+        self
+    }
+    /// 親がparentのときだけの名前
+    pub fn insert_in(&mut self, parent: u32, tag: u32, name: &str) -> &mut Self {
+        self.names
+            .insert((Some(Tag(parent)), Tag(tag)), name.to_owned());
+        self
+    }
+    pub fn name(&self, parent: Option<Tag>, tag: Tag) -> Option<&str> {
+        parent
+            .and_then(|parent| self.names.get(&(Some(parent), tag)))
+            .or_else(|| self.names.get(&(None, tag)))
+            .map(|name| name.as_str())
+    }
+}
+
+impl Default for TagDictionary {
+    fn default() -> Self {
+        let mut dictionary = TagDictionary::new();
+        for (tag, name) in COMMON_TAGS {
+            dictionary.insert(*tag, name);
+        }
+        for parent in [0x62, 0x6F] {
+            for (tag, name) in FCP_TAGS {
+                dictionary.insert_in(parent, *tag, name);
+            }
+        }
+        // FCIの中のA5はEMVのFCI Proprietary Template
+        dictionary.insert_in(0x6F, 0xA5, "FCI Proprietary Template");
+        dictionary
+    }
+}
+
+/// 1行に1つのデータオブジェクトを、字下げと辞書の名前付きで表示する
+/// プリミティブの値は16進で、すべて表示可能なASCIIなら文字列も添える
+pub fn pretty(tlvs: &[Tlv], dictionary: &TagDictionary) -> String {
+    let mut out = String::new();
+    pretty_lines(&mut out, tlvs, None, 0, dictionary);
+    out
+}
+
+fn pretty_lines(
+    out: &mut String,
+    tlvs: &[Tlv],
+    parent: Option<Tag>,
+    depth: usize,
+    dictionary: &TagDictionary,
+) {
+    for tlv in tlvs {
+        out.push_str(&"  ".repeat(depth));
+        out.push_str(&tlv.tag.to_string());
+        if let Some(name) = dictionary.name(parent, tlv.tag) {
+            out.push(' ');
+            out.push_str(name);
+        }
+        match &tlv.value {
+            TlvValue::Primitive(value) => {
+                out.push_str(": ");
+                out.push_str(
+                    &value
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect::<String>(),
+                );
+                if !value.is_empty() && value.iter().all(|b| (0x20..0x7F).contains(b)) {
+                    out.push_str(&format!(" \"{}\"", String::from_utf8_lossy(value)));
+                }
+                out.push('\n');
+            }
+            TlvValue::Constructed(children) => {
+                out.push('\n');
+                pretty_lines(out, children, Some(tlv.tag), depth + 1, dictionary);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BerTlvErrorKind {
+    /// タグ・長さ・値の途中でデータが終わった
+    UnexpectedEnd,
+    /// 不定長(80)
+    IndefiniteLength,
+    /// 長さのバイト数が多すぎる
+    InvalidLength(u8),
+    /// 4バイトを越えるタグ
+    TagTooLong,
+    /// 構造化の入れ子がMAX_DEPTHを越えた
+    TooDeep,
+    InvalidPath(String),
+}
+
+#[derive(Debug)]
+pub struct BerTlvError {
+    code: BerTlvErrorKind,
+}
+
+impl BerTlvError {
+    pub fn new(code: BerTlvErrorKind) -> Self {
+        BerTlvError { code }
+    }
+    pub fn kind(&self) -> &BerTlvErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for BerTlvError {}
+impl std::fmt::Display for BerTlvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn ber_tlv_codec() {
+    // EMVのPPSEの応答
+    let fci = [
+        0x6F, 0x23, 0x84, 0x0E, 0x32, 0x50, 0x41, 0x59, 0x2E, 0x53, 0x59, 0x53, 0x2E, 0x44, 0x44,
+        0x46, 0x30, 0x31, 0xA5, 0x11, 0xBF, 0x0C, 0x0E, 0x61, 0x0C, 0x4F, 0x07, 0xA0, 0x00, 0x00,
+        0x00, 0x03, 0x10, 0x10, 0x87, 0x01, 0x01,
+    ];
+    let tlvs = Tlv::parse_all(&fci).unwrap();
+    assert_eq!(tlvs.len(), 1);
+    assert!(tlvs[0].tag.is_constructed());
+    assert_eq!(tlvs[0].tag.class(), TagClass::Application);
+    let path = "6F/A5/BF0C/61/4F".parse::<TagPath>().unwrap();
+    assert_eq!(path.to_string(), "6F/A5/BF0C/61/4F");
+    assert_eq!(
+        find(&tlvs, &path).unwrap().value(),
+        Some(&[0xA0, 0x00, 0x00, 0x00, 0x03, 0x10, 0x10][..])
+    );
+    let name = tlvs[0].get(&"84".parse().unwrap()).unwrap();
+    assert_eq!(name.value(), Some(&b"2PAY.SYS.DDF01"[..]));
+    assert_eq!(tlvs[0].to_bytes(), fci);
+    assert_eq!(
+        tlvs[0].to_string(),
+        "6F File Control Information (FCI) Template\n\
+         \x20 84 DF Name: 325041592E5359532E4444463031 \"2PAY.SYS.DDF01\"\n\
+         \x20 A5 FCI Proprietary Template\n\
+         \x20   BF0C FCI Issuer Discretionary Data\n\
+         \x20     61 Application Template\n\
+         \x20       4F Application Identifier (AID): A0000000031010\n\
+         \x20       87 Application Priority Indicator: 01\n"
+    );
+
+    // 複数の61・長い形式の長さ・詰め物
+    let record = Tlv::primitive(0x53, &[0x5A; 0x100]);
+    let encoded = record.to_bytes();
+    assert_eq!(encoded[..4], [0x53, 0x82, 0x01, 0x00]);
+    let tlv = Tlv::constructed(
+        0x70,
+        vec![
+            Tlv::constructed(0x61, vec![Tlv::primitive(0x4F, &[0x01])]),
+            Tlv::constructed(0x61, vec![Tlv::primitive(0x4F, &[0x02])]),
+            record,
+        ],
+    );
+    let mut data = vec![0x00, 0xFF];
+    data.extend(tlv.to_bytes());
+    data.push(0x00);
+    let tlvs = Tlv::parse_all(&data).unwrap();
+    assert_eq!(tlvs, vec![tlv]);
+    let aids = find_all(&tlvs, &"70/61/4F".parse().unwrap());
+    assert_eq!(aids.len(), 2);
+    assert_eq!(aids[1].value(), Some(&[0x02][..]));
+    assert_eq!(encode_length(0x1_0000), [0x83, 0x01, 0x00, 0x00]);
+
+    // 不正な符号化とパス
+    let errors: [(&[u8], BerTlvErrorKind); 5] = [
+        (&[0x30, 0x80, 0x00, 0x00], BerTlvErrorKind::IndefiniteLength),
+        (
+            &[0x53, 0x85, 0, 0, 0, 0, 1],
+            BerTlvErrorKind::InvalidLength(0x85),
+        ),
+        (&[0x5F], BerTlvErrorKind::UnexpectedEnd),
+        (
+            &[0x9F, 0x81, 0x81, 0x81, 0x01, 0x00],
+            BerTlvErrorKind::TagTooLong,
+        ),
+        (
+            &[0x61, 0x03, 0x4F, 0x02, 0x01],
+            BerTlvErrorKind::UnexpectedEnd,
+        ),
+    ];
+    for (data, kind) in errors.iter() {
+        assert_eq!(Tlv::parse_all(data).unwrap_err().kind(), kind);
+    }
+
+    // 入れ子の上限
+    let nested = |depth: usize| {
+        (0..depth).fold(Tlv::primitive(0x80, &[0x01]), |inner, _| {
+            Tlv::constructed(0xA0, vec![inner])
+        })
+    };
+    let deepest = nested(MAX_DEPTH);
+    assert_eq!(Tlv::parse_all(&deepest.to_bytes()).unwrap(), vec![deepest]);
+    assert_eq!(
+        Tlv::parse(&nested(MAX_DEPTH + 1).to_bytes())
+            .unwrap_err()
+            .kind(),
+        &BerTlvErrorKind::TooDeep
+    );
+    // 深い入れ子でもスタックを使い切らずに拒否する（後ろから組み立てて逆順にする）
+    let mut bomb = vec![0x00, 0x05];
+    for _ in 0..100_000 {
+        let mut length = encode_length(bomb.len());
+        length.reverse();
+        bomb.extend(length);
+        bomb.push(0x30);
+    }
+    bomb.reverse();
+    assert_eq!(
+        Tlv::parse_all(&bomb).unwrap_err().kind(),
+        &BerTlvErrorKind::TooDeep
+    );
+    for path in ["6F//4F", "5F", "1F", "6F/GG", "9F81"] {
+        assert_eq!(
+            path.parse::<TagPath>().unwrap_err().kind(),
+            &BerTlvErrorKind::InvalidPath(path.to_owned())
+        );
+    }
+}
+
+#[test]
+fn ber_tlv_forms() {
+    // 長い形式の長さ（最短でない符号化も受け付ける）
+    let lengths: [(&[u8], usize, usize); 6] = [
+        (&[0x7F], 0x7F, 1),
+        (&[0x81, 0x80], 0x80, 2),
+        (&[0x81, 0x02], 2, 2),
+        (&[0x82, 0x01, 0x00], 0x100, 3),
+        (&[0x83, 0x01, 0x00, 0x00], 0x1_0000, 4),
+        (&[0x84, 0x01, 0x00, 0x00, 0x00], 0x100_0000, 5),
+    ];
+    for (data, len, size) in lengths.iter() {
+        assert_eq!(decode_length(data).unwrap(), (*len, *size));
+    }
+    for len in [
+        0, 0x7F, 0x80, 0xFF, 0x100, 0xFFFF, 0x1_0000, 0xFF_FFFF, 0x100_0000,
+    ] {
+        let encoded = encode_length(len);
+        assert_eq!(decode_length(&encoded).unwrap(), (len, encoded.len()));
+    }
+    assert_eq!(encode_length(0x80), [0x81, 0x80]);
+    assert_eq!(encode_length(0x100_0000), [0x84, 0x01, 0x00, 0x00, 0x00]);
+    let (tlv, size) = Tlv::parse(&[0x53, 0x84, 0x00, 0x00, 0x00, 0x02, 0x12, 0x34, 0x90]).unwrap();
+    assert_eq!(tlv, Tlv::primitive(0x53, &[0x12, 0x34]));
+    assert_eq!(size, 8);
+    // 作り直すと最短の形式になる
+    assert_eq!(tlv.to_bytes(), [0x53, 0x02, 0x12, 0x34]);
+
+    // 複数バイトのタグ
+    let tags: [(&[u8], u32, TagClass, bool); 5] = [
+        (&[0x5F, 0x2D], 0x5F2D, TagClass::Application, false),
+        (&[0x9F, 0x38], 0x9F38, TagClass::ContextSpecific, false),
+        (&[0xBF, 0x0C], 0xBF0C, TagClass::ContextSpecific, true),
+        (&[0xDF, 0x81, 0x01], 0xDF8101, TagClass::Private, false),
+        (
+            &[0x7F, 0x81, 0x82, 0x03],
+            0x7F81_8203,
+            TagClass::Application,
+            true,
+        ),
+    ];
+    for (bytes, value, class, constructed) in tags.iter() {
+        let mut data = bytes.to_vec();
+        data.extend([0x00, 0xAA]);
+        assert_eq!(Tag::from_bytes(&data).unwrap(), (Tag(*value), bytes.len()));
+        let tag = Tag(*value);
+        assert_eq!(tag.to_bytes(), *bytes);
+        assert_eq!(tag.class(), *class);
+        assert_eq!(tag.is_constructed(), *constructed);
+        let path = tag.to_string().parse::<TagPath>().unwrap();
+        assert_eq!(path, TagPath(vec![tag]));
+    }
+    let tlvs =
+        Tlv::parse_all(&[0xDF, 0x81, 0x01, 0x01, 0x42, 0x7F, 0x81, 0x82, 0x03, 0x00]).unwrap();
+    assert_eq!(
+        tlvs,
+        vec![
+            Tlv::primitive(0xDF8101, &[0x42]),
+            Tlv::constructed(0x7F81_8203, Vec::new())
+        ]
+    );
+
+    // パスでの検索
+    let tlv = Tlv::constructed(
+        0xE1,
+        vec![
+            Tlv::constructed(
+                0x61,
+                vec![
+                    Tlv::primitive(0x4F, &[0x01]),
+                    Tlv::constructed(0x73, vec![Tlv::primitive(0x9F38, &[0x11])]),
+                ],
+            ),
+            Tlv::primitive(0x4F, &[0x02]),
+            Tlv::constructed(0x61, vec![Tlv::primitive(0x50, b"B")]),
+        ],
+    );
+    let tlvs = vec![tlv.clone()];
+    let path = |path: &str| path.parse::<TagPath>().unwrap();
+    assert_eq!(
+        find(&tlvs, &path("E1/4F")).unwrap().value(),
+        Some(&[0x02][..])
+    );
+    assert_eq!(
+        tlv.get(&path("61/73/9F38")).unwrap().value(),
+        Some(&[0x11][..])
+    );
+    assert_eq!(find_all(&tlvs, &path("E1/61")).len(), 2);
+    // 最初の61にはないが、2つ目の61にある
+    assert_eq!(
+        find(&tlvs, &path("E1/61/50")).unwrap().value(),
+        Some(&b"B"[..])
+    );
+    assert!(find(&tlvs, &path("E1/61/4F/4F")).is_none());
+    assert!(find(&tlvs, &path("61")).is_none());
+    assert!(tlv.get(&path("E1")).is_none());
+    assert!(find(&tlvs, &TagPath(Vec::new())).is_none());
+    assert_eq!(path("e1/9f38").to_string(), "E1/9F38");
+    assert_eq!(path(" E1 / 61 ").to_string(), "E1/61");
+}
+
+#[test]
+fn ber_tlv_malformed() {
+    // どこで切れてもUnexpectedEnd
+    let data = Tlv::constructed(
+        0x70,
+        vec![
+            Tlv::primitive(0x5F2D, b"en"),
+            Tlv::primitive(0x53, &[0x00; 0x81]),
+        ],
+    )
+    .to_bytes();
+    assert_eq!(data[..2], [0x70, 0x81]);
+    for end in 1..data.len() {
+        assert_eq!(
+            Tlv::parse(&data[..end]).unwrap_err().kind(),
+            &BerTlvErrorKind::UnexpectedEnd,
+            "{}",
+            end
+        );
+    }
+    assert_eq!(Tlv::parse_all(&[]).unwrap(), Vec::new());
+    assert_eq!(Tlv::parse_all(&[0x00, 0xFF, 0x00]).unwrap(), Vec::new());
+    assert_eq!(
+        Tlv::parse(&[]).unwrap_err().kind(),
+        &BerTlvErrorKind::UnexpectedEnd
+    );
+    for truncated in [
+        &[0x53, 0x82, 0x01][..],
+        &[0x53, 0x84],
+        &[0xBF],
+        &[0xDF, 0x81],
+    ] {
+        assert_eq!(
+            read_objects(truncated).unwrap_err().kind(),
+            &BerTlvErrorKind::UnexpectedEnd
+        );
+    }
+
+    // 不定長は外側でも内側でも拒否する
+    for data in [
+        &[0x62, 0x80, 0x80, 0x01, 0x00, 0x00, 0x00][..],
+        &[0x62, 0x04, 0xA5, 0x80, 0x00, 0x00],
+        &[0x04, 0x80, 0x00, 0x00],
+    ] {
+        assert_eq!(
+            Tlv::parse_all(data).unwrap_err().kind(),
+            &BerTlvErrorKind::IndefiniteLength
+        );
+    }
+    assert_eq!(
+        read_object(&[0x62, 0x80]).unwrap_err().kind(),
+        &BerTlvErrorKind::IndefiniteLength
+    );
+    for first in 0x85..=0xFF {
+        assert_eq!(
+            decode_length(&[first, 0, 0, 0, 0, 0]).unwrap_err().kind(),
+            &BerTlvErrorKind::InvalidLength(first)
+        );
+    }
+
+    // 4バイトを越えるタグ
+    for data in [
+        &[0x5F, 0x81, 0x81, 0x81, 0x01, 0x00][..],
+        &[0xBF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x00],
+    ] {
+        assert_eq!(
+            Tlv::parse_all(data).unwrap_err().kind(),
+            &BerTlvErrorKind::TagTooLong
+        );
+    }
+    // 長さを書かないうちに切れたタグ
+    assert_eq!(
+        Tag::from_bytes(&[0x5F, 0x81, 0x81]).unwrap_err().kind(),
+        &BerTlvErrorKind::UnexpectedEnd
+    );
+
+    // 不正なパス
+    for path in [
+        "",
+        "/",
+        "6F/",
+        "/6F",
+        "6F4F",
+        "5F80",
+        "005F",
+        "9F81818101",
+        "+6",
+        "-6",
+        "6F/0x4F",
+        "6F\\4F",
+        "６F",
+    ] {
+        assert_eq!(
+            path.parse::<TagPath>().unwrap_err().kind(),
+            &BerTlvErrorKind::InvalidPath(path.to_owned()),
+            "{}",
+            path
+        );
+    }
+}
@@ -0,0 +1,592 @@
+// カードのダンプ
+// MIFARE Classic / Ultralight(NTAG) / FeliCa の読める内容をメモリ上のモデルに読み出し、
+// 各種ファイル形式への変換とカードへの書き戻しを行う。
+// 形式変換はリーダーなしでも使える。
+
+use crate::felica::command::{
+    is_readable_without_encryption, FelicaNode, IDM_SIZE, SYSTEM_CODE_WILDCARD,
+};
+use crate::felica::{self, Felica};
+use crate::mifare_classic::access_bits::SectorTrailer;
+use crate::mifare_classic::dictionary::{KeyDictionary, KeyRecovery, SectorKeys};
+use crate::mifare_classic::{self, MifareClassic, MifareClassicKind};
+use crate::pc_sc_standard::MifareKeyType;
+use crate::smart_card::{Smartcard, SmartcardError};
+use crate::ultralight::{Ultralight, PAGE_SIZE};
+
+pub mod flipper;
+pub mod proxmark;
+pub mod raw;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardDump {
+    pub uid: Vec<u8>,
+    pub content: DumpContent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpContent {
+    MifareClassic(MifareClassicDump),
+    Ultralight(UltralightDump),
+    Felica(FelicaDump),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MifareClassicDump {
+    pub kind: MifareClassicKind,
+    /// 表示順(上位バイトが先)のATQA
+    pub atqa: [u8; 2],
+    pub sak: u8,
+    /// 読み出せなかったブロックはNone
+    pub blocks: Vec<Option<[u8; mifare_classic::BLOCK_SIZE]>>,
+    pub keys: Vec<SectorKeys>,
+}
+
+impl MifareClassicDump {
+    /// 全ブロックが未読の状態で生成する
+    pub fn new(kind: MifareClassicKind) -> Self {
+        let (atqa, sak) = match kind {
+            MifareClassicKind::Mini => ([0x00, 0x04], 0x09),
+            MifareClassicKind::Classic1k | MifareClassicKind::Classic2k => ([0x00, 0x04], 0x08),
+            MifareClassicKind::Classic4k => ([0x00, 0x02], 0x18),
+        };
+        MifareClassicDump {
+            kind,
+            atqa,
+            sak,
+            blocks: vec![None; kind.block_count()],
+            keys: vec![SectorKeys::default(); kind.sector_count() as usize],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UltralightDump {
+    pub atqa: [u8; 2],
+    pub sak: u8,
+    /// GET_VERSIONの応答（取得していなければNone）
+    pub version: Option<Vec<u8>>,
+    /// READ_SIGの応答（取得していなければNone）
+    pub signature: Option<Vec<u8>>,
+    pub pages: Vec<[u8; PAGE_SIZE]>,
+}
+
+impl UltralightDump {
+    pub fn new(pages: Vec<[u8; PAGE_SIZE]>) -> Self {
+        UltralightDump {
+            atqa: [0x00, 0x44],
+            sak: 0x00,
+            version: None,
+            signature: None,
+            pages,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FelicaDump {
+    pub pmm: Vec<u8>,
+    pub services: Vec<FelicaServiceDump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FelicaServiceDump {
+    pub service_code: u16,
+    pub blocks: Vec<[u8; felica::BLOCK_SIZE]>,
+}
+
+/// 書き戻しで変更されるブロックの位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpAddress {
+    Block(u8),
+    Page(u8),
+    Felica { service_code: u16, block: u16 },
+}
+
+/// 書き戻しの差分（currentは読み出せなかった場合None）
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpChange {
+    pub address: DumpAddress,
+    pub current: Option<Vec<u8>>,
+    pub target: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RestoreOptions {
+    /// trueなら書き込まずに差分だけを返す
+    pub dry_run: bool,
+    /// アクセスビットを二度と書き換えられなくなるトレーラの書き込みを許可する
+    pub allow_permanent: bool,
+}
+
+impl CardDump {
+    /// 辞書のキーで読めるセクタを全て読み出す
+    pub fn read_mifare_classic(
+        card: &MifareClassic,
+        dictionary: KeyDictionary,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let uid = card.uid()?;
+        let mut recovery = KeyRecovery::new(card, dictionary);
+        recovery.run()?;
+        let mut dump = MifareClassicDump::new(card.kind());
+        for (sector, blocks) in recovery.dump()?.into_iter().enumerate() {
+            if let Some(blocks) = blocks {
+                let first = MifareClassicKind::first_block(sector as u8) as usize;
+                for (i, block) in blocks.into_iter().enumerate() {
+                    dump.blocks[first + i] = Some(block);
+                }
+            }
+        }
+        dump.keys = recovery.progress().sectors.clone();
+        Ok(CardDump {
+            uid,
+            content: DumpContent::MifareClassic(dump),
+        })
+    }
+    /// GET_VERSIONで判定した全ページを読み出す
+    pub fn read_ultralight(nfc: &dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let card = Ultralight::identify(nfc)?;
+        let uid = card.uid()?;
+        let mut dump = UltralightDump::new(card.read_pages(0, card.page_count())?);
+        dump.version = card.version().map(|version| version.to_bytes().to_vec());
+        // GET_VERSIONに応答する種別はREAD_SIGにも対応している
+        if dump.version.is_some() {
+            dump.signature = Some(card.read_signature()?.to_vec());
+        }
+        Ok(CardDump {
+            uid,
+            content: DumpContent::Ultralight(dump),
+        })
+    }
+    /// Search Service Codeで列挙したサービスのうち、暗号化なしで読めるものを全て読み出す
+    /// Search Service Codeに応答しないカード(FeliCa Lite-Sなど)では、service_codesのうち
+    /// Request Serviceで存在を確かめたものを読む（読めないサービスは含めない）
+    pub fn read_felica(
+        nfc: &dyn Smartcard,
+        service_codes: &[u16],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let card = Felica::new(nfc);
+        let polling = card.polling(SYSTEM_CODE_WILDCARD)?;
+        let idm = polling.idm;
+        let service_codes = match card.search_service_codes(&idm) {
+            Ok(nodes) => nodes
+                .into_iter()
+                .filter_map(|node| match node {
+                    FelicaNode::Service(code) if is_readable_without_encryption(code) => Some(code),
+                    _ => None,
+                })
+                .collect::<Vec<u16>>(),
+            Err(e) if SmartcardError::is_connection_lost(e.as_ref()) => return Err(e),
+            Err(_) => match card.request_service(&idm, service_codes) {
+                Ok(versions) => service_codes
+                    .iter()
+                    .zip(versions)
+                    .filter(|(_, version)| version.is_some())
+                    .map(|(code, _)| *code)
+                    .collect(),
+                Err(e) if SmartcardError::is_connection_lost(e.as_ref()) => return Err(e),
+                // Request Serviceにも応答しなければ、候補をそのまま読んでみる
+                Err(_) => service_codes.to_vec(),
+            },
+        };
+        let mut services = Vec::new();
+        for service_code in service_codes {
+            let blocks = read_felica_service(&card, &idm, service_code)?;
+            if !blocks.is_empty() {
+                services.push(FelicaServiceDump {
+                    service_code,
+                    blocks,
+                });
+            }
+        }
+        Ok(CardDump {
+            uid: idm.to_vec(),
+            content: DumpContent::Felica(FelicaDump {
+                pmm: polling.pmm.to_vec(),
+                services,
+            }),
+        })
+    }
+    /// ダンプの内容をカードへ書き戻す
+    /// 書き込んだ（dry_runなら書き込む予定の）差分を返す
+    pub fn restore(
+        &self,
+        nfc: &dyn Smartcard,
+        options: RestoreOptions,
+    ) -> Result<Vec<DumpChange>, Box<dyn std::error::Error>> {
+        match &self.content {
+            DumpContent::MifareClassic(dump) => restore_mifare_classic(dump, nfc, options),
+            DumpContent::Ultralight(dump) => restore_ultralight(dump, nfc, options),
+            DumpContent::Felica(dump) => restore_felica(dump, nfc, options),
+        }
+    }
+}
+
+fn restore_mifare_classic(
+    dump: &MifareClassicDump,
+    nfc: &dyn Smartcard,
+    options: RestoreOptions,
+) -> Result<Vec<DumpChange>, Box<dyn std::error::Error>> {
+    let card = MifareClassic::from_atr(nfc)?;
+    if card.kind() != dump.kind {
+        return Err(Box::new(DumpError::new(DumpErrorKind::IncompatibleCard)));
+    }
+    // 書き戻し先のキーはダンプのキーか既定のキーのどちらかと想定する
+    let mut dictionary = KeyDictionary::new();
+    for keys in dump.keys.iter() {
+        for key in keys.key_a.iter().chain(keys.key_b.iter()) {
+            dictionary.add(*key);
+        }
+    }
+    for key in KeyDictionary::with_default_keys().keys() {
+        dictionary.add(*key);
+    }
+    let mut recovery = KeyRecovery::new(&card, dictionary);
+    recovery.run()?;
+    let current = recovery.dump()?;
+    let current_keys = recovery.progress().sectors.clone();
+
+    let mut changes = Vec::new();
+    for sector in 0..dump.kind.sector_count() {
+        let first = MifareClassicKind::first_block(sector);
+        let trailer = MifareClassicKind::trailer_block(sector);
+        let mut sector_changes = Vec::new();
+        for block in first..=trailer {
+            // 製造者ブロックは書き換えない
+            if block == 0 {
+                continue;
+            }
+            let target = match dump.blocks[block as usize] {
+                Some(target) => target,
+                None => continue,
+            };
+            let current_block = current[sector as usize]
+                .as_ref()
+                .map(|blocks| blocks[(block - first) as usize]);
+            if current_block == Some(target) {
+                continue;
+            }
+            if block == trailer {
+                // 壊れたアクセスビットや永久ロックになるトレーラはここで弾く
+                SectorTrailer::from_bytes(&target)?.to_bytes(options.allow_permanent)?;
+            }
+            sector_changes.push(DumpChange {
+                address: DumpAddress::Block(block),
+                current: current_block.map(|b| b.to_vec()),
+                target: target.to_vec(),
+            });
+        }
+        if !options.dry_run && !sector_changes.is_empty() {
+            authenticate_for_write(&card, sector, &current_keys[sector as usize])?;
+            // トレーラを書くとキーが変わるので最後に書く
+            for change in sector_changes.iter() {
+                let mut data = [0u8; mifare_classic::BLOCK_SIZE];
+                data.copy_from_slice(&change.target);
+                match change.address {
+                    DumpAddress::Block(block) if block == trailer => {
+                        let trailer = SectorTrailer::from_bytes(&data)?;
+                        card.write_trailer(sector, &trailer, options.allow_permanent)?
+                    }
+                    DumpAddress::Block(block) => card.write_block(block, &data)?,
+                    _ => unreachable!(),
+                }
+            }
+        }
+        changes.extend(sector_changes);
+    }
+    Ok(changes)
+}
+
+/// 書き込みは通常KeyBに許可されているのでKeyBを優先して認証する
+fn authenticate_for_write(
+    card: &MifareClassic,
+    sector: u8,
+    keys: &SectorKeys,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let candidates = [
+        (MifareKeyType::KeyB, keys.key_b),
+        (MifareKeyType::KeyA, keys.key_a),
+    ];
+    let mut last_error: Box<dyn std::error::Error> =
+        Box::new(DumpError::new(DumpErrorKind::KeyNotFound(sector)));
+    for (key_type, key) in candidates.iter() {
+        if let Some(key) = key {
+            match card.authenticate_sector(sector, *key_type, key) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn restore_ultralight(
+    dump: &UltralightDump,
+    nfc: &dyn Smartcard,
+    options: RestoreOptions,
+) -> Result<Vec<DumpChange>, Box<dyn std::error::Error>> {
+    let card = Ultralight::identify(nfc)?;
+    if (card.page_count() as usize) < dump.pages.len() {
+        return Err(Box::new(DumpError::new(DumpErrorKind::IncompatibleCard)));
+    }
+    // UID・ロックバイト・OTP・設定ページは書き換えず、ユーザーメモリだけを書き戻す
+    let user_pages = card.user_pages();
+    let end = user_pages.end.min(dump.pages.len() as u16);
+    let current = card.read_pages(0, end)?;
+    let mut changes = Vec::new();
+    for page in user_pages.start..end {
+        let target = &dump.pages[page as usize];
+        if &current[page as usize] == target {
+            continue;
+        }
+        if !options.dry_run {
+            card.write_page(page as u8, target)?;
+        }
+        changes.push(DumpChange {
+            address: DumpAddress::Page(page as u8),
+            current: Some(current[page as usize].to_vec()),
+            target: target.to_vec(),
+        });
+    }
+    Ok(changes)
+}
+
+/// ブロック0から読み出しに失敗するまで読む
+fn read_felica_service(
+    card: &Felica,
+    idm: &[u8; IDM_SIZE],
+    service_code: u16,
+) -> Result<Vec<[u8; felica::BLOCK_SIZE]>, Box<dyn std::error::Error>> {
+    let mut blocks = Vec::new();
+    for block in 0..=u16::MAX {
+        match card.read_without_encryption(idm, service_code, &[block]) {
+            Ok(data) => blocks.extend(data),
+            Err(e) => {
+                if SmartcardError::is_connection_lost(e.as_ref()) {
+                    return Err(e);
+                }
+                // 範囲外のブロック・読めないサービスで終わる
+                break;
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+fn restore_felica(
+    dump: &FelicaDump,
+    nfc: &dyn Smartcard,
+    options: RestoreOptions,
+) -> Result<Vec<DumpChange>, Box<dyn std::error::Error>> {
+    let card = Felica::new(nfc);
+    let mut changes = Vec::new();
+    for service in dump.services.iter() {
+        let current = card.read_service(service.service_code, service.blocks.len() as u16)?;
+        if current.len() < service.blocks.len() {
+            return Err(Box::new(DumpError::new(DumpErrorKind::IncompatibleCard)));
+        }
+        for (block, target) in service.blocks.iter().enumerate() {
+            if &current[block] == target {
+                continue;
+            }
+            if !options.dry_run {
+                // read_serviceで選択済み
+                card.write_block(block as u16, target)?;
+            }
+            changes.push(DumpChange {
+                address: DumpAddress::Felica {
+                    service_code: service.service_code,
+                    block: block as u16,
+                },
+                current: Some(current[block].to_vec()),
+                target: target.to_vec(),
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// 大文字16進で表記する
+pub(crate) fn to_hex(data: &[u8], separator: &str) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// 空白区切りの有無を問わず16進表記をバイト列にする
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<char>>();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpErrorKind {
+    /// その形式・操作が対応していないカード
+    UnsupportedCard,
+    /// ファイルの内容が形式に合っていない
+    InvalidFormat(String),
+    /// 書き戻し先のカードがダンプと互換性がない
+    IncompatibleCard,
+    /// 書き込みに使えるキーが見つからないセクタ
+    KeyNotFound(u8),
+}
+
+#[derive(Debug)]
+pub struct DumpError {
+    code: DumpErrorKind,
+}
+
+impl DumpError {
+    pub fn new(code: DumpErrorKind) -> Self {
+        DumpError { code }
+    }
+    pub fn invalid_format<S: Into<String>>(msg: S) -> Self {
+        Self::new(DumpErrorKind::InvalidFormat(msg.into()))
+    }
+    pub fn kind(&self) -> &DumpErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for DumpError {}
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn card_dump_restore_dry_run() {
+    use crate::nfc_impl::nfc_mock::{mifare_classic::VirtualMifareClassic, MockSmartcard};
+    let source = MockSmartcard::new(Box::new({
+        let mut card = VirtualMifareClassic::new(MifareClassicKind::Mini);
+        card.set_block(1, &[0x11; 16]);
+        card.set_block(4, &[0x22; 16]);
+        card
+    }));
+    let mfc = MifareClassic::from_atr(&source).unwrap();
+    let dump = CardDump::read_mifare_classic(&mfc, KeyDictionary::with_default_keys()).unwrap();
+
+    let target = MockSmartcard::new(Box::new(VirtualMifareClassic::new(MifareClassicKind::Mini)));
+    let dry_run = RestoreOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let changes = dump.restore(&target, dry_run).unwrap();
+    let addresses = changes.iter().map(|c| c.address).collect::<Vec<_>>();
+    assert_eq!(
+        addresses,
+        vec![DumpAddress::Block(1), DumpAddress::Block(4)]
+    );
+    assert_eq!(changes[0].current, Some(vec![0; 16]));
+
+    dump.restore(&target, RestoreOptions::default()).unwrap();
+    assert!(dump.restore(&target, dry_run).unwrap().is_empty());
+
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<DumpError>().unwrap().kind().clone();
+    // 構成の違うカードには書き戻さない
+    let other = MockSmartcard::new(Box::new(VirtualMifareClassic::new(
+        MifareClassicKind::Classic1k,
+    )));
+    assert_eq!(
+        kind(dump.restore(&other, dry_run).unwrap_err()),
+        DumpErrorKind::IncompatibleCard
+    );
+    // 書き戻し先のキーが辞書にもダンプにもないセクタは書き込めない
+    let target = MockSmartcard::new(Box::new({
+        let mut card = VirtualMifareClassic::new(MifareClassicKind::Mini);
+        card.set_keys(1, &[0x13; 6], &[0x31; 6]);
+        card
+    }));
+    assert_eq!(
+        kind(
+            dump.restore(&target, RestoreOptions::default())
+                .unwrap_err()
+        ),
+        DumpErrorKind::KeyNotFound(1)
+    );
+}
+
+#[test]
+fn card_dump_read_felica() {
+    use crate::felica::ndef::{NDEF_READ_SERVICE, NDEF_WRITE_SERVICE};
+    use crate::nfc_impl::nfc_mock::{felica::VirtualFelicaLiteS, MockSmartcard};
+
+    let mut card = VirtualFelicaLiteS::new();
+    card.set_block(0x01, &[0x5A; felica::BLOCK_SIZE]);
+    card.enable_service_search();
+    let nfc = MockSmartcard::new(Box::new(card));
+    let dump = CardDump::read_felica(&nfc, &[]).unwrap();
+    assert_eq!(dump.uid, [0x01, 0x2E, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+    let felica = match dump.content {
+        DumpContent::Felica(felica) => felica,
+        _ => unreachable!(),
+    };
+    assert_eq!(felica.pmm, [0x00, 0xF1, 0x00, 0x00, 0x00, 0x01, 0x43, 0x00]);
+    // 認証が要るサービス(1008)とエリアは読まない
+    let codes = felica
+        .services
+        .iter()
+        .map(|service| service.service_code)
+        .collect::<Vec<u16>>();
+    assert_eq!(codes, [NDEF_WRITE_SERVICE, NDEF_READ_SERVICE]);
+    // S_PAD0～13とREG
+    assert_eq!(felica.services[1].blocks.len(), 15);
+    assert_eq!(felica.services[1].blocks[1], [0x5A; felica::BLOCK_SIZE]);
+
+    // Search Service Codeに応答しないカードは、候補のうちRequest Serviceで見つかったものだけ
+    let nfc = MockSmartcard::new(Box::new(VirtualFelicaLiteS::new()));
+    let dump = CardDump::read_felica(&nfc, &[0x100B, NDEF_READ_SERVICE]).unwrap();
+    match dump.content {
+        DumpContent::Felica(felica) => {
+            assert_eq!(felica.services.len(), 1);
+            assert_eq!(felica.services[0].service_code, NDEF_READ_SERVICE);
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn card_dump_hex() {
+    assert_eq!(to_hex(&[0x00, 0xAB], " "), "00 AB");
+    assert_eq!(from_hex("00 ab"), Some(vec![0x00, 0xAB]));
+    assert_eq!(from_hex("0"), None);
+    assert_eq!(from_hex("zz"), None);
+}
+
+#[test]
+fn card_dump_ultralight_restore_user_memory() {
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    use crate::ultralight::version::UltralightModel;
+    let source = MockSmartcard::new(Box::new({
+        let mut card = VirtualUltralight::new(UltralightModel::Ntag213);
+        card.set_page(4, &[0x03, 0x00, 0xFE, 0x00]);
+        // 設定ページ(CFG0)は書き戻さない
+        card.set_page(0x29, &[0x04, 0x00, 0x00, 0x10]);
+        card
+    }));
+    let dump = CardDump::read_ultralight(&source).unwrap();
+    match &dump.content {
+        DumpContent::Ultralight(ul) => {
+            assert_eq!(ul.pages.len(), 45);
+            assert_eq!(ul.version.as_ref().unwrap()[6], 0x0F);
+            assert_eq!(ul.signature.as_ref().map(|s| s.len()), Some(32));
+        }
+        _ => panic!("not Ultralight"),
+    }
+
+    let target = MockSmartcard::new(Box::new(VirtualUltralight::new(UltralightModel::Ntag213)));
+    let changes = dump.restore(&target, RestoreOptions::default()).unwrap();
+    let addresses = changes.iter().map(|c| c.address).collect::<Vec<_>>();
+    assert_eq!(addresses, vec![DumpAddress::Page(4)]);
+}
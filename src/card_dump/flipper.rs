@@ -0,0 +1,341 @@
+// Flipper Zeroの.nfc形式
+// "キー: 値" の行が並ぶテキスト形式。読めなかったバイトは "??" で表す。
+// FeliCaのブロックデータは表現できないため対応しない。
+
+use super::{from_hex, to_hex, CardDump, DumpContent, DumpError, DumpErrorKind};
+use super::{MifareClassicDump, UltralightDump, PAGE_SIZE};
+use crate::mifare_classic::{MifareClassicKind, BLOCK_SIZE, KEY_SIZE};
+use crate::ultralight::version::{UltralightModel, UltralightVersion};
+use std::collections::HashMap;
+
+const UNKNOWN_BYTE: &str = "??";
+
+pub fn export(dump: &CardDump) -> Result<String, DumpError> {
+    let mut lines = vec![
+        "Filetype: Flipper NFC device".to_owned(),
+        "Version: 4".to_owned(),
+    ];
+    match &dump.content {
+        DumpContent::MifareClassic(mfc) => {
+            lines.push("Device type: Mifare Classic".to_owned());
+            lines.push(format!("UID: {}", to_hex(&dump.uid, " ")));
+            lines.push(format!("ATQA: {}", to_hex(&mfc.atqa, " ")));
+            lines.push(format!("SAK: {}", to_hex(&[mfc.sak], " ")));
+            // Flipperには2Kの種別がないので、4Kとして書き出し残りのブロックは未読にする
+            let kind = match mfc.kind {
+                MifareClassicKind::Mini => "MINI",
+                MifareClassicKind::Classic1k => "1K",
+                MifareClassicKind::Classic2k | MifareClassicKind::Classic4k => "4K",
+            };
+            lines.push(format!("Mifare Classic type: {}", kind));
+            lines.push("Data format version: 2".to_owned());
+            for (i, block) in mfc.blocks.iter().enumerate() {
+                lines.push(format!(
+                    "Block {}: {}",
+                    i,
+                    mifare_classic_block(mfc, i, block)
+                ));
+            }
+            let unread = MifareClassicKind::Classic4k.block_count();
+            for i in mfc.blocks.len()..unread {
+                lines.push(format!(
+                    "Block {}: {}",
+                    i,
+                    mifare_classic_block(mfc, i, &None)
+                ));
+            }
+        }
+        DumpContent::Ultralight(ul) => {
+            lines.push("Device type: NTAG/Ultralight".to_owned());
+            lines.push(format!("UID: {}", to_hex(&dump.uid, " ")));
+            lines.push(format!("ATQA: {}", to_hex(&ul.atqa, " ")));
+            lines.push(format!("SAK: {}", to_hex(&[ul.sak], " ")));
+            lines.push("Data format version: 2".to_owned());
+            lines.push(format!(
+                "NTAG/Ultralight type: {}",
+                ultralight_type(ul.version.as_deref())
+            ));
+            lines.push(format!(
+                "Signature: {}",
+                to_hex(ul.signature.as_deref().unwrap_or(&[0u8; 32]), " ")
+            ));
+            lines.push(format!(
+                "Mifare version: {}",
+                to_hex(ul.version.as_deref().unwrap_or(&[0u8; 8]), " ")
+            ));
+            for i in 0..3 {
+                lines.push(format!("Counter {}: 0", i));
+                lines.push(format!("Tearing {}: 00", i));
+            }
+            lines.push(format!("Pages total: {}", ul.pages.len()));
+            lines.push(format!("Pages read: {}", ul.pages.len()));
+            for (i, page) in ul.pages.iter().enumerate() {
+                lines.push(format!("Page {}: {}", i, to_hex(page, " ")));
+            }
+            lines.push("Failed authentication attempts: 0".to_owned());
+        }
+        DumpContent::Felica(_) => return Err(DumpError::new(DumpErrorKind::UnsupportedCard)),
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
+
+/// ブロックの表記。トレーラのキーは判明していない場合 "??" にする
+fn mifare_classic_block(
+    mfc: &MifareClassicDump,
+    index: usize,
+    block: &Option<[u8; BLOCK_SIZE]>,
+) -> String {
+    let block = match block {
+        Some(block) => block,
+        None => return vec![UNKNOWN_BYTE; BLOCK_SIZE].join(" "),
+    };
+    let mut bytes = block
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>();
+    if MifareClassicKind::is_trailer_block(index as u8) {
+        let keys = &mfc.keys[MifareClassicKind::sector_of_block(index as u8) as usize];
+        if keys.key_a.is_none() {
+            bytes[0..KEY_SIZE]
+                .iter_mut()
+                .for_each(|b| *b = UNKNOWN_BYTE.to_owned());
+        }
+        if keys.key_b.is_none() {
+            bytes[BLOCK_SIZE - KEY_SIZE..]
+                .iter_mut()
+                .for_each(|b| *b = UNKNOWN_BYTE.to_owned());
+        }
+    }
+    bytes.join(" ")
+}
+
+/// GET_VERSIONの応答から種別名を決める（不明な場合はUltralightとする）
+fn ultralight_type(version: Option<&[u8]>) -> &'static str {
+    let model = version
+        .and_then(UltralightVersion::from_bytes)
+        .map(|version| version.model());
+    match model {
+        Some(UltralightModel::Ntag210) => "NTAG210",
+        Some(UltralightModel::Ntag212) => "NTAG212",
+        Some(UltralightModel::Ntag213) => "NTAG213",
+        Some(UltralightModel::Ntag215) => "NTAG215",
+        Some(UltralightModel::Ntag216) => "NTAG216",
+        Some(UltralightModel::UltralightEv1Mf0ul11) => "Mifare Ultralight 11",
+        Some(UltralightModel::UltralightEv1Mf0ul21) => "Mifare Ultralight 21",
+        _ => "Mifare Ultralight",
+    }
+}
+
+pub fn import(text: &str) -> Result<CardDump, DumpError> {
+    let mut fields = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| DumpError::invalid_format(format!("invalid line {}", line)))?
+            .trim();
+        fields.insert(key, value);
+    }
+    if fields.get("Filetype") != Some(&"Flipper NFC device") {
+        return Err(DumpError::invalid_format("not a Flipper NFC file"));
+    }
+    let uid = hex_field(&fields, "UID")?;
+    match fields.get("Device type") {
+        Some(&"Mifare Classic") => import_mifare_classic(&fields, uid),
+        Some(&"NTAG/Ultralight") => import_ultralight(&fields, uid),
+        _ => Err(DumpError::new(DumpErrorKind::UnsupportedCard)),
+    }
+}
+
+fn hex_field(fields: &HashMap<&str, &str>, name: &str) -> Result<Vec<u8>, DumpError> {
+    fields
+        .get(name)
+        .and_then(|value| from_hex(value))
+        .ok_or_else(|| DumpError::invalid_format(format!("invalid {}", name)))
+}
+
+/// "??" を含む16進表記を読む（不明なバイトはNone）
+fn partial_hex(value: &str) -> Option<Vec<Option<u8>>> {
+    value
+        .split_whitespace()
+        .map(|byte| match byte {
+            UNKNOWN_BYTE => Some(None),
+            _ => u8::from_str_radix(byte, 16).ok().map(Some),
+        })
+        .collect()
+}
+
+fn import_mifare_classic(
+    fields: &HashMap<&str, &str>,
+    uid: Vec<u8>,
+) -> Result<CardDump, DumpError> {
+    let kind = match fields.get("Mifare Classic type") {
+        Some(&"MINI") => MifareClassicKind::Mini,
+        Some(&"1K") => MifareClassicKind::Classic1k,
+        Some(&"4K") => MifareClassicKind::Classic4k,
+        _ => return Err(DumpError::invalid_format("unknown Mifare Classic type")),
+    };
+    let mut mfc = MifareClassicDump::new(kind);
+    if let Ok(atqa) = hex_field(fields, "ATQA") {
+        if atqa.len() == 2 {
+            mfc.atqa = [atqa[0], atqa[1]];
+        }
+    }
+    if let Some(sak) = hex_field(fields, "SAK")
+        .ok()
+        .and_then(|s| s.first().cloned())
+    {
+        mfc.sak = sak;
+    }
+    for index in 0..kind.block_count() {
+        let name = format!("Block {}", index);
+        let bytes = match fields.get(name.as_str()) {
+            Some(value) => partial_hex(value)
+                .filter(|bytes| bytes.len() == BLOCK_SIZE)
+                .ok_or_else(|| DumpError::invalid_format(format!("invalid {}", name)))?,
+            None => continue,
+        };
+        if bytes.iter().all(|b| b.is_none()) {
+            continue;
+        }
+        let mut block = [0u8; BLOCK_SIZE];
+        for (dst, src) in block.iter_mut().zip(bytes.iter()) {
+            *dst = src.unwrap_or(0);
+        }
+        if MifareClassicKind::is_trailer_block(index as u8) {
+            let keys = &mut mfc.keys[MifareClassicKind::sector_of_block(index as u8) as usize];
+            if bytes[0..KEY_SIZE].iter().all(|b| b.is_some()) {
+                let mut key = [0u8; KEY_SIZE];
+                key.copy_from_slice(&block[0..KEY_SIZE]);
+                keys.key_a = Some(key);
+            }
+            if bytes[BLOCK_SIZE - KEY_SIZE..].iter().all(|b| b.is_some()) {
+                let mut key = [0u8; KEY_SIZE];
+                key.copy_from_slice(&block[BLOCK_SIZE - KEY_SIZE..]);
+                keys.key_b = Some(key);
+            }
+        } else if bytes.iter().any(|b| b.is_none()) {
+            // 一部だけ不明なデータブロックは扱えないため未読とする
+            continue;
+        }
+        mfc.blocks[index] = Some(block);
+    }
+    Ok(CardDump {
+        uid,
+        content: DumpContent::MifareClassic(mfc),
+    })
+}
+
+fn import_ultralight(fields: &HashMap<&str, &str>, uid: Vec<u8>) -> Result<CardDump, DumpError> {
+    let total = fields
+        .get("Pages read")
+        .or_else(|| fields.get("Pages total"))
+        .and_then(|value| value.parse::<usize>().ok())
+        .ok_or_else(|| DumpError::invalid_format("invalid Pages read"))?;
+    let mut pages = Vec::new();
+    for index in 0..total {
+        let name = format!("Page {}", index);
+        let data = fields
+            .get(name.as_str())
+            .and_then(|value| from_hex(value))
+            .filter(|data| data.len() == PAGE_SIZE)
+            .ok_or_else(|| DumpError::invalid_format(format!("invalid {}", name)))?;
+        let mut page = [0u8; PAGE_SIZE];
+        page.copy_from_slice(&data);
+        pages.push(page);
+    }
+    let mut ul = UltralightDump::new(pages);
+    if let Ok(atqa) = hex_field(fields, "ATQA") {
+        if atqa.len() == 2 {
+            ul.atqa = [atqa[0], atqa[1]];
+        }
+    }
+    if let Some(sak) = hex_field(fields, "SAK")
+        .ok()
+        .and_then(|s| s.first().cloned())
+    {
+        ul.sak = sak;
+    }
+    // 全て0は未取得として扱う
+    let not_zero = |data: Vec<u8>| Some(data).filter(|d| d.iter().any(|b| *b != 0));
+    ul.version = hex_field(fields, "Mifare version").ok().and_then(not_zero);
+    ul.signature = hex_field(fields, "Signature").ok().and_then(not_zero);
+    Ok(CardDump {
+        uid,
+        content: DumpContent::Ultralight(ul),
+    })
+}
+
+#[test]
+fn flipper_mifare_classic_roundtrip() {
+    let mut mfc = MifareClassicDump::new(MifareClassicKind::Mini);
+    mfc.blocks[0] = Some([
+        0x01, 0x02, 0x03, 0x04, 0x04, 0x08, 0x04, 0x00, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+        0x69,
+    ]);
+    mfc.blocks[3] = Some([
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69, 0, 0, 0, 0, 0, 0,
+    ]);
+    mfc.keys[0].key_a = Some([0xFF; KEY_SIZE]);
+    let dump = CardDump {
+        uid: vec![0x01, 0x02, 0x03, 0x04],
+        content: DumpContent::MifareClassic(mfc),
+    };
+    let text = export(&dump).unwrap();
+    assert!(text.contains("Mifare Classic type: MINI\n"));
+    assert!(text.contains("Block 3: FF FF FF FF FF FF FF 07 80 69 ?? ?? ?? ?? ?? ??\n"));
+    assert!(text.contains("Block 4: ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ??\n"));
+    assert_eq!(import(&text).unwrap(), dump);
+
+    // 2Kは4Kとして書き出し、存在しない32セクタ目以降は未読になる
+    let mut mfc = MifareClassicDump::new(MifareClassicKind::Classic2k);
+    mfc.blocks[126] = Some([0x5A; BLOCK_SIZE]);
+    let dump = CardDump {
+        uid: vec![0x01, 0x02, 0x03, 0x04],
+        content: DumpContent::MifareClassic(mfc),
+    };
+    let text = export(&dump).unwrap();
+    assert!(text.contains("Mifare Classic type: 4K\n"));
+    assert!(text.contains("Block 255: ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ?? ??\n"));
+    match import(&text).unwrap().content {
+        DumpContent::MifareClassic(mfc) => {
+            assert_eq!(mfc.kind, MifareClassicKind::Classic4k);
+            assert_eq!(mfc.blocks[126], Some([0x5A; BLOCK_SIZE]));
+            assert!(mfc.blocks[128..].iter().all(Option::is_none));
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn flipper_ultralight_roundtrip() {
+    let mut ul = UltralightDump::new(vec![[0x04, 0x11, 0x22, 0xBF]; 45]);
+    ul.version = Some(vec![0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03]);
+    let dump = CardDump {
+        uid: vec![0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+        content: DumpContent::Ultralight(ul),
+    };
+    let text = export(&dump).unwrap();
+    assert!(text.contains("NTAG/Ultralight type: NTAG213\n"));
+    assert_eq!(import(&text).unwrap(), dump);
+    assert_eq!(
+        import("Filetype: Flipper NFC device\nDevice type: FeliCa\nUID: 01\n")
+            .unwrap_err()
+            .kind(),
+        &DumpErrorKind::UnsupportedCard
+    );
+    let text = export(&dump)
+        .unwrap()
+        .replace("NTAG213", "NTAG213\nBroken line");
+    assert_eq!(
+        import(&text).unwrap_err().kind(),
+        &DumpErrorKind::InvalidFormat("invalid line Broken line".to_owned())
+    );
+}
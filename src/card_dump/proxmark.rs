@@ -0,0 +1,304 @@
+// Proxmark3のJSON形式
+// MIFARE Classicは FileType "mfcard"、Ultralight/NTAGは "mfu" で出力する。
+// FeliCaはProxmark3側に対応するダンプ形式がないため、
+// 同じ書式に倣った FileType "felica"（Card に IDm/PMm、Services にサービスごとのブロック）で出力する。
+
+use super::{from_hex, to_hex, CardDump, DumpContent, DumpError};
+use super::{FelicaDump, FelicaServiceDump, MifareClassicDump, UltralightDump, PAGE_SIZE};
+use crate::felica;
+use crate::mifare_classic::{MifareClassicKind, BLOCK_SIZE, KEY_SIZE};
+use serde_json::{json, Map, Value};
+
+pub fn export(dump: &CardDump) -> Result<String, DumpError> {
+    let value = match &dump.content {
+        DumpContent::MifareClassic(mfc) => export_mifare_classic(&dump.uid, mfc),
+        DumpContent::Ultralight(ul) => export_ultralight(&dump.uid, ul),
+        DumpContent::Felica(felica) => export_felica(&dump.uid, felica),
+    };
+    serde_json::to_string_pretty(&value).map_err(|e| DumpError::invalid_format(e.to_string()))
+}
+
+pub fn import(text: &str) -> Result<CardDump, DumpError> {
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| DumpError::invalid_format(e.to_string()))?;
+    match value["FileType"].as_str() {
+        Some("mfcard") => import_mifare_classic(&value),
+        Some("mfu") => import_ultralight(&value),
+        Some("felica") => import_felica(&value),
+        _ => Err(DumpError::invalid_format("unknown FileType")),
+    }
+}
+
+fn export_mifare_classic(uid: &[u8], mfc: &MifareClassicDump) -> Value {
+    let mut blocks = Map::new();
+    for (i, block) in mfc.blocks.iter().enumerate() {
+        if let Some(block) = block {
+            blocks.insert(i.to_string(), Value::String(to_hex(block, "")));
+        }
+    }
+    let mut sector_keys = Map::new();
+    for (sector, keys) in mfc.keys.iter().enumerate() {
+        let mut entry = Map::new();
+        if let Some(key_a) = keys.key_a {
+            entry.insert("KeyA".to_owned(), Value::String(to_hex(&key_a, "")));
+        }
+        if let Some(key_b) = keys.key_b {
+            entry.insert("KeyB".to_owned(), Value::String(to_hex(&key_b, "")));
+        }
+        let trailer = MifareClassicKind::trailer_block(sector as u8) as usize;
+        if let Some(trailer) = mfc.blocks[trailer] {
+            entry.insert(
+                "AccessConditions".to_owned(),
+                Value::String(to_hex(&trailer[6..10], "")),
+            );
+        }
+        sector_keys.insert(sector.to_string(), Value::Object(entry));
+    }
+    json!({
+        "Created": "nfc",
+        "FileType": "mfcard",
+        "Card": {
+            "UID": to_hex(uid, ""),
+            // Proxmark3は下位バイトを先に書く
+            "ATQA": to_hex(&[mfc.atqa[1], mfc.atqa[0]], ""),
+            "SAK": to_hex(&[mfc.sak], ""),
+        },
+        "blocks": blocks,
+        "SectorKeys": sector_keys,
+    })
+}
+
+fn export_ultralight(uid: &[u8], ul: &UltralightDump) -> Value {
+    let mut blocks = Map::new();
+    for (i, page) in ul.pages.iter().enumerate() {
+        blocks.insert(i.to_string(), Value::String(to_hex(page, "")));
+    }
+    json!({
+        "Created": "nfc",
+        "FileType": "mfu",
+        "Card": {
+            "UID": to_hex(uid, ""),
+            "Version": to_hex(ul.version.as_deref().unwrap_or(&[0u8; 8]), ""),
+            "TBO_0": "0000",
+            "TBO_1": "00",
+            "Signature": to_hex(ul.signature.as_deref().unwrap_or(&[0u8; 32]), ""),
+            "Counter0": "000000",
+            "Tearing0": "00",
+            "Counter1": "000000",
+            "Tearing1": "00",
+            "Counter2": "000000",
+            "Tearing2": "00",
+        },
+        "blocks": blocks,
+    })
+}
+
+fn export_felica(uid: &[u8], felica: &FelicaDump) -> Value {
+    let mut services = Map::new();
+    for service in felica.services.iter() {
+        let mut blocks = Map::new();
+        for (i, block) in service.blocks.iter().enumerate() {
+            blocks.insert(i.to_string(), Value::String(to_hex(block, "")));
+        }
+        services.insert(
+            format!("{:04X}", service.service_code),
+            Value::Object(blocks),
+        );
+    }
+    json!({
+        "Created": "nfc",
+        "FileType": "felica",
+        "Card": {
+            "IDm": to_hex(uid, ""),
+            "PMm": to_hex(&felica.pmm, ""),
+        },
+        "Services": services,
+    })
+}
+
+fn hex_field(value: &Value, name: &str) -> Result<Vec<u8>, DumpError> {
+    value[name]
+        .as_str()
+        .and_then(from_hex)
+        .ok_or_else(|| DumpError::invalid_format(format!("invalid {}", name)))
+}
+
+/// "blocks"のような 番号→16進文字列 のオブジェクトを取り出す
+fn numbered_blocks(value: &Value, size: usize) -> Result<Vec<(usize, Vec<u8>)>, DumpError> {
+    let blocks = value
+        .as_object()
+        .ok_or_else(|| DumpError::invalid_format("blocks missing"))?;
+    let mut result = Vec::new();
+    for (index, data) in blocks.iter() {
+        let index = index
+            .parse::<usize>()
+            .map_err(|_| DumpError::invalid_format(format!("invalid block number {}", index)))?;
+        let data = data
+            .as_str()
+            .and_then(from_hex)
+            .filter(|data| data.len() == size)
+            .ok_or_else(|| DumpError::invalid_format(format!("invalid block {}", index)))?;
+        result.push((index, data));
+    }
+    result.sort_by_key(|(index, _)| *index);
+    Ok(result)
+}
+
+fn key_field(value: &Value, name: &str) -> Option<[u8; KEY_SIZE]> {
+    let data = value[name].as_str().and_then(from_hex)?;
+    let mut key = [0u8; KEY_SIZE];
+    if data.len() != KEY_SIZE {
+        return None;
+    }
+    key.copy_from_slice(&data);
+    Some(key)
+}
+
+fn import_mifare_classic(value: &Value) -> Result<CardDump, DumpError> {
+    let card = &value["Card"];
+    let uid = hex_field(card, "UID")?;
+    let blocks = numbered_blocks(&value["blocks"], BLOCK_SIZE)?;
+    let sak = hex_field(card, "SAK")
+        .ok()
+        .and_then(|sak| sak.first().cloned());
+    let max_block = blocks.last().map(|(index, _)| *index).unwrap_or(0);
+    let kind = match sak {
+        Some(0x09) => MifareClassicKind::Mini,
+        Some(0x18) => MifareClassicKind::Classic4k,
+        _ if max_block >= 128 => MifareClassicKind::Classic4k,
+        _ if max_block >= 64 => MifareClassicKind::Classic2k,
+        _ => MifareClassicKind::Classic1k,
+    };
+    let mut mfc = MifareClassicDump::new(kind);
+    if let Ok(atqa) = hex_field(card, "ATQA") {
+        if atqa.len() == 2 {
+            mfc.atqa = [atqa[1], atqa[0]];
+        }
+    }
+    if let Some(sak) = sak {
+        mfc.sak = sak;
+    }
+    for (index, data) in blocks {
+        let block = mfc
+            .blocks
+            .get_mut(index)
+            .ok_or_else(|| DumpError::invalid_format(format!("block {} out of range", index)))?;
+        let mut bytes = [0u8; BLOCK_SIZE];
+        bytes.copy_from_slice(&data);
+        *block = Some(bytes);
+    }
+    for (sector, keys) in mfc.keys.iter_mut().enumerate() {
+        let entry = &value["SectorKeys"][sector.to_string()];
+        keys.key_a = key_field(entry, "KeyA");
+        keys.key_b = key_field(entry, "KeyB");
+    }
+    Ok(CardDump {
+        uid,
+        content: DumpContent::MifareClassic(mfc),
+    })
+}
+
+fn import_ultralight(value: &Value) -> Result<CardDump, DumpError> {
+    let card = &value["Card"];
+    let uid = hex_field(card, "UID")?;
+    let pages = numbered_blocks(&value["blocks"], PAGE_SIZE)?;
+    if pages.iter().enumerate().any(|(i, (index, _))| i != *index) {
+        return Err(DumpError::invalid_format("pages are not contiguous"));
+    }
+    let pages = pages
+        .into_iter()
+        .map(|(_, data)| {
+            let mut page = [0u8; PAGE_SIZE];
+            page.copy_from_slice(&data);
+            page
+        })
+        .collect();
+    let mut ul = UltralightDump::new(pages);
+    // 全て0は未取得として扱う
+    let not_zero = |data: Vec<u8>| Some(data).filter(|d| d.iter().any(|b| *b != 0));
+    ul.version = hex_field(card, "Version").ok().and_then(not_zero);
+    ul.signature = hex_field(card, "Signature").ok().and_then(not_zero);
+    Ok(CardDump {
+        uid,
+        content: DumpContent::Ultralight(ul),
+    })
+}
+
+fn import_felica(value: &Value) -> Result<CardDump, DumpError> {
+    let card = &value["Card"];
+    let uid = hex_field(card, "IDm")?;
+    let pmm = hex_field(card, "PMm")?;
+    let mut services = Vec::new();
+    if let Some(entries) = value["Services"].as_object() {
+        for (code, blocks) in entries.iter() {
+            let service_code = u16::from_str_radix(code, 16)
+                .map_err(|_| DumpError::invalid_format(format!("invalid service {}", code)))?;
+            let blocks = numbered_blocks(blocks, felica::BLOCK_SIZE)?
+                .into_iter()
+                .map(|(_, data)| {
+                    let mut block = [0u8; felica::BLOCK_SIZE];
+                    block.copy_from_slice(&data);
+                    block
+                })
+                .collect();
+            services.push(FelicaServiceDump {
+                service_code,
+                blocks,
+            });
+        }
+    }
+    Ok(CardDump {
+        uid,
+        content: DumpContent::Felica(FelicaDump { pmm, services }),
+    })
+}
+
+#[test]
+fn proxmark_mifare_classic_roundtrip() {
+    let mut mfc = MifareClassicDump::new(MifareClassicKind::Classic4k);
+    mfc.blocks[0] = Some([0x01; BLOCK_SIZE]);
+    mfc.blocks[255] = Some([
+        0, 0, 0, 0, 0, 0, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    ]);
+    mfc.keys[39].key_b = Some([0xFF; KEY_SIZE]);
+    let dump = CardDump {
+        uid: vec![0x01, 0x02, 0x03, 0x04],
+        content: DumpContent::MifareClassic(mfc),
+    };
+    let text = export(&dump).unwrap();
+    let value: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value["Card"]["ATQA"], "0200");
+    assert_eq!(value["SectorKeys"]["39"]["AccessConditions"], "FF078069");
+    assert_eq!(import(&text).unwrap(), dump);
+}
+
+#[test]
+fn proxmark_ultralight_and_felica_roundtrip() {
+    let mut ul = UltralightDump::new(vec![[0x04, 0x11, 0x22, 0xBF]; 16]);
+    ul.version = Some(vec![0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0B, 0x03]);
+    let dump = CardDump {
+        uid: vec![0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+        content: DumpContent::Ultralight(ul),
+    };
+    assert_eq!(import(&export(&dump).unwrap()).unwrap(), dump);
+
+    let dump = CardDump {
+        uid: vec![0x01; 8],
+        content: DumpContent::Felica(FelicaDump {
+            pmm: vec![0x02; 8],
+            services: vec![FelicaServiceDump {
+                service_code: 0x090F,
+                blocks: vec![[0x33; 16]; 3],
+            }],
+        }),
+    };
+    assert_eq!(import(&export(&dump).unwrap()).unwrap(), dump);
+    assert_eq!(
+        import("{\"FileType\": \"unknown\"}").unwrap_err().kind(),
+        &super::DumpErrorKind::InvalidFormat("unknown FileType".to_owned())
+    );
+    assert!(matches!(
+        import("{\"FileType\": ").unwrap_err().kind(),
+        super::DumpErrorKind::InvalidFormat(_)
+    ));
+}
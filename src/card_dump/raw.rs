@@ -0,0 +1,127 @@
+// 生のバイナリ形式（.mfd / .bin）
+// ブロック・ページを先頭から順に並べただけの形式。
+// 読み出せなかったブロックは0で埋めるため、取り込み時には読めたものとして扱われる。
+// FeliCaはサービスの構成を表現できないため対応しない。
+
+use super::PAGE_SIZE;
+use super::{CardDump, DumpContent, DumpError, DumpErrorKind, MifareClassicDump, UltralightDump};
+use crate::mifare_classic::{MifareClassicKind, BLOCK_SIZE};
+
+pub fn export(dump: &CardDump) -> Result<Vec<u8>, DumpError> {
+    match &dump.content {
+        DumpContent::MifareClassic(mfc) => Ok(mfc
+            .blocks
+            .iter()
+            .flat_map(|block| block.unwrap_or([0u8; BLOCK_SIZE]).to_vec())
+            .collect()),
+        DumpContent::Ultralight(ul) => Ok(ul.pages.iter().flat_map(|p| p.to_vec()).collect()),
+        DumpContent::Felica(_) => Err(DumpError::new(DumpErrorKind::UnsupportedCard)),
+    }
+}
+
+/// MIFARE Classicの.mfdを取り込む（サイズからMini/1K/2K/4Kを判定する）
+/// トレーラに含まれるキーはダンプのキーとして扱う
+pub fn import_mifare_classic(data: &[u8]) -> Result<CardDump, DumpError> {
+    let kind = [
+        MifareClassicKind::Mini,
+        MifareClassicKind::Classic1k,
+        MifareClassicKind::Classic2k,
+        MifareClassicKind::Classic4k,
+    ]
+    .iter()
+    .find(|kind| kind.block_count() * BLOCK_SIZE == data.len())
+    .cloned()
+    .ok_or_else(|| DumpError::invalid_format(format!("unexpected size {}", data.len())))?;
+    let mut mfc = MifareClassicDump::new(kind);
+    for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(chunk);
+        mfc.blocks[i] = Some(block);
+    }
+    for (sector, keys) in mfc.keys.iter_mut().enumerate() {
+        let trailer = &data[MifareClassicKind::trailer_block(sector as u8) as usize * BLOCK_SIZE..];
+        let mut key_a = [0u8; 6];
+        let mut key_b = [0u8; 6];
+        key_a.copy_from_slice(&trailer[0..6]);
+        key_b.copy_from_slice(&trailer[10..16]);
+        keys.key_a = Some(key_a);
+        keys.key_b = Some(key_b);
+    }
+    Ok(CardDump {
+        // 4バイトUIDとみなす
+        uid: data[0..4].to_vec(),
+        content: DumpContent::MifareClassic(mfc),
+    })
+}
+
+/// Ultralight/NTAGのページを並べたバイナリを取り込む
+pub fn import_ultralight(data: &[u8]) -> Result<CardDump, DumpError> {
+    if !data.chunks_exact(PAGE_SIZE).remainder().is_empty() || data.len() < PAGE_SIZE * 2 {
+        return Err(DumpError::invalid_format(format!(
+            "unexpected size {}",
+            data.len()
+        )));
+    }
+    let pages = data
+        .chunks(PAGE_SIZE)
+        .map(|chunk| {
+            let mut page = [0u8; PAGE_SIZE];
+            page.copy_from_slice(chunk);
+            page
+        })
+        .collect::<Vec<_>>();
+    // UID0-2 | BCC0 | UID3-6
+    let uid = pages[0][0..3]
+        .iter()
+        .chain(pages[1].iter())
+        .cloned()
+        .collect();
+    Ok(CardDump {
+        uid,
+        content: DumpContent::Ultralight(UltralightDump::new(pages)),
+    })
+}
+
+#[test]
+fn raw_mifare_classic_roundtrip() {
+    let mut data = vec![0u8; 1024];
+    data[0..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+    for sector in 0..16 {
+        let offset = (sector * 4 + 3) * BLOCK_SIZE;
+        data[offset..offset + 16].copy_from_slice(&[
+            0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF,
+        ]);
+    }
+    let dump = import_mifare_classic(&data).unwrap();
+    assert_eq!(dump.uid, vec![0xDE, 0xAD, 0xBE, 0xEF]);
+    match &dump.content {
+        DumpContent::MifareClassic(mfc) => {
+            assert_eq!(mfc.kind, MifareClassicKind::Classic1k);
+            assert_eq!(
+                mfc.keys[5].key_a,
+                Some([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5])
+            );
+        }
+        _ => panic!("not MIFARE Classic"),
+    }
+    assert_eq!(export(&dump).unwrap(), data);
+    assert_eq!(
+        import_mifare_classic(&data[..1000]).unwrap_err().kind(),
+        &DumpErrorKind::InvalidFormat("unexpected size 1000".to_owned())
+    );
+    // 2048バイトはMIFARE Plus 2K(SL1)のダンプ
+    match import_mifare_classic(&[0u8; 2048]).unwrap().content {
+        DumpContent::MifareClassic(mfc) => assert_eq!(mfc.kind, MifareClassicKind::Classic2k),
+        _ => panic!("not MIFARE Classic"),
+    }
+}
+
+#[test]
+fn raw_ultralight_uid() {
+    let mut data = vec![0u8; 64];
+    data[0..8].copy_from_slice(&[0x04, 0x11, 0x22, 0xBF, 0x33, 0x44, 0x55, 0x66]);
+    let dump = import_ultralight(&data).unwrap();
+    assert_eq!(dump.uid, vec![0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    assert_eq!(export(&dump).unwrap(), data);
+}
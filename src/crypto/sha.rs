@@ -0,0 +1,16 @@
+// SHA-1・SHA-256
+// eMRTDの鍵導出(KDF)とEF.SODのハッシュの照合に使う。ハッシュ本体はsha1/sha2クレートを使う
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub const SHA1_SIZE: usize = 20;
+pub const SHA256_SIZE: usize = 32;
+
+pub fn sha1(data: &[u8]) -> [u8; SHA1_SIZE] {
+    Sha1::digest(data).into()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_SIZE] {
+    Sha256::digest(data).into()
+}
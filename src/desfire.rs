@@ -0,0 +1,950 @@
+// MIFARE DESFire EV1/EV2 のネイティブコマンド
+// ISO7816-4で包んだAPDU(CLA 90)で送り、応答のSW1は91、SW2がネイティブのステータスになる。
+// 1フレームに収まらないデータはステータスAF(続きあり)を挟み、追加フレーム(コマンドAF)で送受信する。
+// 認証するとセッションを保持し、以降のコマンドにMAC・暗号化をファイルの設定に合わせて掛ける。
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::crypto::secret_random_bytes;
+use crate::pc_sc_standard::ApduBuilderExtWithDesfire;
+use crate::smart_card::{Smartcard, TransmitError};
+use std::cell::{Cell, RefCell};
+
+pub mod auth;
+pub mod file;
+pub mod key;
+pub mod session;
+pub mod version;
+use auth::{AuthMethod, DesfireAuth};
+use file::{read_u24, write_u24, CommunicationMode, FileSettings};
+use key::DesfireKey;
+use session::{SecureMessaging, Session};
+use version::DesfireVersion;
+
+// コマンド
+const CMD_GET_VERSION: u8 = 0x60;
+const CMD_GET_APPLICATION_IDS: u8 = 0x6A;
+const CMD_SELECT_APPLICATION: u8 = 0x5A;
+const CMD_CREATE_APPLICATION: u8 = 0xCA;
+const CMD_DELETE_APPLICATION: u8 = 0xDA;
+const CMD_GET_KEY_SETTINGS: u8 = 0x45;
+const CMD_CHANGE_KEY_SETTINGS: u8 = 0x54;
+const CMD_GET_KEY_VERSION: u8 = 0x64;
+const CMD_CHANGE_KEY: u8 = 0xC4;
+const CMD_GET_FREE_MEMORY: u8 = 0x6E;
+const CMD_FORMAT_PICC: u8 = 0xFC;
+const CMD_GET_FILE_IDS: u8 = 0x6F;
+const CMD_GET_FILE_SETTINGS: u8 = 0xF5;
+const CMD_DELETE_FILE: u8 = 0xDF;
+const CMD_READ_DATA: u8 = 0xBD;
+const CMD_WRITE_DATA: u8 = 0x3D;
+const CMD_GET_VALUE: u8 = 0x6C;
+const CMD_CREDIT: u8 = 0x0C;
+const CMD_DEBIT: u8 = 0xDC;
+const CMD_LIMITED_CREDIT: u8 = 0x1C;
+const CMD_WRITE_RECORD: u8 = 0x3B;
+const CMD_READ_RECORDS: u8 = 0xBB;
+const CMD_CLEAR_RECORD_FILE: u8 = 0xEB;
+const CMD_COMMIT_TRANSACTION: u8 = 0xC7;
+const CMD_ABORT_TRANSACTION: u8 = 0xA7;
+const CMD_ADDITIONAL_FRAME: u8 = 0xAF;
+
+/// ISOラップの応答のSW1
+const SW1_DESFIRE: u8 = 0x91;
+const STATUS_OK: u8 = 0x00;
+const STATUS_NO_CHANGES: u8 = 0x0C;
+const STATUS_ADDITIONAL_FRAME: u8 = 0xAF;
+/// 1フレームで送るデータの最大長（カードの受信バッファに合わせる）
+pub const MAX_FRAME_SIZE: usize = 59;
+/// PICC(カード全体)を表すAID
+pub const PICC_AID: u32 = 0x000000;
+
+/// 鍵の暗号方式（CreateApplicationの鍵数の上位2ビット）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DesfireKeyType {
+    /// DES/2K3DES
+    Des = 0x00,
+    Tdes3k = 0x40,
+    Aes = 0x80,
+}
+
+impl DesfireKeyType {
+    pub fn from_key_count(value: u8) -> Self {
+        match value & 0xC0 {
+            0x40 => DesfireKeyType::Tdes3k,
+            0x80 => DesfireKeyType::Aes,
+            _ => DesfireKeyType::Des,
+        }
+    }
+}
+
+/// マスターキーの設定(KeySettings)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeySettings {
+    /// 鍵の変更に必要な鍵番号（0x0E: その鍵自身、0x0F: 変更不可。PICCでは使わない）
+    pub change_key: u8,
+    /// 設定を変更できる
+    pub configuration_changeable: bool,
+    /// マスターキーの認証なしでアプリケーション・ファイルを作成・削除できる
+    pub free_create_delete: bool,
+    /// マスターキーの認証なしで一覧を取得できる
+    pub free_directory_list: bool,
+    /// マスターキーを変更できる
+    pub master_key_changeable: bool,
+}
+
+impl KeySettings {
+    pub fn from_u8(value: u8) -> Self {
+        KeySettings {
+            change_key: value >> 4,
+            configuration_changeable: value & 0x08 != 0,
+            free_create_delete: value & 0x04 != 0,
+            free_directory_list: value & 0x02 != 0,
+            master_key_changeable: value & 0x01 != 0,
+        }
+    }
+    pub fn to_u8(self) -> u8 {
+        (self.change_key & 0x0F) << 4
+            | (self.configuration_changeable as u8) << 3
+            | (self.free_create_delete as u8) << 2
+            | (self.free_directory_list as u8) << 1
+            | self.master_key_changeable as u8
+    }
+}
+
+impl Default for KeySettings {
+    /// 出荷時の設定(0x0F)
+    fn default() -> Self {
+        Self::from_u8(0x0F)
+    }
+}
+
+pub struct Desfire<'a> {
+    nfc: &'a dyn Smartcard,
+    /// 認証中のセッション
+    session: RefCell<Option<Session>>,
+    /// 選択中のアプリケーション
+    selected: Cell<u32>,
+}
+
+impl<'a> Desfire<'a> {
+    pub fn new(nfc: &'a dyn Smartcard) -> Self {
+        Desfire {
+            nfc,
+            session: RefCell::new(None),
+            selected: Cell::new(PICC_AID),
+        }
+    }
+    /// ネイティブコマンドを送り、応答のデータを返す
+    /// dataが1フレームに収まらなければ分割して送り、応答の追加フレームも全てつなげて返す
+    /// 認証中は平文のコマンドとして扱う（EV1では応答のCMACを検証する）
+    pub fn transceive(
+        &self,
+        command: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.transceive_with(command, data, &[], CommunicationMode::Plain)
+    }
+    /// headerに続くdataをmodeで保護して送る
+    /// dataがなければ応答のデータをmodeで、あれば応答のMACだけを検証する
+    pub fn transceive_with(
+        &self,
+        command: u8,
+        header: &[u8],
+        data: &[u8],
+        mode: CommunicationMode,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let response_mode = match mode {
+            CommunicationMode::Plain => CommunicationMode::Plain,
+            _ if data.is_empty() => mode,
+            _ => CommunicationMode::Maced,
+        };
+        self.transceive_secure(command, header, data, mode, Some(response_mode), None)
+    }
+    /// response_modeがNoneならこのコマンドでセッションが終わる（応答にMACが付かない）
+    fn transceive_secure(
+        &self,
+        command: u8,
+        header: &[u8],
+        data: &[u8],
+        mode: CommunicationMode,
+        response_mode: Option<CommunicationMode>,
+        new_key: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let payload = match self.session.borrow_mut().as_mut() {
+            Some(session) => session.wrap_command(command, header, data, mode, new_key),
+            None => [header, data].concat(),
+        };
+        let result = self
+            .exchange_frames(command, &payload)
+            .and_then(|(status, response)| {
+                Self::check_status(status)?;
+                let mut session = self.session.borrow_mut();
+                match (session.as_mut(), response_mode) {
+                    (Some(session), Some(mode)) => {
+                        Ok(session.unwrap_response(status, &response, mode)?)
+                    }
+                    _ => Ok(response),
+                }
+            });
+        // エラーになるとカードも認証を解除する
+        if result.is_err() || response_mode.is_none() {
+            self.session.replace(None);
+        }
+        result
+    }
+    /// 追加フレームを処理し、最後のステータスと応答のデータを返す
+    fn exchange_frames(
+        &self,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(u8, Vec<u8>), Box<dyn std::error::Error>> {
+        let mut frames = data.chunks(MAX_FRAME_SIZE).collect::<Vec<&[u8]>>();
+        if frames.is_empty() {
+            frames.push(&[]);
+        }
+        let mut code = command;
+        let mut response = Vec::new();
+        let mut status = STATUS_OK;
+        for (i, frame) in frames.iter().enumerate() {
+            let (frame_status, frame_data) = self.exchange(code, frame)?;
+            code = CMD_ADDITIONAL_FRAME;
+            status = frame_status;
+            if i + 1 < frames.len() {
+                // 続きを送れるのはカードがAFを返したときだけ
+                if status != STATUS_ADDITIONAL_FRAME {
+                    Self::check_status(status)?;
+                    return Err(Box::new(DesfireError::new(
+                        DesfireErrorKind::UnexpectedStatus(status),
+                    )));
+                }
+                continue;
+            }
+            response.extend_from_slice(&frame_data);
+        }
+        while status == STATUS_ADDITIONAL_FRAME {
+            let (frame_status, frame_data) = self.exchange(CMD_ADDITIONAL_FRAME, &[])?;
+            status = frame_status;
+            response.extend_from_slice(&frame_data);
+        }
+        Ok((status, response))
+    }
+    /// 1フレームを送り、ステータスとデータを返す
+    fn exchange(
+        &self,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(u8, Vec<u8>), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().desfire_command(command, data).build();
+        match self.nfc.transmit(Box::new(apdu)) {
+            // 91 00以外は全てTransmitErrorになる
+            Ok(data) => Ok((STATUS_OK, data)),
+            Err(e) => match e.downcast_ref::<TransmitError>() {
+                Some(error) => match error.status_word() {
+                    Some((SW1_DESFIRE, status)) => Ok((status, error.data().to_vec())),
+                    _ => Err(e),
+                },
+                None => Err(e),
+            },
+        }
+    }
+    fn check_status(status: u8) -> Result<(), DesfireError> {
+        match status {
+            STATUS_OK | STATUS_NO_CHANGES => Ok(()),
+            _ => Err(DesfireError::new(DesfireErrorKind::Status(
+                DesfireStatus::from(status),
+            ))),
+        }
+    }
+    fn invalid_response(len: usize) -> Box<dyn std::error::Error> {
+        Box::new(DesfireError::new(DesfireErrorKind::InvalidResponse(len)))
+    }
+
+    /// 選択中のアプリケーション(またはPICC)の鍵で認証する
+    pub fn authenticate(
+        &self,
+        method: AuthMethod,
+        key_no: u8,
+        key: &DesfireKey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rnd_a = secret_random_bytes(method.random_size(key))?;
+        self.authenticate_with(method, key_no, key, &rnd_a)
+    }
+    /// RndAを指定して認証する
+    pub fn authenticate_with(
+        &self,
+        method: AuthMethod,
+        key_no: u8,
+        key: &DesfireKey,
+        rnd_a: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.session.replace(None);
+        let mut auth = DesfireAuth::new(method, key_no, key, rnd_a)?;
+        let (status, res) = self.exchange(method as u8, &method.command_data(key_no))?;
+        if status != STATUS_ADDITIONAL_FRAME {
+            Self::check_status(status)?;
+            return Err(Box::new(DesfireError::new(
+                DesfireErrorKind::UnexpectedStatus(status),
+            )));
+        }
+        let token = auth.challenge_response(&res)?;
+        let (status, res) = self.exchange(CMD_ADDITIONAL_FRAME, &token)?;
+        Self::check_status(status)?;
+        self.session.replace(Some(auth.verify(&res)?));
+        Ok(())
+    }
+    /// 認証中の鍵番号
+    pub fn authenticated_key(&self) -> Option<u8> {
+        self.session.borrow().as_ref().map(Session::key_no)
+    }
+    /// 鍵を変更する。認証中の鍵以外を変更するときはold_keyに現在の鍵を渡す
+    /// PICCのマスターキーはnew_keyの暗号方式に切り替わる
+    pub fn change_key(
+        &self,
+        key_no: u8,
+        new_key: &DesfireKey,
+        old_key: Option<&DesfireKey>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (authenticated, messaging) = match self.session.borrow().as_ref() {
+            Some(session) => (session.key_no(), session.messaging()),
+            None => {
+                return Err(Box::new(DesfireError::new(
+                    DesfireErrorKind::NotAuthenticated,
+                )))
+            }
+        };
+        let invalid_key = || DesfireError::new(DesfireErrorKind::InvalidKey);
+        if messaging == SecureMessaging::Legacy && new_key.key_type() != DesfireKeyType::Des {
+            return Err(Box::new(invalid_key()));
+        }
+        let new_bytes = new_key.change_key_bytes();
+        let same = key_no == authenticated;
+        let mut data = new_bytes.clone();
+        if !same {
+            let old_bytes = old_key.ok_or_else(invalid_key)?.change_key_bytes();
+            for (byte, old) in data.iter_mut().zip(old_bytes.iter()) {
+                *byte ^= old;
+            }
+        }
+        if key_version_appended(messaging, new_key.key_type()) {
+            data.push(new_key.version());
+        }
+        let key_no = if self.selected.get() == PICC_AID {
+            key_no | new_key.key_type() as u8
+        } else {
+            key_no
+        };
+        // 認証中の鍵を変えるとセッションは終わる
+        let (response_mode, crc_key) = if same {
+            (None, None)
+        } else {
+            (Some(CommunicationMode::Maced), Some(&new_bytes[..]))
+        };
+        self.transceive_secure(
+            CMD_CHANGE_KEY,
+            &[key_no],
+            &data,
+            CommunicationMode::Enciphered,
+            response_mode,
+            crc_key,
+        )?;
+        Ok(())
+    }
+    /// 鍵設定を変更する（マスターキーで認証しておくこと）
+    pub fn change_key_settings(
+        &self,
+        settings: KeySettings,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.session.borrow().is_none() {
+            return Err(Box::new(DesfireError::new(
+                DesfireErrorKind::NotAuthenticated,
+            )));
+        }
+        self.transceive_with(
+            CMD_CHANGE_KEY_SETTINGS,
+            &[],
+            &[settings.to_u8()],
+            CommunicationMode::Enciphered,
+        )?;
+        Ok(())
+    }
+    pub fn get_key_version(&self, key_no: u8) -> Result<u8, Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_KEY_VERSION, &[key_no])?;
+        match res[..] {
+            [version] => Ok(version),
+            _ => Err(Self::invalid_response(res.len())),
+        }
+    }
+    /// ファイルのデータの保護。認証していなければ平文
+    fn communication_mode(
+        &self,
+        file_no: u8,
+        write: bool,
+    ) -> Result<CommunicationMode, Box<dyn std::error::Error>> {
+        if self.session.borrow().is_none() {
+            return Ok(CommunicationMode::Plain);
+        }
+        let settings = self.get_file_settings(file_no)?;
+        Ok(self.communication_with(&settings, write))
+    }
+    /// 取得済みのファイル設定でのデータの保護
+    fn communication_with(&self, settings: &FileSettings, write: bool) -> CommunicationMode {
+        if self.session.borrow().is_none() {
+            CommunicationMode::Plain
+        } else {
+            settings.communication_for(write)
+        }
+    }
+
+    pub fn get_version(&self) -> Result<DesfireVersion, Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_VERSION, &[])?;
+        DesfireVersion::from_bytes(&res).ok_or_else(|| Self::invalid_response(res.len()))
+    }
+    /// PICCに登録されているアプリケーションのAID一覧
+    pub fn get_application_ids(&self) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_APPLICATION_IDS, &[])?;
+        if !res.chunks_exact(3).remainder().is_empty() {
+            return Err(Self::invalid_response(res.len()));
+        }
+        Ok(res.chunks_exact(3).map(read_u24).collect())
+    }
+    /// アプリケーションを選択する（PICC_AIDでPICCに戻る）
+    pub fn select_application(&self, aid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        // 選択すると認証は解除される
+        self.session.replace(None);
+        self.transceive(CMD_SELECT_APPLICATION, &write_u24(aid))?;
+        self.selected.set(aid);
+        Ok(())
+    }
+    /// PICCを選択した状態で実行する
+    pub fn create_application(
+        &self,
+        aid: u32,
+        settings: KeySettings,
+        key_count: u8,
+        key_type: DesfireKeyType,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = write_u24(aid).to_vec();
+        data.push(settings.to_u8());
+        data.push(key_count & 0x0F | key_type as u8);
+        self.transceive(CMD_CREATE_APPLICATION, &data)?;
+        Ok(())
+    }
+    pub fn delete_application(&self, aid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_DELETE_APPLICATION, &write_u24(aid))?;
+        Ok(())
+    }
+    /// 選択中のアプリケーション(またはPICC)の鍵設定・鍵の数・暗号方式
+    pub fn get_key_settings(
+        &self,
+    ) -> Result<(KeySettings, u8, DesfireKeyType), Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_KEY_SETTINGS, &[])?;
+        if res.len() != 2 {
+            return Err(Self::invalid_response(res.len()));
+        }
+        Ok((
+            KeySettings::from_u8(res[0]),
+            res[1] & 0x0F,
+            DesfireKeyType::from_key_count(res[1]),
+        ))
+    }
+    /// 空き容量(バイト)
+    pub fn get_free_memory(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_FREE_MEMORY, &[])?;
+        if res.len() != 3 {
+            return Err(Self::invalid_response(res.len()));
+        }
+        Ok(read_u24(&res))
+    }
+    /// 全てのアプリケーションを削除する（PICCのマスターキーで認証が必要）
+    pub fn format_picc(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_FORMAT_PICC, &[])?;
+        Ok(())
+    }
+    pub fn get_file_ids(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.transceive(CMD_GET_FILE_IDS, &[])
+    }
+    pub fn get_file_settings(
+        &self,
+        file_no: u8,
+    ) -> Result<FileSettings, Box<dyn std::error::Error>> {
+        let res = self.transceive(CMD_GET_FILE_SETTINGS, &[file_no])?;
+        Ok(FileSettings::parse(&res)?)
+    }
+    /// settingsの種別のファイルを作成する
+    pub fn create_file(
+        &self,
+        file_no: u8,
+        settings: &FileSettings,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = vec![file_no];
+        data.extend_from_slice(&settings.to_create_data());
+        self.transceive(settings.file_type().create_command(), &data)?;
+        Ok(())
+    }
+    pub fn delete_file(&self, file_no: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_DELETE_FILE, &[file_no])?;
+        Ok(())
+    }
+    /// データファイル(標準・バックアップ)を読む。lengthが0ならoffset以降すべて
+    pub fn read_data(
+        &self,
+        file_no: u8,
+        offset: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mode = self.communication_mode(file_no, false)?;
+        let header = Self::file_range(file_no, offset, length);
+        self.transceive_with(CMD_READ_DATA, &header, &[], mode)
+    }
+    /// データファイルに書き込む。バックアップファイルはcommit_transactionで確定する
+    pub fn write_data(
+        &self,
+        file_no: u8,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mode = self.communication_mode(file_no, true)?;
+        let header = Self::file_range(file_no, offset, data.len() as u32);
+        self.transceive_with(CMD_WRITE_DATA, &header, data, mode)?;
+        Ok(())
+    }
+    pub fn get_value(&self, file_no: u8) -> Result<i32, Box<dyn std::error::Error>> {
+        let mode = self.communication_mode(file_no, false)?;
+        let res = self.transceive_with(CMD_GET_VALUE, &[file_no], &[], mode)?;
+        if res.len() != 4 {
+            return Err(Self::invalid_response(res.len()));
+        }
+        Ok(i32::from_le_bytes([res[0], res[1], res[2], res[3]]))
+    }
+    /// 値を加算する（commit_transactionで確定）
+    pub fn credit(&self, file_no: u8, amount: i32) -> Result<(), Box<dyn std::error::Error>> {
+        self.value_operation(CMD_CREDIT, file_no, amount)
+    }
+    pub fn debit(&self, file_no: u8, amount: i32) -> Result<(), Box<dyn std::error::Error>> {
+        self.value_operation(CMD_DEBIT, file_no, amount)
+    }
+    /// 直前のトランザクションで減算した額までを、クレジット用の鍵なしで加算する
+    pub fn limited_credit(
+        &self,
+        file_no: u8,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.value_operation(CMD_LIMITED_CREDIT, file_no, amount)
+    }
+    fn value_operation(
+        &self,
+        command: u8,
+        file_no: u8,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mode = self.communication_mode(file_no, true)?;
+        self.transceive_with(command, &[file_no], &amount.to_le_bytes(), mode)?;
+        Ok(())
+    }
+    /// レコードファイルの新しいレコード(offsetはレコード内の位置)に書き込む
+    pub fn write_record(
+        &self,
+        file_no: u8,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mode = self.communication_mode(file_no, true)?;
+        let header = Self::file_range(file_no, offset, data.len() as u32);
+        self.transceive_with(CMD_WRITE_RECORD, &header, data, mode)?;
+        Ok(())
+    }
+    /// 新しい方からoffset番目のレコードから古い方へcount件読む（countが0なら全件）
+    /// 戻り値はGetFileSettingsのレコードサイズで区切り、古いレコードから順に並ぶ
+    pub fn read_records(
+        &self,
+        file_no: u8,
+        offset: u32,
+        count: u32,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let settings = self.get_file_settings(file_no)?;
+        let record_size = match settings.record_size() {
+            Some(size) if size > 0 => size as usize,
+            _ => return Err(Box::new(DesfireError::new(DesfireErrorKind::NotRecordFile))),
+        };
+        let mode = self.communication_with(&settings, false);
+        let header = Self::file_range(file_no, offset, count);
+        let res = self.transceive_with(CMD_READ_RECORDS, &header, &[], mode)?;
+        if res.len() % record_size != 0 {
+            return Err(Self::invalid_response(res.len()));
+        }
+        Ok(res
+            .chunks(record_size)
+            .map(|record| record.to_vec())
+            .collect())
+    }
+    pub fn clear_record_file(&self, file_no: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_CLEAR_RECORD_FILE, &[file_no])?;
+        Ok(())
+    }
+    /// バックアップ・値・レコードファイルへの変更を確定する
+    pub fn commit_transaction(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_COMMIT_TRANSACTION, &[])?;
+        Ok(())
+    }
+    pub fn abort_transaction(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.transceive(CMD_ABORT_TRANSACTION, &[])?;
+        Ok(())
+    }
+    fn file_range(file_no: u8, offset: u32, length: u32) -> Vec<u8> {
+        let mut data = vec![file_no];
+        data.extend_from_slice(&write_u24(offset));
+        data.extend_from_slice(&write_u24(length));
+        data
+    }
+}
+
+/// ChangeKeyで鍵バージョンのバイトを付けるか（EV1のDES系はパリティビットに持つ）
+pub fn key_version_appended(messaging: SecureMessaging, key_type: DesfireKeyType) -> bool {
+    match messaging {
+        SecureMessaging::Legacy => false,
+        SecureMessaging::Ev1 => key_type == DesfireKeyType::Aes,
+        SecureMessaging::Ev2 => true,
+    }
+}
+
+/// カードが返したエラーステータス
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DesfireStatus {
+    OutOfEeprom,
+    IllegalCommand,
+    IntegrityError,
+    NoSuchKey,
+    LengthError,
+    PermissionDenied,
+    ParameterError,
+    ApplicationNotFound,
+    ApplicationIntegrityError,
+    AuthenticationError,
+    BoundaryError,
+    PiccIntegrityError,
+    CommandAborted,
+    PiccDisabled,
+    CountError,
+    DuplicateError,
+    EepromError,
+    FileNotFound,
+    FileIntegrityError,
+    Other(u8),
+}
+
+impl From<u8> for DesfireStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0E => DesfireStatus::OutOfEeprom,
+            0x1C => DesfireStatus::IllegalCommand,
+            0x1E => DesfireStatus::IntegrityError,
+            0x40 => DesfireStatus::NoSuchKey,
+            0x7E => DesfireStatus::LengthError,
+            0x9D => DesfireStatus::PermissionDenied,
+            0x9E => DesfireStatus::ParameterError,
+            0xA0 => DesfireStatus::ApplicationNotFound,
+            0xA1 => DesfireStatus::ApplicationIntegrityError,
+            0xAE => DesfireStatus::AuthenticationError,
+            0xBE => DesfireStatus::BoundaryError,
+            0xC1 => DesfireStatus::PiccIntegrityError,
+            0xCA => DesfireStatus::CommandAborted,
+            0xCD => DesfireStatus::PiccDisabled,
+            0xCE => DesfireStatus::CountError,
+            0xDE => DesfireStatus::DuplicateError,
+            0xEE => DesfireStatus::EepromError,
+            0xF0 => DesfireStatus::FileNotFound,
+            0xF1 => DesfireStatus::FileIntegrityError,
+            _ => DesfireStatus::Other(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DesfireErrorKind {
+    /// カードがエラーステータスを返した
+    Status(DesfireStatus),
+    /// 追加フレームを送る途中でAF以外のステータスが返った
+    UnexpectedStatus(u8),
+    InvalidResponse(usize),
+    /// 認証方式に合わない鍵、または鍵の変更に必要な現在の鍵がない
+    InvalidKey,
+    /// カードの応答したRndA'が一致しない
+    AuthenticationFailed,
+    /// 認証が必要
+    NotAuthenticated,
+    /// 応答のMACまたはCRCが一致しない
+    IntegrityError,
+    /// レコードファイルではない
+    NotRecordFile,
+}
+
+#[derive(Debug)]
+pub struct DesfireError {
+    code: DesfireErrorKind,
+}
+
+impl DesfireError {
+    pub fn new(code: DesfireErrorKind) -> Self {
+        DesfireError { code }
+    }
+    pub fn kind(&self) -> &DesfireErrorKind {
+        &self.code
+    }
+    /// エラーがカードのステータスならそれを返す
+    pub fn status(e: &(dyn std::error::Error + 'static)) -> Option<DesfireStatus> {
+        match e.downcast_ref::<DesfireError>()?.kind() {
+            DesfireErrorKind::Status(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+impl std::error::Error for DesfireError {}
+impl std::fmt::Display for DesfireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn desfire_applications_and_files() {
+    use crate::nfc_impl::nfc_mock::{desfire::VirtualDesfire, MockSmartcard};
+    use file::{AccessRights, CommunicationMode, FileKind};
+    let nfc = MockSmartcard::new(Box::new(VirtualDesfire::new()));
+    let card = Desfire::new(&nfc);
+    assert_eq!(
+        card.get_version().unwrap().model(),
+        version::DesfireModel::DesfireEv1
+    );
+    assert!(card.get_application_ids().unwrap().is_empty());
+    assert_eq!(card.get_free_memory().unwrap(), 4096);
+
+    let aid = 0x123456;
+    card.create_application(aid, KeySettings::default(), 2, DesfireKeyType::Aes)
+        .unwrap();
+    assert_eq!(card.get_application_ids().unwrap(), vec![aid]);
+    let error = card
+        .create_application(aid, KeySettings::default(), 2, DesfireKeyType::Aes)
+        .unwrap_err();
+    assert_eq!(
+        DesfireError::status(error.as_ref()),
+        Some(DesfireStatus::DuplicateError)
+    );
+    card.select_application(aid).unwrap();
+    assert_eq!(card.get_key_settings().unwrap().2, DesfireKeyType::Aes);
+
+    let plain = |kind| FileSettings {
+        communication: CommunicationMode::Plain,
+        access_rights: AccessRights::free(),
+        kind,
+    };
+    card.create_file(1, &plain(FileKind::StandardData { size: 200 }))
+        .unwrap();
+    card.create_file(
+        2,
+        &plain(FileKind::Value {
+            lower_limit: 0,
+            upper_limit: 1000,
+            value: 100,
+            limited_credit_enabled: true,
+        }),
+    )
+    .unwrap();
+    card.create_file(
+        3,
+        &plain(FileKind::CyclicRecord {
+            record_size: 4,
+            max_records: 3,
+            current_records: 0,
+        }),
+    )
+    .unwrap();
+    assert_eq!(card.get_file_ids().unwrap(), vec![1, 2, 3]);
+    assert_eq!(card.get_free_memory().unwrap(), 4096 - (200 + 4 + 3 * 4));
+
+    // 複数フレームに分かれる書き込みと読み出し
+    let data = (0..150).map(|i| i as u8).collect::<Vec<u8>>();
+    card.write_data(1, 10, &data).unwrap();
+    assert_eq!(card.read_data(1, 10, 150).unwrap(), data);
+    assert_eq!(card.read_data(1, 0, 0).unwrap().len(), 200);
+    assert!(card.read_data(1, 190, 20).is_err());
+
+    card.credit(2, 50).unwrap();
+    assert_eq!(card.get_value(2).unwrap(), 100);
+    card.commit_transaction().unwrap();
+    assert_eq!(card.get_value(2).unwrap(), 150);
+    card.debit(2, 20).unwrap();
+    card.abort_transaction().unwrap();
+    assert_eq!(card.get_value(2).unwrap(), 150);
+    card.debit(2, 30).unwrap();
+    card.commit_transaction().unwrap();
+    // 直前に減算した額までは限定クレジットで戻せる
+    card.limited_credit(2, 30).unwrap();
+    card.commit_transaction().unwrap();
+    assert_eq!(card.get_value(2).unwrap(), 150);
+
+    for record in 1..=4u8 {
+        card.write_record(3, 0, &[record; 4]).unwrap();
+        card.commit_transaction().unwrap();
+    }
+    // 巡回レコードファイルは最大数-1件を保持し、古いものから返る
+    assert_eq!(card.read_records(3, 0, 0).unwrap(), [[3; 4], [4; 4]]);
+    assert_eq!(card.read_records(3, 0, 1).unwrap(), [[4; 4]]);
+    assert_eq!(
+        card.read_records(1, 0, 0)
+            .unwrap_err()
+            .downcast_ref::<DesfireError>()
+            .unwrap()
+            .kind(),
+        &DesfireErrorKind::NotRecordFile
+    );
+    match card.get_file_settings(3).unwrap().kind {
+        FileKind::CyclicRecord {
+            current_records, ..
+        } => assert_eq!(current_records, 2),
+        kind => panic!("{:?}", kind),
+    }
+    card.clear_record_file(3).unwrap();
+    card.commit_transaction().unwrap();
+    let error = card.read_records(3, 0, 0).unwrap_err();
+    assert_eq!(
+        DesfireError::status(error.as_ref()),
+        Some(DesfireStatus::BoundaryError)
+    );
+
+    card.delete_file(1).unwrap();
+    assert_eq!(card.get_file_ids().unwrap(), vec![2, 3]);
+    card.select_application(PICC_AID).unwrap();
+    card.delete_application(aid).unwrap();
+    assert!(card.get_application_ids().unwrap().is_empty());
+
+    // FormatPICCで全てのアプリケーションとファイルが消える
+    card.create_application(aid, KeySettings::default(), 2, DesfireKeyType::Aes)
+        .unwrap();
+    card.select_application(aid).unwrap();
+    card.create_file(1, &plain(FileKind::StandardData { size: 32 }))
+        .unwrap();
+    assert_eq!(card.get_free_memory().unwrap(), 4096 - 32);
+    card.select_application(PICC_AID).unwrap();
+    card.format_picc().unwrap();
+    assert!(card.get_application_ids().unwrap().is_empty());
+    assert_eq!(card.get_free_memory().unwrap(), 4096);
+}
+
+#[test]
+fn desfire_authenticated_files() {
+    use crate::nfc_impl::nfc_mock::{desfire::VirtualDesfire, MockSmartcard};
+    use file::{AccessRights, FileKind};
+    let nfc = MockSmartcard::new(Box::new(VirtualDesfire::new()));
+    let card = Desfire::new(&nfc);
+    // 出荷時のPICCマスターキー(DES、全て0)からAESに切り替える（Legacyの認証では変えられない）
+    let des = DesfireKey::zero(DesfireKeyType::Des);
+    card.authenticate(AuthMethod::Legacy, 0, &des).unwrap();
+    let error = card
+        .change_key(0, &DesfireKey::Aes([0x4D; 16], 1), None)
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<DesfireError>().unwrap().kind(),
+        &DesfireErrorKind::InvalidKey
+    );
+    card.authenticate(AuthMethod::Iso, 0, &des).unwrap();
+    let master = DesfireKey::Aes([0x4D; 16], 1);
+    card.change_key(0, &master, None).unwrap();
+    assert_eq!(card.authenticated_key(), None);
+    let error = card.authenticate(AuthMethod::Iso, 0, &des).unwrap_err();
+    assert_eq!(
+        DesfireError::status(error.as_ref()),
+        Some(DesfireStatus::AuthenticationError)
+    );
+    card.authenticate(AuthMethod::Aes, 0, &master).unwrap();
+    assert_eq!(card.get_key_version(0).unwrap(), 1);
+
+    let cases = [
+        (0x000001, DesfireKey::Tdes2([0x21; 16]), AuthMethod::Legacy),
+        (0x000002, DesfireKey::Tdes3([0x32; 24]), AuthMethod::Iso),
+        (0x000003, DesfireKey::Aes([0x43; 16], 7), AuthMethod::Aes),
+        (
+            0x000004,
+            DesfireKey::Aes([0x54; 16], 9),
+            AuthMethod::Ev2First,
+        ),
+    ];
+    for (aid, key, method) in cases.iter() {
+        card.select_application(PICC_AID).unwrap();
+        card.authenticate(AuthMethod::Aes, 0, &master).unwrap();
+        card.create_application(*aid, KeySettings::default(), 2, key.key_type())
+            .unwrap();
+        card.select_application(*aid).unwrap();
+        // 鍵0で認証して鍵1を変更する
+        let zero = DesfireKey::zero(key.key_type());
+        card.authenticate(*method, 0, &zero).unwrap();
+        card.change_key(1, key, Some(&zero)).unwrap();
+        assert_eq!(card.get_key_version(1).unwrap(), key.version());
+
+        let rights = AccessRights {
+            read: 1,
+            write: 1,
+            read_write: 1,
+            change: 0,
+        };
+        let settings = |communication, kind| FileSettings {
+            communication,
+            access_rights: rights,
+            kind,
+        };
+        card.create_file(
+            1,
+            &settings(
+                CommunicationMode::Enciphered,
+                FileKind::StandardData { size: 100 },
+            ),
+        )
+        .unwrap();
+        card.create_file(
+            2,
+            &settings(CommunicationMode::Maced, FileKind::BackupData { size: 32 }),
+        )
+        .unwrap();
+        card.create_file(
+            3,
+            &settings(
+                CommunicationMode::Enciphered,
+                FileKind::Value {
+                    lower_limit: 0,
+                    upper_limit: 1000,
+                    value: 10,
+                    limited_credit_enabled: false,
+                },
+            ),
+        )
+        .unwrap();
+
+        // 鍵1で認証しないと読めない
+        let error = card.read_data(1, 0, 10).unwrap_err();
+        assert_eq!(
+            DesfireError::status(error.as_ref()),
+            Some(DesfireStatus::PermissionDenied)
+        );
+        card.authenticate(*method, 1, key).unwrap();
+        // 複数フレームに分かれる暗号文
+        let data = (0..90).map(|i| i as u8).collect::<Vec<u8>>();
+        card.write_data(1, 5, &data).unwrap();
+        assert_eq!(card.read_data(1, 5, 90).unwrap(), data);
+        card.write_data(2, 0, &[0xA5; 20]).unwrap();
+        card.credit(3, 15).unwrap();
+        card.commit_transaction().unwrap();
+        assert_eq!(card.read_data(2, 0, 20).unwrap(), [0xA5; 20]);
+        assert_eq!(card.get_value(3).unwrap(), 25);
+        assert_eq!(card.authenticated_key(), Some(1));
+    }
+
+    // マスターキーで鍵設定を変更する
+    card.select_application(0x000003).unwrap();
+    card.authenticate(AuthMethod::Aes, 0, &DesfireKey::zero(DesfireKeyType::Aes))
+        .unwrap();
+    let settings = KeySettings {
+        change_key: 0x0E,
+        ..KeySettings::default()
+    };
+    card.change_key_settings(settings).unwrap();
+    assert_eq!(card.get_key_settings().unwrap().0, settings);
+}
@@ -0,0 +1,403 @@
+// DESFireの相互認証
+// コマンド(鍵番号) → AF || ek(RndB)
+// AF || ek(RndA || RndB') → 00 || ek(RndA')（EV2FirstはTI・RndA'・機能情報）
+// 方式ごとに暗号文の作り方とIVの引き継ぎ方が違う。成功するとSessionを返す。
+
+use super::key::DesfireKey;
+use super::session::{legacy_send, Session};
+use super::{DesfireError, DesfireErrorKind};
+use crate::crypto::rotate_left;
+
+/// EV2Firstの最後の応答の長さ(TI 4 || RndA' 16 || PDcap2 6 || PCDcap2 6)
+const EV2_FIRST_RESPONSE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    /// Authenticate(0A): 初代DESFire互換、DES/2K3DES
+    Legacy = 0x0A,
+    /// AuthenticateISO(1A): DES/2K3DES/3K3DES
+    Iso = 0x1A,
+    /// AuthenticateAES(AA)
+    Aes = 0xAA,
+    /// AuthenticateEV2First(71): AES
+    Ev2First = 0x71,
+}
+
+impl AuthMethod {
+    pub fn from_command(command: u8) -> Option<Self> {
+        match command {
+            0x0A => Some(AuthMethod::Legacy),
+            0x1A => Some(AuthMethod::Iso),
+            0xAA => Some(AuthMethod::Aes),
+            0x71 => Some(AuthMethod::Ev2First),
+            _ => None,
+        }
+    }
+    /// この方式で使える鍵か
+    pub fn supports(self, key: &DesfireKey) -> bool {
+        match (self, key) {
+            (AuthMethod::Legacy, DesfireKey::Des(_))
+            | (AuthMethod::Legacy, DesfireKey::Tdes2(_)) => true,
+            (AuthMethod::Iso, DesfireKey::Aes(..)) => false,
+            (AuthMethod::Iso, _) => true,
+            (AuthMethod::Aes, DesfireKey::Aes(..))
+            | (AuthMethod::Ev2First, DesfireKey::Aes(..)) => true,
+            _ => false,
+        }
+    }
+    /// RndA・RndBの長さ
+    pub fn random_size(self, key: &DesfireKey) -> usize {
+        match key {
+            DesfireKey::Tdes3(_) | DesfireKey::Aes(..) => 16,
+            _ => 8,
+        }
+    }
+    /// 最初のコマンドのデータ。EV2Firstは鍵番号と機能情報の長さ(0)
+    pub fn command_data(self, key_no: u8) -> Vec<u8> {
+        match self {
+            AuthMethod::Ev2First => vec![key_no, 0x00],
+            _ => vec![key_no],
+        }
+    }
+}
+
+/// PCD側の認証1回分の状態
+pub struct DesfireAuth {
+    method: AuthMethod,
+    key_no: u8,
+    key: DesfireKey,
+    rnd_a: Vec<u8>,
+    rnd_b: Vec<u8>,
+    /// 最後の応答の復号に使うIV
+    iv: Vec<u8>,
+}
+
+impl DesfireAuth {
+    pub fn new(
+        method: AuthMethod,
+        key_no: u8,
+        key: &DesfireKey,
+        rnd_a: &[u8],
+    ) -> Result<Self, DesfireError> {
+        if !method.supports(key) || rnd_a.len() != method.random_size(key) {
+            return Err(DesfireError::new(DesfireErrorKind::InvalidKey));
+        }
+        Ok(DesfireAuth {
+            method,
+            key_no,
+            key: key.clone(),
+            rnd_a: rnd_a.to_vec(),
+            rnd_b: Vec::new(),
+            iv: vec![0; key.block_size()],
+        })
+    }
+    /// ek(RndB)から2回目に送るデータを作る
+    pub fn challenge_response(&mut self, encrypted_rnd_b: &[u8]) -> Result<Vec<u8>, DesfireError> {
+        if encrypted_rnd_b.len() != self.rnd_a.len() {
+            return Err(DesfireError::new(DesfireErrorKind::InvalidResponse(
+                encrypted_rnd_b.len(),
+            )));
+        }
+        let cipher = self.key.cipher();
+        let block_size = cipher.block_size();
+        let zero = vec![0u8; block_size];
+        self.rnd_b = cipher.cbc_decrypt(&zero, encrypted_rnd_b);
+        let plain = [&self.rnd_a[..], &rotate_left(&self.rnd_b)].concat();
+        let token = match self.method {
+            AuthMethod::Legacy => legacy_send(&cipher, &plain),
+            AuthMethod::Iso | AuthMethod::Aes => {
+                // 受け取った暗号文の最後のブロックをIVとして引き継ぐ
+                let iv = &encrypted_rnd_b[encrypted_rnd_b.len() - block_size..];
+                let token = cipher.cbc_encrypt(iv, &plain);
+                self.iv = token[token.len() - block_size..].to_vec();
+                token
+            }
+            AuthMethod::Ev2First => cipher.cbc_encrypt(&zero, &plain),
+        };
+        Ok(token)
+    }
+    /// 最後の応答を検証してセッションを作る
+    pub fn verify(&self, response: &[u8]) -> Result<Session, DesfireError> {
+        let expected = match self.method {
+            AuthMethod::Ev2First => EV2_FIRST_RESPONSE_SIZE,
+            _ => self.rnd_a.len(),
+        };
+        if response.len() != expected {
+            return Err(DesfireError::new(DesfireErrorKind::InvalidResponse(
+                response.len(),
+            )));
+        }
+        let plain = self.key.cipher().cbc_decrypt(&self.iv, response);
+        let (ti, rnd_a) = match self.method {
+            AuthMethod::Ev2First => (&plain[0..4], &plain[4..4 + self.rnd_a.len()]),
+            _ => (&[][..], &plain[..]),
+        };
+        if rnd_a != &rotate_left(&self.rnd_a)[..] {
+            return Err(DesfireError::new(DesfireErrorKind::AuthenticationFailed));
+        }
+        let (rnd_a, rnd_b) = (&self.rnd_a, &self.rnd_b);
+        Ok(match self.method {
+            AuthMethod::Legacy => Session::legacy(self.key_no, &self.key, rnd_a, rnd_b),
+            AuthMethod::Iso | AuthMethod::Aes => Session::ev1(self.key_no, &self.key, rnd_a, rnd_b),
+            AuthMethod::Ev2First => {
+                let mut id = [0u8; 4];
+                id.copy_from_slice(ti);
+                Session::ev2(self.key_no, &self.key, rnd_a, rnd_b, id)
+            }
+        })
+    }
+}
+
+#[test]
+fn desfire_auth_vectors() {
+    use super::file::CommunicationMode;
+    use crate::crypto::hex;
+    // AuthenticateEV2First: NXP AN12196 の例（鍵は全て0）
+    let rnd_a = [
+        0x13, 0xC5, 0xDB, 0x8A, 0x59, 0x30, 0x43, 0x9F, 0xC3, 0xDE, 0xF9, 0xA4, 0xC6, 0x75, 0x36,
+        0x0F,
+    ];
+    let mut auth = DesfireAuth::new(
+        AuthMethod::Ev2First,
+        0,
+        &DesfireKey::Aes([0; 16], 0),
+        &rnd_a,
+    )
+    .unwrap();
+    let token = auth
+        .challenge_response(&[
+            0xA0, 0x4C, 0x12, 0x42, 0x13, 0xC1, 0x86, 0xF2, 0x23, 0x99, 0xD3, 0x3A, 0xC2, 0xA3,
+            0x02, 0x15,
+        ])
+        .unwrap();
+    assert_eq!(
+        token,
+        [
+            0x35, 0xC3, 0xE0, 0x5A, 0x75, 0x2E, 0x01, 0x44, 0xBA, 0xC0, 0xDE, 0x51, 0xC1, 0xF2,
+            0x2C, 0x56, 0xB3, 0x44, 0x08, 0xA2, 0x3D, 0x8A, 0xEA, 0x26, 0x6C, 0xAB, 0x94, 0x7E,
+            0xA8, 0xE0, 0x11, 0x8D
+        ]
+    );
+    let mut session = auth
+        .verify(&[
+            0x3F, 0xA6, 0x4D, 0xB5, 0x44, 0x6D, 0x1F, 0x34, 0xCD, 0x6E, 0xA3, 0x11, 0x16, 0x7F,
+            0x5E, 0x49, 0x85, 0xB8, 0x96, 0x90, 0xC0, 0x4A, 0x05, 0xF1, 0x7F, 0xA7, 0xAB, 0x2F,
+            0x08, 0x12, 0x06, 0x63,
+        ])
+        .unwrap();
+    // TI 9D00C4DF、CmdCtr 0 の GetFileSettings(02) のMAC
+    assert_eq!(
+        session.wrap_command(0xF5, &[0x02], &[], CommunicationMode::Maced, None),
+        [0x02, 0x04, 0x6F, 0xD9, 0xC8, 0x0D, 0x11, 0xD1, 0x75]
+    );
+
+    // 以下はPythonのcryptographyパッケージで求めた値（RndA = A0 A1 .., RndB = 01 02 ..）
+    let rnd_a = (0xA0..0xB0).collect::<Vec<u8>>();
+    // Authenticate(0A) 2K3DES
+    let key = DesfireKey::Tdes2([
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ]);
+    let mut auth = DesfireAuth::new(AuthMethod::Legacy, 0, &key, &rnd_a[..8]).unwrap();
+    let token = auth
+        .challenge_response(&[0x00, 0xE2, 0xB1, 0x53, 0x07, 0xA7, 0xA3, 0x30])
+        .unwrap();
+    assert_eq!(
+        token,
+        [
+            0xC1, 0x2F, 0xFE, 0xA3, 0xC3, 0xD6, 0xF4, 0x14, 0xFF, 0x3D, 0x38, 0x3A, 0x1F, 0xB9,
+            0xE5, 0xA5
+        ]
+    );
+    assert!(auth
+        .verify(&[0xDC, 0x01, 0xFF, 0xE1, 0x66, 0x7F, 0x8D, 0xB3])
+        .is_err());
+    let mut session = auth
+        .verify(&[0xDC, 0x01, 0xFF, 0xE1, 0x66, 0x7F, 0x8D, 0xB2])
+        .unwrap();
+    let header = [0x01, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00];
+    let data = (0x11..0x1B).collect::<Vec<u8>>();
+    assert_eq!(
+        session.wrap_command(0x3D, &header, &data, CommunicationMode::Maced, None)[7..],
+        [&data[..], &[0x4F, 0x98, 0x71, 0x17]].concat()[..]
+    );
+    assert_eq!(
+        session.wrap_command(0x3D, &header, &data, CommunicationMode::Enciphered, None)[7..],
+        [
+            0xE5, 0x26, 0x63, 0x6D, 0x5A, 0xD9, 0xF9, 0x37, 0x10, 0xBE, 0xFB, 0x09, 0xDB, 0x1D,
+            0xA8, 0x90
+        ]
+    );
+
+    // AuthenticateISO(1A) 3K3DES
+    let mut key = [0u8; 24];
+    key.iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = i as u8);
+    let mut auth = DesfireAuth::new(AuthMethod::Iso, 0, &DesfireKey::Tdes3(key), &rnd_a).unwrap();
+    let token = auth
+        .challenge_response(&[
+            0xF9, 0x78, 0x12, 0xFD, 0xE0, 0x96, 0x75, 0x39, 0xE4, 0xC6, 0xA2, 0x9E, 0x27, 0xB2,
+            0x55, 0x36,
+        ])
+        .unwrap();
+    assert_eq!(
+        token,
+        [
+            0x20, 0x11, 0x7A, 0x64, 0xEE, 0x62, 0xC8, 0x00, 0x8D, 0x77, 0x0A, 0x84, 0x90, 0xE8,
+            0x53, 0x1F, 0xC5, 0x7F, 0x4E, 0x36, 0xF6, 0x6E, 0x33, 0x07, 0xD7, 0xC2, 0x66, 0xA5,
+            0xD0, 0x02, 0x83, 0x4C
+        ]
+    );
+    let mut session = auth
+        .verify(&[
+            0x28, 0x58, 0xBC, 0x86, 0x0C, 0x8F, 0xE6, 0x21, 0x11, 0x3B, 0x2C, 0xD5, 0xF3, 0xFA,
+            0xD9, 0x0E,
+        ])
+        .unwrap();
+    // 平文のReadDataでも応答にCMACが付く
+    let header = [0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00];
+    session.wrap_command(0xBD, &header, &[], CommunicationMode::Plain, None);
+    let response = [
+        0xDE, 0xAD, 0xBE, 0xEF, 0xB9, 0xF4, 0xC3, 0x44, 0xB1, 0x17, 0xAE, 0xDF,
+    ];
+    assert_eq!(
+        session
+            .unwrap_response(0x00, &response, CommunicationMode::Plain)
+            .unwrap(),
+        [0xDE, 0xAD, 0xBE, 0xEF]
+    );
+
+    // AuthenticateAES(AA)
+    let mut key = [0u8; 16];
+    key.iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = i as u8);
+    let mut auth = DesfireAuth::new(AuthMethod::Aes, 0, &DesfireKey::Aes(key, 0), &rnd_a).unwrap();
+    let token = auth
+        .challenge_response(&[
+            0x08, 0x92, 0x08, 0x56, 0x05, 0xBE, 0x8F, 0x34, 0x9F, 0x58, 0x4A, 0xF9, 0x93, 0xDF,
+            0x11, 0xF8,
+        ])
+        .unwrap();
+    assert_eq!(
+        token,
+        [
+            0xA3, 0xAD, 0xED, 0xA6, 0x7B, 0x65, 0xC9, 0xF6, 0x6A, 0xF8, 0xCA, 0x39, 0x5A, 0x71,
+            0x0E, 0xF1, 0xEF, 0x25, 0xBA, 0xBD, 0xBD, 0x1F, 0xB0, 0x52, 0x5A, 0x3F, 0x23, 0x46,
+            0xDC, 0x7B, 0x7E, 0xE4
+        ]
+    );
+    let mut session = auth
+        .verify(&[
+            0xD8, 0x02, 0x41, 0xF5, 0xDB, 0xF0, 0x31, 0x86, 0x78, 0xC5, 0xDC, 0x62, 0x22, 0xCD,
+            0x2E, 0x58,
+        ])
+        .unwrap();
+    let data = [0x01, 0x02, 0x03, 0x04];
+    assert_eq!(
+        session.wrap_command(0x3D, &header, &data, CommunicationMode::Enciphered, None)[7..],
+        [
+            0x48, 0x22, 0xEB, 0xB3, 0xA9, 0xC8, 0x65, 0x89, 0x02, 0x5F, 0x02, 0x33, 0x60, 0x2F,
+            0xBF, 0x0B
+        ]
+    );
+    assert!(session
+        .unwrap_response(
+            0x00,
+            &[0x16, 0x4F, 0x3F, 0xA6, 0x29, 0x17, 0xE3, 0x9A],
+            CommunicationMode::Maced
+        )
+        .unwrap()
+        .is_empty());
+
+    // ridrixのブログ・Stack Overflowなどで広く引用されている認証の例（鍵は全て0）
+    // Authenticate(0A) DES: ek(RndB) 5D994CE085F24089 → RndB 4FD1B75942A8B8E1
+    let mut auth =
+        DesfireAuth::new(AuthMethod::Legacy, 0, &DesfireKey::Des([0; 8]), &rnd_a[..8]).unwrap();
+    auth.challenge_response(&hex("5D994CE085F24089")).unwrap();
+    assert_eq!(auth.rnd_b, hex("4FD1B75942A8B8E1"));
+    // AuthenticateAES(AA): セッション鍵は F44B26F5C05DDD7110772281C4D066E8
+    let mut auth = DesfireAuth::new(
+        AuthMethod::Aes,
+        0,
+        &DesfireKey::Aes([0; 16], 0),
+        &hex("F44B26F5686F3A391CD38EBD10772281"),
+    )
+    .unwrap();
+    let token = auth
+        .challenge_response(&hex("B969FDFE56FD91FC9DE6F6F213B8FD1E"))
+        .unwrap();
+    assert_eq!(auth.rnd_b, hex("C05DDD714FD788A6B7B754F3C4D066E8"));
+    assert_eq!(
+        token,
+        hex("36AAD7DF6E436BA08D18613830A70D5A D43E3D3F4A8D47541EEE623A934E4774")
+    );
+    let mut session = auth
+        .verify(&hex("800DB680BC146BD121D6578F2D2E2059"))
+        .unwrap();
+
+    // ChangeKey(C4)の暗号文（Pythonのcryptographyパッケージで求めた値）
+    // 上のAESのセッションで、鍵1を 00 01 .. 0F（バージョン10）に変える
+    let mut key = [0u8; 16];
+    key.iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = i as u8);
+    let new_key = DesfireKey::Aes(key, 0x10);
+    let data = [new_key.change_key_bytes(), vec![new_key.version()]].concat();
+    assert_eq!(
+        session.wrap_command(
+            0xC4,
+            &[0x01],
+            &data,
+            CommunicationMode::Enciphered,
+            Some(&new_key.change_key_bytes())
+        ),
+        [
+            vec![0x01],
+            hex("8B92CF2F4AD4F3CF425787A745A92CFF ABE09A713B766DAE1D5D0C4B08D739F6")
+        ]
+        .concat()
+    );
+    // Legacy 2K3DES: 認証中の鍵0、別の鍵1（旧鍵とのXORと、新しい鍵のCRC16を付ける）
+    let rnd_b = (0x01..0x11).collect::<Vec<u8>>();
+    let old_key = hex("00112233445566778899AABBCCDDEEFF");
+    let new_key = (0x10..0x20).collect::<Vec<u8>>();
+    let legacy = |key_no: u8, data: &[u8], new_key: Option<&[u8]>| {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&old_key);
+        let mut session = Session::legacy(0, &DesfireKey::Tdes2(key), &rnd_a[..8], &rnd_b[..8]);
+        session.wrap_command(
+            0xC4,
+            &[key_no],
+            data,
+            CommunicationMode::Enciphered,
+            new_key,
+        )[1..]
+            .to_vec()
+    };
+    assert_eq!(
+        legacy(0, &new_key, None),
+        hex("1D26A18935941C0FD484EF3CE5421E74F7ACDBDFCD19154C")
+    );
+    let xored = new_key
+        .iter()
+        .zip(old_key.iter())
+        .map(|(new, old)| new ^ old)
+        .collect::<Vec<u8>>();
+    assert_eq!(
+        legacy(1, &xored, Some(&new_key)),
+        hex("837965651EFEA969F428C3D95B9752082AF31EFF6303A83F")
+    );
+    // EV1 3K3DES: 認証中の鍵0を 30 31 .. 47 に変える（バージョンは付けない）
+    let mut key = [0u8; 24];
+    key.iter_mut()
+        .enumerate()
+        .for_each(|(i, byte)| *byte = i as u8);
+    let mut session = Session::ev1(0, &DesfireKey::Tdes3(key), &rnd_a, &rnd_b);
+    let new_key = (0x30..0x48).collect::<Vec<u8>>();
+    assert_eq!(
+        session.wrap_command(0xC4, &[0x00], &new_key, CommunicationMode::Enciphered, None)[1..],
+        hex("E9BEF16C1A2A43B3585754E16BD935432BB0A259D32BD994119AE94475E418D8")[..]
+    );
+}
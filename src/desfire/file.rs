@@ -0,0 +1,315 @@
+// DESFireのファイル設定
+// GetFileSettingsの応答とCreate*Fileのコマンドで同じ並び（数値はリトルエンディアン）を使う
+
+use super::{DesfireError, DesfireErrorKind};
+
+/// アクセス権の鍵番号でこの値なら認証不要
+pub const ACCESS_FREE: u8 = 0x0E;
+/// アクセス権の鍵番号でこの値ならアクセス不可
+pub const ACCESS_DENIED: u8 = 0x0F;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    StandardData = 0x00,
+    BackupData = 0x01,
+    Value = 0x02,
+    LinearRecord = 0x03,
+    CyclicRecord = 0x04,
+}
+
+impl FileType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(FileType::StandardData),
+            0x01 => Some(FileType::BackupData),
+            0x02 => Some(FileType::Value),
+            0x03 => Some(FileType::LinearRecord),
+            0x04 => Some(FileType::CyclicRecord),
+            _ => None,
+        }
+    }
+    /// Create*Fileのコマンドコード
+    pub fn create_command(self) -> u8 {
+        match self {
+            FileType::StandardData => 0xCD,
+            FileType::BackupData => 0xCB,
+            FileType::Value => 0xCC,
+            FileType::LinearRecord => 0xC1,
+            FileType::CyclicRecord => 0xC0,
+        }
+    }
+}
+
+/// ファイルのデータを送受信するときの保護
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommunicationMode {
+    Plain = 0x00,
+    /// MAC(EV1以降はCMAC)を付ける
+    Maced = 0x01,
+    /// 暗号化する
+    Enciphered = 0x03,
+}
+
+impl From<u8> for CommunicationMode {
+    fn from(value: u8) -> Self {
+        match value & 0x03 {
+            0x01 => CommunicationMode::Maced,
+            0x03 => CommunicationMode::Enciphered,
+            _ => CommunicationMode::Plain,
+        }
+    }
+}
+
+/// 操作ごとに必要な鍵番号(0～13、ACCESS_FREE、ACCESS_DENIED)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessRights {
+    pub read: u8,
+    pub write: u8,
+    pub read_write: u8,
+    pub change: u8,
+}
+
+impl AccessRights {
+    /// すべての操作を認証なしで許可する
+    pub fn free() -> Self {
+        AccessRights {
+            read: ACCESS_FREE,
+            write: ACCESS_FREE,
+            read_write: ACCESS_FREE,
+            change: ACCESS_FREE,
+        }
+    }
+    pub fn from_u16(value: u16) -> Self {
+        AccessRights {
+            read: (value >> 12) as u8 & 0x0F,
+            write: (value >> 8) as u8 & 0x0F,
+            read_write: (value >> 4) as u8 & 0x0F,
+            change: value as u8 & 0x0F,
+        }
+    }
+    /// 読み出し(writeがfalse)・書き込みに使える鍵番号
+    pub fn keys_for(self, write: bool) -> [u8; 2] {
+        [if write { self.write } else { self.read }, self.read_write]
+    }
+    /// key_no(認証していなければNone)で操作できるか
+    pub fn allows(self, write: bool, key_no: Option<u8>) -> bool {
+        self.keys_for(write)
+            .iter()
+            .any(|key| *key == ACCESS_FREE || Some(*key) == key_no)
+    }
+    pub fn to_u16(self) -> u16 {
+        (self.read as u16 & 0x0F) << 12
+            | (self.write as u16 & 0x0F) << 8
+            | (self.read_write as u16 & 0x0F) << 4
+            | (self.change as u16 & 0x0F)
+    }
+}
+
+/// ファイル種別ごとの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    StandardData {
+        size: u32,
+    },
+    BackupData {
+        size: u32,
+    },
+    Value {
+        lower_limit: i32,
+        upper_limit: i32,
+        /// 作成時は初期値、GetFileSettingsでは限定クレジットの値
+        value: i32,
+        limited_credit_enabled: bool,
+    },
+    LinearRecord {
+        record_size: u32,
+        max_records: u32,
+        /// GetFileSettingsでのみ意味を持つ
+        current_records: u32,
+    },
+    CyclicRecord {
+        record_size: u32,
+        max_records: u32,
+        current_records: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileSettings {
+    pub communication: CommunicationMode,
+    pub access_rights: AccessRights,
+    pub kind: FileKind,
+}
+
+impl FileSettings {
+    pub fn file_type(&self) -> FileType {
+        match self.kind {
+            FileKind::StandardData { .. } => FileType::StandardData,
+            FileKind::BackupData { .. } => FileType::BackupData,
+            FileKind::Value { .. } => FileType::Value,
+            FileKind::LinearRecord { .. } => FileType::LinearRecord,
+            FileKind::CyclicRecord { .. } => FileType::CyclicRecord,
+        }
+    }
+    /// レコードファイルなら1レコードの大きさ
+    pub fn record_size(&self) -> Option<u32> {
+        match self.kind {
+            FileKind::LinearRecord { record_size, .. }
+            | FileKind::CyclicRecord { record_size, .. } => Some(record_size),
+            _ => None,
+        }
+    }
+    /// データの保護。認証不要の操作は平文でやり取りする
+    pub fn communication_for(&self, write: bool) -> CommunicationMode {
+        if self.access_rights.keys_for(write).contains(&ACCESS_FREE) {
+            CommunicationMode::Plain
+        } else {
+            self.communication
+        }
+    }
+    /// GetFileSettingsの応答を読む
+    pub fn parse(data: &[u8]) -> Result<Self, DesfireError> {
+        let invalid = || DesfireError::new(DesfireErrorKind::InvalidResponse(data.len()));
+        if data.len() < 4 {
+            return Err(invalid());
+        }
+        let file_type = FileType::from_u8(data[0]).ok_or_else(invalid)?;
+        let body = &data[4..];
+        let expected = match file_type {
+            FileType::StandardData | FileType::BackupData => 3,
+            FileType::Value => 13,
+            FileType::LinearRecord | FileType::CyclicRecord => 9,
+        };
+        if body.len() < expected {
+            return Err(invalid());
+        }
+        let kind = match file_type {
+            FileType::StandardData => FileKind::StandardData {
+                size: read_u24(&body[0..3]),
+            },
+            FileType::BackupData => FileKind::BackupData {
+                size: read_u24(&body[0..3]),
+            },
+            FileType::Value => FileKind::Value {
+                lower_limit: read_i32(&body[0..4]),
+                upper_limit: read_i32(&body[4..8]),
+                value: read_i32(&body[8..12]),
+                limited_credit_enabled: body[12] & 0x01 != 0,
+            },
+            FileType::LinearRecord => FileKind::LinearRecord {
+                record_size: read_u24(&body[0..3]),
+                max_records: read_u24(&body[3..6]),
+                current_records: read_u24(&body[6..9]),
+            },
+            FileType::CyclicRecord => FileKind::CyclicRecord {
+                record_size: read_u24(&body[0..3]),
+                max_records: read_u24(&body[3..6]),
+                current_records: read_u24(&body[6..9]),
+            },
+        };
+        Ok(FileSettings {
+            communication: CommunicationMode::from(data[1]),
+            access_rights: AccessRights::from_u16(u16::from_le_bytes([data[2], data[3]])),
+            kind,
+        })
+    }
+    /// Create*Fileのデータ部（ファイル番号を除く）
+    pub fn to_create_data(self) -> Vec<u8> {
+        let mut data = vec![self.communication as u8];
+        data.extend_from_slice(&self.access_rights.to_u16().to_le_bytes());
+        match self.kind {
+            FileKind::StandardData { size } | FileKind::BackupData { size } => {
+                data.extend_from_slice(&write_u24(size))
+            }
+            FileKind::Value {
+                lower_limit,
+                upper_limit,
+                value,
+                limited_credit_enabled,
+            } => {
+                data.extend_from_slice(&lower_limit.to_le_bytes());
+                data.extend_from_slice(&upper_limit.to_le_bytes());
+                data.extend_from_slice(&value.to_le_bytes());
+                data.push(limited_credit_enabled as u8);
+            }
+            FileKind::LinearRecord {
+                record_size,
+                max_records,
+                ..
+            }
+            | FileKind::CyclicRecord {
+                record_size,
+                max_records,
+                ..
+            } => {
+                data.extend_from_slice(&write_u24(record_size));
+                data.extend_from_slice(&write_u24(max_records));
+            }
+        }
+        data
+    }
+    /// GetFileSettingsの応答と同じ並びにする
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data = vec![self.file_type() as u8];
+        let create = self.to_create_data();
+        data.extend_from_slice(&create);
+        if let FileKind::LinearRecord {
+            current_records, ..
+        }
+        | FileKind::CyclicRecord {
+            current_records, ..
+        } = self.kind
+        {
+            data.extend_from_slice(&write_u24(current_records));
+        }
+        data
+    }
+}
+
+pub(crate) fn read_u24(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], 0])
+}
+
+pub(crate) fn write_u24(value: u32) -> [u8; 3] {
+    let bytes = value.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+fn read_i32(data: &[u8]) -> i32 {
+    i32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[test]
+fn desfire_file_settings() {
+    // バックアップファイル 32バイト、CMAC、読み出し鍵1・書き込み鍵2・読み書き鍵3・変更鍵0
+    let data = [0x01, 0x01, 0x30, 0x12, 0x20, 0x00, 0x00];
+    let settings = FileSettings::parse(&data).unwrap();
+    assert_eq!(settings.file_type(), FileType::BackupData);
+    assert_eq!(settings.communication, CommunicationMode::Maced);
+    assert_eq!(
+        settings.access_rights,
+        AccessRights {
+            read: 1,
+            write: 2,
+            read_write: 3,
+            change: 0,
+        }
+    );
+    assert_eq!(settings.kind, FileKind::BackupData { size: 32 });
+    assert_eq!(settings.to_bytes(), data);
+
+    let data = [
+        0x04, 0x00, 0xEE, 0xEE, 0x10, 0x00, 0x00, 0x05, 0x00, 0x00, 0x02, 0x00, 0x00,
+    ];
+    let settings = FileSettings::parse(&data).unwrap();
+    assert_eq!(
+        settings.kind,
+        FileKind::CyclicRecord {
+            record_size: 16,
+            max_records: 5,
+            current_records: 2,
+        }
+    );
+    assert_eq!(settings.to_bytes(), data);
+    assert!(FileSettings::parse(&data[..12]).is_err());
+}
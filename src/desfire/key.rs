@@ -0,0 +1,113 @@
+// 認証・ChangeKeyで使う鍵
+// DES系の鍵は各バイトの最下位ビット(パリティビット)に鍵バージョンを持つ
+
+use super::DesfireKeyType;
+use crate::crypto::{Cipher, AES_BLOCK_SIZE, DES_BLOCK_SIZE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DesfireKey {
+    Des([u8; 8]),
+    Tdes2([u8; 16]),
+    Tdes3([u8; 24]),
+    /// 鍵と鍵バージョン
+    Aes([u8; 16], u8),
+}
+
+impl DesfireKey {
+    /// 出荷時の鍵(すべて0)
+    pub fn zero(key_type: DesfireKeyType) -> Self {
+        match key_type {
+            DesfireKeyType::Des => DesfireKey::Des([0; 8]),
+            DesfireKeyType::Tdes3k => DesfireKey::Tdes3([0; 24]),
+            DesfireKeyType::Aes => DesfireKey::Aes([0; 16], 0),
+        }
+    }
+    pub fn key_type(&self) -> DesfireKeyType {
+        match self {
+            DesfireKey::Des(_) | DesfireKey::Tdes2(_) => DesfireKeyType::Des,
+            DesfireKey::Tdes3(_) => DesfireKeyType::Tdes3k,
+            DesfireKey::Aes(..) => DesfireKeyType::Aes,
+        }
+    }
+    /// 鍵の値（バージョンを除く）
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            DesfireKey::Des(key) => key,
+            DesfireKey::Tdes2(key) => key,
+            DesfireKey::Tdes3(key) => key,
+            DesfireKey::Aes(key, _) => key,
+        }
+    }
+    pub fn version(&self) -> u8 {
+        match self {
+            DesfireKey::Aes(_, version) => *version,
+            _ => self.bytes()[..8]
+                .iter()
+                .fold(0, |version, byte| version << 1 | byte & 0x01),
+        }
+    }
+    /// K1 == K2 の2K3DESはDESとして扱う（パリティビットは比べない）
+    pub fn is_des(&self) -> bool {
+        match self {
+            DesfireKey::Des(_) => true,
+            DesfireKey::Tdes2(key) => key[..8]
+                .iter()
+                .zip(key[8..].iter())
+                .all(|(a, b)| a & 0xFE == b & 0xFE),
+            _ => false,
+        }
+    }
+    pub fn cipher(&self) -> Cipher {
+        match self {
+            DesfireKey::Des(key) => Cipher::des(key),
+            DesfireKey::Tdes2(key) => Cipher::tdes2(key),
+            DesfireKey::Tdes3(key) => Cipher::tdes3(key),
+            DesfireKey::Aes(key, _) => Cipher::aes128(key),
+        }
+    }
+    pub fn block_size(&self) -> usize {
+        match self {
+            DesfireKey::Aes(..) => AES_BLOCK_SIZE,
+            _ => DES_BLOCK_SIZE,
+        }
+    }
+    /// ChangeKeyで送る鍵の値。DESは2K3DESの形(K1 == K2)にする
+    pub fn change_key_bytes(&self) -> Vec<u8> {
+        match self {
+            DesfireKey::Des(key) => [&key[..], &key[..]].concat(),
+            _ => self.bytes().to_vec(),
+        }
+    }
+    /// ChangeKeyで受け取った鍵の値から作る
+    pub fn from_change_key_bytes(
+        key_type: DesfireKeyType,
+        data: &[u8],
+        version: u8,
+    ) -> Option<Self> {
+        match (key_type, data.len()) {
+            (DesfireKeyType::Des, 16) => {
+                let mut key = [0u8; 16];
+                key.copy_from_slice(data);
+                Some(DesfireKey::Tdes2(key))
+            }
+            (DesfireKeyType::Tdes3k, 24) => {
+                let mut key = [0u8; 24];
+                key.copy_from_slice(data);
+                Some(DesfireKey::Tdes3(key))
+            }
+            (DesfireKeyType::Aes, 16) => {
+                let mut key = [0u8; 16];
+                key.copy_from_slice(data);
+                Some(DesfireKey::Aes(key, version))
+            }
+            _ => None,
+        }
+    }
+    /// ChangeKeyで送る鍵の長さ
+    pub fn change_key_size(key_type: DesfireKeyType) -> usize {
+        match key_type {
+            DesfireKeyType::Tdes3k => 24,
+            _ => 16,
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// GetVersionの応答
+// 3フレームに分かれて返る（ハードウェア7バイト、ソフトウェア7バイト、UID・製造情報14バイト）
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DesfireModel {
+    /// 初代(EV0)
+    Desfire,
+    DesfireEv1,
+    DesfireEv2,
+    DesfireEv3,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VersionInfo {
+    pub vendor_id: u8,
+    pub product_type: u8,
+    pub product_subtype: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub storage_size: u8,
+    pub protocol_type: u8,
+}
+
+impl VersionInfo {
+    fn from_bytes(data: &[u8]) -> Self {
+        VersionInfo {
+            vendor_id: data[0],
+            product_type: data[1],
+            product_subtype: data[2],
+            major_version: data[3],
+            minor_version: data[4],
+            storage_size: data[5],
+            protocol_type: data[6],
+        }
+    }
+    fn to_bytes(self) -> [u8; 7] {
+        [
+            self.vendor_id,
+            self.product_type,
+            self.product_subtype,
+            self.major_version,
+            self.minor_version,
+            self.storage_size,
+            self.protocol_type,
+        ]
+    }
+    /// 記憶容量(バイト)。上位7ビットがnなら2^n、最下位ビットが1なら2^nと2^(n+1)の間
+    pub fn storage_bytes(&self) -> (usize, bool) {
+        (
+            1usize << (self.storage_size >> 1),
+            self.storage_size & 0x01 != 0,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DesfireVersion {
+    pub hardware: VersionInfo,
+    pub software: VersionInfo,
+    pub uid: [u8; 7],
+    pub batch_no: [u8; 5],
+    /// BCDの製造週
+    pub production_week: u8,
+    /// BCDの製造年(下2桁)
+    pub production_year: u8,
+}
+
+/// GetVersionの応答の長さ
+pub const VERSION_SIZE: usize = 28;
+
+impl DesfireVersion {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != VERSION_SIZE {
+            return None;
+        }
+        let mut uid = [0u8; 7];
+        uid.copy_from_slice(&data[14..21]);
+        let mut batch_no = [0u8; 5];
+        batch_no.copy_from_slice(&data[21..26]);
+        Some(DesfireVersion {
+            hardware: VersionInfo::from_bytes(&data[0..7]),
+            software: VersionInfo::from_bytes(&data[7..14]),
+            uid,
+            batch_no,
+            production_week: data[26],
+            production_year: data[27],
+        })
+    }
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(VERSION_SIZE);
+        data.extend_from_slice(&self.hardware.to_bytes());
+        data.extend_from_slice(&self.software.to_bytes());
+        data.extend_from_slice(&self.uid);
+        data.extend_from_slice(&self.batch_no);
+        data.push(self.production_week);
+        data.push(self.production_year);
+        data
+    }
+    pub fn model(&self) -> DesfireModel {
+        if self.hardware.vendor_id != 0x04 {
+            return DesfireModel::Unknown;
+        }
+        match self.hardware.major_version {
+            0x00 => DesfireModel::Desfire,
+            0x01 => DesfireModel::DesfireEv1,
+            0x12 => DesfireModel::DesfireEv2,
+            0x33 => DesfireModel::DesfireEv3,
+            _ => DesfireModel::Unknown,
+        }
+    }
+}
+
+#[test]
+fn desfire_version_model() {
+    // DESFire EV1 4K
+    let data = [
+        0x04, 0x01, 0x01, 0x01, 0x00, 0x18, 0x05, 0x04, 0x01, 0x01, 0x01, 0x04, 0x18, 0x05, 0x04,
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xBA, 0x54, 0x37, 0x89, 0x10, 0x21, 0x19,
+    ];
+    let version = DesfireVersion::from_bytes(&data).unwrap();
+    assert_eq!(version.model(), DesfireModel::DesfireEv1);
+    assert_eq!(version.hardware.storage_bytes(), (4096, false));
+    assert_eq!(version.uid, [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    assert_eq!(version.production_year, 0x19);
+    assert_eq!(version.to_bytes(), data.to_vec());
+    assert!(DesfireVersion::from_bytes(&data[..27]).is_none());
+}
@@ -0,0 +1,447 @@
+// ICAO 9303 の機械読取式旅行文書(eMRTD、ICパスポート)の読み出し
+// MRZの文書番号・生年月日・有効期限から鍵を導き、PACE(ECDH汎用マッピング)またはBACでSMを確立して、
+// LDS1アプリケーションのEF.COM・EF.SOD・データグループ(DG1 MRZ、DG2 顔画像、DG11/12 追加の情報)を読む。
+// PACEはMFのEF.CardAccessに対応するプロトコルが書かれているときに使い、なければBACで認証する。
+// EF.SODのハッシュとデータグループの照合はできるが、署名(Document Signer証明書)の検証は行わない。
+
+pub mod bac;
+pub mod curve;
+pub mod lds;
+pub mod mrz;
+pub mod pace;
+
+use crate::crypto::sha::{sha1, sha256};
+use crate::crypto::{pad_iso9797, retail_mac, Cipher};
+use crate::iso7816::secure_messaging::{
+    AesSuite, CipherSuite, SecureMessagingCard, SmSession, TdesSuite, MAC_SIZE,
+};
+use crate::iso7816::{FileSystem, SelectResponse, SelectTarget};
+use crate::smart_card::Smartcard;
+use lds::{AdditionalDocumentDetails, AdditionalPersonalDetails, Com, FaceImage, SecurityObject};
+use mrz::{Mrz, MrzKey};
+use pace::Password;
+use std::convert::TryInto;
+
+/// LDS1 eMRTDアプリケーションのAID
+pub const LDS1_AID: [u8; 7] = [0xA0, 0x00, 0x00, 0x02, 0x47, 0x10, 0x01];
+/// MFの下のEF.CardAccess（PACEの情報）
+pub const EF_CARD_ACCESS: u16 = 0x011C;
+pub const EF_CARD_ACCESS_SFI: u8 = 0x1C;
+pub const EF_COM: u16 = 0x011E;
+pub const EF_SOD: u16 = 0x011D;
+/// SMで包んだ応答が短いAPDU(256バイト)に収まるよう、1回に読むバイト数
+const MAX_READ_SIZE: usize = 0xDF;
+/// 最初に読むバイト数（タグと長さ）
+const HEADER_SIZE: usize = 4;
+
+// 鍵導出関数のカウンタ
+const KDF_ENC: u32 = 1;
+const KDF_MAC: u32 = 2;
+const KDF_PASSWORD: u32 = 3;
+
+/// データグループ(1～16)のファイルID
+pub fn data_group_file_id(number: u8) -> u16 {
+    0x0100 | number as u16
+}
+
+/// SMの暗号方式（BACは3DESだけ）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmAlgorithm {
+    Tdes,
+    Aes128,
+    Aes192,
+    Aes256,
+}
+
+impl SmAlgorithm {
+    pub fn block_size(self) -> usize {
+        match self {
+            SmAlgorithm::Tdes => 8,
+            _ => 16,
+        }
+    }
+    /// ICAO 9303-11 9.7.1 の鍵導出関数 KDF(K, c)
+    /// 3DES・AES-128はSHA-1、AES-192/256はSHA-256を使う。3DESの鍵はパリティを合わせる
+    pub fn derive_key(self, secret: &[u8], counter: u32) -> Vec<u8> {
+        let input = [secret, &counter.to_be_bytes()[..]].concat();
+        match self {
+            SmAlgorithm::Tdes => {
+                let mut key = sha1(&input)[..16].to_vec();
+                adjust_parity(&mut key);
+                key
+            }
+            SmAlgorithm::Aes128 => sha1(&input)[..16].to_vec(),
+            SmAlgorithm::Aes192 => sha256(&input)[..24].to_vec(),
+            SmAlgorithm::Aes256 => sha256(&input).to_vec(),
+        }
+    }
+    /// パスワードから導いた鍵 K_π（PACEのノンスの暗号化）
+    pub fn password_key(self, password: &[u8]) -> Vec<u8> {
+        self.derive_key(password, KDF_PASSWORD)
+    }
+    /// 鍵の長さから決まるブロック暗号
+    pub fn cipher(self, key: &[u8]) -> Option<Cipher> {
+        match self {
+            SmAlgorithm::Tdes => Some(Cipher::tdes2(key.try_into().ok()?)),
+            _ => Cipher::aes(key),
+        }
+    }
+    /// MAC(3DESはパディングしてリテールMAC、AESはCMAC)の先頭8バイト
+    pub fn mac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            SmAlgorithm::Tdes => {
+                let mut mac_key = [0u8; 16];
+                mac_key.copy_from_slice(&key[..16]);
+                retail_mac(&mac_key, &pad_iso9797(data, self.block_size())).to_vec()
+            }
+            _ => match Cipher::aes(key) {
+                Some(cipher) => {
+                    let mut mac = cipher.cmac(&[0u8; 16], data);
+                    mac.truncate(MAC_SIZE);
+                    mac
+                }
+                None => Vec::new(),
+            },
+        }
+    }
+    /// 共有秘密からセッション鍵 KS_enc・KS_mac を導き、SMのセッションを作る
+    pub fn session(self, secret: &[u8], ssc: &[u8]) -> SmSession {
+        let enc_key = self.derive_key(secret, KDF_ENC);
+        let mac_key = self.derive_key(secret, KDF_MAC);
+        let suite: Box<dyn CipherSuite> = match self {
+            SmAlgorithm::Tdes => {
+                let mut enc = [0u8; 16];
+                enc.copy_from_slice(&enc_key);
+                let mut mac = [0u8; 16];
+                mac.copy_from_slice(&mac_key);
+                Box::new(TdesSuite::new(&enc, &mac))
+            }
+            // 導いた鍵の長さはAESの鍵長に合っている
+            _ => Box::new(AesSuite::new(&enc_key, &mac_key).unwrap()),
+        };
+        SmSession::new(suite, ssc)
+    }
+}
+
+/// DESの鍵の各バイトを奇数パリティにする
+fn adjust_parity(key: &mut [u8]) {
+    for byte in key.iter_mut() {
+        let bits = *byte & 0xFE;
+        *byte = bits | ((bits.count_ones() as u8 + 1) & 0x01);
+    }
+}
+
+/// SMを確立したeMRTD
+pub struct Emrtd<'a> {
+    sm: SecureMessagingCard<'a>,
+}
+
+impl<'a> Emrtd<'a> {
+    /// EF.CardAccessに対応するPACEのプロトコルがあればPACE、なければBACで認証する
+    pub fn open(nfc: &'a dyn Smartcard, key: &MrzKey) -> Result<Self, Box<dyn std::error::Error>> {
+        let infos = pace::read_card_access(nfc).unwrap_or_default();
+        match infos.iter().find(|info| info.is_supported()) {
+            Some(info) => Self::open_pace_with(nfc, &Password::Mrz(key.clone()), info),
+            None => Self::open_bac(nfc, key),
+        }
+    }
+    /// LDS1アプリケーションを選択してからBACで認証する
+    pub fn open_bac(
+        nfc: &'a dyn Smartcard,
+        key: &MrzKey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        FileSystem::new(nfc).select(SelectTarget::DfName(&LDS1_AID), SelectResponse::NoResponse)?;
+        let session = bac::authenticate(nfc, &bac::BacKeys::new(key))?;
+        Ok(Emrtd {
+            sm: SecureMessagingCard::new(nfc, session),
+        })
+    }
+    /// EF.CardAccessの最初の対応するプロトコルでPACEを行い、LDS1アプリケーションを選択する
+    pub fn open_pace(
+        nfc: &'a dyn Smartcard,
+        password: &Password,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let infos = pace::read_card_access(nfc)?;
+        let info = infos
+            .iter()
+            .find(|info| info.is_supported())
+            .ok_or_else(|| EmrtdError::new(EmrtdErrorKind::UnsupportedProtocol))?;
+        Self::open_pace_with(nfc, password, info)
+    }
+    fn open_pace_with(
+        nfc: &'a dyn Smartcard,
+        password: &Password,
+        info: &pace::PaceInfo,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let session = pace::establish(nfc, password, info)?;
+        let emrtd = Emrtd {
+            sm: SecureMessagingCard::new(nfc, session),
+        };
+        FileSystem::new(&emrtd.sm)
+            .select(SelectTarget::DfName(&LDS1_AID), SelectResponse::NoResponse)?;
+        Ok(emrtd)
+    }
+    /// SMで包んでコマンドを送るSmartcard
+    pub fn card(&self) -> &SecureMessagingCard<'a> {
+        &self.sm
+    }
+    /// LDS1アプリケーションのEFを選択し、先頭のデータオブジェクトの長さの分だけ読み出す
+    pub fn read_file(&self, file_id: u16) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let fs = FileSystem::new(&self.sm);
+        fs.select(SelectTarget::ChildEf(file_id), SelectResponse::NoResponse)?;
+        let mut data = fs.read_binary(0, HEADER_SIZE)?;
+        let total = match object_size(&data) {
+            Some(total) => total,
+            // 長さが4バイトに収まらない（5F2Eなどの2バイトのタグ・83の長さ）
+            None if data.len() == HEADER_SIZE => {
+                data.extend(fs.read_binary(HEADER_SIZE, HEADER_SIZE)?);
+                object_size(&data)
+                    .ok_or_else(|| EmrtdError::new(EmrtdErrorKind::InvalidFile(file_id)))?
+            }
+            None => {
+                return Err(Box::new(EmrtdError::new(EmrtdErrorKind::InvalidFile(
+                    file_id,
+                ))))
+            }
+        };
+        data.truncate(total);
+        while data.len() < total {
+            let chunk = fs.read_binary(data.len(), (total - data.len()).min(MAX_READ_SIZE))?;
+            if chunk.is_empty() {
+                return Err(Box::new(EmrtdError::new(EmrtdErrorKind::InvalidFile(
+                    file_id,
+                ))));
+            }
+            data.extend(chunk);
+        }
+        Ok(data)
+    }
+    /// データグループ(1～16)の内容
+    pub fn read_data_group(&self, number: u8) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.read_file(data_group_file_id(number))
+    }
+    pub fn read_com(&self) -> Result<Com, Box<dyn std::error::Error>> {
+        Ok(Com::parse(&self.read_file(EF_COM)?)?)
+    }
+    pub fn read_sod(&self) -> Result<SecurityObject, Box<dyn std::error::Error>> {
+        Ok(SecurityObject::parse(&self.read_file(EF_SOD)?)?)
+    }
+    /// DG1のMRZ
+    pub fn read_dg1(&self) -> Result<Mrz, Box<dyn std::error::Error>> {
+        Ok(lds::parse_dg1(&self.read_data_group(1)?)?)
+    }
+    /// DG2の顔画像
+    pub fn read_dg2(&self) -> Result<Vec<FaceImage>, Box<dyn std::error::Error>> {
+        Ok(lds::parse_dg2(&self.read_data_group(2)?)?)
+    }
+    /// DG11の本人の追加の情報
+    pub fn read_dg11(&self) -> Result<AdditionalPersonalDetails, Box<dyn std::error::Error>> {
+        Ok(AdditionalPersonalDetails::parse(
+            &self.read_data_group(11)?,
+        )?)
+    }
+    /// DG12の文書の追加の情報
+    pub fn read_dg12(&self) -> Result<AdditionalDocumentDetails, Box<dyn std::error::Error>> {
+        Ok(AdditionalDocumentDetails::parse(
+            &self.read_data_group(12)?,
+        )?)
+    }
+}
+
+/// 先頭のデータオブジェクトの全体のバイト数。タグと長さが揃っていなければNone
+fn object_size(header: &[u8]) -> Option<usize> {
+    let mut position = 1;
+    if header.first()? & 0x1F == 0x1F {
+        while header.get(position)? & 0x80 != 0 {
+            position += 1;
+        }
+        position += 1;
+    }
+    let first = *header.get(position)?;
+    position += 1;
+    let len = match first {
+        0x00..=0x7F => first as usize,
+        0x81..=0x84 => {
+            let count = (first & 0x7F) as usize;
+            let bytes = header.get(position..position + count)?;
+            position += count;
+            bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize)
+        }
+        _ => return None,
+    };
+    Some(position + len)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmrtdErrorKind {
+    /// MRZの文字・長さが正しくない
+    InvalidMrz,
+    /// BAC・PACEの応答が正しくない（乱数・認証トークン・公開鍵が合わない）
+    AuthenticationFailed,
+    /// 対応していないPACEのプロトコル・ドメインパラメータ
+    UnsupportedProtocol,
+    /// EFの長さ・内容がLDSの形式に合わない
+    InvalidFile(u16),
+}
+
+#[derive(Debug)]
+pub struct EmrtdError {
+    code: EmrtdErrorKind,
+}
+
+impl EmrtdError {
+    pub fn new(code: EmrtdErrorKind) -> Self {
+        EmrtdError { code }
+    }
+    pub fn kind(&self) -> &EmrtdErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for EmrtdError {}
+impl std::fmt::Display for EmrtdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn emrtd_read_data_groups() {
+    use crate::ber_tlv::Tlv;
+    use crate::nfc_impl::nfc_mock::emrtd::VirtualEmrtd;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+    use lds::{HashAlgorithm, ImageFormat};
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<EmrtdError>().unwrap().kind().clone();
+
+    let mrz =
+        "P<UTOERIKSSON<<ANNA<MARIA<<<<<<<<<<<<<<<<<<<L898902C<3UTO6908061F9406236ZE184226B<<<<<14";
+    // 顔画像レコード: ヘッダ・顔情報(特徴点なし)・画像情報(480x640)の後にJPEG。
+    // 32KBを超えるので、後ろは奇数INSのREAD BINARYで読む
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0];
+    jpeg.extend((0..40000).map(|i| (i % 251) as u8));
+    jpeg.extend(&[0xFF, 0xD9]);
+    let block_size = 20 + 12 + jpeg.len();
+    let mut record = b"FAC\x00010\x00".to_vec();
+    record.extend(&((14 + block_size) as u32).to_be_bytes());
+    record.extend(&[0x00, 0x01]);
+    record.extend(&(block_size as u32).to_be_bytes());
+    record.extend(&[0u8; 16]);
+    record.extend(&[0x01, 0x01, 0x01, 0xE0, 0x02, 0x80]);
+    record.extend(&[0u8; 6]);
+    record.extend(&jpeg);
+    let dg2 = Tlv::constructed(
+        lds::TAG_DG2,
+        vec![Tlv::constructed(
+            0x7F61,
+            vec![
+                Tlv::primitive(0x02, &[0x01]),
+                Tlv::constructed(0x7F60, vec![Tlv::primitive(0x5F2E, &record)]),
+            ],
+        )],
+    )
+    .to_bytes();
+    let dg11 = Tlv::constructed(
+        lds::TAG_DG11,
+        vec![
+            Tlv::primitive(0x5C, &[0x5F, 0x0E, 0x5F, 0x2B]),
+            Tlv::primitive(0x5F0E, "ERIKSSON<<ANNA<MARIA".as_bytes()),
+            Tlv::primitive(0x5F2B, b"19690806"),
+        ],
+    )
+    .to_bytes();
+    let dg12 = Tlv::constructed(
+        lds::TAG_DG12,
+        vec![
+            Tlv::primitive(0x5C, &[0x5F, 0x19, 0x5F, 0x26]),
+            Tlv::primitive(0x5F19, b"UTOPIA PASSPORT OFFICE"),
+            Tlv::primitive(0x5F26, &[0x20, 0x04, 0x06, 0x24]),
+        ],
+    )
+    .to_bytes();
+    let key = Mrz::parse(mrz).unwrap().key().unwrap();
+    let card = |pace: bool| {
+        let mut card = VirtualEmrtd::new(mrz);
+        card.set_data_group(2, &dg2)
+            .set_data_group(11, &dg11)
+            .set_data_group(12, &dg12);
+        if pace {
+            card.enable_pace(pace::PaceInfo::ecdh_gm(
+                SmAlgorithm::Aes256,
+                pace::PARAMETER_SECP256R1,
+            ))
+            .set_can("123456");
+        }
+        MockSmartcard::new(Box::new(card))
+    };
+
+    // EF.CardAccessがあればPACE
+    let nfc = card(true);
+    let emrtd = Emrtd::open(&nfc, &key).unwrap();
+    assert_eq!(emrtd.card().ssc().len(), 16);
+    let com = emrtd.read_com().unwrap();
+    assert_eq!(com.lds_version, "0107");
+    assert_eq!(com.data_groups, vec![1, 2, 11, 12]);
+    let dg1 = emrtd.read_dg1().unwrap();
+    assert_eq!(dg1.document_number, "L898902C");
+    assert_eq!(dg1.primary_identifier, "ERIKSSON");
+    assert!(dg1.check_digits_valid());
+    let images = emrtd.read_dg2().unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].format, ImageFormat::Jpeg);
+    assert_eq!((images[0].width, images[0].height), (480, 640));
+    assert_eq!(images[0].data, jpeg);
+    assert_eq!(images[0].extension(), "jpg");
+    let details = emrtd.read_dg11().unwrap();
+    assert_eq!(details.full_name.as_deref(), Some("ERIKSSON<<ANNA<MARIA"));
+    assert_eq!(details.full_date_of_birth.as_deref(), Some("19690806"));
+    let document = emrtd.read_dg12().unwrap();
+    assert_eq!(
+        document.issuing_authority.as_deref(),
+        Some("UTOPIA PASSPORT OFFICE")
+    );
+    assert_eq!(document.date_of_issue.as_deref(), Some("20040624"));
+    let sod = emrtd.read_sod().unwrap();
+    assert_eq!(sod.hash_algorithm(), HashAlgorithm::Sha256);
+    for number in com.data_groups {
+        let content = emrtd.read_data_group(number).unwrap();
+        assert_eq!(sod.verify(number, &content), Some(true));
+    }
+    assert_eq!(sod.verify(2, &dg11), Some(false));
+    assert_eq!(sod.verify(3, &dg11), None);
+    // 別のデータグループはEF.COMとして読めない
+    assert_eq!(
+        Com::parse(&dg11).unwrap_err().kind(),
+        &EmrtdErrorKind::InvalidFile(EF_COM)
+    );
+    assert_eq!(
+        lds::parse_dg1(&dg12).unwrap_err().kind(),
+        &EmrtdErrorKind::InvalidFile(data_group_file_id(1))
+    );
+    // CANでもPACEできる
+    let nfc = card(true);
+    let emrtd = Emrtd::open_pace(&nfc, &Password::Can("123456".to_owned())).unwrap();
+    assert_eq!(emrtd.read_dg1().unwrap().document_number, "L898902C");
+
+    // EF.CardAccessがなければBAC
+    let nfc = card(false);
+    assert!(pace::read_card_access(&nfc).is_err());
+    let emrtd = Emrtd::open(&nfc, &key).unwrap();
+    assert_eq!(emrtd.card().ssc().len(), 8);
+    assert_eq!(emrtd.read_dg1().unwrap().date_of_birth, "690806");
+    assert_eq!(emrtd.read_dg2().unwrap()[0].data, jpeg);
+    let wrong = MrzKey::new("L898902C", "690806", "940624").unwrap();
+    assert!(Emrtd::open(&nfc, &wrong).is_err());
+
+    // 対応するPACEのプロトコルがなければ認証しない
+    let mut unsupported = VirtualEmrtd::new(mrz);
+    unsupported.enable_pace(pace::PaceInfo::ecdh_gm(SmAlgorithm::Aes128, 99));
+    let nfc = MockSmartcard::new(Box::new(unsupported));
+    assert_eq!(
+        kind(
+            Emrtd::open_pace(&nfc, &Password::Can("123456".to_owned()))
+                .err()
+                .unwrap()
+        ),
+        EmrtdErrorKind::UnsupportedProtocol
+    );
+}
@@ -0,0 +1,186 @@
+// BAC(Basic Access Control、ICAO 9303-11 4.3)
+// GET CHALLENGEでカードの乱数RND.ICを受け取り、S = RND.IFD || RND.IC || K.IFD を3DES(IVは0)で暗号化して
+// リテールMACを付け、EXTERNAL AUTHENTICATEで送る。カードは RND.IC || RND.IFD || K.IC を同じように返す。
+// K.IFD xor K.IC からセッション鍵を導き、SSCは RND.IC と RND.IFD の下位4バイトずつをつないだもの。
+
+use super::mrz::MrzKey;
+use super::{EmrtdError, EmrtdErrorKind, SmAlgorithm};
+use crate::apdu_contactless::ApduBuilder;
+use crate::crypto::{secret_random_bytes, Cipher, DES_BLOCK_SIZE};
+use crate::iso7816::secure_messaging::{SmSession, MAC_SIZE};
+use crate::pc_sc_standard::ApduBuilderExtWithIso7816;
+use crate::smart_card::Smartcard;
+
+/// RND.IC・RND.IFDの長さ
+pub const CHALLENGE_SIZE: usize = 8;
+/// K.IFD・K.ICの長さ
+pub const KEY_MATERIAL_SIZE: usize = 16;
+/// E_IFD(32バイト) || M_IFD(8バイト)
+const AUTHENTICATION_SIZE: usize = CHALLENGE_SIZE * 2 + KEY_MATERIAL_SIZE + MAC_SIZE;
+
+/// MRZから導いた認証用の鍵 K_enc・K_mac
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacKeys {
+    enc: [u8; 16],
+    mac: [u8; 16],
+}
+
+impl BacKeys {
+    pub fn new(key: &MrzKey) -> Self {
+        let seed = key.key_seed();
+        let mut keys = BacKeys {
+            enc: [0u8; 16],
+            mac: [0u8; 16],
+        };
+        keys.enc
+            .copy_from_slice(&SmAlgorithm::Tdes.derive_key(&seed, super::KDF_ENC));
+        keys.mac
+            .copy_from_slice(&SmAlgorithm::Tdes.derive_key(&seed, super::KDF_MAC));
+        keys
+    }
+    pub fn enc_key(&self) -> &[u8; 16] {
+        &self.enc
+    }
+    pub fn mac_key(&self) -> &[u8; 16] {
+        &self.mac
+    }
+    /// 3DESで暗号化し、MACを付ける（E || M）
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let mut sealed = Cipher::tdes2(&self.enc).cbc_encrypt(&[0u8; DES_BLOCK_SIZE], data);
+        let mac = SmAlgorithm::Tdes.mac(&self.mac, &sealed);
+        sealed.extend(mac);
+        sealed
+    }
+    /// MACを確かめて復号する
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < MAC_SIZE {
+            return None;
+        }
+        let (cryptogram, mac) = sealed.split_at(sealed.len() - MAC_SIZE);
+        if cryptogram.len() % DES_BLOCK_SIZE != 0
+            || SmAlgorithm::Tdes.mac(&self.mac, cryptogram) != mac
+        {
+            return None;
+        }
+        Some(Cipher::tdes2(&self.enc).cbc_decrypt(&[0u8; DES_BLOCK_SIZE], cryptogram))
+    }
+}
+
+/// BACを行い、SMのセッションを返す
+pub fn authenticate(
+    nfc: &dyn Smartcard,
+    keys: &BacKeys,
+) -> Result<SmSession, Box<dyn std::error::Error>> {
+    authenticate_with(
+        nfc,
+        keys,
+        &secret_random_bytes(CHALLENGE_SIZE)?,
+        &secret_random_bytes(KEY_MATERIAL_SIZE)?,
+    )
+}
+
+fn authenticate_with(
+    nfc: &dyn Smartcard,
+    keys: &BacKeys,
+    rnd_ifd: &[u8],
+    k_ifd: &[u8],
+) -> Result<SmSession, Box<dyn std::error::Error>> {
+    let failed = || EmrtdError::new(EmrtdErrorKind::AuthenticationFailed);
+    let apdu = ApduBuilder::new()
+        .get_challenge(CHALLENGE_SIZE as u8)
+        .build();
+    let rnd_ic = nfc.transmit(Box::new(apdu))?;
+    if rnd_ic.len() != CHALLENGE_SIZE {
+        return Err(Box::new(failed()));
+    }
+    let data = keys.seal(&[rnd_ifd, &rnd_ic[..], k_ifd].concat());
+    let apdu = ApduBuilder::new()
+        .external_authenticate(&data, AUTHENTICATION_SIZE as u8)
+        .build();
+    let res = nfc.transmit(Box::new(apdu))?;
+    let plain = keys.open(&res).ok_or_else(failed)?;
+    // RND.IC || RND.IFD || K.IC
+    if plain.len() != CHALLENGE_SIZE * 2 + KEY_MATERIAL_SIZE
+        || plain[..CHALLENGE_SIZE] != rnd_ic[..]
+        || plain[CHALLENGE_SIZE..CHALLENGE_SIZE * 2] != *rnd_ifd
+    {
+        return Err(Box::new(failed()));
+    }
+    let k_ic = &plain[CHALLENGE_SIZE * 2..];
+    let seed = k_ifd
+        .iter()
+        .zip(k_ic.iter())
+        .map(|(a, b)| a ^ b)
+        .collect::<Vec<u8>>();
+    let ssc = [&rnd_ic[4..], &rnd_ifd[4..]].concat();
+    Ok(SmAlgorithm::Tdes.session(&seed, &ssc))
+}
+
+#[test]
+fn emrtd_bac() {
+    use crate::crypto::hex;
+    use crate::emrtd::{Emrtd, EF_COM, LDS1_AID};
+    use crate::iso7816::secure_messaging::SecureMessagingCard;
+    use crate::iso7816::{FileSystem, SelectResponse, SelectTarget};
+    use crate::nfc_impl::nfc_mock::emrtd::VirtualEmrtd;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    // ICAO 9303-11 付録D.3
+    let key = MrzKey::new("L898902C", "690806", "940623").unwrap();
+    let keys = BacKeys::new(&key);
+    assert_eq!(
+        keys.enc_key().to_vec(),
+        hex("AB94FDECF2674FDFB9B391F85D7F76F2")
+    );
+    assert_eq!(
+        keys.mac_key().to_vec(),
+        hex("7962D9ECE03D1ACD4C76089DCE131543")
+    );
+    let rnd_ic = hex("4608F91988702212");
+    let rnd_ifd = hex("781723860C06C226");
+    let k_ifd = hex("0B795240CB7049B01C19B33E32804F0B");
+    assert_eq!(
+        keys.seal(&[&rnd_ifd[..], &rnd_ic[..], &k_ifd[..]].concat()),
+        hex("72C29C2371CC9BDB65B779B8E8D37B29ECC154AA56A8799FAE2F498F76ED92F25F1448EEA8AD90A7")
+    );
+
+    // 付録D.4 のEF.COMを持つ仮想カード。SSCは付録D.4 と同じように進む
+    let com = hex("60145F0104303130365F36063034303030305C026175");
+    let mut card = VirtualEmrtd::new(
+        "P<UTOERIKSSON<<ANNA<MARIA<<<<<<<<<<<<<<<<<<<L898902C<3UTO6908061F9406236ZE184226B<<<<<14",
+    );
+    card.set_file(EF_COM, &com);
+    card.set_bac_randoms(&rnd_ic, &hex("0B4F80323EB3191CB04970CB4052790B"));
+    let nfc = MockSmartcard::new(Box::new(card));
+    FileSystem::new(&nfc)
+        .select(SelectTarget::DfName(&LDS1_AID), SelectResponse::NoResponse)
+        .unwrap();
+    let session = authenticate_with(&nfc, &keys, &rnd_ifd, &k_ifd).unwrap();
+    assert_eq!(session.ssc().to_vec(), hex("887022120C06C226"));
+    let sm = SecureMessagingCard::new(&nfc, session);
+    let emrtd = Emrtd { sm };
+    assert_eq!(emrtd.read_file(EF_COM).unwrap(), com);
+    assert_eq!(emrtd.card().ssc(), hex("887022120C06C22C"));
+
+    // MRZが違うと認証できない
+    let wrong = BacKeys::new(&MrzKey::new("L898902C", "690806", "940624").unwrap());
+    let error = authenticate(&nfc, &wrong).err().unwrap();
+    assert_eq!(
+        crate::iso7816::status_word(error.as_ref()),
+        Some((0x63, 0x00))
+    );
+
+    // チャレンジが8バイトでなければ認証できない
+    let mut card = VirtualEmrtd::new(
+        "P<UTOERIKSSON<<ANNA<MARIA<<<<<<<<<<<<<<<<<<<L898902C<3UTO6908061F9406236ZE184226B<<<<<14",
+    );
+    card.set_bac_randoms(&rnd_ic[..7], &hex("0B4F80323EB3191CB04970CB4052790B"));
+    let nfc = MockSmartcard::new(Box::new(card));
+    let error = authenticate_with(&nfc, &keys, &rnd_ifd, &k_ifd)
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.downcast_ref::<EmrtdError>().unwrap().kind(),
+        &EmrtdErrorKind::AuthenticationFailed
+    );
+}
@@ -0,0 +1,166 @@
+// PACEの鍵共有で使う楕円曲線の演算。点は非圧縮形式(04 || X || Y)、スカラーはビッグエンディアンのバイト列で扱う
+// P-256はp256クレートの定数時間の実装を使う。
+// brainpoolP256r1・secp224r1は使えるクレートがないのでoriginality::eccの実装を使う。
+// こちらは定数時間ではないので、一時鍵がタイミングなどのサイドチャネルから漏れうる。
+
+use crate::crypto::secret_random_bytes;
+use crate::originality::ecc::EllipticCurve;
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use p256::elliptic_curve::group::Group;
+use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use p256::elliptic_curve::{Field, PrimeField};
+use p256::{AffinePoint, EncodedPoint, FieldBytes, ProjectivePoint, Scalar};
+
+/// P-256の座標のバイト数
+const P256_SIZE: usize = 32;
+
+pub enum Curve {
+    /// NIST P-256 (p256クレート)
+    P256,
+    /// originality::eccの曲線
+    Weierstrass(EllipticCurve),
+}
+
+impl Curve {
+    pub fn secp224r1() -> Self {
+        Curve::Weierstrass(EllipticCurve::secp224r1())
+    }
+    pub fn brainpool_p256r1() -> Self {
+        Curve::Weierstrass(EllipticCurve::brainpool_p256r1())
+    }
+    /// 座標1つ分のバイト数
+    pub fn size(&self) -> usize {
+        match self {
+            Curve::P256 => P256_SIZE,
+            Curve::Weierstrass(curve) => curve.size(),
+        }
+    }
+    pub fn generator(&self) -> Vec<u8> {
+        match self {
+            Curve::P256 => encode_p256(&ProjectivePoint::GENERATOR).unwrap(),
+            Curve::Weierstrass(curve) => curve.encode_point(&curve.generator()),
+        }
+    }
+    /// 一時鍵(1～n-1)
+    pub fn random_private_key(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Curve::P256 => loop {
+                // nは2^256に近いので、範囲外を引き直してもほとんど繰り返さない
+                let key = secret_random_bytes(P256_SIZE)?;
+                if p256_scalar(&key).is_some() {
+                    return Ok(key);
+                }
+            },
+            Curve::Weierstrass(curve) => {
+                let order = curve.order();
+                let random = BigUint::from_bytes_be(&secret_random_bytes(curve.size() + 8)?);
+                let key = random % (order - BigUint::one()) + BigUint::one();
+                Ok(curve.encode_coordinate(&key))
+            }
+        }
+    }
+    /// スカラー倍。点が曲線上にない・スカラーが0または位数以上・結果が無限遠点ならNone
+    pub fn multiply(&self, point: &[u8], scalar: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Curve::P256 => encode_p256(&(decode_p256(point)? * p256_scalar(scalar)?)),
+            Curve::Weierstrass(curve) => {
+                let scalar = BigUint::from_bytes_be(scalar);
+                if scalar.is_zero() || &scalar >= curve.order() {
+                    return None;
+                }
+                let point = curve.multiply_point(&curve.decode_point(point)?, &scalar)?;
+                Some(curve.encode_point(&point))
+            }
+        }
+    }
+    /// 2点の和。結果が無限遠点ならNone
+    pub fn add(&self, lhs: &[u8], rhs: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Curve::P256 => encode_p256(&(decode_p256(lhs)? + decode_p256(rhs)?)),
+            Curve::Weierstrass(curve) => {
+                let point =
+                    curve.add_points(&curve.decode_point(lhs)?, &curve.decode_point(rhs)?)?;
+                Some(curve.encode_point(&point))
+            }
+        }
+    }
+    /// 点のx座標
+    pub fn x_coordinate<'a>(&self, point: &'a [u8]) -> &'a [u8] {
+        &point[1..=self.size()]
+    }
+}
+
+/// 0でなく位数未満のスカラー
+fn p256_scalar(bytes: &[u8]) -> Option<Scalar> {
+    if bytes.len() > P256_SIZE {
+        return None;
+    }
+    let mut repr = FieldBytes::default();
+    repr[P256_SIZE - bytes.len()..].copy_from_slice(bytes);
+    let scalar = Option::<Scalar>::from(Scalar::from_repr(repr))?;
+    if bool::from(scalar.is_zero()) {
+        return None;
+    }
+    Some(scalar)
+}
+
+fn decode_p256(data: &[u8]) -> Option<ProjectivePoint> {
+    if data.len() != 1 + P256_SIZE * 2 || data[0] != 0x04 {
+        return None;
+    }
+    let encoded = EncodedPoint::from_bytes(data).ok()?;
+    let point = Option::<AffinePoint>::from(AffinePoint::from_encoded_point(&encoded))?;
+    Some(ProjectivePoint::from(point))
+}
+
+fn encode_p256(point: &ProjectivePoint) -> Option<Vec<u8>> {
+    if bool::from(point.is_identity()) {
+        return None;
+    }
+    Some(
+        point
+            .to_affine()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec(),
+    )
+}
+
+#[test]
+fn emrtd_curve() {
+    use crate::crypto::hex;
+
+    // 2G = G + G（SEC 2 のP-256の生成元から求めた値）
+    let double = hex(concat!(
+        "04",
+        "7CF27B188D034F7E8A52380304B51AC3C08969E277F21B35A60B48FC47669978",
+        "07775510DB8ED040293D9AC69F7430DBBA7DADE63CE982299E04B79D227873D1"
+    ));
+    let curve = Curve::P256;
+    let g = curve.generator();
+    assert_eq!(curve.multiply(&g, &[0x02]), Some(double.clone()));
+    assert_eq!(curve.add(&g, &g), Some(double.clone()));
+    assert_eq!(
+        curve.x_coordinate(&double),
+        &hex("7CF27B188D034F7E8A52380304B51AC3C08969E277F21B35A60B48FC47669978")[..]
+    );
+    // 曲線上にない点・0・位数以上のスカラーは使わない
+    let mut invalid = double.clone();
+    invalid[64] ^= 0x01;
+    assert_eq!(curve.multiply(&invalid, &[0x02]), None);
+    assert_eq!(curve.multiply(&g, &[0x00]), None);
+    let order = hex("FFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551");
+    assert_eq!(curve.multiply(&g, &order), None);
+
+    // どの曲線でも両側の共有秘密が一致する
+    for curve in [Curve::P256, Curve::brainpool_p256r1(), Curve::secp224r1()].iter() {
+        let a = curve.random_private_key().unwrap();
+        let b = curve.random_private_key().unwrap();
+        let g = curve.generator();
+        let shared_a = curve.multiply(&curve.multiply(&g, &b).unwrap(), &a);
+        let shared_b = curve.multiply(&curve.multiply(&g, &a).unwrap(), &b);
+        assert!(shared_a.is_some());
+        assert_eq!(shared_a, shared_b);
+    }
+}
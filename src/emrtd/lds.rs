@@ -0,0 +1,399 @@
+// LDS1のEF.COM・EF.SOD・データグループの解析（ICAO 9303-10）
+// EF.COM(60): LDSのバージョン(5F01)・Unicodeのバージョン(5F36)・あるデータグループのタグの一覧(5C)
+// DG1(61): MRZ(5F1F)
+// DG2(75): 生体情報グループテンプレート(7F61)の 7F60 ごとに ISO/IEC 19794-5 の顔画像レコード(5F2E/7F2E)があり、
+//   レコードのヘッダ('FAC' 00)と顔情報を読み飛ばして JPEG または JPEG 2000 の画像を取り出す。
+// DG11(6B)・DG12(6C): 本人・文書の追加の情報。文字はUTF-8
+// EF.SOD(77): CMSのSignedDataのeContent(LDSSecurityObject)から、データグループごとのハッシュを取り出す
+
+use super::mrz::Mrz;
+use super::{data_group_file_id, EmrtdError, EmrtdErrorKind, EF_COM, EF_SOD};
+use crate::ber_tlv::{self, TagPath, Tlv};
+use crate::crypto::sha::{sha1, sha256};
+
+pub const TAG_COM: u32 = 0x60;
+pub const TAG_DG1: u32 = 0x61;
+pub const TAG_DG2: u32 = 0x75;
+pub const TAG_DG11: u32 = 0x6B;
+pub const TAG_DG12: u32 = 0x6C;
+pub const TAG_SOD: u32 = 0x77;
+/// データグループ1～16のタグ
+const DATA_GROUP_TAGS: [u8; 16] = [
+    0x61, 0x75, 0x63, 0x76, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x6B, 0x6C, 0x6D, 0x6E, 0x6F, 0x70,
+];
+/// ISO/IEC 19794-5 の顔画像レコードのヘッダ('FAC' 00 || 版 || レコード長(4) || 顔の数(2))
+const FACE_RECORD_HEADER_SIZE: usize = 14;
+/// 顔情報ブロック（ブロック長(4)から姿勢の不確かさまで）
+const FACIAL_INFORMATION_SIZE: usize = 20;
+const FEATURE_POINT_SIZE: usize = 8;
+/// 画像情報（種類・データの種類・幅・高さ・色空間・取得元・機器・品質）
+const IMAGE_INFORMATION_SIZE: usize = 12;
+const JPEG_MAGIC: [u8; 3] = [0xFF, 0xD8, 0xFF];
+/// JPEG 2000 のファイル形式(JP2)とコードストリーム
+const JP2_MAGIC: [u8; 6] = [0x00, 0x00, 0x00, 0x0C, 0x6A, 0x50];
+const J2K_MAGIC: [u8; 4] = [0xFF, 0x4F, 0xFF, 0x51];
+// ハッシュアルゴリズムのOID
+const OID_SHA1: [u8; 5] = [0x2B, 0x0E, 0x03, 0x02, 0x1A];
+const OID_SHA256: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+/// データグループのタグから番号(1～16)
+pub fn data_group_number(tag: u8) -> Option<u8> {
+    DATA_GROUP_TAGS
+        .iter()
+        .position(|t| *t == tag)
+        .map(|index| index as u8 + 1)
+}
+
+/// 先頭のタグがtagのデータオブジェクトを解析する
+fn parse_template(data: &[u8], tag: u32, file_id: u16) -> Result<Tlv, EmrtdError> {
+    match Tlv::parse(data) {
+        Ok((tlv, _)) if tlv.tag.0 == tag => Ok(tlv),
+        _ => Err(EmrtdError::new(EmrtdErrorKind::InvalidFile(file_id))),
+    }
+}
+
+fn path(text: &str) -> TagPath {
+    // 定数のパスなので失敗しない
+    text.parse().unwrap()
+}
+
+fn text_of(template: &Tlv, tag: u32) -> Option<String> {
+    template
+        .children()
+        .iter()
+        .find(|child| child.tag.0 == tag)
+        .and_then(|child| child.value())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// 日付・日時の値。ASCIIの数字か、BCD（4バイトの日付など）
+fn date_of(template: &Tlv, tag: u32) -> Option<String> {
+    let value = template
+        .children()
+        .iter()
+        .find(|child| child.tag.0 == tag)?
+        .value()?;
+    if value.iter().all(|b| b.is_ascii_digit()) {
+        return Some(String::from_utf8_lossy(value).into_owned());
+    }
+    Some(value.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+/// A0 { 02 件数, tag 名前 .. } の名前の並び
+fn names_of(template: &Tlv, tag: u32) -> Vec<String> {
+    ber_tlv::find_all(template.children(), &path("A0"))
+        .iter()
+        .flat_map(|list| list.children().iter())
+        .filter(|child| child.tag.0 == tag)
+        .filter_map(|child| child.value())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .collect()
+}
+
+/// EF.COM
+#[derive(Debug, Clone, PartialEq)]
+pub struct Com {
+    /// 例: "0107"
+    pub lds_version: String,
+    /// 例: "040000"
+    pub unicode_version: String,
+    /// あるデータグループの番号
+    pub data_groups: Vec<u8>,
+}
+
+impl Com {
+    pub fn parse(data: &[u8]) -> Result<Self, EmrtdError> {
+        let com = parse_template(data, TAG_COM, EF_COM)?;
+        let tags = com
+            .children()
+            .iter()
+            .find(|child| child.tag.0 == 0x5C)
+            .and_then(|child| child.value())
+            .ok_or_else(|| EmrtdError::new(EmrtdErrorKind::InvalidFile(EF_COM)))?;
+        Ok(Com {
+            lds_version: text_of(&com, 0x5F01).unwrap_or_default(),
+            unicode_version: text_of(&com, 0x5F36).unwrap_or_default(),
+            data_groups: tags
+                .iter()
+                .filter_map(|tag| data_group_number(*tag))
+                .collect(),
+        })
+    }
+}
+
+/// DG1のMRZ
+pub fn parse_dg1(data: &[u8]) -> Result<Mrz, EmrtdError> {
+    let dg1 = parse_template(data, TAG_DG1, data_group_file_id(1))?;
+    let mrz = text_of(&dg1, 0x5F1F)
+        .ok_or_else(|| EmrtdError::new(EmrtdErrorKind::InvalidFile(data_group_file_id(1))))?;
+    Mrz::parse(&mrz)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Jpeg2000,
+    Unknown,
+}
+
+/// DG2の顔画像
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceImage {
+    pub format: ImageFormat,
+    /// 顔画像レコードから読めなければ0
+    pub width: u16,
+    pub height: u16,
+    /// 画像ファイルそのもの
+    pub data: Vec<u8>,
+}
+
+impl FaceImage {
+    /// 拡張子（jpg・jp2）
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Jpeg2000 => "jp2",
+            ImageFormat::Unknown => "bin",
+        }
+    }
+}
+
+fn image_format(data: &[u8]) -> ImageFormat {
+    if data.starts_with(&JPEG_MAGIC) {
+        ImageFormat::Jpeg
+    } else if data.starts_with(&JP2_MAGIC) || data.starts_with(&J2K_MAGIC) {
+        ImageFormat::Jpeg2000
+    } else {
+        ImageFormat::Unknown
+    }
+}
+
+/// ISO/IEC 19794-5 の顔画像レコードから画像を取り出す
+/// 形式が違うときは、JPEG・JPEG 2000の先頭を探してそこから最後までを画像とする
+fn parse_face_record(record: &[u8]) -> Vec<FaceImage> {
+    let read_u32 = |data: &[u8], at: usize| {
+        data.get(at..at + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let read_u16 = |data: &[u8], at: usize| {
+        data.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let mut images = Vec::new();
+    if record.starts_with(b"FAC\0") {
+        let count = read_u16(record, 12).unwrap_or(0);
+        let mut position = FACE_RECORD_HEADER_SIZE;
+        for _ in 0..count {
+            let block = match read_u32(record, position) {
+                Some(block) if block > 0 && position + block <= record.len() => block,
+                _ => break,
+            };
+            let points = read_u16(record, position + 4).unwrap_or(0) as usize;
+            let info = position + FACIAL_INFORMATION_SIZE + points * FEATURE_POINT_SIZE;
+            let start = info + IMAGE_INFORMATION_SIZE;
+            if start <= position + block {
+                let data = record[start..position + block].to_vec();
+                images.push(FaceImage {
+                    format: image_format(&data),
+                    width: read_u16(record, info + 2).unwrap_or(0),
+                    height: read_u16(record, info + 4).unwrap_or(0),
+                    data,
+                });
+            }
+            position += block;
+        }
+    }
+    if images.is_empty() {
+        let start = (0..record.len()).find(|i| {
+            let rest = &record[*i..];
+            rest.starts_with(&JPEG_MAGIC)
+                || rest.starts_with(&JP2_MAGIC)
+                || rest.starts_with(&J2K_MAGIC)
+        });
+        if let Some(start) = start {
+            let data = record[start..].to_vec();
+            images.push(FaceImage {
+                format: image_format(&data),
+                width: 0,
+                height: 0,
+                data,
+            });
+        }
+    }
+    images
+}
+
+/// DG2のすべての顔画像
+pub fn parse_dg2(data: &[u8]) -> Result<Vec<FaceImage>, EmrtdError> {
+    let dg2 = parse_template(data, TAG_DG2, data_group_file_id(2))?;
+    let images = ber_tlv::find_all(dg2.children(), &path("7F61/7F60"))
+        .iter()
+        .filter_map(|template| {
+            template
+                .children()
+                .iter()
+                .find(|child| child.tag.0 == 0x5F2E || child.tag.0 == 0x7F2E)
+        })
+        .flat_map(|block| parse_face_record(&block.value_bytes()))
+        .collect();
+    Ok(images)
+}
+
+/// DG11 本人の追加の情報
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AdditionalPersonalDetails {
+    /// 姓<<名 の形式
+    pub full_name: Option<String>,
+    pub other_names: Vec<String>,
+    pub personal_number: Option<String>,
+    /// YYYYMMDD
+    pub full_date_of_birth: Option<String>,
+    pub place_of_birth: Option<String>,
+    pub address: Option<String>,
+    pub telephone: Option<String>,
+    pub profession: Option<String>,
+    pub title: Option<String>,
+    pub personal_summary: Option<String>,
+    pub other_travel_documents: Option<String>,
+    pub custody_information: Option<String>,
+}
+
+impl AdditionalPersonalDetails {
+    pub fn parse(data: &[u8]) -> Result<Self, EmrtdError> {
+        let dg11 = parse_template(data, TAG_DG11, data_group_file_id(11))?;
+        Ok(AdditionalPersonalDetails {
+            full_name: text_of(&dg11, 0x5F0E),
+            other_names: names_of(&dg11, 0x5F0F),
+            personal_number: text_of(&dg11, 0x5F10),
+            full_date_of_birth: date_of(&dg11, 0x5F2B),
+            place_of_birth: text_of(&dg11, 0x5F11),
+            address: text_of(&dg11, 0x5F42),
+            telephone: text_of(&dg11, 0x5F12),
+            profession: text_of(&dg11, 0x5F13),
+            title: text_of(&dg11, 0x5F14),
+            personal_summary: text_of(&dg11, 0x5F15),
+            other_travel_documents: text_of(&dg11, 0x5F17),
+            custody_information: text_of(&dg11, 0x5F18),
+        })
+    }
+}
+
+/// DG12 文書の追加の情報
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AdditionalDocumentDetails {
+    pub issuing_authority: Option<String>,
+    /// YYYYMMDD
+    pub date_of_issue: Option<String>,
+    pub other_persons: Vec<String>,
+    pub endorsements: Option<String>,
+    pub tax_or_exit_requirements: Option<String>,
+    /// 文書の表・裏の画像
+    pub image_of_front: Option<Vec<u8>>,
+    pub image_of_rear: Option<Vec<u8>>,
+    /// YYYYMMDDhhmmss
+    pub personalization_time: Option<String>,
+    pub personalization_device: Option<String>,
+}
+
+impl AdditionalDocumentDetails {
+    pub fn parse(data: &[u8]) -> Result<Self, EmrtdError> {
+        let dg12 = parse_template(data, TAG_DG12, data_group_file_id(12))?;
+        let bytes_of = |tag: u32| {
+            dg12.children()
+                .iter()
+                .find(|child| child.tag.0 == tag)
+                .and_then(|child| child.value())
+                .map(|value| value.to_vec())
+        };
+        Ok(AdditionalDocumentDetails {
+            issuing_authority: text_of(&dg12, 0x5F19),
+            date_of_issue: date_of(&dg12, 0x5F26),
+            other_persons: names_of(&dg12, 0x5F1A),
+            endorsements: text_of(&dg12, 0x5F1B),
+            tax_or_exit_requirements: text_of(&dg12, 0x5F1C),
+            image_of_front: bytes_of(0x5F1D),
+            image_of_rear: bytes_of(0x5F1E),
+            personalization_time: date_of(&dg12, 0x5F55),
+            personalization_device: text_of(&dg12, 0x5F56),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    /// SHA-224/384/512など（照合はできない）
+    Other,
+}
+
+/// EF.SODのLDSSecurityObject
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityObject {
+    /// ハッシュアルゴリズムのOID
+    pub hash_algorithm_oid: Vec<u8>,
+    /// データグループの番号とハッシュ
+    pub data_group_hashes: Vec<(u8, Vec<u8>)>,
+}
+
+impl SecurityObject {
+    /// 77 { ContentInfo { signedData, [0] SignedData { .., EncapsulatedContentInfo { .., [0] OCTET STRING } } } }
+    pub fn parse(data: &[u8]) -> Result<Self, EmrtdError> {
+        let invalid = || EmrtdError::new(EmrtdErrorKind::InvalidFile(EF_SOD));
+        let sod = parse_template(data, TAG_SOD, EF_SOD)?;
+        let content = sod
+            .get(&path("30/A0/30/30/A0/04"))
+            .and_then(|content| content.value())
+            .ok_or_else(invalid)?;
+        // LDSSecurityObject { version, hashAlgorithm { OID, .. }, SEQUENCE OF { 番号, ハッシュ } }
+        let (object, _) = Tlv::parse(content).map_err(|_| invalid())?;
+        let (algorithm, hashes) = match object.children() {
+            [_, algorithm, hashes, ..] => (algorithm, hashes),
+            _ => return Err(invalid()),
+        };
+        let hash_algorithm_oid = algorithm
+            .children()
+            .first()
+            .filter(|oid| oid.tag.0 == 0x06)
+            .and_then(|oid| oid.value())
+            .ok_or_else(invalid)?
+            .to_vec();
+        let data_group_hashes = hashes
+            .children()
+            .iter()
+            .map(|entry| match entry.children() {
+                [number, hash] => match (number.value(), hash.value()) {
+                    (Some([number]), Some(hash)) if !hash.is_empty() => {
+                        Ok((*number, hash.to_vec()))
+                    }
+                    _ => Err(invalid()),
+                },
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<(u8, Vec<u8>)>, EmrtdError>>()?;
+        Ok(SecurityObject {
+            hash_algorithm_oid,
+            data_group_hashes,
+        })
+    }
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        if self.hash_algorithm_oid == OID_SHA1 {
+            HashAlgorithm::Sha1
+        } else if self.hash_algorithm_oid == OID_SHA256 {
+            HashAlgorithm::Sha256
+        } else {
+            HashAlgorithm::Other
+        }
+    }
+    /// データグループの内容のハッシュがEF.SODと合うか
+    /// EF.SODにない・ハッシュアルゴリズムに対応していないときはNone
+    pub fn verify(&self, number: u8, content: &[u8]) -> Option<bool> {
+        let (_, expected) = self.data_group_hashes.iter().find(|(n, _)| *n == number)?;
+        let hash = match self.hash_algorithm() {
+            HashAlgorithm::Sha1 => sha1(content).to_vec(),
+            HashAlgorithm::Sha256 => sha256(content).to_vec(),
+            HashAlgorithm::Other => return None,
+        };
+        Some(&hash == expected)
+    }
+}
@@ -0,0 +1,352 @@
+// MRZ(機械読取領域)の解析と、BAC・PACEの鍵の元になるMRZ情報
+// 文字は 0-9・A-Z・<(埋め草) で、チェックディジットは重み 7・3・1 を繰り返して掛けた値(A=10～Z=35、<=0)の和の10の剰余。
+// TD1(30文字×3行、IDカード)・TD2(36文字×2行)・TD3(44文字×2行、パスポート)の形式を読む。
+// MRZ情報は 文書番号(9文字に満たなければ<で埋める)・生年月日・有効期限 にそれぞれのチェックディジットを付けたもの。
+
+use super::{EmrtdError, EmrtdErrorKind};
+use crate::crypto::sha::{sha1, SHA1_SIZE};
+
+const TD1_LINE: usize = 30;
+const TD2_LINE: usize = 36;
+const TD3_LINE: usize = 44;
+/// 文書番号の最短の長さ
+const DOCUMENT_NUMBER_SIZE: usize = 9;
+const FILLER: char = '<';
+
+fn char_value(c: char) -> Option<u32> {
+    match c {
+        '0'..='9' => Some(c as u32 - '0' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32 + 10),
+        FILLER => Some(0),
+        _ => None,
+    }
+}
+
+/// チェックディジット（MRZに使えない文字があればNone）
+pub fn check_digit(text: &str) -> Option<char> {
+    let sum = text
+        .chars()
+        .zip([7, 3, 1].iter().cycle())
+        .try_fold(0u32, |sum, (c, weight)| Some(sum + char_value(c)? * weight))?;
+    std::char::from_digit(sum % 10, 10)
+}
+
+fn invalid() -> EmrtdError {
+    EmrtdError::new(EmrtdErrorKind::InvalidMrz)
+}
+
+/// BAC・PACEの鍵の元（文書番号・生年月日・有効期限）
+#[derive(Debug, Clone, PartialEq)]
+pub struct MrzKey {
+    document_number: String,
+    date_of_birth: String,
+    date_of_expiry: String,
+}
+
+impl MrzKey {
+    /// 日付はYYMMDD。文書番号の末尾の<は省いてよい
+    pub fn new(
+        document_number: &str,
+        date_of_birth: &str,
+        date_of_expiry: &str,
+    ) -> Result<Self, EmrtdError> {
+        let mut document_number = document_number.to_ascii_uppercase();
+        while document_number.len() < DOCUMENT_NUMBER_SIZE {
+            document_number.push(FILLER);
+        }
+        let is_date = |date: &str| date.len() == 6 && date.chars().all(|c| c.is_ascii_digit());
+        if check_digit(&document_number).is_none()
+            || !is_date(date_of_birth)
+            || !is_date(date_of_expiry)
+        {
+            return Err(invalid());
+        }
+        Ok(MrzKey {
+            document_number,
+            date_of_birth: date_of_birth.to_owned(),
+            date_of_expiry: date_of_expiry.to_owned(),
+        })
+    }
+    /// チェックディジットを付けたMRZ情報
+    pub fn information(&self) -> String {
+        [
+            &self.document_number,
+            &self.date_of_birth,
+            &self.date_of_expiry,
+        ]
+        .iter()
+        .flat_map(|field| {
+            // 文字はnewで確かめている
+            let digit = check_digit(field).unwrap_or('0');
+            field.chars().chain(std::iter::once(digit))
+        })
+        .collect()
+    }
+    /// MRZ情報のSHA-1（PACEのパスワード）
+    pub fn hash(&self) -> [u8; SHA1_SIZE] {
+        sha1(self.information().as_bytes())
+    }
+    /// BACの鍵の元 K_seed（MRZ情報のSHA-1の先頭16バイト）
+    pub fn key_seed(&self) -> [u8; 16] {
+        let mut seed = [0u8; 16];
+        seed.copy_from_slice(&self.hash()[..16]);
+        seed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentFormat {
+    /// 30文字×3行
+    Td1,
+    /// 36文字×2行
+    Td2,
+    /// 44文字×2行
+    Td3,
+}
+
+/// MRZの各項目（末尾の<は取り除き、名前の<は空白にする）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mrz {
+    pub format: DocumentFormat,
+    /// P(パスポート)・I・A・C など
+    pub document_code: String,
+    pub issuing_state: String,
+    /// 姓
+    pub primary_identifier: String,
+    /// 名
+    pub secondary_identifier: String,
+    pub document_number: String,
+    pub nationality: String,
+    /// YYMMDD
+    pub date_of_birth: String,
+    /// M・F・<
+    pub sex: char,
+    /// YYMMDD
+    pub date_of_expiry: String,
+    /// TD3は個人番号、TD1は1行目の任意データ
+    pub optional_data: String,
+    /// TD1の2行目の任意データ
+    pub optional_data2: String,
+    lines: Vec<String>,
+}
+
+impl Mrz {
+    /// 改行で区切った行、または行をつないだ文字列（DG1の5F1F）を読む
+    /// チェックディジットは確かめない（check_digits_validで確かめる）
+    pub fn parse(text: &str) -> Result<Self, EmrtdError> {
+        let text = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if text.chars().any(|c| char_value(c).is_none()) {
+            return Err(invalid());
+        }
+        let (format, line_size) = match text.len() {
+            90 => (DocumentFormat::Td1, TD1_LINE),
+            72 => (DocumentFormat::Td2, TD2_LINE),
+            88 => (DocumentFormat::Td3, TD3_LINE),
+            _ => return Err(invalid()),
+        };
+        let lines = (0..text.len())
+            .step_by(line_size)
+            .map(|i| text[i..i + line_size].to_owned())
+            .collect::<Vec<String>>();
+        let trim = |field: &str| field.trim_end_matches(FILLER).to_owned();
+        let mrz = if format == DocumentFormat::Td1 {
+            let (line1, line2, line3) = (&lines[0], &lines[1], &lines[2]);
+            let (primary, secondary) = split_name(line3);
+            let (document_number, _, optional_data) = td1_document_number(line1);
+            Mrz {
+                format,
+                document_code: trim(&line1[0..2]),
+                issuing_state: trim(&line1[2..5]),
+                primary_identifier: primary,
+                secondary_identifier: secondary,
+                document_number,
+                nationality: trim(&line2[15..18]),
+                date_of_birth: line2[0..6].to_owned(),
+                sex: line2.as_bytes()[7] as char,
+                date_of_expiry: line2[8..14].to_owned(),
+                optional_data,
+                optional_data2: trim(&line2[18..29]),
+                lines,
+            }
+        } else {
+            let (line1, line2) = (&lines[0], &lines[1]);
+            let (primary, secondary) = split_name(&line1[5..]);
+            let optional_end = line_size - if format == DocumentFormat::Td3 { 2 } else { 1 };
+            Mrz {
+                format,
+                document_code: trim(&line1[0..2]),
+                issuing_state: trim(&line1[2..5]),
+                primary_identifier: primary,
+                secondary_identifier: secondary,
+                document_number: trim(&line2[0..9]),
+                nationality: trim(&line2[10..13]),
+                date_of_birth: line2[13..19].to_owned(),
+                sex: line2.as_bytes()[20] as char,
+                date_of_expiry: line2[21..27].to_owned(),
+                optional_data: trim(&line2[28..optional_end]),
+                optional_data2: String::new(),
+                lines,
+            }
+        };
+        Ok(mrz)
+    }
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+    /// BAC・PACEの鍵の元
+    pub fn key(&self) -> Result<MrzKey, EmrtdError> {
+        MrzKey::new(
+            &self.document_number,
+            &self.date_of_birth,
+            &self.date_of_expiry,
+        )
+    }
+    /// 文書番号・生年月日・有効期限・複合(とTD3の個人番号)のチェックディジットがすべて合うか
+    pub fn check_digits_valid(&self) -> bool {
+        let digit_matches = |field: &str, digit: u8| check_digit(field) == Some(digit as char);
+        match self.format {
+            DocumentFormat::Td1 => {
+                let (line1, line2) = (&self.lines[0], &self.lines[1]);
+                let bytes = line2.as_bytes();
+                let (_, number_digit, _) = td1_document_number(line1);
+                let composite =
+                    [&line1[5..30], &line2[0..7], &line2[8..15], &line2[18..29]].concat();
+                check_digit(&self.document_number) == number_digit
+                    && digit_matches(&line2[0..6], bytes[6])
+                    && digit_matches(&line2[8..14], bytes[14])
+                    && digit_matches(&composite, bytes[29])
+            }
+            DocumentFormat::Td2 | DocumentFormat::Td3 => {
+                let line2 = &self.lines[1];
+                let bytes = line2.as_bytes();
+                let last = line2.len() - 1;
+                let optional_valid = self.format == DocumentFormat::Td2
+                    || digit_matches(&line2[28..42], bytes[42])
+                    // 個人番号がないときはチェックディジットも<でよい
+                    || (bytes[42] == FILLER as u8 && line2[28..42].chars().all(|c| c == FILLER));
+                let composite = [&line2[0..10], &line2[13..20], &line2[21..last]].concat();
+                digit_matches(&line2[0..9], bytes[9])
+                    && digit_matches(&line2[13..19], bytes[19])
+                    && digit_matches(&line2[21..27], bytes[27])
+                    && optional_valid
+                    && digit_matches(&composite, bytes[last])
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Mrz {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.lines.join("\n"))
+    }
+}
+
+/// 名前の欄を 姓<<名 で分け、< を空白にする
+fn split_name(field: &str) -> (String, String) {
+    let field = field.trim_end_matches(FILLER);
+    let (primary, secondary) = match field.find("<<") {
+        Some(index) => (&field[..index], &field[index + 2..]),
+        None => (field, ""),
+    };
+    let readable = |name: &str| name.replace(FILLER, " ").trim().to_owned();
+    (readable(primary), readable(secondary))
+}
+
+/// TD1の文書番号・そのチェックディジット・任意データ
+/// 文書番号が9文字を越えるときは、チェックディジットの位置が<になり、残りとチェックディジットが任意データの先頭に入る
+fn td1_document_number(line1: &str) -> (String, Option<char>, String) {
+    let number = &line1[5..14];
+    let digit = line1.as_bytes()[14] as char;
+    let optional = &line1[15..30];
+    if digit == FILLER {
+        if let Some(end) = optional.find(FILLER).filter(|end| *end > 0) {
+            return (
+                [number, &optional[..end - 1]].concat(),
+                optional[end - 1..end].chars().next(),
+                optional[end..].trim_matches(FILLER).to_owned(),
+            );
+        }
+    }
+    (
+        number.trim_end_matches(FILLER).to_owned(),
+        Some(digit),
+        optional.trim_end_matches(FILLER).to_owned(),
+    )
+}
+
+#[test]
+fn emrtd_mrz() {
+    // ICAO 9303-11 付録D.2 のMRZ情報
+    let key = MrzKey::new("L898902C", "690806", "940623").unwrap();
+    assert_eq!(key.information(), "L898902C<369080619406236");
+    assert_eq!(
+        key.key_seed(),
+        [
+            0x23, 0x9A, 0xB9, 0xCB, 0x28, 0x2D, 0xAF, 0x66, 0x23, 0x1D, 0xC5, 0xA4, 0xDF, 0x6B,
+            0xFB, 0xAE
+        ]
+    );
+    assert_eq!(
+        MrzKey::new("L898902C", "69086", "940623")
+            .unwrap_err()
+            .kind(),
+        &EmrtdErrorKind::InvalidMrz
+    );
+    assert_eq!(
+        MrzKey::new("L89-902C", "690806", "940623")
+            .unwrap_err()
+            .kind(),
+        &EmrtdErrorKind::InvalidMrz
+    );
+
+    // ICAO 9303-4 のTD3の見本
+    let mrz = Mrz::parse(
+        "P<UTOERIKSSON<<ANNA<MARIA<<<<<<<<<<<<<<<<<<<\nL898902C36UTO7408122F1204159ZE184226B<<<<<10",
+    )
+    .unwrap();
+    assert_eq!(mrz.format, DocumentFormat::Td3);
+    assert_eq!(
+        mrz.lines(),
+        [
+            "P<UTOERIKSSON<<ANNA<MARIA<<<<<<<<<<<<<<<<<<<",
+            "L898902C36UTO7408122F1204159ZE184226B<<<<<10"
+        ]
+    );
+    assert_eq!(mrz.document_code, "P");
+    assert_eq!(mrz.issuing_state, "UTO");
+    assert_eq!(mrz.primary_identifier, "ERIKSSON");
+    assert_eq!(mrz.secondary_identifier, "ANNA MARIA");
+    assert_eq!(mrz.document_number, "L898902C3");
+    assert_eq!(mrz.date_of_birth, "740812");
+    assert_eq!(mrz.sex, 'F');
+    assert_eq!(mrz.date_of_expiry, "120415");
+    assert_eq!(mrz.optional_data, "ZE184226B");
+    assert!(mrz.check_digits_valid());
+    assert_eq!(mrz.key().unwrap().information(), "L898902C3674081221204159");
+
+    // ICAO 9303-5 のTD1の見本（改行なし）
+    let mrz = Mrz::parse(
+        "I<UTOD231458907<<<<<<<<<<<<<<<7408122F1204159UTO<<<<<<<<<<<6ERIKSSON<<ANNA<MARIA<<<<<<<<<<",
+    )
+    .unwrap();
+    assert_eq!(mrz.format, DocumentFormat::Td1);
+    assert_eq!(mrz.lines().len(), 3);
+    assert_eq!(mrz.lines()[2], "ERIKSSON<<ANNA<MARIA<<<<<<<<<<");
+    assert_eq!(mrz.document_number, "D23145890");
+    assert_eq!(mrz.nationality, "UTO");
+    assert_eq!(mrz.secondary_identifier, "ANNA MARIA");
+    assert!(mrz.check_digits_valid());
+    // 1文字変えるとチェックディジットが合わない
+    let mrz = Mrz::parse(
+        "I<UTOD231458907<<<<<<<<<<<<<<<7408132F1204159UTO<<<<<<<<<<<6ERIKSSON<<ANNA<MARIA<<<<<<<<<<",
+    )
+    .unwrap();
+    assert!(!mrz.check_digits_valid());
+    assert_eq!(
+        Mrz::parse("P<UTOERIKSSON").unwrap_err().kind(),
+        &EmrtdErrorKind::InvalidMrz
+    );
+}
@@ -0,0 +1,420 @@
+// PACE(Password Authenticated Connection Establishment、ICAO 9303-11 4.4)のECDH汎用マッピング(GM)
+// EF.CardAccessのPACEInfoからプロトコル(OID)とドメインパラメータを選び、MSE:Set ATで指定してから
+// GENERAL AUTHENTICATEを4回送る: 暗号化したノンス(80) → マッピング用の一時公開鍵(81/82)で G~ = s・G + H
+// → G~上の一時公開鍵(83/84)で共有秘密 K → 認証トークン(85/86、相手の一時公開鍵のMAC)。SSCは0から始める。
+// 対応するのはECDH-GMの3DES・AES-128/192/256と、曲線 secp224r1(10)・P-256(12)・brainpoolP256r1(13)。
+
+use super::curve::Curve;
+use super::mrz::MrzKey;
+use super::{EmrtdError, EmrtdErrorKind, SmAlgorithm, EF_CARD_ACCESS_SFI};
+use crate::apdu_contactless::ApduBuilder;
+use crate::ber_tlv::Tlv;
+use crate::iso7816::secure_messaging::SmSession;
+use crate::iso7816::FileSystem;
+use crate::pc_sc_standard::ApduBuilderExtWithIso7816;
+use crate::smart_card::Smartcard;
+
+/// id-PACE-ECDH-GM (0.4.0.127.0.7.2.2.4.2) の符号化。最後に暗号方式(1～4)が付く
+const ID_PACE_ECDH_GM: [u8; 9] = [0x04, 0x00, 0x7F, 0x00, 0x07, 0x02, 0x02, 0x04, 0x02];
+/// 標準ドメインパラメータのID
+pub const PARAMETER_SECP224R1: u32 = 10;
+pub const PARAMETER_SECP256R1: u32 = 12;
+pub const PARAMETER_BRAINPOOL_P256R1: u32 = 13;
+/// MSE:Set AT (P1 = C1 相互認証の設定、P2 = A4 認証テンプレート)
+const MSE_SET_AT: (u8, u8) = (0xC1, 0xA4);
+/// 動的認証データ
+const TAG_DYNAMIC_AUTHENTICATION_DATA: u32 = 0x7C;
+const TAG_ENCRYPTED_NONCE: u32 = 0x80;
+const TAG_MAPPING_DATA: u32 = 0x81;
+const TAG_MAPPING_DATA_IC: u32 = 0x82;
+const TAG_EPHEMERAL_PUBLIC_KEY: u32 = 0x83;
+const TAG_EPHEMERAL_PUBLIC_KEY_IC: u32 = 0x84;
+const TAG_AUTHENTICATION_TOKEN: u32 = 0x85;
+const TAG_AUTHENTICATION_TOKEN_IC: u32 = 0x86;
+/// 認証トークンの公開鍵テンプレート
+const TAG_PUBLIC_KEY: u32 = 0x7F49;
+const TAG_OID: u32 = 0x06;
+const TAG_EC_POINT: u32 = 0x86;
+
+/// PACEのパスワード
+#[derive(Debug, Clone, PartialEq)]
+pub enum Password {
+    /// MRZ情報（SHA-1したものを使う）
+    Mrz(MrzKey),
+    /// カードに印字されたCAN(数字6桁)
+    Can(String),
+}
+
+impl Password {
+    /// MSE:Set ATのパスワードの参照(DO 83)
+    pub fn reference(&self) -> u8 {
+        match self {
+            Password::Mrz(_) => 0x01,
+            Password::Can(_) => 0x02,
+        }
+    }
+    /// 鍵導出に使うパスワードのバイト列 π
+    pub fn secret(&self) -> Vec<u8> {
+        match self {
+            Password::Mrz(key) => key.hash().to_vec(),
+            Password::Can(can) => can.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// EF.CardAccessのPACEInfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaceInfo {
+    /// プロトコルのOID（06の値）
+    pub protocol: Vec<u8>,
+    pub version: u32,
+    /// 標準ドメインパラメータのID
+    pub parameter_id: Option<u32>,
+}
+
+impl PaceInfo {
+    /// ECDH-GM(版2)のPACEInfo
+    pub fn ecdh_gm(algorithm: SmAlgorithm, parameter_id: u32) -> Self {
+        let cipher = match algorithm {
+            SmAlgorithm::Tdes => 1,
+            SmAlgorithm::Aes128 => 2,
+            SmAlgorithm::Aes192 => 3,
+            SmAlgorithm::Aes256 => 4,
+        };
+        PaceInfo {
+            protocol: [&ID_PACE_ECDH_GM[..], &[cipher]].concat(),
+            version: 2,
+            parameter_id: Some(parameter_id),
+        }
+    }
+    /// ECDH-GMのプロトコルなら暗号方式
+    pub fn algorithm(&self) -> Option<SmAlgorithm> {
+        let (prefix, cipher) = self.protocol.split_at(self.protocol.len().checked_sub(1)?);
+        if prefix != ID_PACE_ECDH_GM {
+            return None;
+        }
+        match cipher {
+            [1] => Some(SmAlgorithm::Tdes),
+            [2] => Some(SmAlgorithm::Aes128),
+            [3] => Some(SmAlgorithm::Aes192),
+            [4] => Some(SmAlgorithm::Aes256),
+            _ => None,
+        }
+    }
+    pub fn curve(&self) -> Option<Curve> {
+        match self.parameter_id? {
+            PARAMETER_SECP224R1 => Some(Curve::secp224r1()),
+            PARAMETER_SECP256R1 => Some(Curve::P256),
+            PARAMETER_BRAINPOOL_P256R1 => Some(Curve::brainpool_p256r1()),
+            _ => None,
+        }
+    }
+    pub fn is_supported(&self) -> bool {
+        self.version == 2 && self.algorithm().is_some() && self.curve().is_some()
+    }
+    /// SecurityInfoの符号化（仮想カードのEF.CardAccessで使う）
+    pub fn to_tlv(&self) -> Tlv {
+        let mut children = vec![
+            Tlv::primitive(TAG_OID, &self.protocol),
+            Tlv::primitive(0x02, &encode_integer(self.version)),
+        ];
+        if let Some(id) = self.parameter_id {
+            children.push(Tlv::primitive(0x02, &encode_integer(id)));
+        }
+        Tlv::constructed(0x30, children)
+    }
+}
+
+/// DERのINTEGER（正の小さな値）
+fn encode_integer(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(3);
+    let mut out = bytes[skip..].to_vec();
+    if out[0] & 0x80 != 0 {
+        out.insert(0, 0x00);
+    }
+    out
+}
+
+fn decode_integer(value: &[u8]) -> Option<u32> {
+    if value.is_empty() || value.len() > 5 || (value.len() == 5 && value[0] != 0) {
+        return None;
+    }
+    Some(value.iter().fold(0u32, |n, b| n << 8 | *b as u32))
+}
+
+fn failed() -> EmrtdError {
+    EmrtdError::new(EmrtdErrorKind::AuthenticationFailed)
+}
+
+/// EF.CardAccess(SecurityInfosのSET)からPACEInfoを取り出す（ほかのSecurityInfoは読み飛ばす）
+pub fn parse_card_access(data: &[u8]) -> Result<Vec<PaceInfo>, EmrtdError> {
+    let invalid = || EmrtdError::new(EmrtdErrorKind::InvalidFile(super::EF_CARD_ACCESS));
+    let (set, _) = Tlv::parse(data).map_err(|_| invalid())?;
+    if set.tag.0 != 0x31 {
+        return Err(invalid());
+    }
+    let prefix = &ID_PACE_ECDH_GM[..ID_PACE_ECDH_GM.len() - 1];
+    let infos = set
+        .children()
+        .iter()
+        .filter_map(|info| match info.children() {
+            [protocol, version, rest @ ..]
+                if protocol.tag.0 == TAG_OID && version.tag.0 == 0x02 =>
+            {
+                let protocol = protocol.value()?;
+                if !protocol.starts_with(prefix) {
+                    return None;
+                }
+                Some(PaceInfo {
+                    protocol: protocol.to_vec(),
+                    version: decode_integer(version.value()?)?,
+                    parameter_id: rest
+                        .first()
+                        .filter(|id| id.tag.0 == 0x02)
+                        .and_then(|id| decode_integer(id.value()?)),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    Ok(infos)
+}
+
+/// MFのEF.CardAccessをSFIで読む
+pub fn read_card_access(nfc: &dyn Smartcard) -> Result<Vec<PaceInfo>, Box<dyn std::error::Error>> {
+    let data = FileSystem::new(nfc).read_transparent_ef(Some(EF_CARD_ACCESS_SFI))?;
+    Ok(parse_card_access(&data)?)
+}
+
+/// 汎用マッピング: G~ = s・G + H
+pub fn map_generator(curve: &Curve, nonce: &[u8], shared: &[u8]) -> Option<Vec<u8>> {
+    curve.add(&curve.multiply(&curve.generator(), nonce)?, shared)
+}
+
+/// 認証トークン: 共有秘密から導いたKS_macで 7F49 { 06 プロトコルのOID, 86 相手の一時公開鍵 } のMACを取る
+pub fn authentication_token(
+    algorithm: SmAlgorithm,
+    secret: &[u8],
+    protocol: &[u8],
+    public_key: &[u8],
+) -> Vec<u8> {
+    let data = Tlv::constructed(
+        TAG_PUBLIC_KEY,
+        vec![
+            Tlv::primitive(TAG_OID, protocol),
+            Tlv::primitive(TAG_EC_POINT, public_key),
+        ],
+    )
+    .to_bytes();
+    algorithm.mac(&algorithm.derive_key(secret, super::KDF_MAC), &data)
+}
+
+/// GENERAL AUTHENTICATEで7Cに包んだデータオブジェクトを送り、応答の7Cからtagの値を取り出す
+fn general_authenticate(
+    nfc: &dyn Smartcard,
+    chaining: bool,
+    objects: Vec<Tlv>,
+    tag: u32,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let data = Tlv::constructed(TAG_DYNAMIC_AUTHENTICATION_DATA, objects).to_bytes();
+    let apdu = ApduBuilder::new()
+        .iso_general_authenticate(chaining, &data)
+        .build();
+    let res = nfc.transmit(Box::new(apdu))?;
+    let (template, _) = Tlv::parse(&res).map_err(|_| failed())?;
+    if template.tag.0 != TAG_DYNAMIC_AUTHENTICATION_DATA {
+        return Err(Box::new(failed()));
+    }
+    let value = template
+        .children()
+        .iter()
+        .find(|object| object.tag.0 == tag)
+        .and_then(|object| object.value())
+        .ok_or_else(failed)?
+        .to_vec();
+    Ok(value)
+}
+
+/// PACEを行い、SMのセッションを返す
+pub fn establish(
+    nfc: &dyn Smartcard,
+    password: &Password,
+    info: &PaceInfo,
+) -> Result<SmSession, Box<dyn std::error::Error>> {
+    let curve = info
+        .curve()
+        .ok_or_else(|| EmrtdError::new(EmrtdErrorKind::UnsupportedProtocol))?;
+    let map_key = curve.random_private_key()?;
+    let key = curve.random_private_key()?;
+    establish_with(nfc, password, info, &map_key, &key)
+}
+
+fn establish_with(
+    nfc: &dyn Smartcard,
+    password: &Password,
+    info: &PaceInfo,
+    map_key: &[u8],
+    key: &[u8],
+) -> Result<SmSession, Box<dyn std::error::Error>> {
+    let unsupported = || EmrtdError::new(EmrtdErrorKind::UnsupportedProtocol);
+    let algorithm = info.algorithm().ok_or_else(unsupported)?;
+    let curve = info.curve().ok_or_else(unsupported)?;
+    let mut set_at = Tlv::primitive(0x80, &info.protocol).to_bytes();
+    set_at.extend(Tlv::primitive(0x83, &[password.reference()]).to_bytes());
+    let apdu = ApduBuilder::new()
+        .manage_security_environment(MSE_SET_AT.0, MSE_SET_AT.1, &set_at)
+        .build();
+    nfc.transmit(Box::new(apdu))?;
+
+    // ノンスを K_π で復号する
+    let nonce = general_authenticate(nfc, true, Vec::new(), TAG_ENCRYPTED_NONCE)?;
+    if nonce.is_empty() || nonce.len() % algorithm.block_size() != 0 {
+        return Err(Box::new(failed()));
+    }
+    let password_key = algorithm.password_key(&password.secret());
+    let cipher = algorithm.cipher(&password_key).ok_or_else(unsupported)?;
+    let nonce = cipher.cbc_decrypt(&vec![0u8; algorithm.block_size()], &nonce);
+
+    // マッピング
+    let map_public = curve
+        .multiply(&curve.generator(), map_key)
+        .ok_or_else(failed)?;
+    let map_public_ic = general_authenticate(
+        nfc,
+        true,
+        vec![Tlv::primitive(TAG_MAPPING_DATA, &map_public)],
+        TAG_MAPPING_DATA_IC,
+    )?;
+    let shared = curve.multiply(&map_public_ic, map_key).ok_or_else(failed)?;
+    let mapped = map_generator(&curve, &nonce, &shared).ok_or_else(failed)?;
+
+    // 鍵共有
+    let public = curve.multiply(&mapped, key).ok_or_else(failed)?;
+    let public_ic = general_authenticate(
+        nfc,
+        true,
+        vec![Tlv::primitive(TAG_EPHEMERAL_PUBLIC_KEY, &public)],
+        TAG_EPHEMERAL_PUBLIC_KEY_IC,
+    )?;
+    if public_ic == public {
+        return Err(Box::new(failed()));
+    }
+    let secret = curve.multiply(&public_ic, key).ok_or_else(failed)?;
+    let secret = curve.x_coordinate(&secret).to_vec();
+
+    // 相互認証
+    let token = authentication_token(algorithm, &secret, &info.protocol, &public_ic);
+    let res = general_authenticate(
+        nfc,
+        false,
+        vec![Tlv::primitive(TAG_AUTHENTICATION_TOKEN, &token)],
+        TAG_AUTHENTICATION_TOKEN_IC,
+    )?;
+    if res != authentication_token(algorithm, &secret, &info.protocol, &public) {
+        return Err(Box::new(failed()));
+    }
+    Ok(algorithm.session(&secret, &[]))
+}
+
+#[test]
+fn emrtd_pace() {
+    use crate::crypto::hex;
+    use crate::nfc_impl::nfc_mock::emrtd::VirtualEmrtd;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    // ICAO 9303-11 付録G.1 (ECDH-GM、AES-128、brainpoolP256r1)
+    let info = parse_card_access(&hex("3114301206 0A04007F00070202040202 020102 02010D")).unwrap();
+    assert_eq!(
+        info,
+        vec![PaceInfo::ecdh_gm(
+            SmAlgorithm::Aes128,
+            PARAMETER_BRAINPOOL_P256R1
+        )]
+    );
+    let info = &info[0];
+    let algorithm = info.algorithm().unwrap();
+    let curve = info.curve().unwrap();
+    let password = Password::Mrz(MrzKey::new("T22000129", "640812", "101031").unwrap());
+    let password_key = algorithm.password_key(&password.secret());
+    assert_eq!(password_key, hex("89DED1B26624EC1E634C1989302849DD"));
+    let nonce = hex("3F00C4D39D153F2B2A214A078D899B22");
+    let cipher = algorithm.cipher(&password_key).unwrap();
+    assert_eq!(
+        cipher.cbc_decrypt(&[0u8; 16], &hex("95A3A016522EE98D01E76CB6B98B42C3")),
+        nonce
+    );
+
+    let map_key = hex("7F4EF07B9EA82FD78AD689B38D0BC78CF21F249D953BC46F4C6E19259C010F99");
+    let map_key_ic = hex("498FF49756F2DC1587840041839A85982BE7761D14715FB091EFA7BCE9058560");
+    let map_public_ic = curve.multiply(&curve.generator(), &map_key_ic).unwrap();
+    let shared = curve.multiply(&map_public_ic, &map_key).unwrap();
+    assert_eq!(
+        shared,
+        hex("0460332EF2450B5D247EF6D3868397D398852ED6E8CAF6FFEEF6BF85CA57057FD50840CA7415BAF3E43BD414D35AA4608B93A2CAF3A4E3EA4E82C9C13D03EB7181")
+    );
+    let mapped = map_generator(&curve, &nonce, &shared).unwrap();
+    assert_eq!(
+        mapped,
+        hex("048CED63C91426D4F0EB1435E7CB1D74A46723A0AF21C89634F65A9AE87A9265E28C879506743F8611AC33645C5B985C80B5F09A0B83407C1B6A4D857AE76FE522")
+    );
+
+    let key = hex("A73FB703AC1436A18E0CFA5ABB3F7BEC7A070E7A6788486BEE230C4A22762595");
+    let key_ic = hex("107CF58696EF6155053340FD633392BA81909DF7B9706F226F32086C7AFF974A");
+    let public = curve.multiply(&mapped, &key).unwrap();
+    let public_ic = curve.multiply(&mapped, &key_ic).unwrap();
+    assert_eq!(
+        public_ic,
+        hex("049E880F842905B8B3181F7AF7CAA9F0EFB743847F44A306D2D28C1D9EC65DF6DB7764B22277A2EDDC3C265A9F018F9CB852E111B768B326904B59A0193776F094")
+    );
+    let secret = curve.multiply(&public_ic, &key).unwrap();
+    let secret = curve.x_coordinate(&secret).to_vec();
+    assert_eq!(
+        secret,
+        hex("28768D20701247DAE81804C9E780EDE582A9996DB4A315020B2733197DB84925")
+    );
+    assert_eq!(
+        algorithm.derive_key(&secret, super::KDF_ENC),
+        hex("F5F0E35C0D7161EE6724EE513A0D9A7F")
+    );
+    assert_eq!(
+        algorithm.derive_key(&secret, super::KDF_MAC),
+        hex("FE251C7858B356B24514B3BD5F4297D1")
+    );
+    assert_eq!(
+        authentication_token(algorithm, &secret, &info.protocol, &public_ic),
+        hex("C2B0BD78D94BA866")
+    );
+    assert_eq!(
+        authentication_token(algorithm, &secret, &info.protocol, &public),
+        hex("3ABB9674BCE93C08")
+    );
+
+    // 同じ鍵の仮想カードとPACEを行う
+    let mut card = VirtualEmrtd::new(
+        "P<D<<MUSTERMANN<<ERIKA<<<<<<<<<<<<<<<<<<<<<<T220001293D<<6408125F1010318<<<<<<<<<<<<<<<8",
+    );
+    card.enable_pace(info.clone())
+        .set_can("123456")
+        .set_pace_keys(&nonce, &map_key_ic, &key_ic);
+    let nfc = MockSmartcard::new(Box::new(card));
+    assert_eq!(read_card_access(&nfc).unwrap(), vec![info.clone()]);
+    let session = establish_with(&nfc, &password, info, &map_key, &key).unwrap();
+    assert_eq!(session.ssc().to_vec(), vec![0u8; 16]);
+    // パスワードが違うとノンスが合わず、認証トークンで失敗する
+    let wrong = Password::Can("654321".to_owned());
+    let error = establish(&nfc, &wrong, info).err().unwrap();
+    assert_eq!(
+        crate::iso7816::status_word(error.as_ref()),
+        Some((0x63, 0x00))
+    );
+    assert!(establish(&nfc, &Password::Can("123456".to_owned()), info).is_ok());
+    // 対応していないドメインパラメータ
+    let unsupported = PaceInfo::ecdh_gm(SmAlgorithm::Aes128, 99);
+    assert!(!unsupported.is_supported());
+    let error = establish(&nfc, &password, &unsupported).err().unwrap();
+    assert_eq!(
+        error.downcast_ref::<EmrtdError>().unwrap().kind(),
+        &EmrtdErrorKind::UnsupportedProtocol
+    );
+}
@@ -0,0 +1,127 @@
+// FeliCaの読み書き
+// PaSoRi等のPC/SCドライバが提供する SELECT FILE(サービス選択) と READ/UPDATE BINARY を使う
+// 暗号化を必要としないサービスのみ扱える
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::{ApduBuilderExtWithFelica, ApduBuilderExtWithPcsc3V2};
+use crate::smart_card::{Smartcard, SmartcardError};
+
+pub mod command;
+pub mod ndef;
+
+pub const BLOCK_SIZE: usize = 16;
+
+pub struct Felica<'a> {
+    nfc: &'a dyn Smartcard,
+}
+
+impl<'a> Felica<'a> {
+    pub fn new(nfc: &'a dyn Smartcard) -> Self {
+        Felica { nfc }
+    }
+    /// 製造ID(IDm)
+    pub fn idm(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().get_serial().build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    /// 製造パラメータ(PMm)
+    pub fn pmm(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().get_ats().build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    pub fn select_service(&self, service_code: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().select_service(service_code).build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// 選択中のサービスからブロックを読み出す
+    pub fn read_block(&self, block: u16) -> Result<[u8; BLOCK_SIZE], Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .read_binary(block, BLOCK_SIZE as u8)
+            .build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() != BLOCK_SIZE {
+            return Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let mut data = [0u8; BLOCK_SIZE];
+        data.copy_from_slice(&res);
+        Ok(data)
+    }
+    /// 選択中のサービスのブロックへ書き込む
+    pub fn write_block(
+        &self,
+        block: u16,
+        data: &[u8; BLOCK_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().update_binary(block, data).build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// サービスを選択し、読み出しに失敗するまで（最大max_blocks）ブロックを読む
+    pub fn read_service(
+        &self,
+        service_code: u16,
+        max_blocks: u16,
+    ) -> Result<Vec<[u8; BLOCK_SIZE]>, Box<dyn std::error::Error>> {
+        self.select_service(service_code)?;
+        let mut blocks = Vec::new();
+        for block in 0..max_blocks {
+            match self.read_block(block) {
+                Ok(data) => blocks.push(data),
+                Err(e) => {
+                    if blocks.is_empty() || SmartcardError::is_connection_lost(e.as_ref()) {
+                        return Err(e);
+                    }
+                    // 範囲外のブロックで読み出しが失敗したら終端とみなす
+                    break;
+                }
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FelicaErrorKind {
+    InvalidResponseLength(usize),
+    /// Pollingに応答するカードがない
+    NoTarget,
+    /// リーダー(PN53x)のエラーステータス
+    ReaderStatus(u8),
+    /// カードが返したステータスフラグ1, 2
+    Status(u8, u8),
+    /// Type 3 Tagの属性情報のチェックサムが合わない
+    ChecksumMismatch,
+    /// 対応しない属性情報のバージョン
+    UnsupportedVersion(u8),
+    /// 属性情報のWriteFlagが書き込み中のまま
+    WriteInProgress,
+    /// 属性情報のRWFlagが読み出し専用
+    ReadOnly,
+    /// NDEFメッセージが格納できるサイズを超える
+    NdefTooLarge(usize),
+    /// FeliCa Lite-Sではない
+    NotLiteS,
+}
+
+#[derive(Debug)]
+pub struct FelicaError {
+    code: FelicaErrorKind,
+}
+
+impl FelicaError {
+    pub fn new(code: FelicaErrorKind) -> Self {
+        FelicaError { code }
+    }
+    pub fn kind(&self) -> &FelicaErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for FelicaError {}
+impl std::fmt::Display for FelicaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -0,0 +1,338 @@
+// FeliCaのコマンド（Polling・Request Service・Search Service Code・Read/Write Without Encryption）
+// PC/SCのSELECT FILEではシステムコードを指定できないため、DIRECT TRANSMIT(FF 00 00 00)で
+// PN53xの InListPassiveTarget(212kbps) と InDataExchange にFeliCaのコマンドを載せて送る。
+// FeliCaのフレームは先頭の長さバイトもホストが付ける。
+
+use super::{Felica, FelicaError, FelicaErrorKind, BLOCK_SIZE};
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::ApduBuilderExtWithDirectTransmit;
+
+pub const IDM_SIZE: usize = 8;
+pub const PMM_SIZE: usize = 8;
+/// すべてのシステムに応答させるワイルドカード
+pub const SYSTEM_CODE_WILDCARD: u16 = 0xFFFF;
+
+/// Search Service Codeの列挙の終わり・Request Serviceで存在しないノードの鍵バージョン
+const NODE_NOT_FOUND: u16 = 0xFFFF;
+/// Request Serviceで一度に問い合わせられるノードの数
+const MAX_REQUEST_NODES: usize = 32;
+
+// カードのコマンド（応答のコードは+1）
+const CMD_REQUEST_SERVICE: u8 = 0x02;
+const CMD_READ_WITHOUT_ENCRYPTION: u8 = 0x06;
+const CMD_WRITE_WITHOUT_ENCRYPTION: u8 = 0x08;
+const CMD_SEARCH_SERVICE_CODE: u8 = 0x0A;
+/// Pollingでシステムコードも返させる
+const REQUEST_SYSTEM_CODE: u8 = 0x01;
+
+// PN53xのコマンド
+/// 212kbps FeliCaのターゲットを1枚だけ捕捉する
+const PN53X_IN_LIST_PASSIVE_TARGET: [u8; 4] = [0xD4, 0x4A, 0x01, 0x01];
+const PN53X_IN_LIST_PASSIVE_TARGET_RESPONSE: [u8; 2] = [0xD5, 0x4B];
+const PN53X_IN_DATA_EXCHANGE: [u8; 3] = [0xD4, 0x40, 0x01];
+const PN53X_IN_DATA_EXCHANGE_RESPONSE: [u8; 2] = [0xD5, 0x41];
+
+/// Pollingの応答
+#[derive(Debug, Clone, PartialEq)]
+pub struct PollingResponse {
+    pub idm: [u8; IDM_SIZE],
+    pub pmm: [u8; PMM_SIZE],
+    pub system_code: u16,
+}
+
+impl PollingResponse {
+    /// PMmの2バイト目がICの種別（FeliCa Lite-SはF1）
+    pub fn ic_type(&self) -> u8 {
+        self.pmm[1]
+    }
+}
+
+/// Search Service Codeで見つかったノード
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FelicaNode {
+    /// エリアコードと、エリアに含まれる最後のサービスコード
+    Area {
+        code: u16,
+        end: u16,
+    },
+    Service(u16),
+}
+
+/// サービスコードの下位6ビットの属性が、暗号化なしで読み出せるもの
+/// ランダム・サイクリック(08～0F)・パース(10～17)で、ビット0が1なら認証が要らない
+pub fn is_readable_without_encryption(service_code: u16) -> bool {
+    let attribute = service_code & 0x3F;
+    (0x08..=0x17).contains(&attribute) && attribute & 0x01 != 0
+}
+
+/// ブロックリストの要素
+/// ブロック番号が255以下なら2バイト(80 || 番号)、それ以外は3バイト(00 || 番号 リトルエンディアン)
+fn block_list_element(block: u16) -> Vec<u8> {
+    if block <= 0xFF {
+        vec![0x80, block as u8]
+    } else {
+        let block = block.to_le_bytes();
+        vec![0x00, block[0], block[1]]
+    }
+}
+
+impl<'a> Felica<'a> {
+    /// 指定したシステムコードを持つカードを捕捉する
+    pub fn polling(&self, system_code: u16) -> Result<PollingResponse, Box<dyn std::error::Error>> {
+        let system_code = system_code.to_be_bytes();
+        // 00 || システムコード || リクエストコード || タイムスロット
+        let payload = [
+            &PN53X_IN_LIST_PASSIVE_TARGET[..],
+            &[
+                0x00,
+                system_code[0],
+                system_code[1],
+                REQUEST_SYSTEM_CODE,
+                0x00,
+            ],
+        ]
+        .concat();
+        let apdu = ApduBuilder::new().direct_transmit(&payload).build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() < 3 || res[0..2] != PN53X_IN_LIST_PASSIVE_TARGET_RESPONSE {
+            return Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        // D5 4B NbTg Tg POL_RES長 01 IDm PMm システムコード
+        if res[2] == 0 {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::NoTarget)));
+        }
+        match &res[3..] {
+            [_, len, 0x01, rest @ ..]
+                if *len as usize == rest.len() + 2 && rest.len() >= IDM_SIZE + PMM_SIZE =>
+            {
+                let mut idm = [0u8; IDM_SIZE];
+                let mut pmm = [0u8; PMM_SIZE];
+                idm.copy_from_slice(&rest[..IDM_SIZE]);
+                pmm.copy_from_slice(&rest[IDM_SIZE..IDM_SIZE + PMM_SIZE]);
+                // リクエストコードに対応しないカードはシステムコードを返さない
+                let system_code = match &rest[IDM_SIZE + PMM_SIZE..] {
+                    [high, low] => u16::from_be_bytes([*high, *low]),
+                    _ => u16::from_be_bytes(system_code),
+                };
+                Ok(PollingResponse {
+                    idm,
+                    pmm,
+                    system_code,
+                })
+            }
+            _ => Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            ))),
+        }
+    }
+    /// ノード(エリア・サービス)の鍵バージョンを問い合わせる。存在しないノードはNone
+    pub fn request_service(
+        &self,
+        idm: &[u8; IDM_SIZE],
+        node_codes: &[u16],
+    ) -> Result<Vec<Option<u16>>, Box<dyn std::error::Error>> {
+        let mut versions = Vec::with_capacity(node_codes.len());
+        for nodes in node_codes.chunks(MAX_REQUEST_NODES) {
+            // IDm || ノード数 || ノードコードリスト(リトルエンディアン)
+            let mut command = idm.to_vec();
+            command.push(nodes.len() as u8);
+            for node in nodes {
+                command.extend_from_slice(&node.to_le_bytes());
+            }
+            let res = self.exchange_without_status(CMD_REQUEST_SERVICE, idm, &command)?;
+            // ノード数 || 鍵バージョンリスト(リトルエンディアン)
+            match res.split_first() {
+                Some((count, list))
+                    if *count as usize == nodes.len() && list.len() == nodes.len() * 2 =>
+                {
+                    versions.extend(list.chunks(2).map(|version| {
+                        match u16::from_le_bytes([version[0], version[1]]) {
+                            NODE_NOT_FOUND => None,
+                            version => Some(version),
+                        }
+                    }));
+                }
+                _ => {
+                    return Err(Box::new(FelicaError::new(
+                        FelicaErrorKind::InvalidResponseLength(res.len()),
+                    )))
+                }
+            }
+        }
+        Ok(versions)
+    }
+    /// Search Service Codeでエリアとサービスを順に列挙する
+    pub fn search_service_codes(
+        &self,
+        idm: &[u8; IDM_SIZE],
+    ) -> Result<Vec<FelicaNode>, Box<dyn std::error::Error>> {
+        let mut nodes = Vec::new();
+        for index in 0..=u16::MAX {
+            // IDm || インデックス(リトルエンディアン)
+            let command = [&idm[..], &index.to_le_bytes()].concat();
+            let res = self.exchange_without_status(CMD_SEARCH_SERVICE_CODE, idm, &command)?;
+            // エリアはエリアコード || 最後のサービスコード、サービスはサービスコード（リトルエンディアン）
+            let node = match res.as_slice() {
+                [low, high] => match u16::from_le_bytes([*low, *high]) {
+                    NODE_NOT_FOUND => break,
+                    code => FelicaNode::Service(code),
+                },
+                [low, high, end_low, end_high] => FelicaNode::Area {
+                    code: u16::from_le_bytes([*low, *high]),
+                    end: u16::from_le_bytes([*end_low, *end_high]),
+                },
+                _ => {
+                    return Err(Box::new(FelicaError::new(
+                        FelicaErrorKind::InvalidResponseLength(res.len()),
+                    )))
+                }
+            };
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+    /// 1つのサービスから複数のブロックを読み出す
+    pub fn read_without_encryption(
+        &self,
+        idm: &[u8; IDM_SIZE],
+        service_code: u16,
+        blocks: &[u16],
+    ) -> Result<Vec<[u8; BLOCK_SIZE]>, Box<dyn std::error::Error>> {
+        let command = Self::service_command(idm, service_code, blocks);
+        let res = self.exchange(CMD_READ_WITHOUT_ENCRYPTION, idm, &command)?;
+        // ブロック数 || ブロックデータ
+        match res.split_first() {
+            Some((count, data))
+                if *count as usize == blocks.len() && data.len() == blocks.len() * BLOCK_SIZE =>
+            {
+                Ok(data
+                    .chunks(BLOCK_SIZE)
+                    .map(|chunk| {
+                        let mut block = [0u8; BLOCK_SIZE];
+                        block.copy_from_slice(chunk);
+                        block
+                    })
+                    .collect())
+            }
+            _ => Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            ))),
+        }
+    }
+    /// 1つのサービスの複数のブロックへ書き込む
+    pub fn write_without_encryption(
+        &self,
+        idm: &[u8; IDM_SIZE],
+        service_code: u16,
+        blocks: &[(u16, [u8; BLOCK_SIZE])],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let numbers = blocks.iter().map(|(block, _)| *block).collect::<Vec<u16>>();
+        let mut command = Self::service_command(idm, service_code, &numbers);
+        for (_, data) in blocks {
+            command.extend_from_slice(data);
+        }
+        self.exchange(CMD_WRITE_WITHOUT_ENCRYPTION, idm, &command)?;
+        Ok(())
+    }
+
+    /// IDm || サービス数(1) || サービスコード(リトルエンディアン) || ブロック数 || ブロックリスト
+    fn service_command(idm: &[u8; IDM_SIZE], service_code: u16, blocks: &[u16]) -> Vec<u8> {
+        let mut command = idm.to_vec();
+        command.push(0x01);
+        command.extend_from_slice(&service_code.to_le_bytes());
+        command.push(blocks.len() as u8);
+        for block in blocks {
+            command.extend(block_list_element(*block));
+        }
+        command
+    }
+    /// コマンドを送り、応答のIDmとステータスフラグを確かめて残りを返す
+    fn exchange(
+        &self,
+        code: u8,
+        idm: &[u8; IDM_SIZE],
+        command: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let res = self.exchange_without_status(code, idm, command)?;
+        match res.as_slice() {
+            [flag1, flag2, rest @ ..] => {
+                if *flag1 != 0 {
+                    return Err(Box::new(FelicaError::new(FelicaErrorKind::Status(
+                        *flag1, *flag2,
+                    ))));
+                }
+                Ok(rest.to_vec())
+            }
+            _ => Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            ))),
+        }
+    }
+    /// ステータスフラグを返さないコマンド(Request Service・Search Service Code)用
+    /// 応答のIDmを確かめて残りを返す
+    fn exchange_without_status(
+        &self,
+        code: u8,
+        idm: &[u8; IDM_SIZE],
+        command: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let len = (command.len() + 2) as u8;
+        let payload = [&PN53X_IN_DATA_EXCHANGE[..], &[len, code], command].concat();
+        let apdu = ApduBuilder::new().direct_transmit(&payload).build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() < 3 || res[0..2] != PN53X_IN_DATA_EXCHANGE_RESPONSE {
+            return Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let status = res[2] & 0x3F;
+        if status != 0 {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::ReaderStatus(
+                status,
+            ))));
+        }
+        // 長さ || 応答コード || IDm || ...
+        match &res[3..] {
+            [len, response, rest @ ..]
+                if *len as usize == res.len() - 3
+                    && *response == code + 1
+                    && rest.len() >= IDM_SIZE
+                    && rest[..IDM_SIZE] == idm[..] =>
+            {
+                Ok(rest[IDM_SIZE..].to_vec())
+            }
+            _ => Err(Box::new(FelicaError::new(
+                FelicaErrorKind::InvalidResponseLength(res.len()),
+            ))),
+        }
+    }
+}
+
+#[test]
+fn felica_block_list() {
+    // 認証が要らないランダム・サイクリック・パースのサービスだけ
+    for (service_code, readable) in [
+        (0x000B, true),
+        (0x0009, true),
+        (0x090F, true),
+        (0x0017, true),
+        (0x1008, false),
+        (0x0000, false),
+        (0x0001, false),
+        (0x0018, false),
+    ] {
+        assert_eq!(is_readable_without_encryption(service_code), readable);
+    }
+    assert_eq!(block_list_element(0x0D), [0x80, 0x0D]);
+    assert_eq!(block_list_element(0x0123), [0x00, 0x23, 0x01]);
+    let idm = [0x01, 0x2E, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+    assert_eq!(
+        Felica::service_command(&idm, 0x000B, &[0, 1]),
+        [
+            0x01, 0x2E, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x01, 0x0B, 0x00, 0x02, 0x80, 0x00,
+            0x80, 0x01
+        ]
+    );
+}
@@ -0,0 +1,271 @@
+// NFC Forum Type 3 TagとしてのNDEFの読み書き
+// システムコード12FCで捕捉し、ブロック0の属性情報(Attribute Information Block)に続くブロック1からNDEFメッセージを格納する。
+// 読み出しはサービス000B、書き込みはサービス0009を使う。
+// 書き込みは WriteFlagを0F(書き込み中)にする → メッセージを書く → Lnを更新してWriteFlagを00に戻す の順で行う。
+
+use super::command::{PollingResponse, IDM_SIZE};
+use super::{Felica, FelicaError, FelicaErrorKind, BLOCK_SIZE};
+use crate::ndef::NdefMessage;
+
+pub const NDEF_SYSTEM_CODE: u16 = 0x12FC;
+/// NDEFの読み出し用サービス
+pub const NDEF_READ_SERVICE: u16 = 0x000B;
+/// NDEFの読み書き用サービス
+pub const NDEF_WRITE_SERVICE: u16 = 0x0009;
+/// 属性情報のブロック
+const ATTRIBUTE_BLOCK: u16 = 0;
+
+pub const WRITE_FLAG_DONE: u8 = 0x00;
+pub const WRITE_FLAG_IN_PROGRESS: u8 = 0x0F;
+pub const RW_FLAG_READ_ONLY: u8 = 0x00;
+pub const RW_FLAG_READ_WRITE: u8 = 0x01;
+/// 1回のコマンドで扱うブロック数の上限（PN53xのフレームに収まる数）
+const MAX_BLOCKS_PER_COMMAND: u8 = 12;
+
+/// FeliCa Lite-Sのシステムコード
+pub const LITE_S_SYSTEM_CODE: u16 = 0x88B4;
+/// PMmのICの種別
+const LITE_S_IC_TYPE: u8 = 0xF1;
+/// ユーザーブロック(S_PAD0～13)のうちNDEFに使うブロック1～13
+const LITE_S_NMAXB: u16 = 13;
+const LITE_S_NBR: u8 = 4;
+const LITE_S_NBW: u8 = 1;
+/// MC(メモリコンフィグレーション)ブロック
+const LITE_S_MC_BLOCK: u16 = 0x88;
+/// MCのSYS_OP。01にするとシステムコード12FCのPollingに応答する
+const LITE_S_MC_SYS_OP: usize = 3;
+
+/// 属性情報（ブロック0）
+/// Ver || Nbr || Nbw || Nmaxb(2) || 未使用(4) || WriteFlag || RWFlag || Ln(3) || チェックサム(2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttributeInformation {
+    /// 上位4ビットがメジャー、下位4ビットがマイナーバージョン
+    pub version: u8,
+    /// 1回のCheckで読み出せるブロック数
+    pub nbr: u8,
+    /// 1回のUpdateで書き込めるブロック数
+    pub nbw: u8,
+    /// NDEFに使えるブロック数
+    pub nmaxb: u16,
+    pub write_flag: u8,
+    pub rw_flag: u8,
+    /// NDEFメッセージのバイト数
+    pub ln: usize,
+}
+
+impl AttributeInformation {
+    pub fn new(nbr: u8, nbw: u8, nmaxb: u16) -> Self {
+        AttributeInformation {
+            version: 0x10,
+            nbr,
+            nbw,
+            nmaxb,
+            write_flag: WRITE_FLAG_DONE,
+            rw_flag: RW_FLAG_READ_WRITE,
+            ln: 0,
+        }
+    }
+    /// チェックサムが合わなければエラー
+    pub fn from_bytes(data: &[u8; BLOCK_SIZE]) -> Result<Self, FelicaError> {
+        let checksum = u16::from_be_bytes([data[14], data[15]]);
+        if Self::checksum(&data[..14]) != checksum {
+            return Err(FelicaError::new(FelicaErrorKind::ChecksumMismatch));
+        }
+        Ok(AttributeInformation {
+            version: data[0],
+            nbr: data[1],
+            nbw: data[2],
+            nmaxb: u16::from_be_bytes([data[3], data[4]]),
+            write_flag: data[9],
+            rw_flag: data[10],
+            ln: u32::from_be_bytes([0x00, data[11], data[12], data[13]]) as usize,
+        })
+    }
+    pub fn to_bytes(self) -> [u8; BLOCK_SIZE] {
+        let mut data = [0u8; BLOCK_SIZE];
+        data[0] = self.version;
+        data[1] = self.nbr;
+        data[2] = self.nbw;
+        data[3..5].copy_from_slice(&self.nmaxb.to_be_bytes());
+        data[9] = self.write_flag;
+        data[10] = self.rw_flag;
+        data[11..14].copy_from_slice(&(self.ln as u32).to_be_bytes()[1..]);
+        let checksum = Self::checksum(&data[..14]);
+        data[14..].copy_from_slice(&checksum.to_be_bytes());
+        data
+    }
+    /// ブロック0のバイト0～13の和
+    fn checksum(data: &[u8]) -> u16 {
+        data.iter().map(|b| *b as u16).sum()
+    }
+    pub fn major_version(&self) -> u8 {
+        self.version >> 4
+    }
+    /// 書き込めるNDEFメッセージの最大のバイト数
+    pub fn capacity(&self) -> usize {
+        self.nmaxb as usize * BLOCK_SIZE
+    }
+}
+
+impl<'a> Felica<'a> {
+    /// システムコード12FCで捕捉し、属性情報を読み出す
+    pub fn read_attribute_information(
+        &self,
+    ) -> Result<(PollingResponse, AttributeInformation), Box<dyn std::error::Error>> {
+        let polling = self.polling(NDEF_SYSTEM_CODE)?;
+        let block =
+            self.read_without_encryption(&polling.idm, NDEF_READ_SERVICE, &[ATTRIBUTE_BLOCK])?;
+        let attribute = AttributeInformation::from_bytes(&block[0])?;
+        // 対応するのはメジャーバージョン1
+        if attribute.major_version() != 1 {
+            return Err(Box::new(FelicaError::new(
+                FelicaErrorKind::UnsupportedVersion(attribute.version),
+            )));
+        }
+        Ok((polling, attribute))
+    }
+    pub fn read_ndef(&self) -> Result<NdefMessage, Box<dyn std::error::Error>> {
+        let (polling, attribute) = self.read_attribute_information()?;
+        // 書き込みが途中で止まっている
+        if attribute.write_flag != WRITE_FLAG_DONE {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::WriteInProgress)));
+        }
+        if attribute.ln > attribute.capacity() {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::NdefTooLarge(
+                attribute.ln,
+            ))));
+        }
+        if attribute.ln == 0 {
+            return Ok(NdefMessage::default());
+        }
+        let count = attribute.ln.div_ceil(BLOCK_SIZE) as u16;
+        let numbers = (1..=count).collect::<Vec<u16>>();
+        let mut data = Vec::with_capacity(count as usize * BLOCK_SIZE);
+        let per_command = attribute.nbr.clamp(1, MAX_BLOCKS_PER_COMMAND) as usize;
+        for chunk in numbers.chunks(per_command) {
+            for block in self.read_without_encryption(&polling.idm, NDEF_READ_SERVICE, chunk)? {
+                data.extend_from_slice(&block);
+            }
+        }
+        Ok(NdefMessage::from_bytes(&data[..attribute.ln])?)
+    }
+    pub fn write_ndef(&self, message: &NdefMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let (polling, mut attribute) = self.read_attribute_information()?;
+        if attribute.rw_flag != RW_FLAG_READ_WRITE {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::ReadOnly)));
+        }
+        let data = message.to_bytes();
+        if data.len() > attribute.capacity() {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::NdefTooLarge(
+                attribute.capacity(),
+            ))));
+        }
+        attribute.write_flag = WRITE_FLAG_IN_PROGRESS;
+        self.write_attribute_information(&polling.idm, &attribute)?;
+        let blocks = data
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut block = [0u8; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                (i as u16 + 1, block)
+            })
+            .collect::<Vec<_>>();
+        let per_command = attribute.nbw.clamp(1, MAX_BLOCKS_PER_COMMAND) as usize;
+        for chunk in blocks.chunks(per_command) {
+            self.write_without_encryption(&polling.idm, NDEF_WRITE_SERVICE, chunk)?;
+        }
+        attribute.write_flag = WRITE_FLAG_DONE;
+        attribute.ln = data.len();
+        self.write_attribute_information(&polling.idm, &attribute)
+    }
+    /// FeliCa Lite-SをType 3 Tagとして初期化する（NDEFメッセージは空になる）
+    pub fn format_lite_s(&self) -> Result<AttributeInformation, Box<dyn std::error::Error>> {
+        let polling = self.polling(LITE_S_SYSTEM_CODE)?;
+        if polling.ic_type() != LITE_S_IC_TYPE {
+            return Err(Box::new(FelicaError::new(FelicaErrorKind::NotLiteS)));
+        }
+        let attribute = AttributeInformation::new(LITE_S_NBR, LITE_S_NBW, LITE_S_NMAXB);
+        self.write_attribute_information(&polling.idm, &attribute)?;
+        let mut mc =
+            self.read_without_encryption(&polling.idm, NDEF_READ_SERVICE, &[LITE_S_MC_BLOCK])?[0];
+        if mc[LITE_S_MC_SYS_OP] != 0x01 {
+            mc[LITE_S_MC_SYS_OP] = 0x01;
+            self.write_without_encryption(
+                &polling.idm,
+                NDEF_WRITE_SERVICE,
+                &[(LITE_S_MC_BLOCK, mc)],
+            )?;
+        }
+        Ok(attribute)
+    }
+
+    fn write_attribute_information(
+        &self,
+        idm: &[u8; IDM_SIZE],
+        attribute: &AttributeInformation,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_without_encryption(
+            idm,
+            NDEF_WRITE_SERVICE,
+            &[(ATTRIBUTE_BLOCK, attribute.to_bytes())],
+        )
+    }
+}
+
+#[test]
+fn felica_attribute_information() {
+    let attribute = AttributeInformation::new(4, 1, 13);
+    let data = attribute.to_bytes();
+    assert_eq!(
+        data,
+        [
+            0x10, 0x04, 0x01, 0x00, 0x0D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x23
+        ]
+    );
+    assert_eq!(AttributeInformation::from_bytes(&data).unwrap(), attribute);
+    let mut corrupted = data;
+    corrupted[13] = 0x10;
+    assert_eq!(
+        AttributeInformation::from_bytes(&corrupted)
+            .unwrap_err()
+            .kind(),
+        &FelicaErrorKind::ChecksumMismatch
+    );
+}
+
+#[test]
+fn felica_lite_s_ndef() {
+    use crate::ndef::smart_poster::SmartPoster;
+    use crate::ndef::NdefRecord;
+    use crate::nfc_impl::nfc_mock::{felica::VirtualFelicaLiteS, MockSmartcard};
+
+    let nfc = MockSmartcard::new(Box::new(VirtualFelicaLiteS::new()));
+    let card = Felica::new(&nfc);
+    // 初期化前はシステムコード12FCに応答しない
+    assert!(card.read_ndef().is_err());
+    card.format_lite_s().unwrap();
+    assert_eq!(card.read_ndef().unwrap(), NdefMessage::default());
+
+    let message = NdefMessage::new(vec![SmartPoster::new("https://www.nfc.com")
+        .with_title("en", "NFC Forum")
+        .to_record()]);
+    card.write_ndef(&message).unwrap();
+    let (_, attribute) = card.read_attribute_information().unwrap();
+    assert_eq!(attribute.ln, message.to_bytes().len());
+    assert_eq!(attribute.write_flag, WRITE_FLAG_DONE);
+    assert_eq!(card.read_ndef().unwrap(), message);
+
+    let too_large = NdefMessage::new(vec![NdefRecord::mime("text/plain", &[0x41; 208])]);
+    assert!(card.write_ndef(&too_large).is_err());
+    assert_eq!(card.read_ndef().unwrap(), message);
+
+    // 書き込み途中で止まったタグ
+    let polling = card.polling(NDEF_SYSTEM_CODE).unwrap();
+    let mut interrupted = attribute;
+    interrupted.write_flag = WRITE_FLAG_IN_PROGRESS;
+    card.write_attribute_information(&polling.idm, &interrupted)
+        .unwrap();
+    assert!(card.read_ndef().is_err());
+}
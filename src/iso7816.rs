@@ -0,0 +1,453 @@
+// ISO/IEC 7816-4 のファイルシステムの操作
+// SELECT FILEでMF・DF・EFを選び、トランスペアレントEFはREAD/UPDATE BINARY、レコードEFはREAD RECORDで読み出す。
+// オフセットが7FFFを越えるときは奇数INS(B1/D7)でオフセットをDO 54に入れて送り、データはDO 53で受け渡す。
+// カードが返したエラーのSWは TransmitError のまま返す（62 82 ファイルの終わり、6A 82 ファイルがない など）。
+
+pub mod channel;
+pub mod explorer;
+pub mod fcp;
+pub mod secure_messaging;
+
+use crate::apdu_contactless::{Apdu, ApduBuilder};
+use crate::pc_sc_standard::ApduBuilderExtWithIso7816;
+use crate::smart_card::{Smartcard, TransmitError, APDU};
+use fcp::FileControlInformation;
+
+/// MFのファイルID
+pub const MF_FILE_ID: u16 = 0x3F00;
+/// 短いAPDUのLe(00)で受け取れる最大のバイト数
+pub const MAX_SHORT_LE: usize = 256;
+/// 短いAPDUで送れるデータの最大のバイト数
+const MAX_SHORT_LC: usize = 255;
+/// 偶数INSのREAD/UPDATE BINARYで指定できる最大のオフセット
+const MAX_EVEN_INS_OFFSET: usize = 0x7FFF;
+/// DO 54で指定できる最大のオフセット（3バイト）
+const MAX_ODO_OFFSET: usize = 0xFF_FFFF;
+/// 奇数INSのDO 54(5バイト)とDO 53のタグ・長さ(最大3バイト)
+const ODO_OVERHEAD: usize = 8;
+/// READ RECORDのP2の下位3ビット: P1のレコード番号を読む
+const RECORD_BY_NUMBER: u8 = 0x04;
+
+// ステータスワード
+/// 読み出しの途中でファイルの終わりに達した（データは返る）
+const SW_END_OF_FILE: (u8, u8) = (0x62, 0x82);
+const SW_RECORD_NOT_FOUND: (u8, u8) = (0x6A, 0x83);
+/// オフセットがEFの外
+const SW_WRONG_OFFSET: (u8, u8) = (0x6B, 0x00);
+/// Leが正しくない（SW2が正しい長さ）
+const SW1_WRONG_LE: u8 = 0x6C;
+
+/// SELECT FILEで何を指定して選ぶか（P1）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectTarget<'a> {
+    /// ファイルIDでMF・DF・EFを選ぶ（P1 = 00）
+    FileId(u16),
+    /// MFを選ぶ（P1 = 00、データなし）
+    Mf,
+    /// カレントDFの下のDF（P1 = 01）
+    ChildDf(u16),
+    /// カレントDFの下のEF（P1 = 02）
+    ChildEf(u16),
+    /// カレントDFの親のDF（P1 = 03）
+    ParentDf,
+    /// DF名(AID)で選ぶ（P1 = 04）
+    DfName(&'a [u8]),
+    /// MFからのパス。MFのファイルIDは含めない（P1 = 08）
+    PathFromMf(&'a [u16]),
+    /// カレントDFからのパス（P1 = 09）
+    PathFromCurrentDf(&'a [u16]),
+}
+
+impl<'a> SelectTarget<'a> {
+    fn p1(&self) -> u8 {
+        match self {
+            SelectTarget::FileId(_) | SelectTarget::Mf => 0x00,
+            SelectTarget::ChildDf(_) => 0x01,
+            SelectTarget::ChildEf(_) => 0x02,
+            SelectTarget::ParentDf => 0x03,
+            SelectTarget::DfName(_) => 0x04,
+            SelectTarget::PathFromMf(_) => 0x08,
+            SelectTarget::PathFromCurrentDf(_) => 0x09,
+        }
+    }
+    fn data(&self) -> Vec<u8> {
+        match self {
+            SelectTarget::FileId(id) | SelectTarget::ChildDf(id) | SelectTarget::ChildEf(id) => {
+                id.to_be_bytes().to_vec()
+            }
+            SelectTarget::Mf | SelectTarget::ParentDf => Vec::new(),
+            SelectTarget::DfName(name) => name.to_vec(),
+            SelectTarget::PathFromMf(path) | SelectTarget::PathFromCurrentDf(path) => {
+                path.iter().flat_map(|id| id.to_be_bytes()).collect()
+            }
+        }
+    }
+}
+
+/// SELECT FILEの応答に何を返させるか（P2）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectResponse {
+    /// FCIテンプレート(6F)
+    Fci = 0x00,
+    /// FCPテンプレート(62)
+    Fcp = 0x04,
+    /// FMDテンプレート(64)
+    Fmd = 0x08,
+    /// 応答データなし
+    NoResponse = 0x0C,
+}
+
+pub struct FileSystem<'a> {
+    nfc: &'a dyn Smartcard,
+}
+
+impl<'a> FileSystem<'a> {
+    pub fn new(nfc: &'a dyn Smartcard) -> Self {
+        FileSystem { nfc }
+    }
+    /// ファイルを選択し、応答のテンプレート（NoResponseなら空）を返す
+    pub fn select(
+        &self,
+        target: SelectTarget,
+        response: SelectResponse,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .select_file(target.p1(), response as u8, &target.data())
+            .build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    /// ファイルを選択し、応答のテンプレートを解析して返す
+    pub fn select_fcp(
+        &self,
+        target: SelectTarget,
+        response: SelectResponse,
+    ) -> Result<FileControlInformation, Box<dyn std::error::Error>> {
+        let res = self.select(target, response)?;
+        Ok(FileControlInformation::from_bytes(&res)?)
+    }
+    /// 選択中のトランスペアレントEFのoffsetから最大leバイト(1～256)を読み出す
+    /// ファイルの終わりに達したときは読めた分だけを返す
+    pub fn read_binary(
+        &self,
+        offset: usize,
+        le: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let le = le.clamp(1, MAX_SHORT_LE);
+        if offset > MAX_ODO_OFFSET {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::OffsetOutOfRange(offset),
+            )));
+        }
+        if offset <= MAX_EVEN_INS_OFFSET {
+            let apdu = ApduBuilder::new()
+                .iso_read_binary(offset as u16, le as u8)
+                .build();
+            return self.transmit_read(Box::new(apdu));
+        }
+        // 応答の 53 長さ(128バイト以上は 81 長さ) の分だけLeを増やす
+        let le = (if le >= 0x80 { le + 3 } else { le + 2 }).min(MAX_SHORT_LE);
+        let apdu = ApduBuilder::new()
+            .iso_read_binary_odo(offset as u32, le as u8)
+            .build();
+        let res = self.transmit_read(Box::new(apdu))?;
+        unwrap_discretionary_data(&res)
+    }
+    /// SFIで指定したEFを選択し、offset(0～255)から最大leバイトを読み出す
+    pub fn read_binary_sfi(
+        &self,
+        sfi: u8,
+        offset: u8,
+        le: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        check_sfi(sfi)?;
+        let le = le.clamp(1, MAX_SHORT_LE);
+        let apdu = ApduBuilder::new()
+            .read_binary_sfi(sfi, offset, le as u8)
+            .build();
+        self.transmit_read(Box::new(apdu))
+    }
+    /// 選択中のトランスペアレントEFを終わりまで読み出す
+    /// sfiを指定すると、最初のREAD BINARYでそのEFを選択する
+    pub fn read_transparent_ef(
+        &self,
+        sfi: Option<u8>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = Vec::new();
+        loop {
+            let offset = data.len();
+            let result = match sfi {
+                Some(sfi) if offset == 0 => self.read_binary_sfi(sfi, 0, MAX_SHORT_LE),
+                _ => self.read_binary(offset, MAX_SHORT_LE),
+            };
+            let chunk = match result {
+                Ok(chunk) => chunk,
+                // ちょうどファイルの終わりから読もうとした
+                Err(e) if offset > 0 && status_word(e.as_ref()) == Some(SW_WRONG_OFFSET) => break,
+                Err(e) => return Err(e),
+            };
+            let full = chunk.len() >= Self::read_size(offset);
+            data.extend(chunk);
+            if !full || data.len() > MAX_ODO_OFFSET {
+                break;
+            }
+        }
+        Ok(data)
+    }
+    /// 選択中のトランスペアレントEFのoffsetへ書き込む（長いデータは分けて送る）
+    pub fn update_binary(
+        &self,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if offset + data.len() > MAX_ODO_OFFSET + 1 {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::OffsetOutOfRange(offset + data.len()),
+            )));
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written;
+            let remain = &data[written..];
+            let apdu = if position <= MAX_EVEN_INS_OFFSET {
+                let size = remain.len().min(MAX_SHORT_LC);
+                written += size;
+                ApduBuilder::new()
+                    .iso_update_binary(position as u16, &remain[..size])
+                    .build()
+            } else {
+                let size = remain.len().min(MAX_SHORT_LC - ODO_OVERHEAD);
+                written += size;
+                ApduBuilder::new()
+                    .iso_update_binary_odo(position as u32, &remain[..size])
+                    .build()
+            };
+            self.nfc.transmit(Box::new(apdu))?;
+        }
+        Ok(())
+    }
+    /// レコード番号(1～254)でレコードを読み出す。sfiがNoneなら選択中のEFから読む
+    pub fn read_record(
+        &self,
+        record: u8,
+        sfi: Option<u8>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if record == 0 || record == 0xFF {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::InvalidRecordNumber(record),
+            )));
+        }
+        let sfi = match sfi {
+            Some(sfi) => check_sfi(sfi)?,
+            None => 0,
+        };
+        let apdu = ApduBuilder::new()
+            .read_record(record, sfi << 3 | RECORD_BY_NUMBER, 0x00)
+            .build();
+        self.transmit_read(Box::new(apdu))
+    }
+    /// レコード1から、レコードが見つからなくなるまで読み出す
+    pub fn read_records(
+        &self,
+        sfi: Option<u8>,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut records = Vec::new();
+        for record in 1..0xFF {
+            match self.read_record(record, sfi) {
+                Ok(data) => records.push(data),
+                Err(e) if status_word(e.as_ref()) == Some(SW_RECORD_NOT_FOUND) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(records)
+    }
+
+    /// offsetから1回のREAD BINARY(Le 00)で受け取れるデータの長さ
+    fn read_size(offset: usize) -> usize {
+        if offset <= MAX_EVEN_INS_OFFSET {
+            MAX_SHORT_LE
+        } else {
+            // DO 53のタグと長さ(81 長さ)の分だけ短くなる
+            MAX_SHORT_LE - 3
+        }
+    }
+    /// 読み出し系のコマンドを送る
+    /// 6C xx(Leの誤り)ならLeをxxにして送り直し、62 82(ファイルの終わり)なら読めたデータを返す
+    fn transmit_read(&self, apdu: Box<Apdu>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let command = apdu.read8();
+        let res = match self.nfc.transmit(apdu) {
+            Err(e) => match status_word(e.as_ref()) {
+                Some((SW1_WRONG_LE, le)) => {
+                    let mut command = command;
+                    let last = command.len() - 1;
+                    command[last] = le;
+                    self.nfc.transmit(Box::new(RawApdu(command)))
+                }
+                _ => Err(e),
+            },
+            res => res,
+        };
+        match res {
+            Err(e) if status_word(e.as_ref()) == Some(SW_END_OF_FILE) => {
+                let data = e
+                    .downcast_ref::<TransmitError>()
+                    .map(|e| e.data().to_vec())
+                    .unwrap_or_default();
+                Ok(data)
+            }
+            res => res,
+        }
+    }
+}
+
+/// 組み立て済みのAPDU（6C xxで送り直すときに使う）
+struct RawApdu(Vec<u8>);
+
+impl APDU for RawApdu {
+    fn read8(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+/// エラーがカードのSWなら(SW1, SW2)を返す
+pub fn status_word(error: &(dyn std::error::Error + 'static)) -> Option<(u8, u8)> {
+    error.downcast_ref::<TransmitError>()?.status_word()
+}
+
+fn check_sfi(sfi: u8) -> Result<u8, Iso7816Error> {
+    // SFIは1～30（0は選択中のEF、31は予約）
+    if (1..=30).contains(&sfi) {
+        Ok(sfi)
+    } else {
+        Err(Iso7816Error::new(Iso7816ErrorKind::InvalidSfi(sfi)))
+    }
+}
+
+/// 奇数INSの応答 53 長さ データ からデータを取り出す
+fn unwrap_discretionary_data(res: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let data = match res {
+        // ファイルの終わりから読んだときは空の応答を返すカードがある
+        [] => res,
+        [0x53, 0x82, high, low, data @ ..]
+            if data.len() == u16::from_be_bytes([*high, *low]) as usize =>
+        {
+            data
+        }
+        [0x53, 0x81, len, data @ ..] if data.len() == *len as usize => data,
+        [0x53, len, data @ ..] if *len < 0x80 && data.len() == *len as usize => data,
+        _ => {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::InvalidResponseLength(res.len()),
+            )))
+        }
+    };
+    Ok(data.to_vec())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Iso7816ErrorKind {
+    InvalidResponseLength(usize),
+    /// DO 54で指定できないオフセット
+    OffsetOutOfRange(usize),
+    /// SFIは1～30
+    InvalidSfi(u8),
+    /// レコード番号は1～254
+    InvalidRecordNumber(u8),
+    /// FCP・FMD・FCIの形式が正しくない
+    InvalidTemplate,
+    /// 論理チャネルの番号が範囲外
+    InvalidChannel(u8),
+    /// SMで包めないコマンド（拡張形式など）
+    UnsupportedCommand,
+    /// SMのレスポンスのデータオブジェクト・パディングが正しくない
+    InvalidSecureMessaging,
+    /// SMのMACが合わない、またはない
+    MacMismatch,
+}
+
+#[derive(Debug)]
+pub struct Iso7816Error {
+    code: Iso7816ErrorKind,
+}
+
+impl Iso7816Error {
+    pub fn new(code: Iso7816ErrorKind) -> Self {
+        Iso7816Error { code }
+    }
+    pub fn kind(&self) -> &Iso7816ErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for Iso7816Error {}
+impl std::fmt::Display for Iso7816Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn iso7816_file_system() {
+    use crate::nfc_impl::nfc_mock::iso7816::{VirtualFileSystemCard, ACCESS_NEVER};
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let aid = [0xA0, 0x00, 0x00, 0x00, 0x03, 0x10, 0x10];
+    let data = (0..600).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut card = VirtualFileSystemCard::new();
+    card.add_df(&[0x7F10], Some(&aid))
+        .add_transparent_ef(&[0x7F10, 0x6F01], Some(2), &data)
+        .add_record_ef(&[0x7F10, 0x6F02], Some(3), false, &[&[0x01; 8], &[0x02; 8]])
+        .add_transparent_ef(&[0x7F10, 0x6F03], None, &[0xAA; 16])
+        .set_access(&[0x7F10, 0x6F03], ACCESS_NEVER, ACCESS_NEVER)
+        .add_transparent_ef(&[0x2F00], None, &vec![0x00; 0x9000]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let fs = FileSystem::new(&nfc);
+
+    assert_eq!(
+        fs.select(SelectTarget::Mf, SelectResponse::Fcp).unwrap()[0],
+        0x62
+    );
+    let fci = fs
+        .select(SelectTarget::DfName(&aid), SelectResponse::Fci)
+        .unwrap();
+    assert_eq!(fci[0], 0x6F);
+    assert!(fci.windows(aid.len()).any(|window| window == aid));
+    // 256バイトずつ読み、最後は 62 82 で終わる
+    assert_eq!(fs.read_transparent_ef(Some(2)).unwrap(), data);
+    fs.select(
+        SelectTarget::PathFromMf(&[0x7F10, 0x6F01]),
+        SelectResponse::NoResponse,
+    )
+    .unwrap();
+    assert_eq!(fs.read_binary(590, 256).unwrap(), data[590..]);
+    assert_eq!(
+        fs.read_records(Some(3)).unwrap(),
+        vec![vec![0x01; 8], vec![0x02; 8]]
+    );
+    assert_eq!(fs.read_record(2, None).unwrap(), [0x02; 8]);
+
+    // アクセス条件を満たさない・ファイルがない
+    fs.select(SelectTarget::ChildEf(0x6F03), SelectResponse::NoResponse)
+        .unwrap();
+    let error = fs.read_transparent_ef(None).unwrap_err();
+    assert_eq!(status_word(error.as_ref()), Some((0x69, 0x82)));
+    let error = fs
+        .select(SelectTarget::ChildDf(0x6F01), SelectResponse::Fcp)
+        .unwrap_err();
+    assert_eq!(status_word(error.as_ref()), Some((0x6A, 0x82)));
+    let error = fs.read_record(1, Some(31)).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Iso7816Error>().unwrap().kind(),
+        &Iso7816ErrorKind::InvalidSfi(31)
+    );
+
+    // 7FFFを越える位置は奇数INSで読み書きする
+    fs.select(SelectTarget::ParentDf, SelectResponse::NoResponse)
+        .unwrap();
+    fs.select(SelectTarget::FileId(0x2F00), SelectResponse::NoResponse)
+        .unwrap();
+    let update = (0..0x300).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    fs.update_binary(0x7E80, &update).unwrap();
+    assert_eq!(fs.read_binary(0x8000, 0x10).unwrap(), update[0x180..0x190]);
+    let content = fs.read_transparent_ef(None).unwrap();
+    assert_eq!(content.len(), 0x9000);
+    assert_eq!(content[0x7E80..0x8180], update[..]);
+}
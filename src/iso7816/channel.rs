@@ -0,0 +1,164 @@
+// 論理チャネル
+// MANAGE CHANNELで開いたチャネルの番号を、送るAPDUのCLAに書き込んでから下のSmartcardへ渡す。
+// チャネルごとにカレントDF/EFが分かれるので、複数のアプリケーションを交互に使える。
+// MANAGE CHANNELは基本チャネル(0)で送る。CLA=FF(PC/SCの擬似APDU)はそのまま通す。
+
+use super::{Iso7816Error, Iso7816ErrorKind, RawApdu};
+use crate::apdu_contactless::{class_with_channel, ApduBuilder, MAX_LOGICAL_CHANNEL};
+use crate::pc_sc_standard::{AnswerToReset, ApduBuilderExtWithIso7816};
+use crate::smart_card::{
+    ProtocolType, Smartcard, SmartcardConnectMethod, SmartcardError, SmartcardErrorKind,
+    SmartcardVersion, APDU,
+};
+
+/// 基本チャネル
+pub const BASIC_CHANNEL: u8 = 0;
+
+pub struct LogicalChannel<'a> {
+    nfc: &'a dyn Smartcard,
+    number: u8,
+}
+
+impl<'a> LogicalChannel<'a> {
+    /// 基本チャネル（開く・閉じるは不要）
+    pub fn basic(nfc: &'a dyn Smartcard) -> Self {
+        LogicalChannel {
+            nfc,
+            number: BASIC_CHANNEL,
+        }
+    }
+    /// カードが割り当てた番号のチャネルを開く
+    pub fn open(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().manage_channel_open(None).build();
+        let number = match nfc.transmit(Box::new(apdu))?.as_slice() {
+            [number] if (1..=MAX_LOGICAL_CHANNEL).contains(number) => *number,
+            [number] => {
+                return Err(Box::new(Iso7816Error::new(
+                    Iso7816ErrorKind::InvalidChannel(*number),
+                )))
+            }
+            res => {
+                return Err(Box::new(Iso7816Error::new(
+                    Iso7816ErrorKind::InvalidResponseLength(res.len()),
+                )))
+            }
+        };
+        Ok(LogicalChannel { nfc, number })
+    }
+    /// 番号(1～19)を指定してチャネルを開く
+    pub fn open_number(
+        nfc: &'a dyn Smartcard,
+        number: u8,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if number == BASIC_CHANNEL || number > MAX_LOGICAL_CHANNEL {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::InvalidChannel(number),
+            )));
+        }
+        let apdu = ApduBuilder::new().manage_channel_open(Some(number)).build();
+        nfc.transmit(Box::new(apdu))?;
+        Ok(LogicalChannel { nfc, number })
+    }
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+    /// チャネルを閉じる（基本チャネルは閉じられない）
+    /// 閉じた後に送ったコマンドはカードがエラー(68 81)を返す
+    pub fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.number == BASIC_CHANNEL {
+            return Err(Box::new(Iso7816Error::new(
+                Iso7816ErrorKind::InvalidChannel(self.number),
+            )));
+        }
+        let apdu = ApduBuilder::new().manage_channel_close(self.number).build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+}
+
+impl<'a> Smartcard for LogicalChannel<'a> {
+    fn version_str(&self) -> Option<String> {
+        self.nfc.version_str()
+    }
+    fn version(&self) -> Option<SmartcardVersion> {
+        self.nfc.version()
+    }
+    /// 接続は下のSmartcardで済ませておく
+    fn connect_reader(
+        &mut self,
+        _con_method: SmartcardConnectMethod,
+    ) -> Result<ProtocolType, SmartcardError> {
+        Err(SmartcardError::new(SmartcardErrorKind::NotReady))
+    }
+    fn transmit(&self, data: Box<dyn APDU>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut command = data.read8();
+        if let Some(cla) = command
+            .first()
+            .and_then(|cla| class_with_channel(*cla, self.number))
+        {
+            command[0] = cla;
+        }
+        self.nfc.transmit(Box::new(RawApdu(command)))
+    }
+    /// プロトコルは変えられない（下のSmartcardで設定する）
+    fn config_protocol(&mut self, _protocol: ProtocolType) -> Option<ProtocolType> {
+        None
+    }
+    fn get_atr(&self) -> &AnswerToReset {
+        self.nfc.get_atr()
+    }
+}
+
+#[test]
+fn iso7816_logical_channel() {
+    use super::{status_word, FileSystem, SelectResponse, SelectTarget};
+    use crate::nfc_impl::nfc_mock::iso7816::VirtualFileSystemCard;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let first = [0xA0, 0x00, 0x00, 0x00, 0x01];
+    let second = [0xA0, 0x00, 0x00, 0x00, 0x02];
+    let mut card = VirtualFileSystemCard::new();
+    card.add_df(&[0x7F01], Some(&first))
+        .add_transparent_ef(&[0x7F01, 0x6F01], None, &[0x11; 8])
+        .add_df(&[0x7F02], Some(&second))
+        .add_transparent_ef(&[0x7F02, 0x6F01], None, &[0x22; 8]);
+    let nfc = MockSmartcard::new(Box::new(card));
+
+    // 2つのアプリケーションを別のチャネルで選択し、交互に読む
+    let channel1 = LogicalChannel::open(&nfc).unwrap();
+    let channel5 = LogicalChannel::open_number(&nfc, 5).unwrap();
+    assert_eq!(channel1.number(), 1);
+    let fs1 = FileSystem::new(&channel1);
+    let fs5 = FileSystem::new(&channel5);
+    fs1.select(SelectTarget::DfName(&first), SelectResponse::NoResponse)
+        .unwrap();
+    fs5.select(SelectTarget::DfName(&second), SelectResponse::NoResponse)
+        .unwrap();
+    fs1.select(SelectTarget::ChildEf(0x6F01), SelectResponse::NoResponse)
+        .unwrap();
+    fs5.select(SelectTarget::ChildEf(0x6F01), SelectResponse::NoResponse)
+        .unwrap();
+    assert_eq!(fs1.read_binary(0, 8).unwrap(), [0x11; 8]);
+    assert_eq!(fs5.read_binary(0, 8).unwrap(), [0x22; 8]);
+    // 基本チャネルはEFを選択していない
+    let basic = FileSystem::new(&nfc);
+    let error = basic.read_binary(0, 8).unwrap_err();
+    assert_eq!(status_word(error.as_ref()), Some((0x69, 0x86)));
+
+    // 開いているチャネルは開けない。閉じたチャネルは使えず、同じ番号を開き直せる
+    let error = LogicalChannel::open_number(&nfc, 5).err().unwrap();
+    assert_eq!(status_word(error.as_ref()), Some((0x6A, 0x81)));
+    channel5.close().unwrap();
+    let error = fs5.read_binary(0, 8).unwrap_err();
+    assert_eq!(status_word(error.as_ref()), Some((0x68, 0x81)));
+    let channel5 = LogicalChannel::open_number(&nfc, 5).unwrap();
+    let error = FileSystem::new(&channel5).read_binary(0, 8).unwrap_err();
+    assert_eq!(status_word(error.as_ref()), Some((0x69, 0x86)));
+
+    let error = LogicalChannel::basic(&nfc).close().unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<Iso7816Error>().unwrap().kind(),
+        &Iso7816ErrorKind::InvalidChannel(0)
+    );
+    assert_eq!(fs1.read_binary(0, 8).unwrap(), [0x11; 8]);
+}
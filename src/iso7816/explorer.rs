@@ -0,0 +1,325 @@
+// ファイルシステムの探索
+// MFから順にファイルIDの範囲をSELECT FILE(FCP)で調べ、見つかったDFの下を再帰的に探索する。
+// 読み出せるEFは READ BINARY / READ RECORD で内容を読む。
+// 送るのはSELECT FILEと読み出しのコマンドだけで、カードの状態（データ・リトライカウンタなど）は変えない。
+// 探索はMFからのパス(P1 = 08)で選択するので、カレントDFに依存しない。
+
+use super::fcp::{EfStructure, FileControlInformation, SecurityCondition};
+use super::{status_word, FileSystem, SelectResponse, SelectTarget, MF_FILE_ID};
+use crate::card_dump::to_hex;
+use crate::smart_card::Smartcard;
+use serde_json::{json, Value};
+use std::ops::RangeInclusive;
+
+/// パスの指定に予約されているファイルID
+const RESERVED_FILE_ID: u16 = 0x3FFF;
+const RFU_FILE_ID: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplorerOptions {
+    /// 各DFの下で調べるファイルIDの範囲
+    pub ranges: Vec<RangeInclusive<u16>>,
+    /// MFを0とした、探索するDFの深さ
+    pub max_depth: usize,
+    /// 読み出せるEFの内容を読むか
+    pub read_content: bool,
+}
+
+impl Default for ExplorerOptions {
+    /// よく使われる範囲（01xx・2Fxx・4Fxx・5Fxx・6Fxx・7Fxx）
+    fn default() -> Self {
+        ExplorerOptions {
+            ranges: vec![
+                0x0000..=0x01FF,
+                0x2F00..=0x2FFF,
+                0x4F00..=0x4FFF,
+                0x5F00..=0x5FFF,
+                0x6F00..=0x6FFF,
+                0x7F00..=0x7FFF,
+            ],
+            max_depth: 4,
+            read_content: true,
+        }
+    }
+}
+
+/// EFの内容
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileContent {
+    Transparent(Vec<u8>),
+    Records(Vec<Vec<u8>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileNode {
+    /// MFからのパス（MFは空）
+    pub path: Vec<u16>,
+    /// SELECT FILEの応答
+    pub raw_fcp: Vec<u8>,
+    /// 解析できなければNone
+    pub fcp: Option<FileControlInformation>,
+    /// 選択・読み出しでカードが返したエラーのSW
+    pub status: Option<(u8, u8)>,
+    pub content: Option<FileContent>,
+    pub children: Vec<FileNode>,
+}
+
+impl FileNode {
+    pub fn file_id(&self) -> u16 {
+        self.path.last().cloned().unwrap_or(MF_FILE_ID)
+    }
+    pub fn is_df(&self) -> bool {
+        self.path.is_empty() || self.fcp.as_ref().is_some_and(|fcp| fcp.is_df())
+    }
+    /// パスで子孫のノードを探す
+    pub fn find(&self, path: &[u16]) -> Option<&FileNode> {
+        match path.split_first() {
+            None => Some(self),
+            Some((fid, rest)) => self
+                .children
+                .iter()
+                .find(|child| child.file_id() == *fid)
+                .and_then(|child| child.find(rest)),
+        }
+    }
+    /// バッチ間で比較しやすいよう、キーの順番が決まったJSONにする
+    pub fn to_json(&self) -> Value {
+        let mut path = vec![MF_FILE_ID];
+        path.extend(&self.path);
+        let mut node = json!({
+            "path": path.iter().map(|fid| format!("{:04X}", fid)).collect::<Vec<String>>().join("/"),
+            "type": if self.is_df() { "DF" } else { "EF" },
+            "fcp": to_hex(&self.raw_fcp, ""),
+        });
+        if let Some(fcp) = &self.fcp {
+            if let Some(name) = &fcp.df_name {
+                node["name"] = json!(to_hex(name, ""));
+            }
+            if let Some(structure) = fcp.descriptor.and_then(|d| d.structure()) {
+                node["structure"] = json!(format!("{:?}", structure));
+            }
+            if let Some(size) = fcp.file_size {
+                node["size"] = json!(size);
+            }
+            if let Some(Some(sfi)) = fcp.sfi {
+                node["sfi"] = json!(sfi);
+            }
+            if let Some(life_cycle) = fcp.life_cycle {
+                node["lifeCycle"] = json!(format!("{:?}", life_cycle));
+            }
+            if !fcp.security.is_empty() {
+                node["security"] = json!(fcp
+                    .security
+                    .iter()
+                    .map(|attributes| format!("{:?}", attributes))
+                    .collect::<Vec<String>>());
+            }
+        }
+        if let Some((sw1, sw2)) = self.status {
+            node["status"] = json!(to_hex(&[sw1, sw2], ""));
+        }
+        match &self.content {
+            Some(FileContent::Transparent(data)) => node["content"] = json!(to_hex(data, "")),
+            Some(FileContent::Records(records)) => {
+                node["records"] = json!(records
+                    .iter()
+                    .map(|record| to_hex(record, ""))
+                    .collect::<Vec<String>>())
+            }
+            None => {}
+        }
+        if self.is_df() {
+            node["children"] = Value::Array(self.children.iter().map(|c| c.to_json()).collect());
+        }
+        node
+    }
+}
+
+pub struct Explorer<'a> {
+    fs: FileSystem<'a>,
+    options: ExplorerOptions,
+}
+
+impl<'a> Explorer<'a> {
+    pub fn new(nfc: &'a dyn Smartcard, options: ExplorerOptions) -> Self {
+        Explorer {
+            fs: FileSystem::new(nfc),
+            options,
+        }
+    }
+    /// MFから探索してファイルの木を返す
+    pub fn explore(&self) -> Result<FileNode, Box<dyn std::error::Error>> {
+        let raw_fcp = self.fs.select(SelectTarget::Mf, SelectResponse::Fcp)?;
+        let mut mf = FileNode {
+            fcp: FileControlInformation::from_bytes(&raw_fcp).ok(),
+            raw_fcp,
+            path: Vec::new(),
+            status: None,
+            content: None,
+            children: Vec::new(),
+        };
+        self.explore_df(&mut mf, 0)?;
+        Ok(mf)
+    }
+    fn explore_df(
+        &self,
+        df: &mut FileNode,
+        depth: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for range in self.options.ranges.iter() {
+            for fid in range.clone() {
+                if fid == MF_FILE_ID
+                    || fid == RESERVED_FILE_ID
+                    || fid == RFU_FILE_ID
+                    || df.path.contains(&fid)
+                {
+                    continue;
+                }
+                let mut path = df.path.clone();
+                path.push(fid);
+                let mut node = match self.probe(path)? {
+                    Some(node) => node,
+                    None => continue,
+                };
+                if node.is_df() {
+                    if depth < self.options.max_depth {
+                        self.explore_df(&mut node, depth + 1)?;
+                    }
+                } else if self.options.read_content && node.status.is_none() {
+                    self.read_content(&mut node)?;
+                }
+                df.children.push(node);
+            }
+        }
+        Ok(())
+    }
+    /// ファイルがなければNone。存在するが選択できない(69xx)ときはSWを記録する
+    fn probe(&self, path: Vec<u16>) -> Result<Option<FileNode>, Box<dyn std::error::Error>> {
+        let (raw_fcp, status) = match self
+            .fs
+            .select(SelectTarget::PathFromMf(&path), SelectResponse::Fcp)
+        {
+            Ok(raw_fcp) => (raw_fcp, None),
+            Err(error) => match status_word(error.as_ref()) {
+                Some((0x69, sw2)) => (Vec::new(), Some((0x69, sw2))),
+                Some(_) => return Ok(None),
+                None => return Err(error),
+            },
+        };
+        Ok(Some(FileNode {
+            fcp: FileControlInformation::from_bytes(&raw_fcp).ok(),
+            raw_fcp,
+            path,
+            status,
+            content: None,
+            children: Vec::new(),
+        }))
+    }
+    /// 選択中のEFを構造に合わせて読む。構造が分からなければトランスペアレント、レコードの順に試す
+    fn read_content(&self, node: &mut FileNode) -> Result<(), Box<dyn std::error::Error>> {
+        let fcp = node.fcp.as_ref();
+        if fcp.and_then(|fcp| fcp.read_condition()) == Some(SecurityCondition::Never) {
+            return Ok(());
+        }
+        let structure = fcp
+            .and_then(|fcp| fcp.descriptor)
+            .and_then(|descriptor| descriptor.structure());
+        let transparent = match structure {
+            Some(EfStructure::Transparent) | Some(EfStructure::NoInformation) | None => {
+                Some(self.fs.read_transparent_ef(None))
+            }
+            _ => None,
+        };
+        let result = match transparent {
+            Some(Ok(data)) => Ok(FileContent::Transparent(data)),
+            // 構造が分かっていてトランスペアレントでなければ、レコードとして読む
+            Some(Err(error)) if structure.is_some() => Err(error),
+            _ => self.fs.read_records(None).map(FileContent::Records),
+        };
+        match result {
+            Ok(content) => node.content = Some(content),
+            Err(error) => match status_word(error.as_ref()) {
+                Some(sw) => node.status = Some(sw),
+                None => return Err(error),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn iso7816_explorer() {
+    use crate::nfc_impl::nfc_mock::iso7816::{VirtualFileSystemCard, ACCESS_NEVER};
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let aid = [0xA0, 0x00, 0x00, 0x00, 0x03, 0x10, 0x10];
+    let data = (0..300).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut card = VirtualFileSystemCard::new();
+    card.add_transparent_ef(&[0x2F00], Some(30), &[0x61, 0x00])
+        .add_df(&[0x7F10], Some(&aid))
+        .add_transparent_ef(&[0x7F10, 0x6F01], Some(2), &data)
+        .add_record_ef(&[0x7F10, 0x6F02], Some(3), false, &[&[0x01; 4], &[0x02; 4]])
+        .add_transparent_ef(&[0x7F10, 0x6F03], None, &[0xAA; 16])
+        .set_access(&[0x7F10, 0x6F03], ACCESS_NEVER, ACCESS_NEVER)
+        .add_df(&[0x7F10, 0x5F20], None)
+        .add_transparent_ef(&[0x7F10, 0x5F20, 0x6F01], None, &[0x55; 8])
+        // 範囲の外
+        .add_transparent_ef(&[0x1000], None, &[0x00; 4]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let options = ExplorerOptions {
+        ranges: vec![
+            0x2F00..=0x2F0F,
+            0x5F20..=0x5F20,
+            0x6F00..=0x6F0F,
+            0x7F00..=0x7F1F,
+        ],
+        ..ExplorerOptions::default()
+    };
+    let tree = Explorer::new(&nfc, options).explore().unwrap();
+
+    assert!(tree.is_df());
+    assert_eq!(
+        tree.children
+            .iter()
+            .map(|c| c.file_id())
+            .collect::<Vec<u16>>(),
+        vec![0x2F00, 0x7F10]
+    );
+    assert_eq!(
+        tree.find(&[0x2F00]).unwrap().content,
+        Some(FileContent::Transparent(vec![0x61, 0x00]))
+    );
+    let df = tree.find(&[0x7F10]).unwrap();
+    assert!(df.is_df());
+    assert_eq!(df.fcp.as_ref().unwrap().df_name, Some(aid.to_vec()));
+    assert_eq!(df.children.len(), 4);
+    assert_eq!(
+        tree.find(&[0x7F10, 0x6F01]).unwrap().content,
+        Some(FileContent::Transparent(data))
+    );
+    assert_eq!(
+        tree.find(&[0x7F10, 0x6F02]).unwrap().content,
+        Some(FileContent::Records(vec![vec![0x01; 4], vec![0x02; 4]]))
+    );
+    // 読み出し禁止のEFは読まない
+    let never = tree.find(&[0x7F10, 0x6F03]).unwrap();
+    assert_eq!(never.content, None);
+    assert_eq!(
+        never.fcp.as_ref().unwrap().read_condition(),
+        Some(SecurityCondition::Never)
+    );
+    assert_eq!(
+        tree.find(&[0x7F10, 0x5F20, 0x6F01]).unwrap().content,
+        Some(FileContent::Transparent(vec![0x55; 8]))
+    );
+    assert!(tree.find(&[0x1000]).is_none());
+
+    let json = tree.to_json();
+    assert_eq!(json["path"], "3F00");
+    let ef = &json["children"][1]["children"][2];
+    assert_eq!(ef["path"], "3F00/7F10/6F02");
+    assert_eq!(ef["structure"], "LinearFixed");
+    assert_eq!(ef["sfi"], 3);
+    assert_eq!(ef["records"], json!(["01010101", "02020202"]));
+    assert_eq!(json["children"][0]["content"], "6100");
+}
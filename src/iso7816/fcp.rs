@@ -0,0 +1,630 @@
+// SELECT FILEの応答のテンプレート（FCP 62・FMD 64・FCI 6F）の解析
+// FCIはFCPとFMDのデータオブジェクトをまとめたもので、中に62・64のテンプレートを入れるカードもある。
+// セキュリティ属性はコンパクト形式(8C)・拡張形式(AB)・EF.ARRの参照(8B)・独自形式(86)がある。
+
+use super::{Iso7816Error, Iso7816ErrorKind};
+use crate::ber_tlv;
+
+pub const TAG_FCP: u32 = 0x62;
+pub const TAG_FMD: u32 = 0x64;
+pub const TAG_FCI: u32 = 0x6F;
+const TAG_FILE_SIZE: u32 = 0x80;
+const TAG_TOTAL_FILE_SIZE: u32 = 0x81;
+const TAG_FILE_DESCRIPTOR: u32 = 0x82;
+const TAG_FILE_ID: u32 = 0x83;
+const TAG_DF_NAME: u32 = 0x84;
+const TAG_PROPRIETARY: u32 = 0x85;
+const TAG_PROPRIETARY_SECURITY: u32 = 0x86;
+const TAG_SECURITY_REFERENCED: u32 = 0x8B;
+const TAG_SFI: u32 = 0x88;
+const TAG_LIFE_CYCLE: u32 = 0x8A;
+const TAG_SECURITY_COMPACT: u32 = 0x8C;
+const TAG_PROPRIETARY_TEMPLATE: u32 = 0xA5;
+const TAG_SECURITY_EXPANDED: u32 = 0xAB;
+
+/// テンプレートの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateKind {
+    Fcp,
+    Fmd,
+    Fci,
+}
+
+/// ファイル記述子バイトのファイルの種類（b6～b4）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileCategory {
+    WorkingEf,
+    InternalEf,
+    /// 独自のEF（b6～b4が010～110）
+    ProprietaryEf,
+    Df,
+}
+
+/// EFの構造（b3～b1）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EfStructure {
+    NoInformation,
+    Transparent,
+    LinearFixed,
+    LinearFixedTlv,
+    LinearVariable,
+    LinearVariableTlv,
+    Cyclic,
+    CyclicTlv,
+}
+
+/// ファイル記述子(82) ファイル記述子バイト || データコーディングバイト || 最大レコード長 || レコード数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileDescriptor {
+    pub descriptor: u8,
+    pub data_coding: Option<u8>,
+    pub max_record_size: Option<usize>,
+    pub record_count: Option<usize>,
+}
+
+impl FileDescriptor {
+    fn from_bytes(value: &[u8]) -> Option<Self> {
+        let number = |bytes: &[u8]| bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        let (max_record_size, record_count) = match value.len() {
+            0 => return None,
+            1 | 2 => (None, None),
+            3 => (Some(value[2] as usize), None),
+            4 => (Some(value[2] as usize), Some(value[3] as usize)),
+            // 最大レコード長2バイト・レコード数1バイト、またはどちらも2バイト
+            5 => (Some(number(&value[2..4])), Some(value[4] as usize)),
+            _ => (Some(number(&value[2..4])), Some(number(&value[4..6]))),
+        };
+        Some(FileDescriptor {
+            descriptor: value[0],
+            data_coding: value.get(1).cloned(),
+            max_record_size,
+            record_count,
+        })
+    }
+    pub fn is_shareable(&self) -> bool {
+        self.descriptor & 0x40 != 0
+    }
+    pub fn category(&self) -> FileCategory {
+        match (self.descriptor >> 3) & 0x07 {
+            0b000 => FileCategory::WorkingEf,
+            0b001 => FileCategory::InternalEf,
+            0b111 => FileCategory::Df,
+            _ => FileCategory::ProprietaryEf,
+        }
+    }
+    pub fn is_df(&self) -> bool {
+        self.category() == FileCategory::Df
+    }
+    /// DFならNone
+    pub fn structure(&self) -> Option<EfStructure> {
+        if self.is_df() {
+            return None;
+        }
+        Some(match self.descriptor & 0x07 {
+            0b000 => EfStructure::NoInformation,
+            0b001 => EfStructure::Transparent,
+            0b010 => EfStructure::LinearFixed,
+            0b011 => EfStructure::LinearFixedTlv,
+            0b100 => EfStructure::LinearVariable,
+            0b101 => EfStructure::LinearVariableTlv,
+            0b110 => EfStructure::Cyclic,
+            _ => EfStructure::CyclicTlv,
+        })
+    }
+}
+
+/// ライフサイクルステータス(8A)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LifeCycleStatus {
+    NoInformation,
+    Creation,
+    Initialisation,
+    /// 運用中(活性)
+    Activated,
+    /// 運用中(非活性)
+    Deactivated,
+    Terminated,
+    Proprietary(u8),
+}
+
+impl LifeCycleStatus {
+    pub fn from_byte(value: u8) -> Self {
+        match value {
+            0x00 => LifeCycleStatus::NoInformation,
+            0x01 => LifeCycleStatus::Creation,
+            0x03 => LifeCycleStatus::Initialisation,
+            0x05 | 0x07 => LifeCycleStatus::Activated,
+            0x04 | 0x06 => LifeCycleStatus::Deactivated,
+            0x0C..=0x0F => LifeCycleStatus::Terminated,
+            value => LifeCycleStatus::Proprietary(value),
+        }
+    }
+}
+
+/// SCバイトが表すアクセス条件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecurityCondition {
+    Always,
+    Never,
+    Conditions {
+        /// trueなら全ての条件、falseならいずれかの条件
+        all: bool,
+        secure_messaging: bool,
+        external_authentication: bool,
+        user_authentication: bool,
+        /// セキュリティ環境の番号（0は参照なし）
+        security_environment: u8,
+    },
+}
+
+impl SecurityCondition {
+    pub fn from_byte(value: u8) -> Self {
+        match value {
+            0x00 => SecurityCondition::Always,
+            0xFF => SecurityCondition::Never,
+            value => SecurityCondition::Conditions {
+                all: value & 0x80 != 0,
+                secure_messaging: value & 0x40 != 0,
+                external_authentication: value & 0x20 != 0,
+                user_authentication: value & 0x10 != 0,
+                security_environment: value & 0x0F,
+            },
+        }
+    }
+}
+
+/// アクセスモードバイトのビット（EF）
+pub const AM_EF_READ: u8 = 0x01;
+pub const AM_EF_UPDATE: u8 = 0x02;
+pub const AM_EF_WRITE: u8 = 0x04;
+pub const AM_DEACTIVATE: u8 = 0x08;
+pub const AM_ACTIVATE: u8 = 0x10;
+pub const AM_TERMINATE: u8 = 0x20;
+pub const AM_DELETE_SELF: u8 = 0x40;
+/// アクセスモードバイトのビット（DF）
+pub const AM_DF_DELETE_CHILD: u8 = 0x01;
+pub const AM_DF_CREATE_EF: u8 = 0x02;
+pub const AM_DF_CREATE_DF: u8 = 0x04;
+
+/// 拡張形式のアクセスモードのデータオブジェクト
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessModeObject {
+    /// 80: アクセスモードバイト
+    AccessModeByte(u8),
+    /// 81～8F: コマンドヘッダ（タグの下位4ビットがCLA・INS・P1・P2のどれを含むか）
+    Command {
+        cla: Option<u8>,
+        ins: Option<u8>,
+        p1: Option<u8>,
+        p2: Option<u8>,
+    },
+    /// 9C: 独自のアクセスモード
+    Proprietary(Vec<u8>),
+}
+
+/// 拡張形式のセキュリティ条件のデータオブジェクト
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConditionObject {
+    /// 90
+    Always,
+    /// 97
+    Never,
+    /// 9E: SCバイト
+    Condition(SecurityCondition),
+    /// コントロールリファレンステンプレート(A4・B4・B6・B8)、OR(A0)・AND(AF)・NOT(A7)のテンプレート
+    Template { tag: u32, value: Vec<u8> },
+}
+
+/// アクセスモードと、それに続くセキュリティ条件
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandedRule {
+    pub access_mode: AccessModeObject,
+    pub conditions: Vec<ConditionObject>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecurityAttributes {
+    /// 8C: アクセスモードバイトと、立っているビット(b7→b1の順)ごとのSCバイト
+    Compact {
+        access_mode: u8,
+        conditions: Vec<(u8, SecurityCondition)>,
+    },
+    /// AB
+    Expanded(Vec<ExpandedRule>),
+    /// 8B: EF.ARRのファイルIDとレコード番号（SEごとの組もある）
+    Referenced { file_id: u16, records: Vec<u8> },
+    /// 86
+    Proprietary(Vec<u8>),
+}
+
+impl SecurityAttributes {
+    fn compact(value: &[u8]) -> Option<Self> {
+        let (access_mode, sc) = value.split_first()?;
+        let bits = (0..7)
+            .rev()
+            .map(|bit| 1u8 << bit)
+            .filter(|bit| access_mode & bit != 0);
+        let conditions = bits
+            .zip(sc.iter())
+            .map(|(bit, sc)| (bit, SecurityCondition::from_byte(*sc)))
+            .collect();
+        Some(SecurityAttributes::Compact {
+            access_mode: *access_mode,
+            conditions,
+        })
+    }
+    fn expanded(value: &[u8]) -> Result<Self, Iso7816Error> {
+        let mut rules: Vec<ExpandedRule> = Vec::new();
+        for (tag, value) in parse_objects(value)? {
+            let access_mode = match tag {
+                0x80 => value
+                    .first()
+                    .map(|am| AccessModeObject::AccessModeByte(*am)),
+                0x81..=0x8F => {
+                    // b4: CLA, b3: INS, b2: P1, b1: P2 の順に値がある
+                    let mut bytes = value.iter();
+                    let mut next = |bit: u32| {
+                        if tag & bit != 0 {
+                            bytes.next().cloned()
+                        } else {
+                            None
+                        }
+                    };
+                    Some(AccessModeObject::Command {
+                        cla: next(0x08),
+                        ins: next(0x04),
+                        p1: next(0x02),
+                        p2: next(0x01),
+                    })
+                }
+                0x9C => Some(AccessModeObject::Proprietary(value.to_vec())),
+                _ => None,
+            };
+            if let Some(access_mode) = access_mode {
+                rules.push(ExpandedRule {
+                    access_mode,
+                    conditions: Vec::new(),
+                });
+                continue;
+            }
+            let condition = match tag {
+                0x90 => ConditionObject::Always,
+                0x97 => ConditionObject::Never,
+                0x9E if value.len() == 1 => {
+                    ConditionObject::Condition(SecurityCondition::from_byte(value[0]))
+                }
+                tag => ConditionObject::Template {
+                    tag,
+                    value: value.to_vec(),
+                },
+            };
+            // アクセスモードより前のセキュリティ条件は不正
+            rules
+                .last_mut()
+                .ok_or_else(|| Iso7816Error::new(Iso7816ErrorKind::InvalidTemplate))?
+                .conditions
+                .push(condition);
+        }
+        Ok(SecurityAttributes::Expanded(rules))
+    }
+}
+
+/// FCP・FMD・FCIを解析した内容
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileControlInformation {
+    pub template: TemplateKind,
+    /// 80: データのバイト数
+    pub file_size: Option<usize>,
+    /// 81: 構造の情報を含むバイト数
+    pub total_file_size: Option<usize>,
+    pub descriptor: Option<FileDescriptor>,
+    pub file_id: Option<u16>,
+    pub df_name: Option<Vec<u8>>,
+    /// 88: 値がなければSFIを使えない（Some(None)）
+    pub sfi: Option<Option<u8>>,
+    pub life_cycle: Option<LifeCycleStatus>,
+    pub security: Vec<SecurityAttributes>,
+    /// 85・A5の値
+    pub proprietary: Vec<Vec<u8>>,
+    /// 上記以外のデータオブジェクト
+    pub others: Vec<(u32, Vec<u8>)>,
+}
+
+impl FileControlInformation {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Iso7816Error> {
+        let objects = parse_objects(data)?;
+        let (tag, value) = match objects.as_slice() {
+            [(tag, value)] => (*tag, *value),
+            _ => {
+                return Err(Iso7816Error::new(Iso7816ErrorKind::InvalidTemplate));
+            }
+        };
+        let template = match tag {
+            TAG_FCP => TemplateKind::Fcp,
+            TAG_FMD => TemplateKind::Fmd,
+            TAG_FCI => TemplateKind::Fci,
+            _ => return Err(Iso7816Error::new(Iso7816ErrorKind::InvalidTemplate)),
+        };
+        let mut fci = FileControlInformation {
+            template,
+            file_size: None,
+            total_file_size: None,
+            descriptor: None,
+            file_id: None,
+            df_name: None,
+            sfi: None,
+            life_cycle: None,
+            security: Vec::new(),
+            proprietary: Vec::new(),
+            others: Vec::new(),
+        };
+        fci.decode(value, false)?;
+        Ok(fci)
+    }
+    /// nestedはFCIの中のFCP・FMDを解析しているとき
+    fn decode(&mut self, data: &[u8], nested: bool) -> Result<(), Iso7816Error> {
+        let number = |value: &[u8]| value.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        for (tag, value) in parse_objects(data)? {
+            match tag {
+                // FCIの中のFCP・FMD（入れ子は1段だけ）
+                TAG_FCP | TAG_FMD if self.template == TemplateKind::Fci => {
+                    if nested {
+                        return Err(Iso7816Error::new(Iso7816ErrorKind::InvalidTemplate));
+                    }
+                    self.decode(value, true)?
+                }
+                TAG_FILE_SIZE => self.file_size = Some(number(value)),
+                TAG_TOTAL_FILE_SIZE => self.total_file_size = Some(number(value)),
+                TAG_FILE_DESCRIPTOR => self.descriptor = FileDescriptor::from_bytes(value),
+                TAG_FILE_ID if value.len() == 2 => {
+                    self.file_id = Some(u16::from_be_bytes([value[0], value[1]]))
+                }
+                TAG_DF_NAME => self.df_name = Some(value.to_vec()),
+                TAG_SFI => self.sfi = Some(value.first().map(|sfi| sfi >> 3)),
+                TAG_LIFE_CYCLE if value.len() == 1 => {
+                    self.life_cycle = Some(LifeCycleStatus::from_byte(value[0]))
+                }
+                TAG_SECURITY_COMPACT => self.security.extend(SecurityAttributes::compact(value)),
+                TAG_SECURITY_EXPANDED => self.security.push(SecurityAttributes::expanded(value)?),
+                TAG_SECURITY_REFERENCED if value.len() >= 2 => {
+                    self.security.push(SecurityAttributes::Referenced {
+                        file_id: u16::from_be_bytes([value[0], value[1]]),
+                        records: value[2..].to_vec(),
+                    })
+                }
+                TAG_PROPRIETARY_SECURITY => self
+                    .security
+                    .push(SecurityAttributes::Proprietary(value.to_vec())),
+                TAG_PROPRIETARY | TAG_PROPRIETARY_TEMPLATE => self.proprietary.push(value.to_vec()),
+                tag => self.others.push((tag, value.to_vec())),
+            }
+        }
+        Ok(())
+    }
+    pub fn is_df(&self) -> bool {
+        match self.descriptor {
+            Some(descriptor) => descriptor.is_df(),
+            // ファイル記述子がなくてもDF名があればDF
+            None => self.df_name.is_some(),
+        }
+    }
+    /// コンパクト形式のセキュリティ属性から、アクセスモードのビットの条件を探す
+    pub fn compact_condition(&self, access_mode: u8) -> Option<SecurityCondition> {
+        self.security
+            .iter()
+            .find_map(|attributes| match attributes {
+                SecurityAttributes::Compact { conditions, .. } => conditions
+                    .iter()
+                    .find(|(bit, _)| *bit == access_mode)
+                    .map(|(_, condition)| *condition),
+                _ => None,
+            })
+    }
+    /// EFの読み出し(READ BINARY/RECORD)の条件
+    pub fn read_condition(&self) -> Option<SecurityCondition> {
+        if self.is_df() {
+            return None;
+        }
+        self.compact_condition(AM_EF_READ)
+            .or_else(|| self.expanded_condition(AM_EF_READ))
+    }
+    /// 拡張形式で、アクセスモードバイトのビットに対応する条件が1つだけのとき
+    fn expanded_condition(&self, access_mode: u8) -> Option<SecurityCondition> {
+        self.security
+            .iter()
+            .find_map(|attributes| match attributes {
+                SecurityAttributes::Expanded(rules) => rules.iter().find_map(|rule| {
+                    match (&rule.access_mode, rule.conditions.as_slice()) {
+                        (AccessModeObject::AccessModeByte(am), [condition])
+                            if am & access_mode != 0 =>
+                        {
+                            match condition {
+                                ConditionObject::Always => Some(SecurityCondition::Always),
+                                ConditionObject::Never => Some(SecurityCondition::Never),
+                                ConditionObject::Condition(condition) => Some(*condition),
+                                ConditionObject::Template { .. } => None,
+                            }
+                        }
+                        _ => None,
+                    }
+                }),
+                _ => None,
+            })
+    }
+}
+
+impl std::fmt::Display for FileControlInformation {
+    /// 1行の要約（例: EF 6F01 transparent 600 bytes SFI 02 activated read: always）
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", if self.is_df() { "DF" } else { "EF" })?;
+        if let Some(file_id) = self.file_id {
+            write!(f, " {:04X}", file_id)?;
+        }
+        if let Some(name) = &self.df_name {
+            write!(
+                f,
+                " name {}",
+                name.iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>()
+            )?;
+        }
+        if let Some(structure) = self.descriptor.and_then(|d| d.structure()) {
+            write!(f, " {:?}", structure)?;
+        }
+        if let Some(size) = self.file_size {
+            write!(f, " {} bytes", size)?;
+        }
+        if let Some(Some(sfi)) = self.sfi {
+            write!(f, " SFI {:02X}", sfi)?;
+        }
+        if let Some(life_cycle) = self.life_cycle {
+            write!(f, " {:?}", life_cycle)?;
+        }
+        if let Some(condition) = self.read_condition() {
+            write!(f, " read: {:?}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// データオブジェクトのタグと値の並び
+fn parse_objects(data: &[u8]) -> Result<Vec<(u32, &[u8])>, Iso7816Error> {
+    let objects = ber_tlv::read_objects(data)
+        .map_err(|_| Iso7816Error::new(Iso7816ErrorKind::InvalidTemplate))?;
+    Ok(objects
+        .into_iter()
+        .map(|(tag, value)| (tag.0, value))
+        .collect())
+}
+
+#[test]
+fn iso7816_fcp_decode() {
+    use super::{FileSystem, SelectResponse, SelectTarget};
+    use crate::nfc_impl::nfc_mock::iso7816::{VirtualFileSystemCard, ACCESS_NEVER};
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    // トランスペアレントEF: 600バイト、SFI 02、運用中、読み出しは常に、更新は外部認証(SE 1)
+    let fcp = [
+        0x62, 0x16, 0x80, 0x02, 0x02, 0x58, 0x82, 0x01, 0x01, 0x83, 0x02, 0x6F, 0x01, 0x88, 0x01,
+        0x10, 0x8A, 0x01, 0x05, 0x8C, 0x03, 0x03, 0xA1, 0x00,
+    ];
+    let fcp = FileControlInformation::from_bytes(&fcp).unwrap();
+    assert_eq!(fcp.template, TemplateKind::Fcp);
+    assert_eq!(fcp.file_size, Some(600));
+    assert_eq!(fcp.file_id, Some(0x6F01));
+    assert_eq!(fcp.sfi, Some(Some(2)));
+    assert_eq!(fcp.life_cycle, Some(LifeCycleStatus::Activated));
+    assert_eq!(fcp.descriptor.unwrap().category(), FileCategory::WorkingEf);
+    assert_eq!(
+        fcp.descriptor.unwrap().structure(),
+        Some(EfStructure::Transparent)
+    );
+    assert_eq!(fcp.read_condition(), Some(SecurityCondition::Always));
+    assert_eq!(
+        fcp.compact_condition(AM_EF_UPDATE),
+        Some(SecurityCondition::Conditions {
+            all: true,
+            secure_messaging: false,
+            external_authentication: true,
+            user_authentication: false,
+            security_environment: 1,
+        })
+    );
+    assert_eq!(
+        fcp.to_string(),
+        "EF 6F01 Transparent 600 bytes SFI 02 Activated read: Always"
+    );
+
+    // 共有のサイクリックEF(最大レコード長2バイト)・SFIなし・EF.ARRの参照・拡張形式・独自データ
+    let fcp = [
+        0x62, 0x24, 0x82, 0x05, 0x46, 0x21, 0x01, 0x00, 0x05, 0x83, 0x02, 0x2F, 0x01, 0x88, 0x00,
+        0x8B, 0x03, 0x2F, 0x06, 0x02, 0xAB, 0x0B, 0x80, 0x01, 0x01, 0x90, 0x00, 0x84, 0x01, 0xD6,
+        0x9E, 0x01, 0x12, 0xA5, 0x03, 0xC0, 0x01, 0xFF,
+    ];
+    let fcp = FileControlInformation::from_bytes(&fcp).unwrap();
+    let descriptor = fcp.descriptor.unwrap();
+    assert!(descriptor.is_shareable());
+    assert_eq!(descriptor.structure(), Some(EfStructure::Cyclic));
+    assert_eq!(descriptor.max_record_size, Some(256));
+    assert_eq!(descriptor.record_count, Some(5));
+    assert_eq!(fcp.sfi, Some(None));
+    assert_eq!(fcp.proprietary, vec![vec![0xC0, 0x01, 0xFF]]);
+    assert_eq!(
+        fcp.security,
+        vec![
+            SecurityAttributes::Referenced {
+                file_id: 0x2F06,
+                records: vec![0x02],
+            },
+            SecurityAttributes::Expanded(vec![
+                ExpandedRule {
+                    access_mode: AccessModeObject::AccessModeByte(AM_EF_READ),
+                    conditions: vec![ConditionObject::Always],
+                },
+                ExpandedRule {
+                    access_mode: AccessModeObject::Command {
+                        cla: None,
+                        ins: Some(0xD6),
+                        p1: None,
+                        p2: None,
+                    },
+                    conditions: vec![ConditionObject::Condition(SecurityCondition::from_byte(
+                        0x12
+                    ))],
+                },
+            ]),
+        ]
+    );
+    assert_eq!(fcp.read_condition(), Some(SecurityCondition::Always));
+
+    // FCI: DF名と、複数バイトのタグ
+    let fci = [
+        0x6F, 0x15, 0x84, 0x07, 0xA0, 0x00, 0x00, 0x00, 0x03, 0x10, 0x10, 0xA5, 0x05, 0xBF, 0x0C,
+        0x02, 0x01, 0x02, 0x5F, 0x2D, 0x02, 0x65, 0x6E,
+    ];
+    let fci = FileControlInformation::from_bytes(&fci).unwrap();
+    assert_eq!(fci.template, TemplateKind::Fci);
+    assert!(fci.is_df());
+    assert_eq!(fci.proprietary, vec![vec![0xBF, 0x0C, 0x02, 0x01, 0x02]]);
+    assert_eq!(fci.others, vec![(0x5F2D, vec![0x65, 0x6E])]);
+    // FCIの中のFCP
+    let fci = [0x6F, 0x06, 0x62, 0x04, 0x80, 0x02, 0x01, 0x00];
+    let fci = FileControlInformation::from_bytes(&fci).unwrap();
+    assert_eq!(fci.file_size, Some(256));
+    // 不定長・長さが足りない・テンプレートでない・FCIの中のFCPの中のFCP
+    for invalid in [
+        &[0x62, 0x80, 0x00, 0x00][..],
+        &[0x62, 0x03, 0x80, 0x02],
+        &[0x84, 0x00],
+        &[0x6F, 0x04, 0x62, 0x02, 0x62, 0x00],
+    ] {
+        let error = FileControlInformation::from_bytes(invalid).unwrap_err();
+        assert_eq!(error.kind(), &Iso7816ErrorKind::InvalidTemplate);
+    }
+
+    // 仮想カードのSELECT FILEの応答
+    let mut card = VirtualFileSystemCard::new();
+    card.add_df(&[0x7F10], Some(&[0xA0, 0x00, 0x00, 0x00, 0x03, 0x10, 0x10]))
+        .add_record_ef(&[0x7F10, 0x6F02], Some(3), true, &[&[0x01; 8], &[0x02; 8]])
+        .set_access(&[0x7F10, 0x6F02], ACCESS_NEVER, ACCESS_NEVER);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let fs = FileSystem::new(&nfc);
+    let mf = fs
+        .select_fcp(SelectTarget::Mf, SelectResponse::Fcp)
+        .unwrap();
+    assert!(mf.is_df());
+    assert_eq!(mf.file_id, Some(0x3F00));
+    let ef = fs
+        .select_fcp(
+            SelectTarget::PathFromMf(&[0x7F10, 0x6F02]),
+            SelectResponse::Fcp,
+        )
+        .unwrap();
+    let descriptor = ef.descriptor.unwrap();
+    assert_eq!(descriptor.structure(), Some(EfStructure::Cyclic));
+    assert_eq!(descriptor.max_record_size, Some(8));
+    assert_eq!(descriptor.record_count, Some(2));
+    assert_eq!(ef.sfi, Some(Some(3)));
+    assert_eq!(ef.read_condition(), Some(SecurityCondition::Never));
+}
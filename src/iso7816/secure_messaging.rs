@@ -0,0 +1,474 @@
+// ISO/IEC 7816-4 箇条6 のセキュアメッセージング（ICAO 9303-11 9.8 の形式）
+// コマンド: CLAにSMの指定(ヘッダ認証あり)を入れ、データは DO 87(偶数INS、先頭01はパディングの指示)
+// または DO 85(奇数INS) に暗号化して入れ、Leは DO 97、MACは DO 8E で付ける。Leは常に00。
+// レスポンス: DO 87/85(暗号化データ)・DO 81(平文データ)・DO 99(SW) と DO 8E(MAC)。
+// MACは SSC || パディングしたヘッダ || DO の並び をパディングして計算し、SSCはコマンドとレスポンスの前に1ずつ増やす。
+// 暗号スイートは 3DES(IVは0、リテールMAC) と AES(IVはSSCを暗号化したもの、CMACの先頭8バイト)。
+
+use super::{Iso7816Error, Iso7816ErrorKind, RawApdu};
+use crate::apdu_contactless::{ApduBuilder, SecureMessaging};
+use crate::ber_tlv::{self, Tlv};
+use crate::crypto::{pad_iso9797, retail_mac, unpad_iso9797, Cipher, DES_BLOCK_SIZE};
+use crate::pc_sc_standard::AnswerToReset;
+use crate::smart_card::{
+    ProtocolType, Smartcard, SmartcardConnectMethod, SmartcardError, SmartcardErrorKind,
+    SmartcardVersion, TransmitError, APDU,
+};
+use std::cell::RefCell;
+
+const DO_PLAIN_VALUE: u32 = 0x81;
+const DO_CRYPTOGRAM_ODD: u32 = 0x85;
+const DO_CRYPTOGRAM: u32 = 0x87;
+const DO_CHECKSUM: u32 = 0x8E;
+const DO_LE: u32 = 0x97;
+const DO_STATUS_WORD: u32 = 0x99;
+/// DO 87の先頭: パディング方式2で埋めた
+const PADDING_INDICATOR: u8 = 0x01;
+/// MACの長さ
+pub const MAC_SIZE: usize = 8;
+
+/// SMの暗号スイート
+pub trait CipherSuite {
+    /// ブロック長（SSCの長さ）
+    fn block_size(&self) -> usize;
+    /// パディング済みのデータを暗号化する
+    fn encrypt(&self, ssc: &[u8], data: &[u8]) -> Vec<u8>;
+    fn decrypt(&self, ssc: &[u8], data: &[u8]) -> Vec<u8>;
+    /// パディング済みのデータのMAC(8バイト)
+    fn mac(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// 2-key 3DESのCBC(IVは0)とリテールMAC
+pub struct TdesSuite {
+    cipher: Cipher,
+    mac_key: [u8; 16],
+}
+
+impl TdesSuite {
+    pub fn new(enc_key: &[u8; 16], mac_key: &[u8; 16]) -> Self {
+        TdesSuite {
+            cipher: Cipher::tdes2(enc_key),
+            mac_key: *mac_key,
+        }
+    }
+}
+
+impl CipherSuite for TdesSuite {
+    fn block_size(&self) -> usize {
+        DES_BLOCK_SIZE
+    }
+    fn encrypt(&self, _ssc: &[u8], data: &[u8]) -> Vec<u8> {
+        self.cipher.cbc_encrypt(&[0u8; DES_BLOCK_SIZE], data)
+    }
+    fn decrypt(&self, _ssc: &[u8], data: &[u8]) -> Vec<u8> {
+        self.cipher.cbc_decrypt(&[0u8; DES_BLOCK_SIZE], data)
+    }
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        retail_mac(&self.mac_key, data).to_vec()
+    }
+}
+
+/// AESのCBC(IVはSSCを暗号化したもの)とCMAC
+pub struct AesSuite {
+    cipher: Cipher,
+    mac: Cipher,
+}
+
+impl AesSuite {
+    /// 鍵は16/24/32バイト
+    pub fn new(enc_key: &[u8], mac_key: &[u8]) -> Option<Self> {
+        Some(AesSuite {
+            cipher: Cipher::aes(enc_key)?,
+            mac: Cipher::aes(mac_key)?,
+        })
+    }
+}
+
+impl CipherSuite for AesSuite {
+    fn block_size(&self) -> usize {
+        self.cipher.block_size()
+    }
+    fn encrypt(&self, ssc: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut iv = ssc.to_vec();
+        self.cipher.encrypt_block(&mut iv);
+        self.cipher.cbc_encrypt(&iv, data)
+    }
+    fn decrypt(&self, ssc: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut iv = ssc.to_vec();
+        self.cipher.encrypt_block(&mut iv);
+        self.cipher.cbc_decrypt(&iv, data)
+    }
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac.cmac(&vec![0u8; self.mac.block_size()], data);
+        mac.truncate(MAC_SIZE);
+        mac
+    }
+}
+
+/// セッション鍵とSSC
+pub struct SmSession {
+    suite: Box<dyn CipherSuite>,
+    ssc: Vec<u8>,
+}
+
+impl SmSession {
+    /// sscは暗号スイートのブロック長
+    pub fn new(suite: Box<dyn CipherSuite>, ssc: &[u8]) -> Self {
+        let mut initial = vec![0u8; suite.block_size()];
+        let len = ssc.len().min(initial.len());
+        let start = initial.len() - len;
+        initial[start..].copy_from_slice(&ssc[ssc.len() - len..]);
+        SmSession {
+            suite,
+            ssc: initial,
+        }
+    }
+    pub fn ssc(&self) -> &[u8] {
+        &self.ssc
+    }
+    fn increment_ssc(&mut self) {
+        for byte in self.ssc.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let input = [&self.ssc[..], data].concat();
+        self.suite
+            .mac(&pad_iso9797(&input, self.suite.block_size()))
+    }
+    /// 短いAPDU(ケース1～4)をSMで包む
+    pub fn wrap_command(&mut self, apdu: &[u8]) -> Result<Vec<u8>, Iso7816Error> {
+        let (header, data, le) = split_command(apdu)
+            .ok_or_else(|| Iso7816Error::new(Iso7816ErrorKind::UnsupportedCommand))?;
+        self.increment_ssc();
+        let block_size = self.suite.block_size();
+        let cla = ApduBuilder::new()
+            .set_raw_classs_code(header[0])
+            .set_secure_mode(SecureMessaging::Clause6HeaderAuth)
+            .build()
+            .read8()[0];
+        let header = [cla, header[1], header[2], header[3]];
+        let mut objects = Vec::new();
+        if !data.is_empty() {
+            let cryptogram = self
+                .suite
+                .encrypt(&self.ssc, &pad_iso9797(data, block_size));
+            // 奇数INSはデータがBER-TLVなので、パディングの指示を付けない DO 85
+            objects.extend(if header[1] & 0x01 == 0 {
+                Tlv::primitive(
+                    DO_CRYPTOGRAM,
+                    &[&[PADDING_INDICATOR], &cryptogram[..]].concat(),
+                )
+                .to_bytes()
+            } else {
+                Tlv::primitive(DO_CRYPTOGRAM_ODD, &cryptogram).to_bytes()
+            });
+        }
+        if let Some(le) = le {
+            objects.extend(Tlv::primitive(DO_LE, &[le]).to_bytes());
+        }
+        let mac = self.mac(&[&pad_iso9797(&header, block_size)[..], &objects[..]].concat());
+        objects.extend(Tlv::primitive(DO_CHECKSUM, &mac).to_bytes());
+        if objects.len() > 0xFF {
+            return Err(Iso7816Error::new(Iso7816ErrorKind::UnsupportedCommand));
+        }
+        let mut command = header.to_vec();
+        command.push(objects.len() as u8);
+        command.extend(objects);
+        command.push(0x00);
+        Ok(command)
+    }
+    /// レスポンスのデータオブジェクトを検証・復号し、データとDO 99のSW(なければ90 00)を返す
+    /// MACを確かめてから復号する
+    pub fn unwrap_response(&mut self, res: &[u8]) -> Result<(Vec<u8>, [u8; 2]), Iso7816Error> {
+        self.increment_ssc();
+        let invalid = || Iso7816Error::new(Iso7816ErrorKind::InvalidSecureMessaging);
+        let mut authenticated = Vec::new();
+        let mut objects = Vec::new();
+        let mut mac = None;
+        let mut rest = res;
+        while !rest.is_empty() {
+            let (tag, value, size) = ber_tlv::read_object(rest).map_err(|_| invalid())?;
+            if tag.0 == DO_CHECKSUM {
+                mac = Some(value);
+            } else {
+                authenticated.extend_from_slice(&rest[..size]);
+                objects.push((tag.0, value));
+            }
+            rest = &rest[size..];
+        }
+        if mac != Some(&self.mac(&authenticated)[..]) {
+            return Err(Iso7816Error::new(Iso7816ErrorKind::MacMismatch));
+        }
+        let block_size = self.suite.block_size();
+        let mut data = Vec::new();
+        let mut status_word = [0x90, 0x00];
+        for (tag, value) in objects {
+            match (tag, value) {
+                (DO_CRYPTOGRAM, [PADDING_INDICATOR, cryptogram @ ..])
+                | (DO_CRYPTOGRAM_ODD, cryptogram)
+                    if !cryptogram.is_empty() && cryptogram.len() % block_size == 0 =>
+                {
+                    let plain = self.suite.decrypt(&self.ssc, cryptogram);
+                    data = unpad_iso9797(&plain).ok_or_else(invalid)?.to_vec();
+                }
+                (DO_PLAIN_VALUE, value) => data = value.to_vec(),
+                (DO_STATUS_WORD, [sw1, sw2]) => status_word = [*sw1, *sw2],
+                _ => return Err(invalid()),
+            }
+        }
+        Ok((data, status_word))
+    }
+    /// カード側（仮想カードで使う）: SMで包まれたコマンドを検証・復号し、SMの指定を外した平文のAPDUを返す
+    pub fn unwrap_command(&mut self, apdu: &[u8]) -> Result<Vec<u8>, Iso7816Error> {
+        self.increment_ssc();
+        let invalid = || Iso7816Error::new(Iso7816ErrorKind::InvalidSecureMessaging);
+        let (header, body, _) = split_command(apdu).ok_or_else(invalid)?;
+        let mut authenticated = Vec::new();
+        let mut objects = Vec::new();
+        let mut mac = None;
+        let mut rest = body;
+        while !rest.is_empty() {
+            let (tag, value, size) = ber_tlv::read_object(rest).map_err(|_| invalid())?;
+            if tag.0 == DO_CHECKSUM {
+                mac = Some(value);
+            } else {
+                authenticated.extend_from_slice(&rest[..size]);
+                objects.push((tag.0, value));
+            }
+            rest = &rest[size..];
+        }
+        let block_size = self.suite.block_size();
+        let expected =
+            self.mac(&[&pad_iso9797(&header, block_size)[..], &authenticated[..]].concat());
+        if mac != Some(&expected[..]) {
+            return Err(Iso7816Error::new(Iso7816ErrorKind::MacMismatch));
+        }
+        let mut data = Vec::new();
+        let mut le = None;
+        for (tag, value) in objects {
+            match (tag, value) {
+                (DO_CRYPTOGRAM, [PADDING_INDICATOR, cryptogram @ ..])
+                | (DO_CRYPTOGRAM_ODD, cryptogram)
+                    if !cryptogram.is_empty() && cryptogram.len() % block_size == 0 =>
+                {
+                    let plain = self.suite.decrypt(&self.ssc, cryptogram);
+                    data = unpad_iso9797(&plain).ok_or_else(invalid)?.to_vec();
+                }
+                (DO_LE, [value]) => le = Some(*value),
+                _ => return Err(invalid()),
+            }
+        }
+        let cla = ApduBuilder::new()
+            .set_raw_classs_code(header[0])
+            .set_secure_mode(SecureMessaging::Plain)
+            .build()
+            .read8()[0];
+        let mut command = vec![cla, header[1], header[2], header[3]];
+        if !data.is_empty() {
+            command.push(data.len() as u8);
+            command.extend(data);
+        }
+        command.extend(le);
+        Ok(command)
+    }
+    /// カード側（仮想カードで使う）: レスポンスのデータとSWを DO 87(奇数INSは DO 85)・DO 99・DO 8E で包む
+    pub fn wrap_response(&mut self, ins: u8, data: &[u8], status_word: [u8; 2]) -> Vec<u8> {
+        self.increment_ssc();
+        let mut objects = Vec::new();
+        if !data.is_empty() {
+            let cryptogram = self
+                .suite
+                .encrypt(&self.ssc, &pad_iso9797(data, self.suite.block_size()));
+            objects.extend(if ins & 0x01 == 0 {
+                Tlv::primitive(
+                    DO_CRYPTOGRAM,
+                    &[&[PADDING_INDICATOR], &cryptogram[..]].concat(),
+                )
+                .to_bytes()
+            } else {
+                Tlv::primitive(DO_CRYPTOGRAM_ODD, &cryptogram).to_bytes()
+            });
+        }
+        objects.extend(Tlv::primitive(DO_STATUS_WORD, &status_word).to_bytes());
+        let mac = self.mac(&objects);
+        objects.extend(Tlv::primitive(DO_CHECKSUM, &mac).to_bytes());
+        objects
+    }
+}
+
+/// 短いAPDUをヘッダ・データ・Leに分ける。拡張形式はNone
+fn split_command(apdu: &[u8]) -> Option<([u8; 4], &[u8], Option<u8>)> {
+    let (header, body) = apdu.split_at(apdu.len().min(4));
+    let header = [
+        *header.first()?,
+        *header.get(1)?,
+        *header.get(2)?,
+        *header.get(3)?,
+    ];
+    match body {
+        [] => Some((header, &[], None)),
+        [le] => Some((header, &[], Some(*le))),
+        [0x00, ..] => None,
+        [lc, data @ ..] if data.len() == *lc as usize => Some((header, data, None)),
+        [lc, data @ .., le] if data.len() == *lc as usize => Some((header, data, Some(*le))),
+        _ => None,
+    }
+}
+
+/// SMを掛けて下のSmartcardへ送る
+/// セッション鍵を確立してから使う。カードがSMなしでエラー(69 87・69 88など)を返したときはそのまま返す
+pub struct SecureMessagingCard<'a> {
+    nfc: &'a dyn Smartcard,
+    session: RefCell<SmSession>,
+}
+
+impl<'a> SecureMessagingCard<'a> {
+    pub fn new(nfc: &'a dyn Smartcard, session: SmSession) -> Self {
+        SecureMessagingCard {
+            nfc,
+            session: RefCell::new(session),
+        }
+    }
+    pub fn ssc(&self) -> Vec<u8> {
+        self.session.borrow().ssc().to_vec()
+    }
+}
+
+impl<'a> Smartcard for SecureMessagingCard<'a> {
+    fn version_str(&self) -> Option<String> {
+        self.nfc.version_str()
+    }
+    fn version(&self) -> Option<SmartcardVersion> {
+        self.nfc.version()
+    }
+    /// 接続は下のSmartcardで済ませておく
+    fn connect_reader(
+        &mut self,
+        _con_method: SmartcardConnectMethod,
+    ) -> Result<ProtocolType, SmartcardError> {
+        Err(SmartcardError::new(SmartcardErrorKind::NotReady))
+    }
+    fn transmit(&self, data: Box<dyn APDU>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let command = self.session.borrow_mut().wrap_command(&data.read8())?;
+        let res = match self.nfc.transmit(Box::new(RawApdu(command))) {
+            Ok(res) => res,
+            // 警告のSW(62xx・63xx)でもSMのデータオブジェクトを返すカードがある
+            Err(error) => match error.downcast_ref::<TransmitError>() {
+                Some(transmit) if !transmit.data().is_empty() => transmit.data().to_vec(),
+                _ => return Err(error),
+            },
+        };
+        let (data, status_word) = self.session.borrow_mut().unwrap_response(&res)?;
+        TransmitError::check_response([&data[..], &status_word[..]].concat())
+    }
+    /// プロトコルは変えられない（下のSmartcardで設定する）
+    fn config_protocol(&mut self, _protocol: ProtocolType) -> Option<ProtocolType> {
+        None
+    }
+    fn get_atr(&self) -> &AnswerToReset {
+        self.nfc.get_atr()
+    }
+}
+
+#[test]
+fn iso7816_secure_messaging() {
+    use super::{FileSystem, SelectResponse, SelectTarget};
+    use crate::crypto::hex;
+    use crate::nfc_impl::nfc_mock::{MockSmartcard, VirtualCard};
+
+    /// 決まったコマンドに決まったレスポンスを返す
+    struct ScriptedCard(Vec<(Vec<u8>, Vec<u8>)>);
+    impl VirtualCard for ScriptedCard {
+        fn atr(&self) -> Vec<u8> {
+            vec![0x3B, 0x80, 0x80, 0x01, 0x01]
+        }
+        fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+            let (command, res) = self.0.remove(0);
+            assert_eq!(apdu, &command[..]);
+            Some(res)
+        }
+    }
+
+    // ICAO 9303-11 付録D.4 のBACの後のSM
+    let mut enc_key = [0u8; 16];
+    enc_key.copy_from_slice(&hex("979EC13B1CBFE9DCD01AB0FED307EAE5"));
+    let mut mac_key = [0u8; 16];
+    mac_key.copy_from_slice(&hex("F1CB1F1FB5ADF208806B89DC579DC1F8"));
+    let card = ScriptedCard(vec![
+        (
+            hex("0CA4020C158709016375432908C044F68E08BF8B92D635FF24F800"),
+            hex("990290008E08FA855A5D4C50A8ED9000"),
+        ),
+        (
+            hex("0CB000000D9701048E08ED6705417E96BA5500"),
+            hex("8709019FF0EC34F9922651990290008E08AD55CC17140B2DED9000"),
+        ),
+        (
+            hex("0CB000040D9701128E082EA28A70F3C7B53500"),
+            hex("871901FB9235F4E4037F2327DCC8964F1F9B8C30F42C8E2FFF224A990290008E08C8B2787EAEA07D749000"),
+        ),
+    ]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let session = SmSession::new(
+        Box::new(TdesSuite::new(&enc_key, &mac_key)),
+        &hex("887022120C06C226"),
+    );
+    let sm = SecureMessagingCard::new(&nfc, session);
+    let fs = FileSystem::new(&sm);
+    fs.select(SelectTarget::ChildEf(0x011E), SelectResponse::NoResponse)
+        .unwrap();
+    assert_eq!(fs.read_binary(0, 4).unwrap(), hex("60145F01"));
+    assert_eq!(
+        fs.read_binary(4, 0x12).unwrap(),
+        hex("04303130365F36063034303030305C026175")
+    );
+    assert_eq!(sm.ssc(), hex("887022120C06C22C"));
+
+    // MACが合わない・MACがない
+    let mut session = SmSession::new(
+        Box::new(TdesSuite::new(&enc_key, &mac_key)),
+        &hex("887022120C06C227"),
+    );
+    let error = session
+        .unwrap_response(&hex("990290008E08FA855A5D4C50A8EE"))
+        .unwrap_err();
+    assert_eq!(error.kind(), &Iso7816ErrorKind::MacMismatch);
+    let error = session.unwrap_response(&hex("99029000")).unwrap_err();
+    assert_eq!(error.kind(), &Iso7816ErrorKind::MacMismatch);
+    // 拡張形式のAPDUは包めない
+    let error = session.wrap_command(&hex("00B00000000100")).unwrap_err();
+    assert_eq!(error.kind(), &Iso7816ErrorKind::UnsupportedCommand);
+
+    // カード側: 付録D.4 の最初のコマンドを開き、レスポンスを包む
+    let mut card_session = SmSession::new(
+        Box::new(TdesSuite::new(&enc_key, &mac_key)),
+        &hex("887022120C06C226"),
+    );
+    assert_eq!(
+        card_session
+            .unwrap_command(&hex(
+                "0CA4020C158709016375432908C044F68E08BF8B92D635FF24F800"
+            ))
+            .unwrap(),
+        hex("00A4020C02011E")
+    );
+    assert_eq!(
+        card_session.wrap_response(0xA4, &[], [0x90, 0x00]),
+        hex("990290008E08FA855A5D4C50A8ED")
+    );
+
+    // AESはSSCもブロック長(16バイト)で、DO 87は 01 || 16バイト
+    let mut session = SmSession::new(
+        Box::new(AesSuite::new(&[0x11; 16], &[0x22; 16]).unwrap()),
+        &[],
+    );
+    let command = session.wrap_command(&hex("00D600000401020304")).unwrap();
+    assert_eq!(command[..7], [0x0C, 0xD6, 0x00, 0x00, 0x1D, 0x87, 0x11]);
+    assert_eq!(command[24..26], [0x8E, 0x08]);
+    assert_eq!(command.len(), 35);
+    assert_eq!(session.ssc(), &[&[0u8; 15][..], &[1]].concat()[..]);
+}
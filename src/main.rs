@@ -1,7 +1,21 @@
 mod apdu_contactless;
+mod ber_tlv;
+mod card_dump;
+mod crypto;
+mod desfire;
+mod emrtd;
+mod felica;
+mod iso7816;
+mod mifare_classic;
+mod mifare_plus;
+mod ndef;
 mod nfc_impl;
+mod originality;
 mod pc_sc_standard;
 mod smart_card;
+mod topaz;
+mod type4_tag;
+mod ultralight;
 use std::fmt::LowerHex;
 
 use nfc_impl::NfcFactory;
@@ -19,7 +33,13 @@ use crate::{
 
 fn main() {
     let mut nfc: Box<dyn smart_card::Smartcard> =
-        NfcFactory::create_nfc_instance(nfc_impl::FactoryType::WindowsScardAPI);
+        match NfcFactory::create_nfc_instance(nfc_impl::FactoryType::WindowsScardAPI) {
+            Ok(nfc) => nfc,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
     nfc.connect_reader(smart_card::SmartcardConnectMethod::UserPrompt)
         .unwrap();
     let apdu = apdu_contactless::ApduBuilder::new().get_card_kind().build();
@@ -0,0 +1,312 @@
+// MIFARE Classic 1K/4K/Mini の読み書き
+// PC/SC Part3 の LOAD KEYS / GENERAL AUTHENTICATE / READ BINARY / UPDATE BINARY を使う
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::{
+    ApduBuilderExtWithPcsc3V2, CardName, MifareExt, MifareKeyType, KEY_STRUCTURE_VOLATILE,
+};
+use crate::smart_card::Smartcard;
+
+pub mod access_bits;
+pub mod dictionary;
+pub mod value_block;
+use access_bits::SectorTrailer;
+use value_block::ValueBlockMode;
+
+pub const BLOCK_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 6;
+/// 出荷時のデフォルトキー
+pub const DEFAULT_KEY: [u8; KEY_SIZE] = [0xFF; KEY_SIZE];
+
+/// MIFARE Classicのメモリ構成
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MifareClassicKind {
+    /// 5セクタ x 4ブロック
+    Mini,
+    /// 16セクタ x 4ブロック
+    Classic1k,
+    /// 32セクタ x 4ブロック（SL1のMIFARE Plus 2K）
+    Classic2k,
+    /// 32セクタ x 4ブロック + 8セクタ x 16ブロック
+    Classic4k,
+}
+
+impl MifareClassicKind {
+    pub fn from_card_name(card_name: &CardName) -> Option<Self> {
+        match card_name {
+            CardName::MifareMini => Some(MifareClassicKind::Mini),
+            CardName::MifareClassic1k => Some(MifareClassicKind::Classic1k),
+            CardName::MifareClassic4k => Some(MifareClassicKind::Classic4k),
+            CardName::MifarePlusSl12k => Some(MifareClassicKind::Classic2k),
+            CardName::MifarePlusSl14k => Some(MifareClassicKind::Classic4k),
+            _ => None,
+        }
+    }
+    pub fn sector_count(&self) -> u8 {
+        match self {
+            MifareClassicKind::Mini => 5,
+            MifareClassicKind::Classic1k => 16,
+            MifareClassicKind::Classic2k => 32,
+            MifareClassicKind::Classic4k => 40,
+        }
+    }
+    pub fn block_count(&self) -> usize {
+        (0..self.sector_count())
+            .map(|s| Self::blocks_in_sector(s) as usize)
+            .sum()
+    }
+    /// 4Kの32セクタ目以降は16ブロックで構成される
+    pub fn blocks_in_sector(sector: u8) -> u8 {
+        if sector < 32 {
+            4
+        } else {
+            16
+        }
+    }
+    pub fn first_block(sector: u8) -> u8 {
+        if sector < 32 {
+            sector * 4
+        } else {
+            128 + (sector - 32) * 16
+        }
+    }
+    /// セクタトレーラ（キーとアクセスビットを格納する最終ブロック）
+    pub fn trailer_block(sector: u8) -> u8 {
+        Self::first_block(sector) + (Self::blocks_in_sector(sector) - 1)
+    }
+    pub fn sector_of_block(block: u8) -> u8 {
+        if block < 128 {
+            block / 4
+        } else {
+            32 + (block - 128) / 16
+        }
+    }
+    pub fn is_trailer_block(block: u8) -> bool {
+        Self::trailer_block(Self::sector_of_block(block)) == block
+    }
+}
+
+pub struct MifareClassic<'a> {
+    nfc: &'a dyn Smartcard,
+    kind: MifareClassicKind,
+    value_mode: ValueBlockMode,
+}
+
+impl<'a> MifareClassic<'a> {
+    pub fn new(nfc: &'a dyn Smartcard, kind: MifareClassicKind) -> Self {
+        MifareClassic {
+            nfc,
+            kind,
+            value_mode: ValueBlockMode::ReaderCommand,
+        }
+    }
+    /// 接続中カードのATRからメモリ構成を判定する
+    pub fn from_atr(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let kind = nfc
+            .get_atr()
+            .card_name
+            .as_ref()
+            .and_then(|(_, name)| MifareClassicKind::from_card_name(name));
+        match kind {
+            Some(kind) => Ok(Self::new(nfc, kind)),
+            None => Err(Box::new(MifareClassicError::new(
+                MifareClassicErrorKind::NotMifareClassic,
+            ))),
+        }
+    }
+    pub fn kind(&self) -> MifareClassicKind {
+        self.kind
+    }
+    pub fn uid(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().get_serial().build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    /// リーダーの揮発性キー格納領域にキーを書き込む
+    pub fn load_key(
+        &self,
+        key_no: u8,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .load_auth_keys(KEY_STRUCTURE_VOLATILE, key_no, key)
+            .build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// 格納済みのキーでblockを含むセクタへ認証する
+    pub fn authenticate(
+        &self,
+        block: u8,
+        key_type: MifareKeyType,
+        key_no: u8,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_block(block)?;
+        let apdu = ApduBuilder::new()
+            .general_authenticate(block, key_type, key_no)
+            .build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// キー格納領域0番を使ってセクタへ認証する
+    pub fn authenticate_sector(
+        &self,
+        sector: u8,
+        key_type: MifareKeyType,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_sector(sector)?;
+        self.load_key(0, key)?;
+        self.authenticate(MifareClassicKind::first_block(sector), key_type, 0)
+    }
+    /// 認証済みセクタのブロックを読み出す
+    pub fn read_block(&self, block: u8) -> Result<[u8; BLOCK_SIZE], Box<dyn std::error::Error>> {
+        self.check_block(block)?;
+        let apdu = ApduBuilder::new().read_block(block).build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() != BLOCK_SIZE {
+            return Err(Box::new(MifareClassicError::new(
+                MifareClassicErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let mut data = [0u8; BLOCK_SIZE];
+        data.copy_from_slice(&res);
+        Ok(data)
+    }
+    /// 認証済みセクタのデータブロックへ書き込む
+    /// セクタトレーラはwrite_trailerで書き込むこと
+    pub fn write_block(
+        &self,
+        block: u8,
+        data: &[u8; BLOCK_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_block(block)?;
+        if block == 0 {
+            // 製造者ブロックは書き換えられない
+            return Err(Box::new(MifareClassicError::new(
+                MifareClassicErrorKind::ManufacturerBlock,
+            )));
+        }
+        if MifareClassicKind::is_trailer_block(block) {
+            return Err(Box::new(MifareClassicError::new(
+                MifareClassicErrorKind::TrailerBlock(block),
+            )));
+        }
+        let apdu = ApduBuilder::new().update_block(block, data).build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// 値ブロックの加減算をリーダーのコマンドで行うか、読み書きで代替するかを設定する
+    pub fn set_value_block_mode(&mut self, mode: ValueBlockMode) -> &mut Self {
+        self.value_mode = mode;
+        self
+    }
+    /// 認証済みセクタのトレーラを読み出す
+    pub fn read_trailer(&self, sector: u8) -> Result<SectorTrailer, Box<dyn std::error::Error>> {
+        self.check_sector(sector)?;
+        let data = self.read_block(MifareClassicKind::trailer_block(sector))?;
+        Ok(SectorTrailer::from_bytes(&data)?)
+    }
+    /// 認証済みセクタのトレーラを書き込む
+    /// アクセスビットを書き換えられなくなる設定はallow_permanentがtrueの場合のみ書き込む
+    pub fn write_trailer(
+        &self,
+        sector: u8,
+        trailer: &SectorTrailer,
+        allow_permanent: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_sector(sector)?;
+        let data = trailer.to_bytes(allow_permanent)?;
+        let apdu = ApduBuilder::new()
+            .update_block(MifareClassicKind::trailer_block(sector), &data)
+            .build();
+        self.nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// 認証済みセクタの全ブロックを読み出す
+    pub fn read_sector(
+        &self,
+        sector: u8,
+    ) -> Result<Vec<[u8; BLOCK_SIZE]>, Box<dyn std::error::Error>> {
+        self.check_sector(sector)?;
+        (MifareClassicKind::first_block(sector)..=MifareClassicKind::trailer_block(sector))
+            .map(|block| self.read_block(block))
+            .collect()
+    }
+    fn check_sector(&self, sector: u8) -> Result<(), MifareClassicError> {
+        if sector < self.kind.sector_count() {
+            Ok(())
+        } else {
+            Err(MifareClassicError::new(
+                MifareClassicErrorKind::SectorOutOfRange(sector),
+            ))
+        }
+    }
+    fn check_block(&self, block: u8) -> Result<(), MifareClassicError> {
+        if (block as usize) < self.kind.block_count() {
+            Ok(())
+        } else {
+            Err(MifareClassicError::new(
+                MifareClassicErrorKind::BlockOutOfRange(block),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MifareClassicErrorKind {
+    /// 接続中のカードがMIFARE Classicではない
+    NotMifareClassic,
+    SectorOutOfRange(u8),
+    BlockOutOfRange(u8),
+    /// ブロック0(製造者ブロック)への書き込み
+    ManufacturerBlock,
+    /// データブロック用の書き込みでセクタトレーラを指定した
+    TrailerBlock(u8),
+    InvalidResponseLength(usize),
+    /// 値ブロックの形式になっていない（値・反転値・アドレスの不一致）
+    NotValueBlock(u8),
+    /// 値ブロックの加減算で32bitの範囲を超える
+    ValueOverflow,
+    /// アクセスビットと反転ビットが一致しない
+    AccessBitsCorrupted,
+    /// アクセスビットで表現できない権限の組み合わせ
+    UnsupportedPermission,
+    /// アクセスビットを二度と書き換えられなくなる設定
+    PermanentLock,
+}
+
+#[derive(Debug)]
+pub struct MifareClassicError {
+    code: MifareClassicErrorKind,
+}
+
+impl MifareClassicError {
+    pub fn new(code: MifareClassicErrorKind) -> Self {
+        MifareClassicError { code }
+    }
+    pub fn kind(&self) -> &MifareClassicErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for MifareClassicError {}
+impl std::fmt::Display for MifareClassicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn mifare_classic_geometry() {
+    assert_eq!(MifareClassicKind::Mini.block_count(), 20);
+    assert_eq!(MifareClassicKind::Classic1k.block_count(), 64);
+    assert_eq!(MifareClassicKind::Classic2k.block_count(), 128);
+    assert_eq!(MifareClassicKind::Classic4k.block_count(), 256);
+    assert_eq!(MifareClassicKind::trailer_block(0), 3);
+    assert_eq!(MifareClassicKind::trailer_block(31), 127);
+    assert_eq!(MifareClassicKind::first_block(32), 128);
+    assert_eq!(MifareClassicKind::trailer_block(39), 255);
+    assert_eq!(MifareClassicKind::sector_of_block(143), 32);
+    assert_eq!(MifareClassicKind::sector_of_block(144), 33);
+    assert!(MifareClassicKind::is_trailer_block(143));
+    assert!(!MifareClassicKind::is_trailer_block(142));
+}
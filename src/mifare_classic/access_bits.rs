@@ -0,0 +1,371 @@
+// セクタトレーラのアクセスビット(C1/C2/C3)の解析と生成
+//
+// トレーラ: KeyA(6) | アクセスビット(3) | 汎用バイト(1) | KeyB(6)
+// アクセスビットの各バイトは以下のように格納される（nはブロック群の番号0～3）
+//   byte6: bit(4+n)=~C2n, bit(n)=~C1n
+//   byte7: bit(4+n)=C1n,  bit(n)=~C3n
+//   byte8: bit(4+n)=C3n,  bit(n)=C2n
+
+use super::{MifareClassicError, MifareClassicErrorKind, BLOCK_SIZE, KEY_SIZE};
+
+/// アクセスに必要なキー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessCondition {
+    Never,
+    KeyA,
+    KeyB,
+    KeyAB,
+}
+
+/// データブロックの権限
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataBlockPermission {
+    pub read: AccessCondition,
+    pub write: AccessCondition,
+    pub increment: AccessCondition,
+    /// decrement / transfer / restore
+    pub decrement: AccessCondition,
+}
+
+/// セクタトレーラの権限（KeyAはどの設定でも読み出せない）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrailerPermission {
+    pub key_a_write: AccessCondition,
+    pub access_bits_read: AccessCondition,
+    pub access_bits_write: AccessCondition,
+    pub key_b_read: AccessCondition,
+    pub key_b_write: AccessCondition,
+}
+
+use AccessCondition::*;
+
+// C1C2C3 を3ビットの値(C1が最上位)としたときの権限表
+const DATA_BLOCK_TABLE: [DataBlockPermission; 8] = [
+    // 000 トランスポート設定
+    DataBlockPermission {
+        read: KeyAB,
+        write: KeyAB,
+        increment: KeyAB,
+        decrement: KeyAB,
+    },
+    // 001 値ブロック（減算のみ）
+    DataBlockPermission {
+        read: KeyAB,
+        write: Never,
+        increment: Never,
+        decrement: KeyAB,
+    },
+    // 010 読み出し専用
+    DataBlockPermission {
+        read: KeyAB,
+        write: Never,
+        increment: Never,
+        decrement: Never,
+    },
+    // 011
+    DataBlockPermission {
+        read: KeyB,
+        write: KeyB,
+        increment: Never,
+        decrement: Never,
+    },
+    // 100
+    DataBlockPermission {
+        read: KeyAB,
+        write: KeyB,
+        increment: Never,
+        decrement: Never,
+    },
+    // 101
+    DataBlockPermission {
+        read: KeyB,
+        write: Never,
+        increment: Never,
+        decrement: Never,
+    },
+    // 110 値ブロック
+    DataBlockPermission {
+        read: KeyAB,
+        write: KeyB,
+        increment: KeyB,
+        decrement: KeyAB,
+    },
+    // 111 アクセス不可
+    DataBlockPermission {
+        read: Never,
+        write: Never,
+        increment: Never,
+        decrement: Never,
+    },
+];
+
+const TRAILER_TABLE: [TrailerPermission; 8] = [
+    // 000
+    TrailerPermission {
+        key_a_write: KeyA,
+        access_bits_read: KeyA,
+        access_bits_write: Never,
+        key_b_read: KeyA,
+        key_b_write: KeyA,
+    },
+    // 001 トランスポート設定
+    TrailerPermission {
+        key_a_write: KeyA,
+        access_bits_read: KeyA,
+        access_bits_write: KeyA,
+        key_b_read: KeyA,
+        key_b_write: KeyA,
+    },
+    // 010
+    TrailerPermission {
+        key_a_write: Never,
+        access_bits_read: KeyA,
+        access_bits_write: Never,
+        key_b_read: KeyA,
+        key_b_write: Never,
+    },
+    // 011
+    TrailerPermission {
+        key_a_write: KeyB,
+        access_bits_read: KeyAB,
+        access_bits_write: KeyB,
+        key_b_read: Never,
+        key_b_write: KeyB,
+    },
+    // 100
+    TrailerPermission {
+        key_a_write: KeyB,
+        access_bits_read: KeyAB,
+        access_bits_write: Never,
+        key_b_read: Never,
+        key_b_write: KeyB,
+    },
+    // 101
+    TrailerPermission {
+        key_a_write: Never,
+        access_bits_read: KeyAB,
+        access_bits_write: KeyB,
+        key_b_read: Never,
+        key_b_write: Never,
+    },
+    // 110
+    TrailerPermission {
+        key_a_write: Never,
+        access_bits_read: KeyAB,
+        access_bits_write: Never,
+        key_b_read: Never,
+        key_b_write: Never,
+    },
+    // 111
+    TrailerPermission {
+        key_a_write: Never,
+        access_bits_read: KeyAB,
+        access_bits_write: Never,
+        key_b_read: Never,
+        key_b_write: Never,
+    },
+];
+
+/// 出荷時のアクセスビット（データブロック000、トレーラ001）
+pub const TRANSPORT_ACCESS_BITS: [u8; 3] = [0xFF, 0x07, 0x80];
+
+/// ブロック群0～2とトレーラ(3)それぞれのC1C2C3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessBits {
+    conditions: [u8; 4],
+}
+
+impl AccessBits {
+    /// 3バイトのアクセスビットを解析する
+    /// 反転ビットとの整合性が取れない場合はエラーとする
+    pub fn decode(bytes: &[u8; 3]) -> Result<Self, MifareClassicError> {
+        let mut conditions = [0u8; 4];
+        for (n, condition) in conditions.iter_mut().enumerate() {
+            let c1 = (bytes[1] >> (4 + n)) & 1;
+            let c2 = (bytes[2] >> n) & 1;
+            let c3 = (bytes[2] >> (4 + n)) & 1;
+            let not_c1 = (bytes[0] >> n) & 1;
+            let not_c2 = (bytes[0] >> (4 + n)) & 1;
+            let not_c3 = (bytes[1] >> n) & 1;
+            if c1 == not_c1 || c2 == not_c2 || c3 == not_c3 {
+                return Err(MifareClassicError::new(
+                    MifareClassicErrorKind::AccessBitsCorrupted,
+                ));
+            }
+            *condition = c1 << 2 | c2 << 1 | c3;
+        }
+        Ok(AccessBits { conditions })
+    }
+    pub fn encode(&self) -> [u8; 3] {
+        let mut bytes = [0u8; 3];
+        for (n, condition) in self.conditions.iter().enumerate() {
+            let c1 = (condition >> 2) & 1;
+            let c2 = (condition >> 1) & 1;
+            let c3 = condition & 1;
+            bytes[0] |= (c2 ^ 1) << (4 + n) | (c1 ^ 1) << n;
+            bytes[1] |= c1 << (4 + n) | (c3 ^ 1) << n;
+            bytes[2] |= c3 << (4 + n) | c2 << n;
+        }
+        bytes
+    }
+    /// 希望する権限からアクセスビットを組み立てる
+    /// 表に存在しない権限の組み合わせはエラーとする
+    pub fn from_permissions(
+        data_blocks: &[DataBlockPermission; 3],
+        trailer: &TrailerPermission,
+    ) -> Result<Self, MifareClassicError> {
+        let mut conditions = [0u8; 4];
+        for (condition, permission) in conditions.iter_mut().zip(data_blocks.iter()) {
+            *condition = DATA_BLOCK_TABLE
+                .iter()
+                .position(|p| p == permission)
+                .ok_or_else(|| {
+                    MifareClassicError::new(MifareClassicErrorKind::UnsupportedPermission)
+                })? as u8;
+        }
+        // 110と111はトレーラの権限として同一なので、先に見つかる110を使う
+        conditions[3] = TRAILER_TABLE
+            .iter()
+            .position(|p| p == trailer)
+            .ok_or_else(|| MifareClassicError::new(MifareClassicErrorKind::UnsupportedPermission))?
+            as u8;
+        Ok(AccessBits { conditions })
+    }
+    /// ブロック群(0～2)の権限
+    /// 16ブロック構成のセクタでは1群が5ブロックに相当する
+    pub fn data_block(&self, group: usize) -> DataBlockPermission {
+        DATA_BLOCK_TABLE[self.conditions[group] as usize]
+    }
+    pub fn trailer(&self) -> TrailerPermission {
+        TRAILER_TABLE[self.conditions[3] as usize]
+    }
+    /// ブロック群のC1C2C3(C1が最上位ビット)
+    pub fn condition_bits(&self, group: usize) -> u8 {
+        self.conditions[group]
+    }
+    /// アクセスビットを二度と書き換えられない設定かどうか
+    pub fn is_permanent(&self) -> bool {
+        self.trailer().access_bits_write == Never
+    }
+    /// セクタ内のブロック番号(0起点)が属するブロック群
+    pub fn group_of_block(block_in_sector: u8, blocks_in_sector: u8) -> usize {
+        if blocks_in_sector == 4 {
+            block_in_sector as usize
+        } else if block_in_sector == blocks_in_sector - 1 {
+            3
+        } else {
+            (block_in_sector / 5) as usize
+        }
+    }
+}
+
+impl Default for AccessBits {
+    fn default() -> Self {
+        AccessBits {
+            conditions: [0b000, 0b000, 0b000, 0b001],
+        }
+    }
+}
+
+/// セクタトレーラ
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectorTrailer {
+    pub key_a: [u8; KEY_SIZE],
+    pub access_bits: AccessBits,
+    /// 汎用バイト（アクセスビットの直後の1バイト、MADではバージョン情報を置く）
+    pub general_purpose_byte: u8,
+    pub key_b: [u8; KEY_SIZE],
+}
+
+impl SectorTrailer {
+    pub fn new(key_a: [u8; KEY_SIZE], access_bits: AccessBits, key_b: [u8; KEY_SIZE]) -> Self {
+        SectorTrailer {
+            key_a,
+            access_bits,
+            general_purpose_byte: 0x69,
+            key_b,
+        }
+    }
+    /// 読み出したトレーラを解析する
+    /// KeyAは読み出せないため、カードから読んだ場合は0が入る
+    pub fn from_bytes(bytes: &[u8; BLOCK_SIZE]) -> Result<Self, MifareClassicError> {
+        let mut key_a = [0u8; KEY_SIZE];
+        let mut access = [0u8; 3];
+        let mut key_b = [0u8; KEY_SIZE];
+        key_a.copy_from_slice(&bytes[0..6]);
+        access.copy_from_slice(&bytes[6..9]);
+        key_b.copy_from_slice(&bytes[10..16]);
+        Ok(SectorTrailer {
+            key_a,
+            access_bits: AccessBits::decode(&access)?,
+            general_purpose_byte: bytes[9],
+            key_b,
+        })
+    }
+    /// 書き込み用のバイト列を生成する
+    /// アクセスビットが書き換えられなくなる設定はallow_permanentがtrueでなければエラーとする
+    pub fn to_bytes(&self, allow_permanent: bool) -> Result<[u8; BLOCK_SIZE], MifareClassicError> {
+        if self.access_bits.is_permanent() && !allow_permanent {
+            return Err(MifareClassicError::new(
+                MifareClassicErrorKind::PermanentLock,
+            ));
+        }
+        let mut bytes = [0u8; BLOCK_SIZE];
+        bytes[0..6].copy_from_slice(&self.key_a);
+        bytes[6..9].copy_from_slice(&self.access_bits.encode());
+        bytes[9] = self.general_purpose_byte;
+        bytes[10..16].copy_from_slice(&self.key_b);
+        Ok(bytes)
+    }
+}
+
+#[test]
+fn access_bits_transport() {
+    let bits = AccessBits::decode(&TRANSPORT_ACCESS_BITS).unwrap();
+    assert_eq!(bits, AccessBits::default());
+    assert_eq!(bits.data_block(0), DATA_BLOCK_TABLE[0]);
+    assert_eq!(bits.trailer().access_bits_write, KeyA);
+    assert!(!bits.is_permanent());
+    assert_eq!(bits.encode(), TRANSPORT_ACCESS_BITS);
+}
+
+#[test]
+fn access_bits_mad_sector() {
+    // MADセクタの典型的な設定 78 77 88: データはKeyBで書き込み、トレーラは011
+    let bits = AccessBits::decode(&[0x78, 0x77, 0x88]).unwrap();
+    for group in 0..3 {
+        assert_eq!(bits.condition_bits(group), 0b100);
+        assert_eq!(bits.data_block(group).write, KeyB);
+    }
+    assert_eq!(bits.condition_bits(3), 0b011);
+    assert_eq!(bits.trailer().key_b_read, Never);
+    let rebuilt = AccessBits::from_permissions(
+        &[bits.data_block(0), bits.data_block(1), bits.data_block(2)],
+        &bits.trailer(),
+    )
+    .unwrap();
+    assert_eq!(rebuilt.encode(), [0x78, 0x77, 0x88]);
+}
+
+#[test]
+fn access_bits_corrupted() {
+    assert_eq!(
+        AccessBits::decode(&[0xFF, 0x07, 0x81]).unwrap_err().kind(),
+        &MifareClassicErrorKind::AccessBitsCorrupted
+    );
+}
+
+#[test]
+fn sector_trailer_refuses_permanent_lock() {
+    let read_only = DATA_BLOCK_TABLE[0b010];
+    let bits =
+        AccessBits::from_permissions(&[read_only, read_only, read_only], &TRAILER_TABLE[0b110])
+            .unwrap();
+    let trailer = SectorTrailer::new([0xFF; 6], bits, [0xFF; 6]);
+    assert_eq!(
+        trailer.to_bytes(false).unwrap_err().kind(),
+        &MifareClassicErrorKind::PermanentLock
+    );
+    let bytes = trailer.to_bytes(true).unwrap();
+    assert_eq!(SectorTrailer::from_bytes(&bytes).unwrap(), trailer);
+}
@@ -0,0 +1,350 @@
+// キー辞書による MIFARE Classic のキー復旧
+// キーを紛失した自社カード向け。辞書の候補キーを全セクタのKeyA/KeyBで順に試す。
+// カードが外れた場合は途中経過を保持したままエラーを返すので、
+// カードを置き直して run を呼べば続きから再開できる。
+
+use super::{MifareClassic, BLOCK_SIZE, KEY_SIZE};
+use crate::pc_sc_standard::MifareKeyType;
+use crate::smart_card::SmartcardError;
+use serde::{Deserialize, Serialize};
+
+/// よく使われるキー（出荷時キー、MAD/NDEFの公開キーなど）
+pub const DEFAULT_KEYS: [[u8; KEY_SIZE]; 12] = [
+    [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+    [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5],
+    [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5],
+    [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD],
+    [0x1A, 0x98, 0x2C, 0x7E, 0x45, 0x9A],
+    [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+    [0x71, 0x4C, 0x5C, 0x88, 0x6E, 0x97],
+    [0x58, 0x7E, 0xE5, 0xF9, 0x35, 0x0F],
+    [0xA0, 0x47, 0x8C, 0xC3, 0x90, 0x91],
+    [0x53, 0x3C, 0xB6, 0xC7, 0x23, 0xF6],
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyDictionary {
+    keys: Vec<[u8; KEY_SIZE]>,
+}
+
+impl KeyDictionary {
+    pub fn new() -> Self {
+        KeyDictionary { keys: Vec::new() }
+    }
+    pub fn with_default_keys() -> Self {
+        let mut dict = Self::new();
+        for key in DEFAULT_KEYS.iter() {
+            dict.add(*key);
+        }
+        dict
+    }
+    /// 重複しない場合のみ追加する
+    pub fn add(&mut self, key: [u8; KEY_SIZE]) -> &mut Self {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+    /// 1行に1キー(16進12桁)のテキストを読み込む
+    /// '#'以降はコメント、空行は無視する
+    pub fn parse(&mut self, text: &str) -> Result<&mut Self, KeyDictionaryError> {
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.len() != KEY_SIZE * 2 || !line.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(KeyDictionaryError::new(line_no + 1));
+            }
+            let mut key = [0u8; KEY_SIZE];
+            for (i, b) in key.iter_mut().enumerate() {
+                *b = u8::from_str_radix(&line[i * 2..i * 2 + 2], 16).unwrap();
+            }
+            self.add(key);
+        }
+        Ok(self)
+    }
+    pub fn load_file<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(self.parse(&text)?)
+    }
+    pub fn keys(&self) -> &[[u8; KEY_SIZE]] {
+        &self.keys
+    }
+}
+
+/// 1セクタ分のブロック（トレーラを含む）
+pub type SectorData = Vec<[u8; BLOCK_SIZE]>;
+
+/// セクタごとに判明したキー
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SectorKeys {
+    pub key_a: Option<[u8; KEY_SIZE]>,
+    pub key_b: Option<[u8; KEY_SIZE]>,
+}
+
+/// 探索の途中経過
+/// シリアライズして保存しておけば、別の実行からでも再開できる
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryProgress {
+    pub sectors: Vec<SectorKeys>,
+    /// 次に試す位置（セクタ、KeyBかどうか、辞書内の位置）
+    next_sector: u8,
+    next_is_key_b: bool,
+    next_key_index: usize,
+}
+
+impl RecoveryProgress {
+    pub fn is_finished(&self) -> bool {
+        self.next_sector as usize >= self.sectors.len()
+    }
+}
+
+pub struct KeyRecovery<'a, 'b> {
+    card: &'b MifareClassic<'a>,
+    dictionary: KeyDictionary,
+    progress: RecoveryProgress,
+}
+
+impl<'a, 'b> KeyRecovery<'a, 'b> {
+    pub fn new(card: &'b MifareClassic<'a>, dictionary: KeyDictionary) -> Self {
+        let progress = RecoveryProgress {
+            sectors: vec![SectorKeys::default(); card.kind().sector_count() as usize],
+            ..Default::default()
+        };
+        Self::resume(card, dictionary, progress)
+    }
+    /// 保存しておいた途中経過から再開する
+    /// 辞書は中断時と同じ内容・順序であること
+    pub fn resume(
+        card: &'b MifareClassic<'a>,
+        dictionary: KeyDictionary,
+        progress: RecoveryProgress,
+    ) -> Self {
+        KeyRecovery {
+            card,
+            dictionary,
+            progress,
+        }
+    }
+    pub fn progress(&self) -> &RecoveryProgress {
+        &self.progress
+    }
+    /// 残りの候補を全て試す
+    /// カードが外れた場合はその時点でエラーを返す（途中経過は保持される）
+    pub fn run(&mut self) -> Result<&RecoveryProgress, Box<dyn std::error::Error>> {
+        while !self.progress.is_finished() {
+            let sector = self.progress.next_sector;
+            let key_type = if self.progress.next_is_key_b {
+                MifareKeyType::KeyB
+            } else {
+                MifareKeyType::KeyA
+            };
+            let key = match self.dictionary.keys.get(self.progress.next_key_index) {
+                Some(key) => *key,
+                None => {
+                    self.advance_key_type();
+                    continue;
+                }
+            };
+            match self.card.authenticate_sector(sector, key_type, &key) {
+                Ok(()) => {
+                    let keys = &mut self.progress.sectors[sector as usize];
+                    match key_type {
+                        MifareKeyType::KeyA => keys.key_a = Some(key),
+                        MifareKeyType::KeyB => keys.key_b = Some(key),
+                    }
+                    self.advance_key_type();
+                }
+                Err(e) => {
+                    if SmartcardError::is_connection_lost(e.as_ref()) {
+                        return Err(e);
+                    }
+                    // 認証失敗は次の候補へ
+                    self.progress.next_key_index += 1;
+                }
+            }
+        }
+        Ok(&self.progress)
+    }
+    fn advance_key_type(&mut self) {
+        self.progress.next_key_index = 0;
+        if self.progress.next_is_key_b {
+            self.progress.next_is_key_b = false;
+            self.progress.next_sector += 1;
+        } else {
+            self.progress.next_is_key_b = true;
+        }
+    }
+    /// 判明したキーで読めるセクタを全て読み出す
+    /// 読めないセクタはNone。トレーラには判明したキーを埋めて返す
+    pub fn dump(&self) -> Result<Vec<Option<SectorData>>, Box<dyn std::error::Error>> {
+        let mut dump = Vec::new();
+        for (sector, keys) in self.progress.sectors.iter().enumerate() {
+            let sector = sector as u8;
+            let candidates = [
+                (MifareKeyType::KeyA, keys.key_a),
+                (MifareKeyType::KeyB, keys.key_b),
+            ];
+            let mut blocks = None;
+            for (key_type, key) in candidates.iter() {
+                let key = match key {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if let Err(e) = self.card.authenticate_sector(sector, *key_type, key) {
+                    if SmartcardError::is_connection_lost(e.as_ref()) {
+                        return Err(e);
+                    }
+                    // 復旧後にキーが変えられた場合などはもう一方のキーを試す
+                    continue;
+                }
+                match self.card.read_sector(sector) {
+                    Ok(data) => {
+                        blocks = Some(data);
+                        break;
+                    }
+                    Err(e) => {
+                        if SmartcardError::is_connection_lost(e.as_ref()) {
+                            return Err(e);
+                        }
+                        // アクセスビットで読み出しが禁止されている場合はもう一方のキーを試す
+                    }
+                }
+            }
+            if let Some(blocks) = blocks.as_mut() {
+                let trailer: &mut [u8; BLOCK_SIZE] = blocks.last_mut().unwrap();
+                if let Some(key_a) = keys.key_a {
+                    trailer[0..6].copy_from_slice(&key_a);
+                }
+                if let Some(key_b) = keys.key_b {
+                    trailer[10..16].copy_from_slice(&key_b);
+                }
+            }
+            dump.push(blocks);
+        }
+        Ok(dump)
+    }
+}
+
+/// 辞書ファイルの書式エラー（行番号は1起点）
+#[derive(Debug)]
+pub struct KeyDictionaryError {
+    line: usize,
+}
+
+impl KeyDictionaryError {
+    pub fn new(line: usize) -> Self {
+        KeyDictionaryError { line }
+    }
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+impl std::error::Error for KeyDictionaryError {}
+impl std::fmt::Display for KeyDictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid key at line {}", self.line)
+    }
+}
+
+#[test]
+fn key_dictionary_parse() {
+    let mut dict = KeyDictionary::new();
+    dict.parse("# comment\nA0A1A2A3A4A5\n\nffffffffffff # default\na0a1a2a3a4a5\n")
+        .unwrap();
+    assert_eq!(
+        dict.keys(),
+        &[[0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5], [0xFF; KEY_SIZE]]
+    );
+    assert!(KeyDictionary::new().parse("FFFFFFFFFFF\n").is_err());
+}
+
+#[test]
+fn key_dictionary_load_file() {
+    let path = std::env::temp_dir().join(format!("nfc_key_dictionary_{}.txt", std::process::id()));
+    std::fs::write(
+        &path,
+        "# 社内カード用\n\nA0A1A2A3A4A5\n  d3f7d3f7d3f7  # NDEF\n\n",
+    )
+    .unwrap();
+    let mut dict = KeyDictionary::new();
+    let loaded = dict.load_file(&path).map(|dict| dict.keys().to_vec());
+    std::fs::write(&path, "FFFFFFFFFFFF\n\n# 4行目が不正\n12345G789ABC\n").unwrap();
+    let error = KeyDictionary::new().load_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        loaded.unwrap(),
+        [
+            [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5],
+            [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7]
+        ]
+    );
+    assert_eq!(
+        error.downcast_ref::<KeyDictionaryError>().unwrap().line(),
+        4
+    );
+    assert!(KeyDictionary::new().load_file(&path).is_err());
+}
+
+#[test]
+fn key_recovery_dump_skips_sector_with_changed_key() {
+    use crate::mifare_classic::MifareClassicKind;
+    use crate::nfc_impl::nfc_mock::{mifare_classic::VirtualMifareClassic, MockSmartcard};
+    let nfc = MockSmartcard::new(Box::new(VirtualMifareClassic::new(MifareClassicKind::Mini)));
+    let mfc = MifareClassic::from_atr(&nfc).unwrap();
+    let mut recovery = KeyRecovery::new(&mfc, KeyDictionary::with_default_keys());
+    let progress = recovery.run().unwrap().clone();
+
+    // 復旧後にセクタ1のキーが書き換えられた
+    let mut card = VirtualMifareClassic::new(MifareClassicKind::Mini);
+    card.set_keys(1, &[0x11; KEY_SIZE], &[0x22; KEY_SIZE]);
+    card.set_block(4, &[0x41; BLOCK_SIZE]);
+    card.set_block(8, &[0x42; BLOCK_SIZE]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let mfc = MifareClassic::from_atr(&nfc).unwrap();
+    let recovery = KeyRecovery::resume(&mfc, KeyDictionary::with_default_keys(), progress);
+    let dump = recovery.dump().unwrap();
+    assert_eq!(dump.len(), 5);
+    assert!(dump[1].is_none());
+    assert_eq!(dump[2].as_ref().unwrap()[0], [0x42; BLOCK_SIZE]);
+    assert_eq!(dump.iter().filter(|sector| sector.is_some()).count(), 4);
+}
+
+#[test]
+fn key_recovery_resumes_after_card_removal() {
+    use crate::mifare_classic::MifareClassicKind;
+    use crate::nfc_impl::nfc_mock::{mifare_classic::VirtualMifareClassic, MockSmartcard};
+    let secret_a = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC];
+    let secret_b = [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD];
+    let mut card = VirtualMifareClassic::new(MifareClassicKind::Mini).remove_at(30);
+    card.set_keys(2, &secret_a, &secret_b);
+    card.set_block(8, &[0x42; BLOCK_SIZE]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let mfc = MifareClassic::from_atr(&nfc).unwrap();
+    let mut dict = KeyDictionary::with_default_keys();
+    dict.parse("123456789abc").unwrap();
+
+    let mut recovery = KeyRecovery::new(&mfc, dict);
+    let err = recovery.run().unwrap_err();
+    assert!(SmartcardError::is_connection_lost(err.as_ref()));
+    assert!(!recovery.progress().is_finished());
+
+    let progress = recovery.run().unwrap();
+    assert!(progress.is_finished());
+    assert_eq!(progress.sectors[0].key_a, Some([0xFF; KEY_SIZE]));
+    assert_eq!(progress.sectors[2].key_a, Some(secret_a));
+    assert_eq!(progress.sectors[2].key_b, Some(secret_b));
+
+    let dump = recovery.dump().unwrap();
+    assert_eq!(dump.len(), 5);
+    let sector2 = dump[2].as_ref().unwrap();
+    assert_eq!(sector2[0], [0x42; BLOCK_SIZE]);
+    assert_eq!(&sector2[3][0..6], &secret_a);
+    assert_eq!(&sector2[3][10..16], &secret_b);
+}
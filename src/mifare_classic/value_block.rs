@@ -0,0 +1,241 @@
+// MIFARE Classicの値ブロック
+//
+// 値ブロックの形式（値はリトルエンディアンの符号付き32bit）
+//   0-3: 値 | 4-7: 値の反転 | 8-11: 値 | 12: アドレス | 13: ~アドレス | 14: アドレス | 15: ~アドレス
+
+use super::{MifareClassic, MifareClassicError, MifareClassicErrorKind, BLOCK_SIZE};
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::{MifareExt, ValueBlockOperation};
+use std::convert::TryFrom;
+
+/// 加算・減算・復元をどう実行するか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueBlockMode {
+    /// リーダーの値ブロックコマンド(FF D7/FF B1)を使う
+    ReaderCommand,
+    /// 値ブロックコマンドを持たないリーダー向けに、読み出し→計算→書き込みで代替する
+    /// カード側の加減算と違いアトミックではない点に注意
+    ReadModifyWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueBlock {
+    pub value: i32,
+    /// バックアップ管理などに使う任意の1バイト（通常はブロック番号）
+    pub address: u8,
+}
+
+impl ValueBlock {
+    pub fn new(value: i32, address: u8) -> Self {
+        ValueBlock { value, address }
+    }
+    pub fn to_bytes(self) -> [u8; BLOCK_SIZE] {
+        let value = self.value.to_le_bytes();
+        let inverted = (!self.value).to_le_bytes();
+        let mut bytes = [0u8; BLOCK_SIZE];
+        bytes[0..4].copy_from_slice(&value);
+        bytes[4..8].copy_from_slice(&inverted);
+        bytes[8..12].copy_from_slice(&value);
+        bytes[12] = self.address;
+        bytes[13] = !self.address;
+        bytes[14] = self.address;
+        bytes[15] = !self.address;
+        bytes
+    }
+    /// 冗長部分を検査して値ブロックを取り出す
+    /// blockはエラー報告用のブロック番号
+    pub fn from_bytes(bytes: &[u8; BLOCK_SIZE], block: u8) -> Result<Self, MifareClassicError> {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&bytes[0..4]);
+        let value = i32::from_le_bytes(value);
+        let valid = (0..4).all(|i| bytes[i] == !bytes[4 + i] && bytes[i] == bytes[8 + i])
+            && bytes[12] == !bytes[13]
+            && bytes[12] == bytes[14]
+            && bytes[13] == bytes[15];
+        if !valid {
+            return Err(MifareClassicError::new(
+                MifareClassicErrorKind::NotValueBlock(block),
+            ));
+        }
+        Ok(ValueBlock {
+            value,
+            address: bytes[12],
+        })
+    }
+}
+
+impl<'a> MifareClassic<'a> {
+    /// 認証済みセクタのブロックを値ブロックとして初期化する
+    pub fn format_value_block(
+        &self,
+        block: u8,
+        value: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_block(block, &ValueBlock::new(value, block).to_bytes())
+    }
+    /// 値ブロックを読み出し、形式を検査して返す
+    pub fn read_value(&self, block: u8) -> Result<ValueBlock, Box<dyn std::error::Error>> {
+        let data = self.read_block(block)?;
+        Ok(ValueBlock::from_bytes(&data, block)?)
+    }
+    /// 値ブロックに加算して同じブロックへ転送する
+    pub fn increment(&self, block: u8, delta: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.value_operation(block, ValueBlockOperation::Increment, delta)
+    }
+    /// 値ブロックから減算して同じブロックへ転送する
+    pub fn decrement(&self, block: u8, delta: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.value_operation(block, ValueBlockOperation::Decrement, delta)
+    }
+    /// sourceの値ブロックをtargetへ復元（restore + transfer）する
+    /// 同じセクタ内でのみ有効
+    pub fn restore(&self, source: u8, target: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_block(source)?;
+        self.check_block(target)?;
+        match self.value_mode {
+            ValueBlockMode::ReaderCommand => {
+                let apdu = ApduBuilder::new()
+                    .restore_value_block(source, target)
+                    .build();
+                self.nfc.transmit(Box::new(apdu))?;
+                Ok(())
+            }
+            ValueBlockMode::ReadModifyWrite => {
+                let value = self.read_value(source)?;
+                self.write_block(target, &value.to_bytes())
+            }
+        }
+    }
+    fn value_operation(
+        &self,
+        block: u8,
+        operation: ValueBlockOperation,
+        delta: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_block(block)?;
+        let delta = i32::try_from(delta)
+            .map_err(|_| MifareClassicError::new(MifareClassicErrorKind::ValueOverflow))?;
+        match self.value_mode {
+            ValueBlockMode::ReaderCommand => {
+                let apdu = ApduBuilder::new()
+                    .value_block_operation(block, operation, delta)
+                    .build();
+                self.nfc.transmit(Box::new(apdu))?;
+                Ok(())
+            }
+            ValueBlockMode::ReadModifyWrite => {
+                let current = self.read_value(block)?;
+                let value = match operation {
+                    ValueBlockOperation::Increment => current.value.checked_add(delta),
+                    ValueBlockOperation::Decrement => current.value.checked_sub(delta),
+                    ValueBlockOperation::Store => Some(delta),
+                }
+                .ok_or_else(|| MifareClassicError::new(MifareClassicErrorKind::ValueOverflow))?;
+                self.write_block(block, &ValueBlock::new(value, current.address).to_bytes())
+            }
+        }
+    }
+}
+
+#[test]
+fn value_block_roundtrip() {
+    let block = ValueBlock::new(100, 0x05);
+    let bytes = block.to_bytes();
+    assert_eq!(
+        bytes,
+        [
+            0x64, 0x00, 0x00, 0x00, 0x9B, 0xFF, 0xFF, 0xFF, 0x64, 0x00, 0x00, 0x00, 0x05, 0xFA,
+            0x05, 0xFA
+        ]
+    );
+    assert_eq!(ValueBlock::from_bytes(&bytes, 5).unwrap(), block);
+    let negative = ValueBlock::new(-1, 0x10);
+    assert_eq!(
+        ValueBlock::from_bytes(&negative.to_bytes(), 16).unwrap(),
+        negative
+    );
+}
+
+#[test]
+fn value_block_rejects_broken_redundancy() {
+    let mut bytes = ValueBlock::new(100, 0x05).to_bytes();
+    bytes[9] ^= 0x01;
+    assert_eq!(
+        ValueBlock::from_bytes(&bytes, 5).unwrap_err().kind(),
+        &MifareClassicErrorKind::NotValueBlock(5)
+    );
+    let mut bytes = ValueBlock::new(100, 0x05).to_bytes();
+    bytes[15] = 0x05;
+    assert!(ValueBlock::from_bytes(&bytes, 5).is_err());
+}
+
+#[test]
+fn value_block_operations() {
+    use super::{MifareClassicKind, DEFAULT_KEY};
+    use crate::nfc_impl::nfc_mock::{mifare_classic::VirtualMifareClassic, MockSmartcard};
+    use crate::pc_sc_standard::MifareKeyType;
+    use crate::smart_card::Smartcard;
+
+    let kind = |e: Box<dyn std::error::Error>| {
+        e.downcast_ref::<MifareClassicError>()
+            .unwrap()
+            .kind()
+            .clone()
+    };
+    for mode in [
+        ValueBlockMode::ReaderCommand,
+        ValueBlockMode::ReadModifyWrite,
+    ] {
+        let mut card = VirtualMifareClassic::new(MifareClassicKind::Classic1k);
+        let mut broken = ValueBlock::new(7, 6).to_bytes();
+        broken[4] ^= 0x80;
+        card.set_block(6, &broken);
+        let nfc = MockSmartcard::new(Box::new(card));
+        let mut mfc = MifareClassic::from_atr(&nfc).unwrap();
+        mfc.set_value_block_mode(mode);
+        mfc.authenticate_sector(1, MifareKeyType::KeyA, &DEFAULT_KEY)
+            .unwrap();
+
+        mfc.format_value_block(4, 100).unwrap();
+        assert_eq!(mfc.read_value(4).unwrap(), ValueBlock::new(100, 4));
+        mfc.increment(4, 25).unwrap();
+        assert_eq!(mfc.read_value(4).unwrap(), ValueBlock::new(125, 4));
+        mfc.decrement(4, 200).unwrap();
+        assert_eq!(mfc.read_value(4).unwrap(), ValueBlock::new(-75, 4));
+        // 復元先にはアドレスバイトも含めてそのまま複写される
+        mfc.restore(4, 5).unwrap();
+        assert_eq!(mfc.read_value(5).unwrap(), ValueBlock::new(-75, 4));
+        // リーダーの値ブロック読み出し(FF B1)でも同じ値になる
+        let apdu = ApduBuilder::new().read_value_block(5).build();
+        assert_eq!(
+            nfc.transmit(Box::new(apdu)).unwrap(),
+            (-75i32).to_be_bytes()
+        );
+
+        // 冗長部分が壊れたブロックは値ブロックとして扱わない
+        assert_eq!(
+            kind(mfc.read_value(6).unwrap_err()),
+            MifareClassicErrorKind::NotValueBlock(6)
+        );
+        assert!(mfc.increment(6, 1).is_err());
+        assert!(mfc.restore(6, 5).is_err());
+        assert_eq!(mfc.read_block(6).unwrap(), broken);
+
+        // i32に収まらない加減算
+        assert_eq!(
+            kind(mfc.increment(4, 0x8000_0000).unwrap_err()),
+            MifareClassicErrorKind::ValueOverflow
+        );
+        mfc.format_value_block(5, i32::MAX).unwrap();
+        let error = mfc.increment(5, 1).unwrap_err();
+        match mode {
+            ValueBlockMode::ReadModifyWrite => {
+                assert_eq!(kind(error), MifareClassicErrorKind::ValueOverflow)
+            }
+            // リーダーのコマンドではカードが失敗を返す
+            ValueBlockMode::ReaderCommand => {
+                assert!(error.downcast_ref::<MifareClassicError>().is_none())
+            }
+        }
+        assert_eq!(mfc.read_value(5).unwrap().value, i32::MAX);
+    }
+}
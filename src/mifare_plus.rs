@@ -0,0 +1,758 @@
+// MIFARE Plus S/X/EV1 の読み書き
+// SL1はMIFARE Classic互換なので、MifareClassicの操作(PC/SC Part3)をそのまま使う。
+// SL0の初期設定(WritePerso/CommitPerso)、セキュリティレベルの切り替えとSL3のAES認証・読み書きは
+// ネイティブコマンドをDIRECT TRANSMIT(FF 00 00 00)でPN53xの InDataExchange に載せて送る。
+// カードの応答は先頭1バイトがステータス(90で成功)になる。
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::crypto::secret_random_bytes;
+use crate::mifare_classic::{MifareClassic, MifareClassicKind, BLOCK_SIZE};
+use crate::pc_sc_standard::{
+    ApduBuilderExtWithDirectTransmit, ApduBuilderExtWithPcsc3V2, CardName, MifareKeyType,
+};
+use crate::smart_card::Smartcard;
+use std::cell::{Cell, RefCell};
+
+pub mod auth;
+pub mod session;
+use auth::{AuthMethod, PlusAuth, CMD_AUTHENTICATE_CONTINUE, RANDOM_SIZE};
+use session::{Session, MAC_SIZE};
+
+pub const KEY_SIZE: usize = 16;
+/// 1回の読み書きで扱えるブロック数
+pub const MAX_BLOCKS: usize = 3;
+
+// 鍵のアドレス（セクタの鍵はsector_keyで求める）
+pub const MASTER_KEY: u16 = 0x9000;
+pub const CONFIGURATION_KEY: u16 = 0x9001;
+pub const SL2_SWITCH_KEY: u16 = 0x9002;
+pub const SL3_SWITCH_KEY: u16 = 0x9003;
+pub const SL1_CARD_AUTH_KEY: u16 = 0x9004;
+const SECTOR_KEY_BASE: u16 = 0x4000;
+
+// カードのコマンド
+const CMD_WRITE_PERSO: u8 = 0xA8;
+const CMD_COMMIT_PERSO: u8 = 0xAA;
+const CMD_RESET_AUTH: u8 = 0x78;
+// 読み出しは 30 | 応答にMACあり(1) | 平文(2) | コマンドにMACなし(4)
+const CMD_READ_ENCRYPTED: u8 = 0x31;
+const CMD_READ_MACED: u8 = 0x33;
+const CMD_READ_PLAIN: u8 = 0x36;
+// 書き込みは A0 | 応答にMACあり(1) | 平文(2)。コマンドには常にMACを付ける
+const CMD_WRITE_ENCRYPTED: u8 = 0xA1;
+const CMD_WRITE_MACED: u8 = 0xA3;
+
+pub const STATUS_OK: u8 = 0x90;
+
+// PN53xのコマンド（ターゲット1番とISO14443-4のブロックでやり取りする）
+const PN53X_IN_DATA_EXCHANGE: [u8; 3] = [0xD4, 0x40, 0x01];
+const PN53X_IN_DATA_EXCHANGE_RESPONSE: [u8; 2] = [0xD5, 0x41];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecurityLevel {
+    /// 出荷状態。WritePersoで鍵と初期データを書き込む
+    Sl0,
+    /// MIFARE Classic互換
+    Sl1,
+    /// AES認証後にCrypto1で読み書きする
+    Sl2,
+    /// AESで認証し、MACまたは暗号化して読み書きする
+    Sl3,
+}
+
+/// MIFARE Plusのメモリ構成
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MifarePlusSize {
+    /// 32セクタ x 4ブロック
+    Plus2k,
+    /// 32セクタ x 4ブロック + 8セクタ x 16ブロック
+    Plus4k,
+}
+
+impl MifarePlusSize {
+    /// ATRのカード名からメモリ構成とセキュリティレベルを判定する
+    /// SL0・SL3のカードはISO14443-4のカードとして見えるので判定できない
+    pub fn from_card_name(card_name: &CardName) -> Option<(Self, SecurityLevel)> {
+        match card_name {
+            CardName::MifarePlusSl12k => Some((MifarePlusSize::Plus2k, SecurityLevel::Sl1)),
+            CardName::MifarePlusSl14k => Some((MifarePlusSize::Plus4k, SecurityLevel::Sl1)),
+            CardName::MifarePlusSl22k => Some((MifarePlusSize::Plus2k, SecurityLevel::Sl2)),
+            CardName::MifarePlusSl24k => Some((MifarePlusSize::Plus4k, SecurityLevel::Sl2)),
+            _ => None,
+        }
+    }
+    pub fn sector_count(&self) -> u8 {
+        self.classic_kind().sector_count()
+    }
+    pub fn block_count(&self) -> usize {
+        self.classic_kind().block_count()
+    }
+    /// SL1でのMIFARE Classicとしてのメモリ構成
+    pub fn classic_kind(&self) -> MifareClassicKind {
+        match self {
+            MifarePlusSize::Plus2k => MifareClassicKind::Classic2k,
+            MifarePlusSize::Plus4k => MifareClassicKind::Classic4k,
+        }
+    }
+}
+
+/// SL3の読み書きの通信モード
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommunicationMode {
+    /// MACを付けない（読み出しのみ、認証なしでも使える）
+    Plain,
+    /// 平文にMACを付ける
+    Maced,
+    /// 暗号化してMACを付ける
+    Encrypted,
+}
+
+/// セクタの鍵(AES)のアドレス
+pub fn sector_key(sector: u8, key_type: MifareKeyType) -> u16 {
+    let offset = match key_type {
+        MifareKeyType::KeyA => 0,
+        MifareKeyType::KeyB => 1,
+    };
+    SECTOR_KEY_BASE + sector as u16 * 2 + offset
+}
+
+pub struct MifarePlus<'a> {
+    nfc: &'a dyn Smartcard,
+    size: MifarePlusSize,
+    level: Cell<SecurityLevel>,
+    /// SL3で認証中のセッション
+    session: RefCell<Option<Session>>,
+}
+
+impl<'a> MifarePlus<'a> {
+    pub fn new(nfc: &'a dyn Smartcard, size: MifarePlusSize, level: SecurityLevel) -> Self {
+        MifarePlus {
+            nfc,
+            size,
+            level: Cell::new(level),
+            session: RefCell::new(None),
+        }
+    }
+    /// 接続中カードのATRからSL1・SL2のカードを判定する
+    pub fn from_atr(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let identified = nfc
+            .get_atr()
+            .card_name
+            .as_ref()
+            .and_then(|(_, name)| MifarePlusSize::from_card_name(name));
+        match identified {
+            Some((size, level)) => Ok(Self::new(nfc, size, level)),
+            None => Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::NotMifarePlus,
+            ))),
+        }
+    }
+    pub fn size(&self) -> MifarePlusSize {
+        self.size
+    }
+    pub fn security_level(&self) -> SecurityLevel {
+        self.level.get()
+    }
+    /// SL3で認証中の鍵のアドレス
+    pub fn authenticated_key(&self) -> Option<u16> {
+        self.session
+            .borrow()
+            .as_ref()
+            .map(|session| session.key_no())
+    }
+    pub fn uid(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().get_serial().build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    /// ネイティブコマンドを送り、ステータスに続くデータを返す
+    /// ステータスが90以外ならエラーになる
+    pub fn transceive(
+        &self,
+        command: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let payload = PN53X_IN_DATA_EXCHANGE
+            .iter()
+            .chain([command].iter())
+            .chain(data.iter())
+            .cloned()
+            .collect::<Vec<u8>>();
+        let apdu = ApduBuilder::new().direct_transmit(&payload).build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() < 4 || res[0..2] != PN53X_IN_DATA_EXCHANGE_RESPONSE {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        // 下位6ビットがエラーコード
+        let status = res[2] & 0x3F;
+        if status != 0 {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::ReaderStatus(status),
+            )));
+        }
+        match res[3] {
+            STATUS_OK => Ok(res[4..].to_vec()),
+            status => Err(Box::new(MifarePlusError::new(MifarePlusErrorKind::Status(
+                status,
+            )))),
+        }
+    }
+    /// SL1ではMIFARE Classicとして読み書きする
+    pub fn classic(&self) -> Result<MifareClassic<'a>, MifarePlusError> {
+        self.check_level(SecurityLevel::Sl1)?;
+        Ok(MifareClassic::new(self.nfc, self.size.classic_kind()))
+    }
+
+    /// SL0でブロック・鍵・設定を書き込む
+    /// addressはデータブロック(0～)・セクタの鍵(4000～)・カードの鍵(9000～)など
+    pub fn write_perso(
+        &self,
+        address: u16,
+        data: &[u8; BLOCK_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl0)?;
+        let payload = [&address.to_le_bytes()[..], data].concat();
+        self.transceive(CMD_WRITE_PERSO, &payload)?;
+        Ok(())
+    }
+    /// SL0の書き込みを確定してSL1へ移る
+    pub fn commit_perso(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl0)?;
+        self.transceive(CMD_COMMIT_PERSO, &[])?;
+        self.level.set(SecurityLevel::Sl1);
+        Ok(())
+    }
+    /// 切り替え用の鍵でFirst Authenticateを行い、セキュリティレベルを上げる
+    /// 下げることはできない
+    pub fn switch_security_level(
+        &self,
+        level: SecurityLevel,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key_no = match (self.level.get(), level) {
+            (SecurityLevel::Sl1, SecurityLevel::Sl2) => SL2_SWITCH_KEY,
+            (SecurityLevel::Sl1, SecurityLevel::Sl3) | (SecurityLevel::Sl2, SecurityLevel::Sl3) => {
+                SL3_SWITCH_KEY
+            }
+            (current, _) => {
+                return Err(Box::new(MifarePlusError::new(
+                    MifarePlusErrorKind::WrongSecurityLevel(current),
+                )))
+            }
+        };
+        let method = AuthMethod::First;
+        let mut auth = PlusAuth::new(method, key_no, key, &Self::random_a()?);
+        let encrypted_rnd_b = self.transceive(method as u8, &method.command_data(key_no))?;
+        let token = auth.challenge_response(&encrypted_rnd_b)?;
+        // 切り替えの認証はステータスだけを返す
+        let response = self.transceive(CMD_AUTHENTICATE_CONTINUE, &token)?;
+        if !response.is_empty() {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidResponseLength(response.len()),
+            )));
+        }
+        self.level.set(level);
+        self.session.replace(None);
+        Ok(())
+    }
+
+    /// SL3でkey_noの鍵で認証する
+    pub fn authenticate(
+        &self,
+        method: AuthMethod,
+        key_no: u16,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.authenticate_with(method, key_no, key, &Self::random_a()?)
+    }
+    /// RndAを指定して認証する
+    pub fn authenticate_with(
+        &self,
+        method: AuthMethod,
+        key_no: u16,
+        key: &[u8; KEY_SIZE],
+        rnd_a: &[u8; RANDOM_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl3)?;
+        // 失敗するとカードも認証を解除する
+        let previous = self.session.replace(None);
+        if method == AuthMethod::Following && previous.is_none() {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::NotAuthenticated,
+            )));
+        }
+        let mut auth = PlusAuth::new(method, key_no, key, rnd_a);
+        let encrypted_rnd_b = self.transceive(method as u8, &method.command_data(key_no))?;
+        let token = auth.challenge_response(&encrypted_rnd_b)?;
+        let response = self.transceive(CMD_AUTHENTICATE_CONTINUE, &token)?;
+        let session = auth.verify(&response, previous)?;
+        self.session.replace(Some(session));
+        Ok(())
+    }
+    /// セクタの鍵で認証する。認証中ならFollowing Authenticateでカウンタを引き継ぐ
+    pub fn authenticate_sector(
+        &self,
+        sector: u8,
+        key_type: MifareKeyType,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if sector >= self.size.sector_count() {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::SectorOutOfRange(sector),
+            )));
+        }
+        let method = if self.session.borrow().is_some() {
+            AuthMethod::Following
+        } else {
+            AuthMethod::First
+        };
+        self.authenticate(method, sector_key(sector, key_type), key)
+    }
+    /// 認証を解除する
+    pub fn reset_authentication(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl3)?;
+        self.session.replace(None);
+        self.transceive(CMD_RESET_AUTH, &[])?;
+        Ok(())
+    }
+
+    /// blockからcountブロックを読み出す
+    pub fn read_blocks(
+        &self,
+        block: u8,
+        count: u8,
+        mode: CommunicationMode,
+    ) -> Result<Vec<[u8; BLOCK_SIZE]>, Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl3)?;
+        self.check_blocks(block, count as usize)?;
+        let command = match mode {
+            CommunicationMode::Plain => CMD_READ_PLAIN,
+            CommunicationMode::Maced => CMD_READ_MACED,
+            CommunicationMode::Encrypted => CMD_READ_ENCRYPTED,
+        };
+        // BNr(リトルエンディアン) || Ext(ブロック数)
+        let header = [block, 0x00, count];
+        let data_len = count as usize * BLOCK_SIZE;
+        let mut session = self.session.borrow_mut();
+        let data = match session.as_mut() {
+            Some(current) => {
+                let result = self.read_secure(current, command, &header, data_len, mode);
+                // エラーになるとカードも認証を解除する
+                if result.is_err() {
+                    session.take();
+                }
+                result?
+            }
+            None if mode == CommunicationMode::Plain => {
+                let response = self.transceive(command, &header)?;
+                Self::check_length(&response, data_len)?;
+                response
+            }
+            None => {
+                return Err(Box::new(MifarePlusError::new(
+                    MifarePlusErrorKind::NotAuthenticated,
+                )))
+            }
+        };
+        Ok(data
+            .chunks_exact(BLOCK_SIZE)
+            .map(|chunk| {
+                let mut block = [0u8; BLOCK_SIZE];
+                block.copy_from_slice(chunk);
+                block
+            })
+            .collect())
+    }
+    pub fn read_block(
+        &self,
+        block: u8,
+        mode: CommunicationMode,
+    ) -> Result<[u8; BLOCK_SIZE], Box<dyn std::error::Error>> {
+        Ok(self.read_blocks(block, 1, mode)?[0])
+    }
+    /// blockから続けて書き込む
+    /// 書き込みには常にMACを付けるので、PlainはMacedと同じになる
+    pub fn write_blocks(
+        &self,
+        block: u8,
+        data: &[[u8; BLOCK_SIZE]],
+        mode: CommunicationMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl3)?;
+        self.check_blocks(block, data.len())?;
+        if block == 0 {
+            // 製造者ブロックは書き換えられない
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::BlockOutOfRange(block),
+            )));
+        }
+        self.write_address(block as u16, &data.concat(), mode)
+    }
+    pub fn write_block(
+        &self,
+        block: u8,
+        data: &[u8; BLOCK_SIZE],
+        mode: CommunicationMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_blocks(block, &[*data], mode)
+    }
+    /// SL3で鍵を書き換える（常に暗号化して送る）
+    pub fn change_key(
+        &self,
+        key_no: u16,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_level(SecurityLevel::Sl3)?;
+        self.write_address(key_no, key, CommunicationMode::Encrypted)
+    }
+
+    fn write_address(
+        &self,
+        address: u16,
+        data: &[u8],
+        mode: CommunicationMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut session = self.session.borrow_mut();
+        let result = match session.as_mut() {
+            Some(current) => self.write_secure(current, address, data, mode),
+            None => {
+                return Err(Box::new(MifarePlusError::new(
+                    MifarePlusErrorKind::NotAuthenticated,
+                )))
+            }
+        };
+        if result.is_err() {
+            session.take();
+        }
+        result
+    }
+    /// 認証中の読み出し。Plain以外は応答のMACを検証する
+    fn read_secure(
+        &self,
+        session: &mut Session,
+        command: u8,
+        header: &[u8],
+        data_len: usize,
+        mode: CommunicationMode,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let payload = match mode {
+            CommunicationMode::Plain => header.to_vec(),
+            _ => [header, &session.command_mac(command, header, &[])].concat(),
+        };
+        let response = self.transceive(command, &payload)?;
+        let data = match mode {
+            CommunicationMode::Plain => {
+                Self::check_length(&response, data_len)?;
+                response
+            }
+            _ => {
+                Self::check_length(&response, data_len + MAC_SIZE)?;
+                let (data, mac) = response.split_at(data_len);
+                if session.response_mac(command, STATUS_OK, header, data) != mac {
+                    return Err(Box::new(MifarePlusError::new(
+                        MifarePlusErrorKind::IntegrityError,
+                    )));
+                }
+                match mode {
+                    CommunicationMode::Encrypted => session.decrypt_response(command, data),
+                    _ => data.to_vec(),
+                }
+            }
+        };
+        session.advance(command);
+        Ok(data)
+    }
+    fn write_secure(
+        &self,
+        session: &mut Session,
+        address: u16,
+        data: &[u8],
+        mode: CommunicationMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (command, data) = match mode {
+            CommunicationMode::Encrypted => (CMD_WRITE_ENCRYPTED, session.encrypt_command(data)),
+            _ => (CMD_WRITE_MACED, data.to_vec()),
+        };
+        let header = address.to_le_bytes();
+        let mac = session.command_mac(command, &header, &data);
+        let payload = [&header[..], &data, &mac].concat();
+        let response = self.transceive(command, &payload)?;
+        if response[..] != session.response_mac(command, STATUS_OK, &[], &[]) {
+            return Err(Box::new(MifarePlusError::new(
+                MifarePlusErrorKind::IntegrityError,
+            )));
+        }
+        session.advance(command);
+        Ok(())
+    }
+    fn random_a() -> Result<[u8; RANDOM_SIZE], Box<dyn std::error::Error>> {
+        let mut rnd_a = [0u8; RANDOM_SIZE];
+        rnd_a.copy_from_slice(&secret_random_bytes(RANDOM_SIZE)?);
+        Ok(rnd_a)
+    }
+    fn check_level(&self, level: SecurityLevel) -> Result<(), MifarePlusError> {
+        let current = self.level.get();
+        if current == level {
+            Ok(())
+        } else {
+            Err(MifarePlusError::new(
+                MifarePlusErrorKind::WrongSecurityLevel(current),
+            ))
+        }
+    }
+    fn check_blocks(&self, block: u8, count: usize) -> Result<(), MifarePlusError> {
+        if count == 0 || count > MAX_BLOCKS {
+            return Err(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidBlockCount(count),
+            ));
+        }
+        if block as usize + count > self.size.block_count() {
+            return Err(MifarePlusError::new(MifarePlusErrorKind::BlockOutOfRange(
+                block,
+            )));
+        }
+        Ok(())
+    }
+    fn check_length(response: &[u8], expected: usize) -> Result<(), MifarePlusError> {
+        if response.len() == expected {
+            Ok(())
+        } else {
+            Err(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidResponseLength(response.len()),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MifarePlusErrorKind {
+    /// 接続中のカードがMIFARE Plus(SL1/SL2)ではない
+    NotMifarePlus,
+    /// 現在のセキュリティレベルでは使えない操作
+    WrongSecurityLevel(SecurityLevel),
+    /// カードがエラーステータスを返した
+    Status(u8),
+    /// PN53xのエラーコード
+    ReaderStatus(u8),
+    InvalidResponseLength(usize),
+    SectorOutOfRange(u8),
+    BlockOutOfRange(u8),
+    /// 1回で読み書きできるブロック数(1～MAX_BLOCKS)を外れている
+    InvalidBlockCount(usize),
+    /// 認証が必要
+    NotAuthenticated,
+    /// カードの応答したRndA'が一致しない
+    AuthenticationFailed,
+    /// 応答のMACが一致しない
+    IntegrityError,
+}
+
+#[derive(Debug)]
+pub struct MifarePlusError {
+    code: MifarePlusErrorKind,
+}
+
+impl MifarePlusError {
+    pub fn new(code: MifarePlusErrorKind) -> Self {
+        MifarePlusError { code }
+    }
+    pub fn kind(&self) -> &MifarePlusErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for MifarePlusError {}
+impl std::fmt::Display for MifarePlusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn mifare_plus_security_levels() {
+    use crate::mifare_classic::DEFAULT_KEY;
+    use crate::nfc_impl::nfc_mock::mifare_plus::VirtualMifarePlus;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let status =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<MifarePlusError>().unwrap().kind().clone();
+    let key_a = [0x11; KEY_SIZE];
+    let key_b = [0x22; KEY_SIZE];
+    let switch_key = [0x33; KEY_SIZE];
+    let data = [0x5A; BLOCK_SIZE];
+
+    // SL0: 鍵と初期データを書き込んでSL1へ
+    let nfc = MockSmartcard::new(Box::new(VirtualMifarePlus::new(MifarePlusSize::Plus2k)));
+    assert!(MifarePlus::from_atr(&nfc).is_err());
+    let card = MifarePlus::new(&nfc, MifarePlusSize::Plus2k, SecurityLevel::Sl0);
+    card.write_perso(sector_key(1, MifareKeyType::KeyA), &key_a)
+        .unwrap();
+    card.write_perso(sector_key(1, MifareKeyType::KeyB), &key_b)
+        .unwrap();
+    card.write_perso(SL3_SWITCH_KEY, &switch_key).unwrap();
+    card.write_perso(CONFIGURATION_KEY, &[0x44; KEY_SIZE])
+        .unwrap();
+    card.write_perso(4, &data).unwrap();
+    assert_eq!(
+        status(card.write_perso(0x8000, &data).unwrap_err()),
+        MifarePlusErrorKind::Status(0x09)
+    );
+    card.commit_perso().unwrap();
+    assert_eq!(card.security_level(), SecurityLevel::Sl1);
+    assert_eq!(
+        status(card.commit_perso().unwrap_err()),
+        MifarePlusErrorKind::WrongSecurityLevel(SecurityLevel::Sl1)
+    );
+
+    // SL1: MIFARE Classicとして読める（2Kは32セクタまで）
+    assert_eq!(card.uid().unwrap(), [0x01, 0x02, 0x03, 0x04]);
+    let classic = card.classic().unwrap();
+    assert_eq!(classic.kind(), MifareClassicKind::Classic2k);
+    classic
+        .authenticate_sector(1, MifareKeyType::KeyA, &DEFAULT_KEY)
+        .unwrap();
+    assert_eq!(classic.read_block(4).unwrap(), data);
+    let classic_error = |e: Box<dyn std::error::Error>| {
+        e.downcast_ref::<crate::mifare_classic::MifareClassicError>()
+            .unwrap()
+            .kind()
+            .clone()
+    };
+    assert_eq!(
+        classic_error(
+            classic
+                .authenticate_sector(32, MifareKeyType::KeyA, &DEFAULT_KEY)
+                .unwrap_err()
+        ),
+        crate::mifare_classic::MifareClassicErrorKind::SectorOutOfRange(32)
+    );
+    assert_eq!(
+        classic_error(classic.read_block(128).unwrap_err()),
+        crate::mifare_classic::MifareClassicErrorKind::BlockOutOfRange(128)
+    );
+    assert_eq!(
+        status(
+            card.switch_security_level(SecurityLevel::Sl3, &key_a)
+                .unwrap_err()
+        ),
+        MifarePlusErrorKind::Status(0x06)
+    );
+    card.switch_security_level(SecurityLevel::Sl3, &switch_key)
+        .unwrap();
+    assert_eq!(card.security_level(), SecurityLevel::Sl3);
+    assert!(card.classic().is_err());
+
+    // SL3: 認証してMAC・暗号化で読み書きする
+    assert_eq!(
+        status(card.read_block(4, CommunicationMode::Maced).unwrap_err()),
+        MifarePlusErrorKind::NotAuthenticated
+    );
+    card.read_block(0, CommunicationMode::Plain).unwrap();
+    card.authenticate_sector(1, MifareKeyType::KeyA, &key_a)
+        .unwrap();
+    assert_eq!(
+        card.authenticated_key(),
+        Some(sector_key(1, MifareKeyType::KeyA))
+    );
+    assert_eq!(card.read_block(4, CommunicationMode::Maced).unwrap(), data);
+    let blocks = [[0xA5; BLOCK_SIZE], [0x3C; BLOCK_SIZE]];
+    card.write_blocks(5, &blocks, CommunicationMode::Encrypted)
+        .unwrap();
+    assert_eq!(
+        card.read_blocks(5, 2, CommunicationMode::Encrypted)
+            .unwrap(),
+        blocks
+    );
+    card.write_block(4, &blocks[1], CommunicationMode::Maced)
+        .unwrap();
+    assert_eq!(
+        card.read_blocks(4, 3, CommunicationMode::Plain).unwrap(),
+        [blocks[1], blocks[0], blocks[1]]
+    );
+    assert_eq!(
+        status(
+            card.read_blocks(4, 4, CommunicationMode::Maced)
+                .unwrap_err()
+        ),
+        MifarePlusErrorKind::InvalidBlockCount(4)
+    );
+    // 別のセクタは読めず、エラーで認証が解除される
+    assert_eq!(
+        status(card.read_block(8, CommunicationMode::Maced).unwrap_err()),
+        MifarePlusErrorKind::Status(0x0B)
+    );
+    assert_eq!(card.authenticated_key(), None);
+
+    // Following Authenticateで鍵を切り替え、KeyBでKeyAを変更する
+    card.authenticate_sector(1, MifareKeyType::KeyA, &key_a)
+        .unwrap();
+    let ti = |card: &MifarePlus| card.session.borrow().as_ref().unwrap().ti();
+    let first_ti = ti(&card);
+    card.authenticate_sector(1, MifareKeyType::KeyB, &key_b)
+        .unwrap();
+    // TIはFirst Authenticateでカードが決め、Followingでは引き継ぐ
+    assert_eq!(ti(&card), first_ti);
+    assert_eq!(
+        card.read_block(6, CommunicationMode::Encrypted).unwrap(),
+        blocks[1]
+    );
+    let new_key = [0x77; KEY_SIZE];
+    card.change_key(sector_key(1, MifareKeyType::KeyA), &new_key)
+        .unwrap();
+    assert_eq!(
+        status(
+            card.authenticate(
+                AuthMethod::Following,
+                sector_key(1, MifareKeyType::KeyA),
+                &key_a
+            )
+            .unwrap_err()
+        ),
+        MifarePlusErrorKind::Status(0x06)
+    );
+    card.authenticate_sector(1, MifareKeyType::KeyA, &new_key)
+        .unwrap();
+    assert_eq!(
+        status(
+            card.change_key(sector_key(1, MifareKeyType::KeyB), &new_key)
+                .unwrap_err()
+        ),
+        MifarePlusErrorKind::Status(0x0B)
+    );
+    card.authenticate_sector(1, MifareKeyType::KeyA, &new_key)
+        .unwrap();
+    card.reset_authentication().unwrap();
+    assert_eq!(card.authenticated_key(), None);
+
+    // SL2のカードはATRで判定でき、SL3へ切り替えられる
+    let mut virtual_card = VirtualMifarePlus::new(MifarePlusSize::Plus4k);
+    virtual_card.set_security_level(SecurityLevel::Sl2);
+    let nfc = MockSmartcard::new(Box::new(virtual_card));
+    let card = MifarePlus::from_atr(&nfc).unwrap();
+    assert_eq!(card.size(), MifarePlusSize::Plus4k);
+    assert_eq!(card.security_level(), SecurityLevel::Sl2);
+    card.switch_security_level(SecurityLevel::Sl3, &[0xFF; KEY_SIZE])
+        .unwrap();
+
+    // 初期設定済みのSL3のカード
+    let sector_key_b = [0x66; KEY_SIZE];
+    let mut virtual_card = VirtualMifarePlus::new(MifarePlusSize::Plus4k);
+    virtual_card.set_security_level(SecurityLevel::Sl3);
+    virtual_card.set_key(sector_key(33, MifareKeyType::KeyB), &sector_key_b);
+    virtual_card.set_block(150, &data);
+    let nfc = MockSmartcard::new(Box::new(virtual_card));
+    let card = MifarePlus::new(&nfc, MifarePlusSize::Plus4k, SecurityLevel::Sl3);
+    assert_eq!(
+        status(
+            card.authenticate_sector(33, MifareKeyType::KeyB, &[0xFF; KEY_SIZE])
+                .unwrap_err()
+        ),
+        MifarePlusErrorKind::Status(0x06)
+    );
+    card.authenticate_sector(33, MifareKeyType::KeyB, &sector_key_b)
+        .unwrap();
+    assert_eq!(
+        card.read_block(150, CommunicationMode::Encrypted).unwrap(),
+        data
+    );
+}
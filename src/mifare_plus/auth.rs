@@ -0,0 +1,131 @@
+// MIFARE Plus SL3のAES認証
+// First Authenticate(70) KeyNo || LenCap || PCDcap2 → 90 || E(RndB)
+// Following Authenticate(76) KeyNo → 90 || E(RndB)
+// 72 || E(RndA || RndB') → 90 || E(TI || RndA' || PICCcap2 || PCDcap2)（Followingは E(RndA')）
+// 暗号化はすべてIV 0のAES-CBC。Followingは最初の認証のTIとカウンタを引き継ぐ。
+
+use super::session::{Session, TI_SIZE};
+use super::{MifarePlusError, MifarePlusErrorKind, KEY_SIZE};
+use crate::crypto::{rotate_left, Cipher, AES_BLOCK_SIZE};
+
+/// RndA・RndBの長さ
+pub const RANDOM_SIZE: usize = 16;
+/// First Authenticateの最後の応答の長さ(TI 4 || RndA' 16 || PICCcap2 6 || PCDcap2 6)
+const FIRST_RESPONSE_SIZE: usize = 32;
+/// 2回目のコマンド
+pub const CMD_AUTHENTICATE_CONTINUE: u8 = 0x72;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    /// First Authenticate(70): TIを決め、カウンタを0にする
+    First = 0x70,
+    /// Following Authenticate(76): 別のセクタの鍵で認証し直す
+    Following = 0x76,
+}
+
+impl AuthMethod {
+    pub fn from_command(command: u8) -> Option<Self> {
+        match command {
+            0x70 => Some(AuthMethod::First),
+            0x76 => Some(AuthMethod::Following),
+            _ => None,
+        }
+    }
+    /// 最初のコマンドのデータ。鍵のアドレスはリトルエンディアン、Firstは機能情報の長さ(0)を続ける
+    pub fn command_data(self, key_no: u16) -> Vec<u8> {
+        let key_no = key_no.to_le_bytes();
+        match self {
+            AuthMethod::First => vec![key_no[0], key_no[1], 0x00],
+            AuthMethod::Following => key_no.to_vec(),
+        }
+    }
+}
+
+/// PCD側の認証1回分の状態
+pub struct PlusAuth {
+    method: AuthMethod,
+    key_no: u16,
+    key: [u8; KEY_SIZE],
+    rnd_a: [u8; RANDOM_SIZE],
+    rnd_b: Vec<u8>,
+}
+
+impl PlusAuth {
+    pub fn new(
+        method: AuthMethod,
+        key_no: u16,
+        key: &[u8; KEY_SIZE],
+        rnd_a: &[u8; RANDOM_SIZE],
+    ) -> Self {
+        PlusAuth {
+            method,
+            key_no,
+            key: *key,
+            rnd_a: *rnd_a,
+            rnd_b: Vec::new(),
+        }
+    }
+    /// E(RndB)から2回目に送るデータを作る
+    pub fn challenge_response(
+        &mut self,
+        encrypted_rnd_b: &[u8],
+    ) -> Result<Vec<u8>, MifarePlusError> {
+        if encrypted_rnd_b.len() != RANDOM_SIZE {
+            return Err(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidResponseLength(encrypted_rnd_b.len()),
+            ));
+        }
+        let cipher = Cipher::aes128(&self.key);
+        let zero = [0u8; AES_BLOCK_SIZE];
+        self.rnd_b = cipher.cbc_decrypt(&zero, encrypted_rnd_b);
+        let plain = [&self.rnd_a[..], &rotate_left(&self.rnd_b)].concat();
+        Ok(cipher.cbc_encrypt(&zero, &plain))
+    }
+    /// 最後の応答を検証してセッションを作る
+    /// Followingは認証中のセッション(previous)の鍵を差し替える
+    pub fn verify(
+        &self,
+        response: &[u8],
+        previous: Option<Session>,
+    ) -> Result<Session, MifarePlusError> {
+        let expected = match self.method {
+            AuthMethod::First => FIRST_RESPONSE_SIZE,
+            AuthMethod::Following => RANDOM_SIZE,
+        };
+        if response.len() != expected {
+            return Err(MifarePlusError::new(
+                MifarePlusErrorKind::InvalidResponseLength(response.len()),
+            ));
+        }
+        let plain = Cipher::aes128(&self.key).cbc_decrypt(&[0u8; AES_BLOCK_SIZE], response);
+        let rnd_a = match self.method {
+            AuthMethod::First => &plain[TI_SIZE..TI_SIZE + RANDOM_SIZE],
+            AuthMethod::Following => &plain[..],
+        };
+        if rnd_a != &rotate_left(&self.rnd_a)[..] {
+            return Err(MifarePlusError::new(
+                MifarePlusErrorKind::AuthenticationFailed,
+            ));
+        }
+        match (self.method, previous) {
+            (AuthMethod::First, _) => {
+                let mut ti = [0u8; TI_SIZE];
+                ti.copy_from_slice(&plain[..TI_SIZE]);
+                Ok(Session::new(
+                    self.key_no,
+                    &self.key,
+                    &self.rnd_a,
+                    &self.rnd_b,
+                    ti,
+                ))
+            }
+            (AuthMethod::Following, Some(mut session)) => {
+                session.follow(self.key_no, &self.key, &self.rnd_a, &self.rnd_b);
+                Ok(session)
+            }
+            (AuthMethod::Following, None) => {
+                Err(MifarePlusError::new(MifarePlusErrorKind::NotAuthenticated))
+            }
+        }
+    }
+}
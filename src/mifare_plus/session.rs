@@ -0,0 +1,184 @@
+// MIFARE Plus SL3のセッション
+// 認証で決めたセッション鍵(Kenc/Kmac)・TI・読み出し/書き込みカウンタを保持し、
+// コマンドと応答のMAC計算・データの暗号化を行う。カウンタは応答を受けるたびに加算する。
+// PCD側とカード側(モック)の両方で使う。
+
+use super::KEY_SIZE;
+use crate::crypto::{Cipher, AES_BLOCK_SIZE};
+
+/// コマンド・応答に付けるMACの長さ
+pub const MAC_SIZE: usize = 8;
+pub const TI_SIZE: usize = 4;
+
+pub struct Session {
+    key_no: u16,
+    enc: Cipher,
+    mac: Cipher,
+    ti: [u8; TI_SIZE],
+    read_counter: u16,
+    write_counter: u16,
+}
+
+impl Session {
+    /// First Authenticateの結果から作る（カウンタは0から数える）
+    pub fn new(
+        key_no: u16,
+        key: &[u8; KEY_SIZE],
+        rnd_a: &[u8],
+        rnd_b: &[u8],
+        ti: [u8; TI_SIZE],
+    ) -> Self {
+        let (enc, mac) = session_keys(key, rnd_a, rnd_b);
+        Session {
+            key_no,
+            enc,
+            mac,
+            ti,
+            read_counter: 0,
+            write_counter: 0,
+        }
+    }
+    /// Following Authenticateの結果で鍵を更新する（TIとカウンタは引き継ぐ）
+    pub fn follow(&mut self, key_no: u16, key: &[u8; KEY_SIZE], rnd_a: &[u8], rnd_b: &[u8]) {
+        let (enc, mac) = session_keys(key, rnd_a, rnd_b);
+        self.key_no = key_no;
+        self.enc = enc;
+        self.mac = mac;
+    }
+    /// 認証に使った鍵のアドレス
+    pub fn key_no(&self) -> u16 {
+        self.key_no
+    }
+    pub fn ti(&self) -> [u8; TI_SIZE] {
+        self.ti
+    }
+    /// コマンドのMAC: cmd || Ctr || TI || header || data
+    pub fn command_mac(&self, command: u8, header: &[u8], data: &[u8]) -> [u8; MAC_SIZE] {
+        self.calculate_mac(command, self.counter(command), header, data)
+    }
+    /// 応答のMAC: status || Ctr+1 || TI || header || data
+    /// headerは読み出しコマンドのBNr・Extで、書き込みの応答では空にする
+    pub fn response_mac(
+        &self,
+        command: u8,
+        status: u8,
+        header: &[u8],
+        data: &[u8],
+    ) -> [u8; MAC_SIZE] {
+        let counter = self.counter(command).wrapping_add(1);
+        self.calculate_mac(status, counter, header, data)
+    }
+    /// 書き込むデータの暗号化。IVは TI || (R_Ctr || W_Ctr) x 3
+    pub fn encrypt_command(&self, data: &[u8]) -> Vec<u8> {
+        self.enc.cbc_encrypt(&self.command_iv(), data)
+    }
+    pub fn decrypt_command(&self, data: &[u8]) -> Vec<u8> {
+        self.enc.cbc_decrypt(&self.command_iv(), data)
+    }
+    /// 読み出したデータの暗号化。IVは加算後のカウンタで (R_Ctr || W_Ctr) x 3 || TI
+    pub fn encrypt_response(&self, command: u8, data: &[u8]) -> Vec<u8> {
+        self.enc.cbc_encrypt(&self.response_iv(command), data)
+    }
+    pub fn decrypt_response(&self, command: u8, data: &[u8]) -> Vec<u8> {
+        self.enc.cbc_decrypt(&self.response_iv(command), data)
+    }
+    /// 応答を受けたのでコマンドに対応するカウンタを進める
+    pub fn advance(&mut self, command: u8) {
+        if is_read(command) {
+            self.read_counter = self.read_counter.wrapping_add(1);
+        } else {
+            self.write_counter = self.write_counter.wrapping_add(1);
+        }
+    }
+
+    fn counter(&self, command: u8) -> u16 {
+        if is_read(command) {
+            self.read_counter
+        } else {
+            self.write_counter
+        }
+    }
+    fn calculate_mac(&self, first: u8, counter: u16, header: &[u8], data: &[u8]) -> [u8; MAC_SIZE] {
+        let input = [&[first][..], &counter.to_le_bytes(), &self.ti, header, data].concat();
+        let cmac = self.mac.cmac(&[0u8; AES_BLOCK_SIZE], &input);
+        // CMACの奇数番目のバイトを使う
+        let mut mac = [0u8; MAC_SIZE];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = cmac[i * 2 + 1];
+        }
+        mac
+    }
+    fn counters(read_counter: u16, write_counter: u16) -> Vec<u8> {
+        [read_counter.to_le_bytes(), write_counter.to_le_bytes()]
+            .repeat(3)
+            .concat()
+    }
+    fn command_iv(&self) -> Vec<u8> {
+        [
+            &self.ti[..],
+            &Self::counters(self.read_counter, self.write_counter),
+        ]
+        .concat()
+    }
+    fn response_iv(&self, command: u8) -> Vec<u8> {
+        let (read_counter, write_counter) = if is_read(command) {
+            (self.read_counter.wrapping_add(1), self.write_counter)
+        } else {
+            (self.read_counter, self.write_counter.wrapping_add(1))
+        };
+        [&Self::counters(read_counter, write_counter)[..], &self.ti].concat()
+    }
+}
+
+/// 読み出し系のコマンド(30～37)はR_Ctr、それ以外はW_Ctrを使う
+pub fn is_read(command: u8) -> bool {
+    command & 0xF8 == 0x30
+}
+
+/// Kenc = E(K, RndA[11..16] || RndB[11..16] || (RndA[4..9] ^ RndB[4..9]) || 11)
+/// Kmac = E(K, RndA[7..12] || RndB[7..12] || (RndA[0..5] ^ RndB[0..5]) || 22)
+fn session_keys(key: &[u8; KEY_SIZE], rnd_a: &[u8], rnd_b: &[u8]) -> (Cipher, Cipher) {
+    let cipher = Cipher::aes128(key);
+    let derive = |start: usize, xor_start: usize, constant: u8| {
+        let mut block = [0u8; KEY_SIZE];
+        block[0..5].copy_from_slice(&rnd_a[start..start + 5]);
+        block[5..10].copy_from_slice(&rnd_b[start..start + 5]);
+        for i in 0..5 {
+            block[10 + i] = rnd_a[xor_start + i] ^ rnd_b[xor_start + i];
+        }
+        block[15] = constant;
+        cipher.encrypt_block(&mut block);
+        Cipher::aes128(&block)
+    };
+    (derive(11, 4, 0x11), derive(7, 0, 0x22))
+}
+
+#[test]
+fn mifare_plus_session_keys() {
+    let key: [u8; KEY_SIZE] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F,
+    ];
+    let rnd_a = (0xA0..0xB0).collect::<Vec<u8>>();
+    let rnd_b = (0xB0..0xC0).collect::<Vec<u8>>();
+    let mut session = Session::new(0x4002, &key, &rnd_a, &rnd_b, [0x01, 0x02, 0x03, 0x04]);
+    // Read MACed(33) ブロック4を1ブロック
+    assert_eq!(
+        session.command_mac(0x33, &[0x04, 0x00, 0x01], &[]),
+        [0x0A, 0x20, 0x46, 0x0E, 0xC9, 0xF9, 0x19, 0xFB]
+    );
+    assert_eq!(
+        session.encrypt_command(&[0u8; 16]),
+        vec![
+            0xF4, 0x76, 0x2A, 0x84, 0x9B, 0x64, 0x82, 0xAD, 0xCF, 0xA0, 0xDB, 0x4D, 0xD4, 0xBA,
+            0x2F, 0xD6
+        ]
+    );
+    // カウンタが進むと同じコマンドでもMACが変わる
+    session.advance(0x33);
+    assert_ne!(
+        session.command_mac(0x33, &[0x04, 0x00, 0x01], &[]),
+        [0x0A, 0x20, 0x46, 0x0E, 0xC9, 0xF9, 0x19, 0xFB]
+    );
+    assert!(is_read(0x36) && !is_read(0xA1));
+}
@@ -0,0 +1,525 @@
+// NDEF (NFC Data Exchange Format) のメッセージとレコード
+// タグ(Type 1～4)から読み出したNDEFメッセージを解析し、書き込むバイト列を組み立てる。
+// タグのデータは信頼できないので、長さフィールドは必ず残りのデータと突き合わせてから使う。
+// 分割(チャンク)されたレコードは解析時に1つのレコードへまとめる。
+
+pub mod handover;
+pub mod smart_poster;
+pub mod text;
+pub mod tlv;
+pub mod uri;
+use handover::{BluetoothOob, Handover, WifiCredential, BLUETOOTH_MIME_TYPE, WIFI_MIME_TYPE};
+use smart_poster::SmartPoster;
+use text::TextRecord;
+use uri::UriRecord;
+
+// レコードヘッダのフラグ
+/// Message Begin
+const FLAG_MB: u8 = 0x80;
+/// Message End
+const FLAG_ME: u8 = 0x40;
+/// Chunk Flag（続きのチャンクがある）
+const FLAG_CF: u8 = 0x20;
+/// Short Record（ペイロード長が1バイト）
+const FLAG_SR: u8 = 0x10;
+/// ID Lengthフィールドがある
+const FLAG_IL: u8 = 0x08;
+const TNF_MASK: u8 = 0x07;
+
+/// Android Application Recordのタイプ
+pub const ANDROID_APPLICATION_TYPE: &str = "android.com:pkg";
+
+/// Type Name Format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tnf {
+    Empty = 0x00,
+    /// NFC Forum Well-Known Type (T, U, Sp など)
+    WellKnown = 0x01,
+    /// RFC 2046のメディアタイプ
+    Media = 0x02,
+    AbsoluteUri = 0x03,
+    /// NFC Forum External Type (domain:type)
+    External = 0x04,
+    Unknown = 0x05,
+    /// 分割されたレコードの2つ目以降のチャンク
+    Unchanged = 0x06,
+    Reserved = 0x07,
+}
+
+impl Tnf {
+    fn from_bits(value: u8) -> Self {
+        match value & TNF_MASK {
+            0x00 => Tnf::Empty,
+            0x01 => Tnf::WellKnown,
+            0x02 => Tnf::Media,
+            0x03 => Tnf::AbsoluteUri,
+            0x04 => Tnf::External,
+            0x05 => Tnf::Unknown,
+            0x06 => Tnf::Unchanged,
+            _ => Tnf::Reserved,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NdefRecord {
+    pub tnf: Tnf,
+    pub record_type: Vec<u8>,
+    pub id: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl NdefRecord {
+    pub fn new(tnf: Tnf, record_type: &[u8], id: &[u8], payload: &[u8]) -> Self {
+        NdefRecord {
+            tnf,
+            record_type: record_type.to_vec(),
+            id: id.to_vec(),
+            payload: payload.to_vec(),
+        }
+    }
+    /// 空のNDEFメッセージに使うレコード
+    pub fn empty() -> Self {
+        Self::new(Tnf::Empty, &[], &[], &[])
+    }
+    pub fn well_known(record_type: &[u8], payload: &[u8]) -> Self {
+        Self::new(Tnf::WellKnown, record_type, &[], payload)
+    }
+    pub fn mime(mime_type: &str, data: &[u8]) -> Self {
+        Self::new(Tnf::Media, mime_type.as_bytes(), &[], data)
+    }
+    pub fn absolute_uri(uri: &str) -> Self {
+        Self::new(Tnf::AbsoluteUri, uri.as_bytes(), &[], &[])
+    }
+    /// External Typeのタイプ名は大文字小文字を区別しないので小文字で格納する
+    pub fn external(domain_type: &str, payload: &[u8]) -> Self {
+        Self::new(
+            Tnf::External,
+            domain_type.to_ascii_lowercase().as_bytes(),
+            &[],
+            payload,
+        )
+    }
+    pub fn text(language: &str, text: &str) -> Self {
+        TextRecord::new(language, text).to_record()
+    }
+    pub fn uri(uri: &str) -> Self {
+        UriRecord::new(uri).to_record()
+    }
+    /// Android Application Record（指定したパッケージのアプリで開かせる）
+    pub fn android_application(package: &str) -> Self {
+        Self::external(ANDROID_APPLICATION_TYPE, package.as_bytes())
+    }
+    pub fn with_id(mut self, id: &[u8]) -> Self {
+        self.id = id.to_vec();
+        self
+    }
+    /// Well-Known Typeは大文字小文字を区別し、メディアタイプ・External Typeは区別しない
+    pub fn is_type(&self, tnf: Tnf, record_type: &[u8]) -> bool {
+        self.tnf == tnf
+            && match tnf {
+                Tnf::Media | Tnf::External => self.record_type.eq_ignore_ascii_case(record_type),
+                _ => self.record_type == record_type,
+            }
+    }
+    /// レコードの種類に合わせてペイロードを解析する
+    pub fn content(&self) -> Result<RecordContent, NdefError> {
+        let content = match self.tnf {
+            Tnf::Empty => RecordContent::Empty,
+            Tnf::WellKnown => match &self.record_type[..] {
+                b"T" => RecordContent::Text(TextRecord::from_payload(&self.payload)?),
+                b"U" => RecordContent::Uri(UriRecord::from_payload(&self.payload)?),
+                b"Sp" => RecordContent::SmartPoster(SmartPoster::from_payload(&self.payload)?),
+                b"Hs" | b"Hr" => RecordContent::Handover(Handover::from_payload(&self.payload)?),
+                _ => RecordContent::Unknown,
+            },
+            Tnf::Media if self.is_type(Tnf::Media, WIFI_MIME_TYPE.as_bytes()) => {
+                RecordContent::WifiCredential(WifiCredential::from_payload(&self.payload)?)
+            }
+            Tnf::Media if self.is_type(Tnf::Media, BLUETOOTH_MIME_TYPE.as_bytes()) => {
+                RecordContent::Bluetooth(BluetoothOob::from_payload(&self.payload)?)
+            }
+            Tnf::Media => RecordContent::Mime {
+                mime_type: utf8(&self.record_type)?,
+                data: self.payload.clone(),
+            },
+            Tnf::External if self.is_type(Tnf::External, ANDROID_APPLICATION_TYPE.as_bytes()) => {
+                RecordContent::AndroidApplication(utf8(&self.payload)?)
+            }
+            Tnf::External => RecordContent::External {
+                domain_type: utf8(&self.record_type)?,
+                payload: self.payload.clone(),
+            },
+            Tnf::AbsoluteUri => RecordContent::AbsoluteUri(utf8(&self.record_type)?),
+            _ => RecordContent::Unknown,
+        };
+        Ok(content)
+    }
+    /// レコードを1つ以上のチャンクに分けて書き出す
+    /// chunk_sizeがNoneなら分割しない
+    fn encode(&self, first: bool, last: bool, chunk_size: Option<usize>, out: &mut Vec<u8>) {
+        let chunks = match chunk_size {
+            Some(size) if size > 0 && self.payload.len() > size => {
+                self.payload.chunks(size).collect::<Vec<_>>()
+            }
+            _ => vec![&self.payload[..]],
+        };
+        let count = chunks.len();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let mut header = 0u8;
+            if first && i == 0 {
+                header |= FLAG_MB;
+            }
+            if last && i == count - 1 {
+                header |= FLAG_ME;
+            }
+            if i < count - 1 {
+                header |= FLAG_CF;
+            }
+            if chunk.len() < 0x100 {
+                header |= FLAG_SR;
+            }
+            // 2つ目以降のチャンクはタイプとIDを持たない
+            let (tnf, record_type, id) = if i == 0 {
+                (self.tnf, &self.record_type[..], &self.id[..])
+            } else {
+                (Tnf::Unchanged, &[][..], &[][..])
+            };
+            if !id.is_empty() {
+                header |= FLAG_IL;
+            }
+            out.push(header | tnf as u8);
+            out.push(record_type.len() as u8);
+            if chunk.len() < 0x100 {
+                out.push(chunk.len() as u8);
+            } else {
+                out.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            }
+            if !id.is_empty() {
+                out.push(id.len() as u8);
+            }
+            out.extend_from_slice(record_type);
+            out.extend_from_slice(id);
+            out.extend_from_slice(chunk);
+        }
+    }
+}
+
+/// 解析したレコードの内容
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordContent {
+    Empty,
+    Text(TextRecord),
+    Uri(UriRecord),
+    SmartPoster(SmartPoster),
+    /// Handover Select(Hs) / Handover Request(Hr)
+    Handover(Handover),
+    WifiCredential(WifiCredential),
+    Bluetooth(BluetoothOob),
+    /// パッケージ名
+    AndroidApplication(String),
+    Mime {
+        mime_type: String,
+        data: Vec<u8>,
+    },
+    External {
+        domain_type: String,
+        payload: Vec<u8>,
+    },
+    AbsoluteUri(String),
+    /// 解析できないタイプ
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NdefMessage {
+    pub records: Vec<NdefRecord>,
+}
+
+impl NdefMessage {
+    pub fn new(records: Vec<NdefRecord>) -> Self {
+        NdefMessage { records }
+    }
+    /// 空のNDEFメッセージ（Emptyのレコード1つ）
+    pub fn empty() -> Self {
+        Self::new(vec![NdefRecord::empty()])
+    }
+    /// 空のレコードだけ、またはレコードがない
+    pub fn is_empty(&self) -> bool {
+        self.records.iter().all(|record| record.tnf == Tnf::Empty)
+    }
+    pub fn from_bytes(data: &[u8]) -> Result<Self, NdefError> {
+        let mut reader = Reader { data, offset: 0 };
+        let mut records = Vec::new();
+        // 分割されたレコードの途中
+        let mut chunked: Option<NdefRecord> = None;
+        loop {
+            let offset = reader.offset;
+            let header = reader.byte()?;
+            let first = offset == 0;
+            if first != (header & FLAG_MB != 0) {
+                return Err(NdefError::new(NdefErrorKind::InvalidHeader(offset)));
+            }
+            let type_len = reader.byte()? as usize;
+            let payload_len = if header & FLAG_SR != 0 {
+                reader.byte()? as usize
+            } else {
+                let mut len = [0u8; 4];
+                len.copy_from_slice(reader.take(4)?);
+                u32::from_be_bytes(len) as usize
+            };
+            let id_len = if header & FLAG_IL != 0 {
+                reader.byte()? as usize
+            } else {
+                0
+            };
+            let record_type = reader.take(type_len)?;
+            let id = reader.take(id_len)?;
+            let payload = reader.take(payload_len)?;
+            let tnf = Tnf::from_bits(header);
+            let is_chunk = header & FLAG_CF != 0;
+            match chunked.as_mut() {
+                Some(record) => {
+                    // 2つ目以降のチャンクはUnchangedで、タイプとIDを持たない
+                    if tnf != Tnf::Unchanged || type_len != 0 || id_len != 0 {
+                        return Err(NdefError::new(NdefErrorKind::InvalidChunk(offset)));
+                    }
+                    record.payload.extend_from_slice(payload);
+                    if !is_chunk {
+                        records.extend(chunked.take());
+                    }
+                }
+                None => {
+                    let valid = match tnf {
+                        Tnf::Unchanged | Tnf::Reserved => false,
+                        Tnf::Empty => type_len == 0 && id_len == 0 && payload_len == 0,
+                        // Unknownはタイプを持たない
+                        Tnf::Unknown => type_len == 0,
+                        _ => type_len != 0,
+                    };
+                    if !valid {
+                        return Err(NdefError::new(NdefErrorKind::InvalidHeader(offset)));
+                    }
+                    let record = NdefRecord::new(tnf, record_type, id, payload);
+                    if is_chunk {
+                        chunked = Some(record);
+                    } else {
+                        records.push(record);
+                    }
+                }
+            }
+            if header & FLAG_ME != 0 {
+                if chunked.is_some() {
+                    return Err(NdefError::new(NdefErrorKind::InvalidChunk(offset)));
+                }
+                if reader.offset != data.len() {
+                    return Err(NdefError::new(NdefErrorKind::TrailingData(reader.offset)));
+                }
+                return Ok(Self::new(records));
+            }
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode(None)
+    }
+    /// ペイロードをchunk_sizeバイトごとのチャンクに分けて書き出す
+    pub fn to_bytes_chunked(&self, chunk_size: usize) -> Vec<u8> {
+        self.encode(Some(chunk_size))
+    }
+    fn encode(&self, chunk_size: Option<usize>) -> Vec<u8> {
+        if self.records.is_empty() {
+            return Self::empty().encode(chunk_size);
+        }
+        let mut out = Vec::new();
+        let last = self.records.len() - 1;
+        for (i, record) in self.records.iter().enumerate() {
+            record.encode(i == 0, i == last, chunk_size, &mut out);
+        }
+        out
+    }
+}
+
+/// 長さを確かめながら読み進める
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, NdefError> {
+        Ok(self.take(1)?[0])
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NdefError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| NdefError::new(NdefErrorKind::Truncated(self.offset)))?;
+        let slice = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+}
+
+fn utf8(data: &[u8]) -> Result<String, NdefError> {
+    String::from_utf8(data.to_vec()).map_err(|_| NdefError::new(NdefErrorKind::InvalidEncoding))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NdefErrorKind {
+    /// 指定の位置で長さが足りない
+    Truncated(usize),
+    /// MBの位置が正しくない、またはTNFとタイプ長の組み合わせが正しくない
+    InvalidHeader(usize),
+    /// チャンクの並びが正しくない
+    InvalidChunk(usize),
+    /// MEのレコードの後にデータが続いている
+    TrailingData(usize),
+    /// ペイロードの形式が正しくない
+    InvalidPayload,
+    /// 文字列がUTF-8/UTF-16として正しくない
+    InvalidEncoding,
+    /// 指定の位置のTLVがデータエリアに収まっていない
+    InvalidTlv(usize),
+    /// タグの容量を超える（格納できる最大のバイト数）
+    MessageTooLarge(usize),
+}
+
+#[derive(Debug)]
+pub struct NdefError {
+    code: NdefErrorKind,
+}
+
+impl NdefError {
+    pub fn new(code: NdefErrorKind) -> Self {
+        NdefError { code }
+    }
+    pub fn kind(&self) -> &NdefErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for NdefError {}
+impl std::fmt::Display for NdefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn ndef_message_round_trip() {
+    // Text "Hello"(en) と URI https://www.nfc.com
+    let data = [
+        0x91, 0x01, 0x08, 0x54, 0x02, 0x65, 0x6E, 0x48, 0x65, 0x6C, 0x6C, 0x6F, 0x51, 0x01, 0x08,
+        0x55, 0x02, 0x6E, 0x66, 0x63, 0x2E, 0x63, 0x6F, 0x6D,
+    ];
+    let message = NdefMessage::from_bytes(&data).unwrap();
+    assert_eq!(message.records.len(), 2);
+    assert_eq!(
+        message.records[0].content().unwrap(),
+        RecordContent::Text(TextRecord::new("en", "Hello"))
+    );
+    assert_eq!(
+        message.records[1].content().unwrap(),
+        RecordContent::Uri(UriRecord::new("https://www.nfc.com"))
+    );
+    assert_eq!(message.to_bytes(), data);
+
+    // ID付き・4バイト長のペイロード・チャンク
+    let message = NdefMessage::new(vec![
+        NdefRecord::mime("image/png", &[0x5A; 300]).with_id(b"icon"),
+        NdefRecord::android_application("com.example.app"),
+        NdefRecord::external("Example.com:Kind", &[0x01, 0x02]),
+        NdefRecord::absolute_uri("https://example.com/"),
+    ]);
+    let data = message.to_bytes();
+    assert_eq!(data[0], FLAG_MB | FLAG_IL | Tnf::Media as u8);
+    assert_eq!(NdefMessage::from_bytes(&data).unwrap(), message);
+    let chunked = message.to_bytes_chunked(100);
+    assert_eq!(chunked[0] & FLAG_CF, FLAG_CF);
+    assert_eq!(NdefMessage::from_bytes(&chunked).unwrap(), message);
+    assert_eq!(
+        message.records[1].content().unwrap(),
+        RecordContent::AndroidApplication("com.example.app".to_owned())
+    );
+    assert_eq!(
+        message.records[2].content().unwrap(),
+        RecordContent::External {
+            domain_type: "example.com:kind".to_owned(),
+            payload: vec![0x01, 0x02]
+        }
+    );
+
+    assert_eq!(NdefMessage::default().to_bytes(), [0xD0, 0x00, 0x00]);
+    assert!(NdefMessage::from_bytes(&[0xD0, 0x00, 0x00])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn ndef_untrusted_input() {
+    let kind = |data: &[u8]| NdefMessage::from_bytes(data).unwrap_err().kind().clone();
+    // MBがない・MEの後にデータ・途中で終わるチャンク・巨大なペイロード長
+    assert_eq!(
+        kind(&[0x51, 0x01, 0x00, 0x54]),
+        NdefErrorKind::InvalidHeader(0)
+    );
+    assert_eq!(
+        kind(&[0xD1, 0x01, 0x00, 0x54, 0x00]),
+        NdefErrorKind::TrailingData(4)
+    );
+    assert_eq!(
+        kind(&[0xF1, 0x01, 0x01, 0x54, 0x00]),
+        NdefErrorKind::InvalidChunk(0)
+    );
+    assert_eq!(
+        kind(&[0xC1, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0x54]),
+        NdefErrorKind::Truncated(7)
+    );
+    assert_eq!(
+        kind(&[0xB1, 0x01, 0x01, 0x54, 0x00, 0x51, 0x01, 0x00, 0x54]),
+        NdefErrorKind::InvalidChunk(5)
+    );
+
+    // 正しいメッセージの切り詰め・ビット反転・疑似乱数列で解析が失敗してもパニックしない
+    let message = NdefMessage::new(vec![
+        smart_poster::SmartPoster::new("https://example.com").to_record(),
+        NdefRecord::text("ja", "こんにちは"),
+        NdefRecord::mime(
+            WIFI_MIME_TYPE,
+            &WifiCredential::new(b"ssid", b"password").to_payload(),
+        ),
+    ]);
+    let data = message.to_bytes_chunked(7);
+    let parse_all = |data: &[u8]| {
+        if let Ok(message) = NdefMessage::from_bytes(data) {
+            for record in &message.records {
+                let _ = record.content();
+            }
+        }
+    };
+    for len in 0..data.len() {
+        parse_all(&data[..len]);
+    }
+    for bit in 0..data.len() * 8 {
+        let mut corrupted = data.clone();
+        corrupted[bit / 8] ^= 1 << (bit % 8);
+        parse_all(&corrupted);
+    }
+    let mut seed = 0x1234_5678u32;
+    for _ in 0..2000 {
+        let random = (0..64)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect::<Vec<u8>>();
+        parse_all(&random);
+        // レコードの中身だけが壊れている場合
+        for tnf in 1..5u8 {
+            for record_type in [&b"T"[..], b"U", b"Sp", b"Hs", WIFI_MIME_TYPE.as_bytes()] {
+                let record = NdefRecord::new(Tnf::from_bits(tnf), record_type, &[], &random);
+                let _ = record.content();
+            }
+        }
+    }
+}
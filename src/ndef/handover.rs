@@ -0,0 +1,464 @@
+// Connection Handover（NFCでWi-Fi・Bluetoothの接続情報を渡す）
+// Handover Select(Hs) / Handover Request(Hr): バージョン(1バイト) || NDEFメッセージ
+//   Alternative Carrier(ac): CPS || CDR長 || CDR || 補助データ数 || (長さ || 参照)...
+//   CDR・補助データの参照先は同じメッセージにあるレコードのID
+// 搬送波の設定レコード
+//   Wi-Fi: application/vnd.wfa.wsc（WSCのCredential属性）
+//   Bluetooth: application/vnd.bluetooth.ep.oob（OOBデータ長 || BD_ADDR || EIR）
+
+use super::{NdefError, NdefErrorKind, NdefMessage, NdefRecord, Tnf};
+
+pub const WIFI_MIME_TYPE: &str = "application/vnd.wfa.wsc";
+pub const BLUETOOTH_MIME_TYPE: &str = "application/vnd.bluetooth.ep.oob";
+/// Connection Handover 1.2
+pub const HANDOVER_VERSION: u8 = 0x12;
+
+// WSCの属性
+const WSC_VERSION: u16 = 0x104A;
+const WSC_CREDENTIAL: u16 = 0x100E;
+const WSC_NETWORK_INDEX: u16 = 0x1026;
+const WSC_SSID: u16 = 0x1045;
+const WSC_AUTH_TYPE: u16 = 0x1003;
+const WSC_ENCRYPTION_TYPE: u16 = 0x100F;
+const WSC_NETWORK_KEY: u16 = 0x1027;
+const WSC_MAC_ADDRESS: u16 = 0x1020;
+const WSC_VENDOR_EXTENSION: u16 = 0x1049;
+/// WFAのベンダー拡張(OUI 00 37 2A) でVersion2 = 2.0
+const WSC_WFA_VERSION2: [u8; 6] = [0x00, 0x37, 0x2A, 0x00, 0x01, 0x20];
+
+// WSCの認証方式
+pub const WIFI_AUTH_OPEN: u16 = 0x0001;
+pub const WIFI_AUTH_WPA_PSK: u16 = 0x0002;
+pub const WIFI_AUTH_WPA2_PSK: u16 = 0x0020;
+pub const WIFI_AUTH_WPA_WPA2_PSK: u16 = 0x0022;
+// WSCの暗号化方式
+pub const WIFI_ENCRYPTION_NONE: u16 = 0x0001;
+pub const WIFI_ENCRYPTION_WEP: u16 = 0x0002;
+pub const WIFI_ENCRYPTION_TKIP: u16 = 0x0004;
+pub const WIFI_ENCRYPTION_AES: u16 = 0x0008;
+pub const WIFI_ENCRYPTION_AES_TKIP: u16 = 0x000C;
+
+// EIRのデータタイプ
+const EIR_SHORT_LOCAL_NAME: u8 = 0x08;
+const EIR_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const EIR_CLASS_OF_DEVICE: u8 = 0x0D;
+/// OOBデータ長 2 || BD_ADDR 6
+const BLUETOOTH_OOB_HEADER_SIZE: usize = 8;
+
+/// Carrier Power State
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CarrierPowerState {
+    Inactive = 0x00,
+    Active = 0x01,
+    Activating = 0x02,
+    Unknown = 0x03,
+}
+
+impl CarrierPowerState {
+    fn from_byte(value: u8) -> Self {
+        match value & 0x03 {
+            0x00 => CarrierPowerState::Inactive,
+            0x01 => CarrierPowerState::Active,
+            0x02 => CarrierPowerState::Activating,
+            _ => CarrierPowerState::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlternativeCarrier {
+    pub power_state: CarrierPowerState,
+    /// 搬送波の設定レコードのID
+    pub carrier_data_reference: Vec<u8>,
+    pub auxiliary_data_references: Vec<Vec<u8>>,
+}
+
+impl AlternativeCarrier {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let mut reader = super::Reader {
+            data: payload,
+            offset: 0,
+        };
+        let invalid = |_| NdefError::new(NdefErrorKind::InvalidPayload);
+        let power_state = CarrierPowerState::from_byte(reader.byte().map_err(invalid)?);
+        let len = reader.byte().map_err(invalid)? as usize;
+        let carrier_data_reference = reader.take(len).map_err(invalid)?.to_vec();
+        let count = reader.byte().map_err(invalid)?;
+        let mut auxiliary_data_references = Vec::new();
+        for _ in 0..count {
+            let len = reader.byte().map_err(invalid)? as usize;
+            auxiliary_data_references.push(reader.take(len).map_err(invalid)?.to_vec());
+        }
+        Ok(AlternativeCarrier {
+            power_state,
+            carrier_data_reference,
+            auxiliary_data_references,
+        })
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![self.power_state as u8];
+        push_reference(&mut payload, &self.carrier_data_reference);
+        payload.push(self.auxiliary_data_references.len() as u8);
+        for reference in &self.auxiliary_data_references {
+            push_reference(&mut payload, reference);
+        }
+        payload
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"ac", &self.to_payload())
+    }
+}
+
+fn push_reference(payload: &mut Vec<u8>, reference: &[u8]) {
+    let reference = &reference[..reference.len().min(0xFF)];
+    payload.push(reference.len() as u8);
+    payload.extend_from_slice(reference);
+}
+
+/// Handover Select / Handover Requestのレコード
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handover {
+    /// 上位4ビットがメジャー、下位4ビットがマイナー
+    pub version: u8,
+    /// Collision Resolution(cr)の乱数。Handover Requestのみ
+    pub collision_resolution: Option<u16>,
+    pub carriers: Vec<AlternativeCarrier>,
+}
+
+impl Handover {
+    pub fn new(carriers: Vec<AlternativeCarrier>) -> Self {
+        Handover {
+            version: HANDOVER_VERSION,
+            collision_resolution: None,
+            carriers,
+        }
+    }
+    /// 知らないレコード(err など)は読み飛ばす
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let (version, message) = payload
+            .split_first()
+            .ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        let mut handover = Handover {
+            version: *version,
+            collision_resolution: None,
+            carriers: Vec::new(),
+        };
+        // 搬送波がなければメッセージを持たない
+        if message.is_empty() {
+            return Ok(handover);
+        }
+        for record in NdefMessage::from_bytes(message)?.records {
+            match (record.tnf, &record.record_type[..]) {
+                (Tnf::WellKnown, b"ac") => {
+                    handover
+                        .carriers
+                        .push(AlternativeCarrier::from_payload(&record.payload)?);
+                }
+                (Tnf::WellKnown, b"cr") => {
+                    if record.payload.len() != 2 {
+                        return Err(NdefError::new(NdefErrorKind::InvalidPayload));
+                    }
+                    handover.collision_resolution =
+                        Some(u16::from_be_bytes([record.payload[0], record.payload[1]]));
+                }
+                _ => {}
+            }
+        }
+        Ok(handover)
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(random) = self.collision_resolution {
+            records.push(NdefRecord::well_known(b"cr", &random.to_be_bytes()));
+        }
+        records.extend(self.carriers.iter().map(|carrier| carrier.to_record()));
+        let mut payload = vec![self.version];
+        if !records.is_empty() {
+            payload.extend(NdefMessage::new(records).to_bytes());
+        }
+        payload
+    }
+    pub fn select_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"Hs", &self.to_payload())
+    }
+    pub fn request_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"Hr", &self.to_payload())
+    }
+    /// Hsと搬送波の設定レコードからなる静的ハンドオーバーのメッセージを作る
+    /// IDのない設定レコードには連番のIDを付ける
+    pub fn select_message(carriers: &[(CarrierPowerState, NdefRecord)]) -> NdefMessage {
+        let configurations = carriers
+            .iter()
+            .enumerate()
+            .map(|(i, (_, record))| {
+                if record.id.is_empty() {
+                    record.clone().with_id(i.to_string().as_bytes())
+                } else {
+                    record.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        let alternatives = carriers
+            .iter()
+            .zip(configurations.iter())
+            .map(|((power_state, _), record)| AlternativeCarrier {
+                power_state: *power_state,
+                carrier_data_reference: record.id.clone(),
+                auxiliary_data_references: Vec::new(),
+            })
+            .collect();
+        let mut records = vec![Handover::new(alternatives).select_record()];
+        records.extend(configurations);
+        NdefMessage::new(records)
+    }
+}
+
+/// Wi-Fiの接続情報（WSCのCredential）
+#[derive(Debug, Clone, PartialEq)]
+pub struct WifiCredential {
+    pub ssid: Vec<u8>,
+    pub network_key: Vec<u8>,
+    pub auth_type: u16,
+    pub encryption_type: u16,
+    /// 特定しないときはFF:FF:FF:FF:FF:FF
+    pub mac_address: [u8; 6],
+}
+
+impl WifiCredential {
+    /// WPA2-PSK / AES
+    pub fn new(ssid: &[u8], network_key: &[u8]) -> Self {
+        WifiCredential {
+            ssid: ssid.to_vec(),
+            network_key: network_key.to_vec(),
+            auth_type: WIFI_AUTH_WPA2_PSK,
+            encryption_type: WIFI_ENCRYPTION_AES,
+            mac_address: [0xFF; 6],
+        }
+    }
+    /// 最初のCredential属性を読む。SSIDは必須
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let credential = wsc_attributes(payload)?
+            .into_iter()
+            .find(|(id, _)| *id == WSC_CREDENTIAL)
+            .ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?
+            .1;
+        let mut ssid = None;
+        let mut wifi = WifiCredential::new(&[], &[]);
+        for (id, value) in wsc_attributes(credential)? {
+            match (id, value.len()) {
+                (WSC_SSID, _) => ssid = Some(value.to_vec()),
+                (WSC_NETWORK_KEY, _) => wifi.network_key = value.to_vec(),
+                (WSC_AUTH_TYPE, 2) => wifi.auth_type = u16::from_be_bytes([value[0], value[1]]),
+                (WSC_ENCRYPTION_TYPE, 2) => {
+                    wifi.encryption_type = u16::from_be_bytes([value[0], value[1]])
+                }
+                (WSC_MAC_ADDRESS, 6) => wifi.mac_address.copy_from_slice(value),
+                _ => {}
+            }
+        }
+        wifi.ssid = ssid.ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        Ok(wifi)
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut credential = Vec::new();
+        push_wsc_attribute(&mut credential, WSC_NETWORK_INDEX, &[0x01]);
+        push_wsc_attribute(&mut credential, WSC_SSID, &self.ssid);
+        push_wsc_attribute(
+            &mut credential,
+            WSC_AUTH_TYPE,
+            &self.auth_type.to_be_bytes(),
+        );
+        push_wsc_attribute(
+            &mut credential,
+            WSC_ENCRYPTION_TYPE,
+            &self.encryption_type.to_be_bytes(),
+        );
+        push_wsc_attribute(&mut credential, WSC_NETWORK_KEY, &self.network_key);
+        push_wsc_attribute(&mut credential, WSC_MAC_ADDRESS, &self.mac_address);
+        let mut payload = Vec::new();
+        push_wsc_attribute(&mut payload, WSC_VERSION, &[0x10]);
+        push_wsc_attribute(&mut payload, WSC_CREDENTIAL, &credential);
+        push_wsc_attribute(&mut payload, WSC_VENDOR_EXTENSION, &WSC_WFA_VERSION2);
+        payload
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::mime(WIFI_MIME_TYPE, &self.to_payload())
+    }
+}
+
+/// WSCの属性(ID 2バイト || 長さ 2バイト || 値)を並べる
+fn wsc_attributes(data: &[u8]) -> Result<Vec<(u16, &[u8])>, NdefError> {
+    let mut reader = super::Reader { data, offset: 0 };
+    let mut attributes = Vec::new();
+    while reader.offset < data.len() {
+        let header = reader
+            .take(4)
+            .map_err(|_| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let value = reader
+            .take(len)
+            .map_err(|_| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        attributes.push((id, value));
+    }
+    Ok(attributes)
+}
+
+fn push_wsc_attribute(out: &mut Vec<u8>, id: u16, value: &[u8]) {
+    let value = &value[..value.len().min(0xFFFF)];
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EirData {
+    pub data_type: u8,
+    pub data: Vec<u8>,
+}
+
+/// Bluetooth BR/EDRの接続情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct BluetoothOob {
+    /// 表記どおりの順(00:11:22:33:44:55 なら [0x00, 0x11, ...])
+    pub address: [u8; 6],
+    pub local_name: Option<String>,
+    pub class_of_device: Option<[u8; 3]>,
+    /// 名前・デバイスクラス以外のEIR
+    pub eir: Vec<EirData>,
+}
+
+impl BluetoothOob {
+    pub fn new(address: [u8; 6]) -> Self {
+        BluetoothOob {
+            address,
+            local_name: None,
+            class_of_device: None,
+            eir: Vec::new(),
+        }
+    }
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let invalid = || NdefError::new(NdefErrorKind::InvalidPayload);
+        if payload.len() < BLUETOOTH_OOB_HEADER_SIZE {
+            return Err(invalid());
+        }
+        // OOBデータ長は自身を含む全体の長さ
+        let len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+        if len < BLUETOOTH_OOB_HEADER_SIZE || len > payload.len() {
+            return Err(invalid());
+        }
+        let mut address = [0u8; 6];
+        address.copy_from_slice(&payload[2..BLUETOOTH_OOB_HEADER_SIZE]);
+        address.reverse();
+        let mut oob = BluetoothOob::new(address);
+        let mut reader = super::Reader {
+            data: &payload[..len],
+            offset: BLUETOOTH_OOB_HEADER_SIZE,
+        };
+        while reader.offset < len {
+            // EIRの長さはデータタイプを含む。0は残りが埋め草
+            let eir_len = reader.byte()? as usize;
+            if eir_len == 0 {
+                break;
+            }
+            let eir = reader.take(eir_len).map_err(|_| invalid())?;
+            let (data_type, data) = (eir[0], &eir[1..]);
+            match data_type {
+                EIR_SHORT_LOCAL_NAME | EIR_COMPLETE_LOCAL_NAME => {
+                    oob.local_name = Some(super::utf8(data)?);
+                }
+                EIR_CLASS_OF_DEVICE if data.len() == 3 => {
+                    let mut class = [0u8; 3];
+                    class.copy_from_slice(data);
+                    oob.class_of_device = Some(class);
+                }
+                _ => oob.eir.push(EirData {
+                    data_type,
+                    data: data.to_vec(),
+                }),
+            }
+        }
+        Ok(oob)
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![0x00, 0x00];
+        payload.extend(self.address.iter().rev());
+        if let Some(name) = &self.local_name {
+            push_eir(&mut payload, EIR_COMPLETE_LOCAL_NAME, name.as_bytes());
+        }
+        if let Some(class) = &self.class_of_device {
+            push_eir(&mut payload, EIR_CLASS_OF_DEVICE, class);
+        }
+        for eir in &self.eir {
+            push_eir(&mut payload, eir.data_type, &eir.data);
+        }
+        let len = (payload.len() as u16).to_le_bytes();
+        payload[..2].copy_from_slice(&len);
+        payload
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::mime(BLUETOOTH_MIME_TYPE, &self.to_payload())
+    }
+}
+
+fn push_eir(out: &mut Vec<u8>, data_type: u8, data: &[u8]) {
+    let data = &data[..data.len().min(0xFE)];
+    out.push(data.len() as u8 + 1);
+    out.push(data_type);
+    out.extend_from_slice(data);
+}
+
+#[test]
+fn ndef_connection_handover() {
+    use super::RecordContent;
+
+    let mut wifi = WifiCredential::new(b"MyNetwork", b"secret123");
+    wifi.mac_address = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+    let mut bluetooth = BluetoothOob::new([0x00, 0x1B, 0xDC, 0x06, 0x8A, 0x9F]);
+    bluetooth.local_name = Some("Headset".to_owned());
+    bluetooth.class_of_device = Some([0x04, 0x04, 0x24]);
+    let payload = bluetooth.to_payload();
+    assert_eq!(
+        payload[..10],
+        [0x16, 0x00, 0x9F, 0x8A, 0x06, 0xDC, 0x1B, 0x00, 0x08, 0x09]
+    );
+
+    let message = Handover::select_message(&[
+        (CarrierPowerState::Active, wifi.to_record()),
+        (CarrierPowerState::Activating, bluetooth.to_record()),
+    ]);
+    let parsed = NdefMessage::from_bytes(&message.to_bytes()).unwrap();
+    assert_eq!(parsed, message);
+    let handover = match parsed.records[0].content().unwrap() {
+        RecordContent::Handover(handover) => handover,
+        content => panic!("{:?}", content),
+    };
+    assert_eq!(handover.version, HANDOVER_VERSION);
+    assert_eq!(handover.carriers.len(), 2);
+    assert_eq!(
+        handover.carriers[1].power_state,
+        CarrierPowerState::Activating
+    );
+    // acの参照先のレコード
+    let record = parsed
+        .records
+        .iter()
+        .find(|record| record.id == handover.carriers[1].carrier_data_reference)
+        .unwrap();
+    assert_eq!(
+        record.content().unwrap(),
+        RecordContent::Bluetooth(bluetooth)
+    );
+    assert_eq!(
+        parsed.records[1].content().unwrap(),
+        RecordContent::WifiCredential(wifi)
+    );
+
+    let mut request = Handover::new(Vec::new());
+    request.collision_resolution = Some(0x1234);
+    assert_eq!(
+        Handover::from_payload(&request.request_record().payload).unwrap(),
+        request
+    );
+    assert_eq!(Handover::new(Vec::new()).to_payload(), [HANDOVER_VERSION]);
+}
@@ -0,0 +1,169 @@
+// Well-Known Type "Sp"（スマートポスター）
+// ペイロードはNDEFメッセージで、URI(U)を1つと任意のタイトル(T)、アクション(act)、
+// サイズ(s)、タイプ(t)、アイコン(画像・動画のMIMEレコード)を含む
+
+use super::text::TextRecord;
+use super::uri::UriRecord;
+use super::{NdefError, NdefErrorKind, NdefMessage, NdefRecord, RecordContent, Tnf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmartPosterAction {
+    /// 開く・電話をかけるなど
+    Execute = 0x00,
+    /// 後で使うために保存する
+    Save = 0x01,
+    /// 編集のために開く
+    Edit = 0x02,
+}
+
+impl SmartPosterAction {
+    fn from_byte(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(SmartPosterAction::Execute),
+            0x01 => Some(SmartPosterAction::Save),
+            0x02 => Some(SmartPosterAction::Edit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmartPosterIcon {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmartPoster {
+    pub uri: String,
+    /// 言語ごとのタイトル
+    pub titles: Vec<TextRecord>,
+    pub action: Option<SmartPosterAction>,
+    /// URIの先にあるコンテンツのバイト数
+    pub size: Option<u32>,
+    /// URIの先にあるコンテンツのMIMEタイプ
+    pub mime_type: Option<String>,
+    pub icons: Vec<SmartPosterIcon>,
+}
+
+impl SmartPoster {
+    pub fn new(uri: &str) -> Self {
+        SmartPoster {
+            uri: uri.to_owned(),
+            titles: Vec::new(),
+            action: None,
+            size: None,
+            mime_type: None,
+            icons: Vec::new(),
+        }
+    }
+    pub fn with_title(mut self, language: &str, title: &str) -> Self {
+        self.titles.push(TextRecord::new(language, title));
+        self
+    }
+    pub fn with_action(mut self, action: SmartPosterAction) -> Self {
+        self.action = Some(action);
+        self
+    }
+    /// 知らないレコードと予約のアクションは読み飛ばす
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let message = NdefMessage::from_bytes(payload)?;
+        let mut uri = None;
+        let mut poster = SmartPoster::new("");
+        for record in &message.records {
+            match (record.tnf, &record.record_type[..]) {
+                (Tnf::WellKnown, b"U") => {
+                    // URIはちょうど1つ
+                    if uri.is_some() {
+                        return Err(NdefError::new(NdefErrorKind::InvalidPayload));
+                    }
+                    uri = Some(UriRecord::from_payload(&record.payload)?.uri);
+                }
+                (Tnf::WellKnown, b"T") => {
+                    poster
+                        .titles
+                        .push(TextRecord::from_payload(&record.payload)?);
+                }
+                (Tnf::WellKnown, b"act") => {
+                    let action = record
+                        .payload
+                        .first()
+                        .ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+                    poster.action = SmartPosterAction::from_byte(*action);
+                }
+                (Tnf::WellKnown, b"s") => {
+                    if record.payload.len() != 4 {
+                        return Err(NdefError::new(NdefErrorKind::InvalidPayload));
+                    }
+                    let mut size = [0u8; 4];
+                    size.copy_from_slice(&record.payload);
+                    poster.size = Some(u32::from_be_bytes(size));
+                }
+                (Tnf::WellKnown, b"t") => {
+                    poster.mime_type = Some(super::utf8(&record.payload)?);
+                }
+                (Tnf::Media, _) => {
+                    if let RecordContent::Mime { mime_type, data } = record.content()? {
+                        if mime_type.starts_with("image/") || mime_type.starts_with("video/") {
+                            poster.icons.push(SmartPosterIcon { mime_type, data });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        poster.uri = uri.ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        Ok(poster)
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut records = vec![UriRecord::new(&self.uri).to_record()];
+        records.extend(self.titles.iter().map(|title| title.to_record()));
+        if let Some(action) = self.action {
+            records.push(NdefRecord::well_known(b"act", &[action as u8]));
+        }
+        if let Some(size) = self.size {
+            records.push(NdefRecord::well_known(b"s", &size.to_be_bytes()));
+        }
+        if let Some(mime_type) = &self.mime_type {
+            records.push(NdefRecord::well_known(b"t", mime_type.as_bytes()));
+        }
+        records.extend(
+            self.icons
+                .iter()
+                .map(|icon| NdefRecord::mime(&icon.mime_type, &icon.data)),
+        );
+        NdefMessage::new(records).to_bytes()
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"Sp", &self.to_payload())
+    }
+}
+
+#[test]
+fn ndef_smart_poster() {
+    let mut poster = SmartPoster::new("https://www.nfc.com")
+        .with_title("en", "NFC Forum")
+        .with_title("ja", "NFCフォーラム")
+        .with_action(SmartPosterAction::Save);
+    poster.size = Some(1024);
+    poster.mime_type = Some("text/html".to_owned());
+    poster.icons.push(SmartPosterIcon {
+        mime_type: "image/png".to_owned(),
+        data: vec![0x89, 0x50, 0x4E, 0x47],
+    });
+    let record = poster.to_record();
+    assert_eq!(
+        record.content().unwrap(),
+        RecordContent::SmartPoster(poster.clone())
+    );
+    let message = NdefMessage::new(vec![record]);
+    let parsed = NdefMessage::from_bytes(&message.to_bytes()).unwrap();
+    assert_eq!(
+        parsed.records[0].content().unwrap(),
+        RecordContent::SmartPoster(poster)
+    );
+
+    // URIがない
+    let payload = NdefMessage::new(vec![NdefRecord::text("en", "title")]).to_bytes();
+    assert!(SmartPoster::from_payload(&payload).is_err());
+}
@@ -0,0 +1,122 @@
+// Well-Known Type "T"（テキスト）
+// ステータスバイト(bit7: UTF-16, bit0-5: 言語コードの長さ) || 言語コード(ASCII) || テキスト
+// UTF-16はBOMがあればそれに従い、なければビッグエンディアンとして読む
+
+use super::{NdefError, NdefErrorKind, NdefRecord};
+
+const STATUS_UTF16: u8 = 0x80;
+const LANGUAGE_LENGTH_MASK: u8 = 0x3F;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextRecord {
+    /// IANAの言語コード(en, ja-JP など)
+    pub language: String,
+    pub text: String,
+    pub encoding: TextEncoding,
+}
+
+impl TextRecord {
+    pub fn new(language: &str, text: &str) -> Self {
+        TextRecord {
+            language: language.to_owned(),
+            text: text.to_owned(),
+            encoding: TextEncoding::Utf8,
+        }
+    }
+    pub fn with_encoding(mut self, encoding: TextEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let (status, rest) = payload
+            .split_first()
+            .ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        let language_len = (status & LANGUAGE_LENGTH_MASK) as usize;
+        if rest.len() < language_len {
+            return Err(NdefError::new(NdefErrorKind::InvalidPayload));
+        }
+        let (language, text) = rest.split_at(language_len);
+        if !language.is_ascii() {
+            return Err(NdefError::new(NdefErrorKind::InvalidEncoding));
+        }
+        let (text, encoding) = if status & STATUS_UTF16 != 0 {
+            (decode_utf16(text)?, TextEncoding::Utf16)
+        } else {
+            (super::utf8(text)?, TextEncoding::Utf8)
+        };
+        Ok(TextRecord {
+            language: String::from_utf8_lossy(language).into_owned(),
+            text,
+            encoding,
+        })
+    }
+    /// 言語コードは63バイトまで
+    pub fn to_payload(&self) -> Vec<u8> {
+        let language = &self.language.as_bytes()[..self.language.len().min(0x3F)];
+        let mut status = language.len() as u8;
+        let text = match self.encoding {
+            TextEncoding::Utf8 => self.text.as_bytes().to_vec(),
+            TextEncoding::Utf16 => {
+                status |= STATUS_UTF16;
+                self.text
+                    .encode_utf16()
+                    .flat_map(|unit| unit.to_be_bytes().to_vec())
+                    .collect()
+            }
+        };
+        [&[status][..], language, &text].concat()
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"T", &self.to_payload())
+    }
+}
+
+fn decode_utf16(data: &[u8]) -> Result<String, NdefError> {
+    if !data.chunks_exact(2).remainder().is_empty() {
+        return Err(NdefError::new(NdefErrorKind::InvalidEncoding));
+    }
+    let (data, little_endian) = match data {
+        [0xFE, 0xFF, rest @ ..] => (rest, false),
+        [0xFF, 0xFE, rest @ ..] => (rest, true),
+        _ => (data, false),
+    };
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| {
+            if little_endian {
+                u16::from_le_bytes([unit[0], unit[1]])
+            } else {
+                u16::from_be_bytes([unit[0], unit[1]])
+            }
+        })
+        .collect::<Vec<u16>>();
+    String::from_utf16(&units).map_err(|_| NdefError::new(NdefErrorKind::InvalidEncoding))
+}
+
+#[test]
+fn ndef_text_record() {
+    // UTF-16LE(BOM付き) "Hi"
+    let text =
+        TextRecord::from_payload(&[0x82, 0x65, 0x6E, 0xFF, 0xFE, 0x48, 0x00, 0x69, 0x00]).unwrap();
+    assert_eq!(text.language, "en");
+    assert_eq!(text.text, "Hi");
+    assert_eq!(text.encoding, TextEncoding::Utf16);
+
+    let text = TextRecord::new("ja", "日本語").with_encoding(TextEncoding::Utf16);
+    assert_eq!(
+        text.to_payload(),
+        [0x82, 0x6A, 0x61, 0x65, 0xE5, 0x67, 0x2C, 0x8A, 0x9E]
+    );
+    assert_eq!(TextRecord::from_payload(&text.to_payload()).unwrap(), text);
+
+    // 言語コードの長さが足りない・奇数長のUTF-16・不正なUTF-8
+    assert!(TextRecord::from_payload(&[0x05, 0x65, 0x6E]).is_err());
+    assert!(TextRecord::from_payload(&[0x80, 0x00]).is_err());
+    assert!(TextRecord::from_payload(&[0x00, 0xFF]).is_err());
+}
@@ -0,0 +1,271 @@
+// Type 1 / Type 2 TagのデータエリアにあるTLVの並び
+// タグ(1バイト) || 長さ(1バイト、または FF || 2バイト) || 値
+// NULL(00)とTerminator(FE)は長さを持たない。
+// Lock Control(01)・Memory Control(02)が指す領域はデータエリアから除いて読み進める。
+
+use super::{NdefError, NdefErrorKind};
+use std::ops::Range;
+
+pub const TLV_NULL: u8 = 0x00;
+pub const TLV_LOCK_CONTROL: u8 = 0x01;
+pub const TLV_MEMORY_CONTROL: u8 = 0x02;
+pub const TLV_NDEF_MESSAGE: u8 = 0x03;
+pub const TLV_PROPRIETARY: u8 = 0xFD;
+pub const TLV_TERMINATOR: u8 = 0xFE;
+/// 長さが3バイト形式であることを示す
+const LENGTH_3BYTE: u8 = 0xFF;
+/// 1バイト形式で表せる最大の長さ
+const MAX_SHORT_LENGTH: usize = 0xFE;
+const MAX_LONG_LENGTH: usize = 0xFFFE;
+
+/// NDEF Message TLVの位置（すべてタグ先頭からのアドレス）
+#[derive(Debug, Clone, PartialEq)]
+pub struct NdefTlv {
+    pub tag: usize,
+    pub length: Vec<usize>,
+    pub value: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlvLayout {
+    /// データエリアの終わり
+    end: usize,
+    /// Lock Control / Memory Control TLVが指す、データに使えない領域
+    pub reserved: Vec<Range<usize>>,
+    /// 最初のNDEF Message TLV
+    pub ndef: Option<NdefTlv>,
+    /// NDEF Message TLVを置く位置（既存のNDEF Message TLVか、制御TLVの後の最初の空き）
+    pub ndef_start: Option<usize>,
+}
+
+impl TlvLayout {
+    /// memoryはタグ先頭からのメモリの内容、start..endがデータエリア
+    pub fn parse(memory: &[u8], start: usize, end: usize) -> Result<Self, NdefError> {
+        Self::parse_with_reserved(memory, start, end, &[])
+    }
+    /// 制御TLVとは別に、ロックバイトなどのデータに使えない領域が決まっているとき
+    pub fn parse_with_reserved(
+        memory: &[u8],
+        start: usize,
+        end: usize,
+        reserved: &[Range<usize>],
+    ) -> Result<Self, NdefError> {
+        let end = end.min(memory.len());
+        let mut layout = TlvLayout {
+            end,
+            reserved: reserved.to_vec(),
+            ndef: None,
+            ndef_start: None,
+        };
+        // NDEF Message TLVを置ける位置（NULL・Terminatorの位置で、制御TLVより後ろ）
+        let mut candidate = None;
+        let mut position = layout.first(start);
+        while let Some(tag_position) = position {
+            let tag = memory[tag_position];
+            match tag {
+                TLV_NULL => {
+                    candidate.get_or_insert(tag_position);
+                    position = layout.next(tag_position);
+                    continue;
+                }
+                TLV_TERMINATOR => {
+                    candidate.get_or_insert(tag_position);
+                    break;
+                }
+                _ => {}
+            }
+            // 長さのフィールド
+            let mut length = vec![layout
+                .next(tag_position)
+                .ok_or_else(|| invalid(tag_position))?];
+            let mut len = memory[length[0]] as usize;
+            if len == LENGTH_3BYTE as usize {
+                let high = layout
+                    .next(length[0])
+                    .ok_or_else(|| invalid(tag_position))?;
+                let low = layout.next(high).ok_or_else(|| invalid(tag_position))?;
+                length.extend_from_slice(&[high, low]);
+                len = u16::from_be_bytes([memory[high], memory[low]]) as usize;
+            }
+            let mut value = Vec::with_capacity(len.min(end));
+            let mut last = length[length.len() - 1];
+            for _ in 0..len {
+                last = layout.next(last).ok_or_else(|| invalid(tag_position))?;
+                value.push(last);
+            }
+            if tag == TLV_NDEF_MESSAGE {
+                candidate = Some(tag_position);
+                layout.ndef = Some(NdefTlv {
+                    tag: tag_position,
+                    length,
+                    value,
+                });
+                break;
+            }
+            if (tag == TLV_LOCK_CONTROL || tag == TLV_MEMORY_CONTROL) && value.len() == 3 {
+                let bytes = [memory[value[0]], memory[value[1]], memory[value[2]]];
+                layout.reserved.push(control_area(tag, bytes));
+            }
+            // 制御TLV・Proprietary TLVは上書きしない
+            candidate = None;
+            position = layout.next(last);
+        }
+        layout.ndef_start = candidate;
+        Ok(layout)
+    }
+    fn first(&self, start: usize) -> Option<usize> {
+        self.skip_reserved(start)
+    }
+    /// positionの次に使えるアドレス
+    fn next(&self, position: usize) -> Option<usize> {
+        self.skip_reserved(position + 1)
+    }
+    fn skip_reserved(&self, mut position: usize) -> Option<usize> {
+        while let Some(area) = self.reserved.iter().find(|area| area.contains(&position)) {
+            position = area.end;
+        }
+        if position < self.end {
+            Some(position)
+        } else {
+            None
+        }
+    }
+    /// fromから使えるアドレスを順に並べる
+    pub fn positions_from(&self, from: usize) -> Vec<usize> {
+        let mut positions = Vec::new();
+        let mut position = self.first(from);
+        while let Some(p) = position {
+            positions.push(p);
+            position = self.next(p);
+        }
+        positions
+    }
+    /// 格納できるNDEFメッセージの最大のバイト数
+    pub fn capacity(&self) -> usize {
+        let available = self
+            .ndef_start
+            .map(|start| self.positions_from(start).len())
+            .unwrap_or(0);
+        // タグと1バイトの長さ、または3バイトの長さを除いた分
+        let short = available.saturating_sub(2).min(MAX_SHORT_LENGTH);
+        let long = available.saturating_sub(4).min(MAX_LONG_LENGTH);
+        short.max(long)
+    }
+    /// NDEF Message TLV(とTerminator TLV)を書き込んだメモリの内容を作る
+    /// 1つ目は長さを0にしたもの、2つ目が最終的な内容
+    pub fn place(&self, memory: &[u8], message: &[u8]) -> Result<(Vec<u8>, Vec<u8>), NdefError> {
+        let too_large = || NdefError::new(NdefErrorKind::MessageTooLarge(self.capacity()));
+        let start = self.ndef_start.ok_or_else(too_large)?;
+        let positions = self.positions_from(start);
+        let length = if message.len() <= MAX_SHORT_LENGTH {
+            vec![message.len() as u8]
+        } else if message.len() <= MAX_LONG_LENGTH {
+            let len = (message.len() as u16).to_be_bytes();
+            vec![LENGTH_3BYTE, len[0], len[1]]
+        } else {
+            return Err(too_large());
+        };
+        let mut tlv = [&[TLV_NDEF_MESSAGE][..], &length, message].concat();
+        if tlv.len() > positions.len() {
+            return Err(too_large());
+        }
+        // 余裕があればTerminator TLVを続ける
+        if tlv.len() < positions.len() {
+            tlv.push(TLV_TERMINATOR);
+        }
+        let mut image = memory.to_vec();
+        for (position, byte) in positions.iter().zip(tlv.iter()) {
+            image[*position] = *byte;
+        }
+        let mut empty = image.clone();
+        for position in &positions[1..1 + length.len()] {
+            empty[*position] = 0x00;
+        }
+        Ok((empty, image))
+    }
+}
+
+/// 制御TLVの値が指す領域
+/// 値: ページアドレス(上位4ビット)・バイトオフセット(下位4ビット) || サイズ || ページのバイト数(下位4ビット, 2^n)
+fn control_area(tag: u8, value: [u8; 3]) -> Range<usize> {
+    let page = (value[0] >> 4) as usize;
+    let offset = (value[0] & 0x0F) as usize;
+    let bytes_per_page = 1usize << (value[2] & 0x0F);
+    let size = match value[1] {
+        0 => 256,
+        size => size as usize,
+    };
+    // Lock Controlのサイズはビット数
+    let size = if tag == TLV_LOCK_CONTROL {
+        size.div_ceil(8)
+    } else {
+        size
+    };
+    let start = page * bytes_per_page + offset;
+    start..start + size
+}
+
+fn invalid(position: usize) -> NdefError {
+    NdefError::new(NdefErrorKind::InvalidTlv(position))
+}
+
+#[test]
+fn ndef_tlv_layout() {
+    // Lock Control TLV(ページ0x0A×16バイト = 0xA0から16ビット)、データエリア 0x10..0xC0
+    let mut memory = vec![0u8; 0xD0];
+    memory[0x10..0x1A]
+        .copy_from_slice(&[0x01, 0x03, 0xA0, 0x10, 0x44, 0x03, 0x03, 0xD0, 0x00, 0x00]);
+    memory[0x1A] = TLV_TERMINATOR;
+    let layout = TlvLayout::parse(&memory, 0x10, 0xC0).unwrap();
+    assert_eq!(layout.reserved, vec![0xA0..0xA2]);
+    let ndef = layout.ndef.clone().unwrap();
+    assert_eq!(ndef.tag, 0x15);
+    assert_eq!(ndef.value, vec![0x17, 0x18, 0x19]);
+    // 0x15..0xC0から予約の2バイトを除き、タグと長さの2バイトを引く
+    assert_eq!(layout.capacity(), 0xC0 - 0x15 - 2 - 2);
+
+    // 予約領域をまたいで書き込む
+    let message = vec![0x5A; 0xA0 - 0x17 + 4];
+    let (empty, image) = layout.place(&memory, &message).unwrap();
+    assert_eq!(image[0x16], message.len() as u8);
+    assert_eq!(empty[0x16], 0x00);
+    assert_eq!(image[0x9F], 0x5A);
+    assert_eq!(image[0xA0..0xA2], [0x00, 0x00]);
+    assert_eq!(image[0xA2..0xA6], [0x5A; 4]);
+    assert_eq!(image[0xA6], TLV_TERMINATOR);
+    let layout = TlvLayout::parse(&image, 0x10, 0xC0).unwrap();
+    let value = layout.ndef.unwrap().value;
+    assert_eq!(
+        value.iter().map(|p| image[*p]).collect::<Vec<u8>>(),
+        message
+    );
+    assert!(TlvLayout::parse(&image, 0x10, 0xC0)
+        .unwrap()
+        .place(&memory, &vec![0; 0x100])
+        .is_err());
+
+    // 3バイトの長さ・途中で終わるTLV・NDEF Message TLVがない
+    let mut memory = vec![0u8; 0x200];
+    memory[0x10..0x14].copy_from_slice(&[0x03, 0xFF, 0x01, 0x00]);
+    let layout = TlvLayout::parse(&memory, 0x10, 0x200).unwrap();
+    assert_eq!(layout.ndef.unwrap().value.len(), 0x100);
+    assert!(TlvLayout::parse(&memory, 0x10, 0x80).is_err());
+    let layout = TlvLayout::parse(&[0u8; 0x40], 0x10, 0x40).unwrap();
+    assert!(layout.ndef.is_none());
+    assert_eq!(layout.ndef_start, Some(0x10));
+
+    // 壊れたデータエリアでもパニックしない
+    let mut seed = 0x0BAD_CAFEu32;
+    for _ in 0..500 {
+        let memory = (0..0x60)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                // TLVのタグになりやすい値に寄せる
+                [0x00, 0x01, 0x02, 0x03, 0xFD, 0xFE, 0xFF][(seed >> 16) as usize % 7]
+            })
+            .collect::<Vec<u8>>();
+        if let Ok(layout) = TlvLayout::parse(&memory, 0x10, 0x60) {
+            let _ = layout.place(&memory, &[0x5A; 0x20]);
+        }
+    }
+}
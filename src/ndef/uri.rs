@@ -0,0 +1,99 @@
+// Well-Known Type "U"（URI）
+// 識別コード(1バイト) || URIの残り(UTF-8)
+// 識別コードはよく使う接頭辞を1バイトに縮めたもの
+
+use super::{NdefError, NdefErrorKind, NdefRecord};
+
+/// 識別コードに対応する接頭辞（0x24以降は予約）
+const PREFIXES: [&str; 0x24] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UriRecord {
+    pub uri: String,
+}
+
+impl UriRecord {
+    pub fn new(uri: &str) -> Self {
+        UriRecord {
+            uri: uri.to_owned(),
+        }
+    }
+    /// 予約の識別コードは接頭辞なしとして扱う
+    pub fn from_payload(payload: &[u8]) -> Result<Self, NdefError> {
+        let (code, rest) = payload
+            .split_first()
+            .ok_or_else(|| NdefError::new(NdefErrorKind::InvalidPayload))?;
+        let prefix = PREFIXES.get(*code as usize).unwrap_or(&"");
+        Ok(UriRecord {
+            uri: format!("{}{}", prefix, super::utf8(rest)?),
+        })
+    }
+    /// 一番長く一致する接頭辞を識別コードに置き換える
+    pub fn to_payload(&self) -> Vec<u8> {
+        let (code, prefix) = PREFIXES
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| self.uri.starts_with(*prefix))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .unwrap_or((0, &""));
+        [&[code as u8][..], &self.uri.as_bytes()[prefix.len()..]].concat()
+    }
+    pub fn to_record(&self) -> NdefRecord {
+        NdefRecord::well_known(b"U", &self.to_payload())
+    }
+}
+
+#[test]
+fn ndef_uri_record() {
+    let cases: [(&str, &[u8]); 5] = [
+        ("https://www.nfc.com", b"\x02nfc.com"),
+        ("tel:+81312345678", b"\x05+81312345678"),
+        ("urn:epc:id:sgtin:1", b"\x1Esgtin:1"),
+        ("urn:nfc:ext:example", b"\x23ext:example"),
+        ("geo:35.6,139.7", b"\x00geo:35.6,139.7"),
+    ];
+    for (uri, payload) in cases.iter() {
+        assert_eq!(UriRecord::new(uri).to_payload(), *payload);
+        assert_eq!(UriRecord::from_payload(payload).unwrap().uri, *uri);
+    }
+    // 予約の識別コード
+    assert_eq!(UriRecord::from_payload(b"\x30abc").unwrap().uri, "abc");
+    assert!(UriRecord::from_payload(&[]).is_err());
+}
@@ -1,21 +1,34 @@
 // NFCのFactoryメソッド
 // SCardAPIとLibNFCに対応するようにする。
 use crate::smart_card::*;
+#[cfg(windows)]
 mod nfc_winscard;
+// テスト用の仮想カード（Windows以外でも動くようにしておく）
+pub mod nfc_mock;
 pub enum FactoryType{
     WindowsScardAPI,LibMFC
 }
 
 pub struct NfcFactory{}
 impl NfcFactory{
-    pub fn create_nfc_instance(ftype:FactoryType)->Box<dyn Smartcard>{
+    /// このプラットフォームで使えない実装を指定するとNotSupportedを返す
+    pub fn create_nfc_instance(ftype:FactoryType)->Result<Box<dyn Smartcard>,SmartcardError>{
         match ftype{
+            #[cfg(windows)]
             FactoryType::WindowsScardAPI => {
-                Box::new(crate::nfc_impl::nfc_winscard::WinScardNFC::new().unwrap())
+                Ok(Box::new(crate::nfc_impl::nfc_winscard::WinScardNFC::new()?))
+            },
+            #[cfg(not(windows))]
+            FactoryType::WindowsScardAPI => {
+                Err(SmartcardError::new(SmartcardErrorKind::NotSupported))
             },
             FactoryType::LibMFC => {
-                unimplemented!()
-            } 
+                Err(SmartcardError::new(SmartcardErrorKind::NotSupported))
+            }
         }
     }
+    /// 仮想カードを載せたモックのリーダーを生成する
+    pub fn create_mock_instance(card: Box<dyn nfc_mock::VirtualCard>)->Box<dyn Smartcard>{
+        Box::new(nfc_mock::MockSmartcard::new(card))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,76 @@
+// 仮想カードを使ったモック実装を記述する
+// 実機のリーダーなしでAPDUのやり取りを試験するために使う
+use crate::pc_sc_standard::*;
+use crate::smart_card::*;
+use std::cell::RefCell;
+
+pub mod desfire;
+pub mod emrtd;
+pub mod felica;
+pub mod iso7816;
+pub mod mifare_classic;
+pub mod mifare_plus;
+pub mod topaz;
+pub mod type4_tag;
+pub mod ultralight;
+
+/// ソフトウェアで実装したカード
+pub trait VirtualCard {
+    /// カードのATR（最大32バイト）
+    fn atr(&self) -> Vec<u8>;
+    /// コマンドAPDUを処理し、レスポンス(データ+SW1SW2)を返す
+    /// Noneを返した場合はカードが取り外されたものとして扱う
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>>;
+}
+
+pub struct MockSmartcard {
+    card: RefCell<Box<dyn VirtualCard>>,
+    atr: AnswerToReset,
+    protocol: ProtocolType,
+}
+
+impl MockSmartcard {
+    pub fn new(card: Box<dyn VirtualCard>) -> Self {
+        let mut raw_atr = [0u8; 32];
+        let atr = card.atr();
+        let len = atr.len().min(raw_atr.len());
+        raw_atr[..len].copy_from_slice(&atr[..len]);
+        MockSmartcard {
+            atr: AnswerToReset::new(&raw_atr).unwrap_or_default(),
+            card: RefCell::new(card),
+            protocol: ProtocolType::T1,
+        }
+    }
+}
+
+impl Smartcard for MockSmartcard {
+    fn version_str(&self) -> Option<String> {
+        Some("NFC Mock 0.0.0.1".to_owned())
+    }
+    fn version(&self) -> Option<SmartcardVersion> {
+        Some(SmartcardVersion::new(0, 0, 0, 1))
+    }
+    fn connect_reader(
+        &mut self,
+        _con_method: SmartcardConnectMethod,
+    ) -> Result<ProtocolType, SmartcardError> {
+        Ok(self.protocol)
+    }
+    fn transmit(&self, data: Box<dyn APDU>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self.card.borrow_mut().process_apdu(&data.read8()) {
+            Some(res) => TransmitError::check_response(res),
+            None => Err(Box::new(SmartcardError::new(
+                SmartcardErrorKind::ConnectionLost,
+            ))),
+        }
+    }
+    fn config_protocol(&mut self, protocol: ProtocolType) -> Option<ProtocolType> {
+        if protocol != ProtocolType::InActive {
+            self.protocol = protocol;
+        }
+        Some(self.protocol)
+    }
+    fn get_atr(&self) -> &AnswerToReset {
+        &self.atr
+    }
+}
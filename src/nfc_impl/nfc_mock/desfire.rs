@@ -0,0 +1,789 @@
+// ISOラップのネイティブコマンド(CLA 90)に応答するDESFire EV1の仮想カード
+// 認証とセキュアメッセージングはライブラリのSessionをカード側として使う。
+// アクセス権はファイル操作・鍵の変更だけを検査する（アプリケーションの作成・削除は検査しない）
+
+use super::VirtualCard;
+use crate::crypto::{random_bytes, rotate_left};
+use crate::desfire::auth::AuthMethod;
+use crate::desfire::file::{read_u24, write_u24, CommunicationMode, FileKind, FileSettings};
+use crate::desfire::key::DesfireKey;
+use crate::desfire::session::{legacy_receive, Session};
+use crate::desfire::{key_version_appended, DesfireKeyType, MAX_FRAME_SIZE};
+use std::collections::{BTreeMap, VecDeque};
+
+const SW1: u8 = 0x91;
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6E, 0x00];
+const STATUS_OK: u8 = 0x00;
+const STATUS_ILLEGAL_COMMAND: u8 = 0x1C;
+const STATUS_INTEGRITY_ERROR: u8 = 0x1E;
+const STATUS_NO_SUCH_KEY: u8 = 0x40;
+const STATUS_LENGTH_ERROR: u8 = 0x7E;
+const STATUS_PERMISSION_DENIED: u8 = 0x9D;
+const STATUS_PARAMETER_ERROR: u8 = 0x9E;
+const STATUS_APPLICATION_NOT_FOUND: u8 = 0xA0;
+const STATUS_AUTHENTICATION_ERROR: u8 = 0xAE;
+const STATUS_ADDITIONAL_FRAME: u8 = 0xAF;
+const STATUS_BOUNDARY_ERROR: u8 = 0xBE;
+const STATUS_DUPLICATE_ERROR: u8 = 0xDE;
+const STATUS_FILE_NOT_FOUND: u8 = 0xF0;
+/// EV1 4K
+const TOTAL_MEMORY: u32 = 4096;
+
+struct VirtualFile {
+    settings: FileSettings,
+    data: Vec<u8>,
+    value: i32,
+    records: Vec<Vec<u8>>,
+    // CommitTransactionまで反映しない変更
+    pending_data: Option<Vec<u8>>,
+    pending_value: Option<i32>,
+    pending_records: Option<Vec<Vec<u8>>>,
+}
+
+impl VirtualFile {
+    fn new(settings: FileSettings) -> Self {
+        let (size, value) = match settings.kind {
+            FileKind::StandardData { size } | FileKind::BackupData { size } => (size, 0),
+            FileKind::Value { value, .. } => (0, value),
+            _ => (0, 0),
+        };
+        VirtualFile {
+            settings,
+            data: vec![0; size as usize],
+            value,
+            records: Vec::new(),
+            pending_data: None,
+            pending_value: None,
+            pending_records: None,
+        }
+    }
+    fn memory_size(&self) -> u32 {
+        match self.settings.kind {
+            FileKind::StandardData { size } => size,
+            FileKind::BackupData { size } => size * 2,
+            FileKind::Value { .. } => 4,
+            FileKind::LinearRecord {
+                record_size,
+                max_records,
+                ..
+            }
+            | FileKind::CyclicRecord {
+                record_size,
+                max_records,
+                ..
+            } => record_size * max_records,
+        }
+    }
+    fn commit(&mut self) {
+        if let Some(data) = self.pending_data.take() {
+            self.data = data;
+        }
+        if let Some(value) = self.pending_value.take() {
+            self.value = value;
+        }
+        if let Some(mut records) = self.pending_records.take() {
+            // 巡回レコードファイルは1件を予備に使うので最大数-1件を保持する
+            if let FileKind::CyclicRecord { max_records, .. } = self.settings.kind {
+                while records.len() >= max_records as usize {
+                    records.remove(0);
+                }
+            }
+            self.records = records;
+        }
+    }
+    fn abort(&mut self) {
+        self.pending_data = None;
+        self.pending_value = None;
+        self.pending_records = None;
+    }
+    /// GetFileSettingsの応答
+    fn settings(&self) -> FileSettings {
+        let mut settings = self.settings;
+        match &mut settings.kind {
+            FileKind::LinearRecord {
+                current_records, ..
+            }
+            | FileKind::CyclicRecord {
+                current_records, ..
+            } => *current_records = self.records.len() as u32,
+            FileKind::Value { value, .. } => *value = 0,
+            _ => {}
+        }
+        settings
+    }
+}
+
+struct VirtualApplication {
+    key_settings: u8,
+    /// 鍵の数と暗号方式(上位2ビット)
+    key_count: u8,
+    keys: Vec<DesfireKey>,
+    files: BTreeMap<u8, VirtualFile>,
+}
+
+/// 認証の途中の状態
+struct PendingAuth {
+    method: AuthMethod,
+    key_no: u8,
+    key: DesfireKey,
+    rnd_b: Vec<u8>,
+    /// 2回目のデータの復号に使うIV
+    iv: Vec<u8>,
+}
+
+pub struct VirtualDesfire {
+    version: Vec<u8>,
+    picc_key_settings: u8,
+    picc_key: DesfireKey,
+    applications: BTreeMap<u32, VirtualApplication>,
+    selected: u32,
+    /// 追加フレームで受信中のコマンドと全体の長さ
+    pending_command: Option<(u8, Vec<u8>, usize)>,
+    /// 追加フレームで返す残りの応答
+    pending_response: VecDeque<Vec<u8>>,
+    pending_auth: Option<PendingAuth>,
+    session: Option<Session>,
+}
+
+impl VirtualDesfire {
+    /// UID 04 11 22 33 44 55 66 の出荷状態のDESFire EV1 4K
+    pub fn new() -> Self {
+        VirtualDesfire {
+            version: vec![
+                0x04, 0x01, 0x01, 0x01, 0x00, 0x18, 0x05, 0x04, 0x01, 0x01, 0x01, 0x04, 0x18, 0x05,
+                0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xBA, 0x54, 0x37, 0x89, 0x10, 0x21, 0x19,
+            ],
+            picc_key_settings: 0x0F,
+            picc_key: DesfireKey::zero(DesfireKeyType::Des),
+            applications: BTreeMap::new(),
+            selected: 0,
+            pending_command: None,
+            pending_response: VecDeque::new(),
+            pending_auth: None,
+            session: None,
+        }
+    }
+    fn application(&mut self) -> Result<&mut VirtualApplication, u8> {
+        let selected = self.selected;
+        self.applications
+            .get_mut(&selected)
+            .ok_or(STATUS_ILLEGAL_COMMAND)
+    }
+    fn file(&mut self, file_no: u8) -> Result<&mut VirtualFile, u8> {
+        self.application()?
+            .files
+            .get_mut(&file_no)
+            .ok_or(STATUS_FILE_NOT_FOUND)
+    }
+    /// アクセス権を確かめてファイルを返す
+    fn file_access(&mut self, file_no: u8, write: bool) -> Result<&mut VirtualFile, u8> {
+        let key_no = self.session.as_ref().map(Session::key_no);
+        let file = self.file(file_no)?;
+        if file.settings.access_rights.allows(write, key_no) {
+            Ok(file)
+        } else {
+            Err(STATUS_PERMISSION_DENIED)
+        }
+    }
+    fn file_settings(&self, file_no: u8) -> Option<&FileSettings> {
+        let app = self.applications.get(&self.selected)?;
+        app.files.get(&file_no).map(|file| &file.settings)
+    }
+    fn key(&self, key_no: u8) -> Option<&DesfireKey> {
+        if self.selected == 0 {
+            Some(&self.picc_key).filter(|_| key_no == 0)
+        } else {
+            self.applications
+                .get(&self.selected)?
+                .keys
+                .get(key_no as usize)
+        }
+    }
+    fn key_settings(&self) -> u8 {
+        match self.applications.get(&self.selected) {
+            Some(app) => app.key_settings,
+            None => self.picc_key_settings,
+        }
+    }
+    /// ヘッダの長さとコマンド・応答のデータの保護
+    fn protection(
+        &self,
+        command: u8,
+        data: &[u8],
+    ) -> (usize, CommunicationMode, CommunicationMode) {
+        let file_mode = |write| match data.first().and_then(|no| self.file_settings(*no)) {
+            Some(settings) => settings.communication_for(write),
+            None => CommunicationMode::Plain,
+        };
+        // 書き込みの応答はMACだけ
+        let response = |mode| match mode {
+            CommunicationMode::Plain => CommunicationMode::Plain,
+            _ => CommunicationMode::Maced,
+        };
+        let (header, mode, response_mode) = match command {
+            0xBD | 0xBB => (7, file_mode(false), file_mode(false)),
+            0x6C => (1, file_mode(false), file_mode(false)),
+            0x3D | 0x3B => (7, file_mode(true), response(file_mode(true))),
+            0x0C | 0xDC | 0x1C => (1, file_mode(true), response(file_mode(true))),
+            0x54 => (0, CommunicationMode::Enciphered, CommunicationMode::Maced),
+            _ => (
+                data.len(),
+                CommunicationMode::Plain,
+                CommunicationMode::Plain,
+            ),
+        };
+        (header.min(data.len()), mode, response_mode)
+    }
+    fn free_memory(&self) -> u32 {
+        let used = self
+            .applications
+            .values()
+            .flat_map(|app| app.files.values())
+            .map(|file| file.memory_size())
+            .sum::<u32>();
+        TOTAL_MEMORY.saturating_sub(used)
+    }
+    /// 追加フレームで続きを受け取る必要があるときの全体の長さ
+    fn expected_length(&self, command: u8, data: &[u8]) -> usize {
+        match command {
+            0x3D | 0x3B if data.len() >= 7 => {
+                let length = read_u24(&data[4..7]) as usize;
+                let (_, mode, _) = self.protection(command, data);
+                match &self.session {
+                    Some(session) => 7 + session.wrapped_length(length, mode),
+                    None => 7 + length,
+                }
+            }
+            _ => data.len(),
+        }
+    }
+    /// 認証の1回目: ek(RndB)を返す
+    fn start_authentication(&mut self, method: AuthMethod, data: &[u8]) -> Option<Vec<u8>> {
+        self.session = None;
+        let key_no = match data {
+            [key_no] | [key_no, 0x00] => *key_no,
+            _ => return Self::respond(&[], STATUS_LENGTH_ERROR),
+        };
+        let key = match self.key(key_no) {
+            Some(key) => key.clone(),
+            None => return Self::respond(&[], STATUS_NO_SUCH_KEY),
+        };
+        if !method.supports(&key) {
+            return Self::respond(&[], STATUS_AUTHENTICATION_ERROR);
+        }
+        let rnd_b = random_bytes(method.random_size(&key));
+        let cipher = key.cipher();
+        let block_size = cipher.block_size();
+        let encrypted = cipher.cbc_encrypt(&vec![0; block_size], &rnd_b);
+        let iv = match method {
+            AuthMethod::Iso | AuthMethod::Aes => encrypted[encrypted.len() - block_size..].to_vec(),
+            _ => vec![0; block_size],
+        };
+        self.pending_auth = Some(PendingAuth {
+            method,
+            key_no,
+            key,
+            rnd_b,
+            iv,
+        });
+        Self::respond(&encrypted, STATUS_ADDITIONAL_FRAME)
+    }
+    /// 認証の2回目: RndB'を確かめ、ek(RndA')を返してセッションを始める
+    fn finish_authentication(&mut self, auth: PendingAuth, token: &[u8]) -> Option<Vec<u8>> {
+        let size = auth.rnd_b.len();
+        if token.len() != size * 2 {
+            return Self::respond(&[], STATUS_LENGTH_ERROR);
+        }
+        let cipher = auth.key.cipher();
+        let block_size = cipher.block_size();
+        let zero = vec![0u8; block_size];
+        let plain = match auth.method {
+            AuthMethod::Legacy => legacy_receive(&cipher, token),
+            _ => cipher.cbc_decrypt(&auth.iv, token),
+        };
+        let (rnd_a, rnd_b) = plain.split_at(size);
+        if rnd_b != &rotate_left(&auth.rnd_b)[..] {
+            return Self::respond(&[], STATUS_AUTHENTICATION_ERROR);
+        }
+        let rnd_a_rotated = rotate_left(rnd_a);
+        let (response, session) = match auth.method {
+            AuthMethod::Legacy => (
+                cipher.cbc_encrypt(&zero, &rnd_a_rotated),
+                Session::legacy(auth.key_no, &auth.key, rnd_a, &auth.rnd_b),
+            ),
+            AuthMethod::Iso | AuthMethod::Aes => (
+                cipher.cbc_encrypt(&token[token.len() - block_size..], &rnd_a_rotated),
+                Session::ev1(auth.key_no, &auth.key, rnd_a, &auth.rnd_b),
+            ),
+            AuthMethod::Ev2First => {
+                let mut ti = [0u8; 4];
+                ti.copy_from_slice(&random_bytes(4));
+                // PDcap2・PCDcap2は全て0
+                let plain = [&ti[..], &rnd_a_rotated, &[0u8; 12]].concat();
+                (
+                    cipher.cbc_encrypt(&zero, &plain),
+                    Session::ev2(auth.key_no, &auth.key, rnd_a, &auth.rnd_b, ti),
+                )
+            }
+        };
+        self.session = Some(session);
+        Self::respond(&response, STATUS_OK)
+    }
+    /// ChangeKey: 暗号文を復号して鍵を差し替える
+    fn change_key(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        let (header, rest) = payload.split_at(payload.len().min(1));
+        let key_no = match header {
+            [key_no] => *key_no,
+            _ => return Self::respond(&[], STATUS_LENGTH_ERROR),
+        };
+        let (authenticated, messaging) = match &self.session {
+            Some(session) => (session.key_no(), session.messaging()),
+            None => return Self::respond(&[], STATUS_AUTHENTICATION_ERROR),
+        };
+        // PICCでは鍵番号の上位2ビットが新しい暗号方式
+        let (target, key_type) = if self.selected == 0 {
+            (key_no & 0x0F, DesfireKeyType::from_key_count(key_no))
+        } else {
+            let app = self.application().ok()?;
+            (key_no & 0x0F, DesfireKeyType::from_key_count(app.key_count))
+        };
+        let old_key = match self.key(target) {
+            Some(key) => key.clone(),
+            None => return Self::respond(&[], STATUS_NO_SUCH_KEY),
+        };
+        let settings = self.key_settings();
+        let allowed = if target == 0 {
+            authenticated == 0 && settings & 0x01 != 0
+        } else {
+            match settings >> 4 {
+                0x0E => authenticated == target,
+                0x0F => false,
+                change_key => authenticated == change_key,
+            }
+        };
+        if !allowed {
+            self.session = None;
+            return Self::respond(&[], STATUS_PERMISSION_DENIED);
+        }
+        let session = self.session.as_mut()?;
+        let plain = match session.decrypt_command_data(0xC4, header, rest) {
+            Ok(plain) => plain,
+            Err(_) => {
+                self.session = None;
+                return Self::respond(&[], STATUS_INTEGRITY_ERROR);
+            }
+        };
+        let size = DesfireKey::change_key_size(key_type);
+        let data_len = size + key_version_appended(messaging, key_type) as usize;
+        if plain.len() < data_len {
+            self.session = None;
+            return Self::respond(&[], STATUS_LENGTH_ERROR);
+        }
+        let same = target == authenticated;
+        let mut key_bytes = plain[..size].to_vec();
+        if !same {
+            for (byte, old) in key_bytes.iter_mut().zip(old_key.change_key_bytes().iter()) {
+                *byte ^= old;
+            }
+        }
+        let crc_key = if same { None } else { Some(&key_bytes[..]) };
+        let expected = session.command_plaintext(0xC4, header, &plain[..data_len], crc_key);
+        let version = if data_len > size { plain[size] } else { 0 };
+        let new_key = match DesfireKey::from_change_key_bytes(key_type, &key_bytes, version) {
+            Some(key) if expected == plain => key,
+            _ => {
+                self.session = None;
+                return Self::respond(&[], STATUS_INTEGRITY_ERROR);
+            }
+        };
+        if self.selected == 0 {
+            self.picc_key = new_key;
+        } else {
+            let app = self.application().ok()?;
+            app.keys[target as usize] = new_key;
+        }
+        if same {
+            // 認証中の鍵を変えたらセッションは終わる
+            self.session = None;
+            return Self::respond(&[], STATUS_OK);
+        }
+        let response =
+            self.session
+                .as_mut()?
+                .wrap_response(STATUS_OK, &[], CommunicationMode::Maced);
+        Self::respond(&response, STATUS_OK)
+    }
+    /// 保護を外したコマンドを処理し、応答を保護して返す
+    fn dispatch(&mut self, command: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        if let Some(method) = AuthMethod::from_command(command) {
+            return self.start_authentication(method, payload);
+        }
+        if command == 0xC4 {
+            return self.change_key(payload);
+        }
+        let (header_len, mode, response_mode) = self.protection(command, payload);
+        let (header, rest) = payload.split_at(header_len);
+        let data = match self.session.as_mut() {
+            Some(session) => match session.unwrap_command(command, header, rest, mode) {
+                Ok(data) => [header, &data].concat(),
+                Err(_) => {
+                    self.session = None;
+                    return Self::respond(&[], STATUS_INTEGRITY_ERROR);
+                }
+            },
+            None => payload.to_vec(),
+        };
+        if command == 0x5A {
+            // 選択すると認証は解除される
+            self.session = None;
+        }
+        match self.command(command, &data) {
+            Ok(res) => {
+                let res = match self.session.as_mut() {
+                    Some(session) => session.wrap_response(STATUS_OK, &res, response_mode),
+                    None => res,
+                };
+                self.respond_frames(command, res)
+            }
+            Err(status) => {
+                self.session = None;
+                Self::respond(&[], status)
+            }
+        }
+    }
+    /// コマンドを処理し、応答のデータを返す。Errはエラーステータス
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<Vec<u8>, u8> {
+        match (command, data) {
+            (0x60, []) => Ok(self.version.clone()),
+            (0x6A, []) if self.selected == 0 => Ok(self
+                .applications
+                .keys()
+                .flat_map(|aid| write_u24(*aid).to_vec())
+                .collect()),
+            (0x5A, [_, _, _]) => {
+                let aid = read_u24(data);
+                if aid != 0 && !self.applications.contains_key(&aid) {
+                    return Err(STATUS_APPLICATION_NOT_FOUND);
+                }
+                if let Ok(app) = self.application() {
+                    app.files.values_mut().for_each(VirtualFile::abort);
+                }
+                self.selected = aid;
+                Ok(vec![])
+            }
+            (0xCA, [_, _, _, key_settings, key_count]) if self.selected == 0 => {
+                let aid = read_u24(data);
+                if aid == 0 || key_count & 0x0F == 0 || key_count & 0x0F > 14 {
+                    return Err(STATUS_PARAMETER_ERROR);
+                }
+                if self.applications.contains_key(&aid) {
+                    return Err(STATUS_DUPLICATE_ERROR);
+                }
+                let key = DesfireKey::zero(DesfireKeyType::from_key_count(*key_count));
+                self.applications.insert(
+                    aid,
+                    VirtualApplication {
+                        key_settings: *key_settings,
+                        key_count: *key_count,
+                        keys: vec![key; (key_count & 0x0F) as usize],
+                        files: BTreeMap::new(),
+                    },
+                );
+                Ok(vec![])
+            }
+            (0xDA, [_, _, _]) => {
+                let aid = read_u24(data);
+                if self.applications.remove(&aid).is_none() {
+                    return Err(STATUS_APPLICATION_NOT_FOUND);
+                }
+                if self.selected == aid {
+                    self.selected = 0;
+                }
+                Ok(vec![])
+            }
+            (0x45, []) => {
+                if self.selected == 0 {
+                    Ok(vec![
+                        self.picc_key_settings,
+                        0x01 | self.picc_key.key_type() as u8,
+                    ])
+                } else {
+                    let app = self.application()?;
+                    Ok(vec![app.key_settings, app.key_count])
+                }
+            }
+            (0x54, [key_settings]) => {
+                // マスターキーで認証し、設定の変更が許されていること
+                if self.session.as_ref().map(Session::key_no) != Some(0) {
+                    return Err(STATUS_AUTHENTICATION_ERROR);
+                }
+                if self.key_settings() & 0x08 == 0 {
+                    return Err(STATUS_PERMISSION_DENIED);
+                }
+                match self.applications.get_mut(&self.selected) {
+                    Some(app) => app.key_settings = *key_settings,
+                    None => self.picc_key_settings = *key_settings,
+                }
+                Ok(vec![])
+            }
+            (0x64, [key_no]) => self
+                .key(*key_no)
+                .map(|key| vec![key.version()])
+                .ok_or(STATUS_NO_SUCH_KEY),
+            (0x6E, []) => Ok(write_u24(self.free_memory()).to_vec()),
+            (0xFC, []) if self.selected == 0 => {
+                self.applications.clear();
+                Ok(vec![])
+            }
+            (0x6F, []) => Ok(self.application()?.files.keys().cloned().collect()),
+            (0xF5, [file_no]) => Ok(self.file(*file_no)?.settings().to_bytes()),
+            (0xDF, [file_no]) => {
+                self.application()?
+                    .files
+                    .remove(file_no)
+                    .ok_or(STATUS_FILE_NOT_FOUND)?;
+                Ok(vec![])
+            }
+            (0xCD, [file_no, ..])
+            | (0xCB, [file_no, ..])
+            | (0xCC, [file_no, ..])
+            | (0xC1, [file_no, ..])
+            | (0xC0, [file_no, ..]) => {
+                let file_type = match command {
+                    0xCD => 0x00,
+                    0xCB => 0x01,
+                    0xCC => 0x02,
+                    0xC1 => 0x03,
+                    _ => 0x04,
+                };
+                // GetFileSettingsと同じ並び（レコード数は0）にして読む
+                let mut settings = vec![file_type];
+                settings.extend_from_slice(&data[1..]);
+                settings.extend_from_slice(&[0, 0, 0]);
+                let settings = FileSettings::parse(&settings).map_err(|_| STATUS_LENGTH_ERROR)?;
+                let file_no = *file_no;
+                let app = self.application()?;
+                if app.files.contains_key(&file_no) {
+                    return Err(STATUS_DUPLICATE_ERROR);
+                }
+                app.files.insert(file_no, VirtualFile::new(settings));
+                Ok(vec![])
+            }
+            (0xBD, [file_no, ..]) if data.len() == 7 => {
+                let (offset, length) = (read_u24(&data[1..4]), read_u24(&data[4..7]));
+                let file = self.file_access(*file_no, false)?;
+                let size = match file.settings.kind {
+                    FileKind::StandardData { size } | FileKind::BackupData { size } => size,
+                    _ => return Err(STATUS_PARAMETER_ERROR),
+                };
+                let length = if length == 0 {
+                    size.saturating_sub(offset)
+                } else {
+                    length
+                };
+                if offset + length > size {
+                    return Err(STATUS_BOUNDARY_ERROR);
+                }
+                Ok(file.data[offset as usize..(offset + length) as usize].to_vec())
+            }
+            (0x3D, [file_no, ..]) if data.len() >= 7 => {
+                let offset = read_u24(&data[1..4]) as usize;
+                let payload = &data[7..];
+                let file = self.file_access(*file_no, true)?;
+                let backup = match file.settings.kind {
+                    FileKind::StandardData { .. } => false,
+                    FileKind::BackupData { .. } => true,
+                    _ => return Err(STATUS_PARAMETER_ERROR),
+                };
+                if offset + payload.len() > file.data.len() {
+                    return Err(STATUS_BOUNDARY_ERROR);
+                }
+                let target = if backup {
+                    let current = file.data.clone();
+                    file.pending_data.get_or_insert(current)
+                } else {
+                    &mut file.data
+                };
+                target[offset..offset + payload.len()].copy_from_slice(payload);
+                Ok(vec![])
+            }
+            (0x6C, [file_no]) => {
+                let file = self.file_access(*file_no, false)?;
+                match file.settings.kind {
+                    FileKind::Value { .. } => Ok(file.value.to_le_bytes().to_vec()),
+                    _ => Err(STATUS_PARAMETER_ERROR),
+                }
+            }
+            (0x0C, [file_no, a, b, c, d])
+            | (0xDC, [file_no, a, b, c, d])
+            | (0x1C, [file_no, a, b, c, d]) => {
+                let amount = i32::from_le_bytes([*a, *b, *c, *d]);
+                let file = self.file_access(*file_no, true)?;
+                let (lower_limit, upper_limit) = match file.settings.kind {
+                    FileKind::Value {
+                        lower_limit,
+                        upper_limit,
+                        ..
+                    } => (lower_limit, upper_limit),
+                    _ => return Err(STATUS_PARAMETER_ERROR),
+                };
+                if amount < 0 {
+                    return Err(STATUS_PARAMETER_ERROR);
+                }
+                let current = file.pending_value.unwrap_or(file.value);
+                let value = if command == 0xDC {
+                    current.checked_sub(amount)
+                } else {
+                    current.checked_add(amount)
+                }
+                .filter(|value| *value >= lower_limit && *value <= upper_limit)
+                .ok_or(STATUS_BOUNDARY_ERROR)?;
+                file.pending_value = Some(value);
+                Ok(vec![])
+            }
+            (0x3B, [file_no, ..]) if data.len() >= 7 => {
+                let offset = read_u24(&data[1..4]) as usize;
+                let payload = &data[7..];
+                let file = self.file_access(*file_no, true)?;
+                let (record_size, max_records, cyclic) = match file.settings.kind {
+                    FileKind::LinearRecord {
+                        record_size,
+                        max_records,
+                        ..
+                    } => (record_size as usize, max_records as usize, false),
+                    FileKind::CyclicRecord {
+                        record_size,
+                        max_records,
+                        ..
+                    } => (record_size as usize, max_records as usize, true),
+                    _ => return Err(STATUS_PARAMETER_ERROR),
+                };
+                if offset + payload.len() > record_size {
+                    return Err(STATUS_BOUNDARY_ERROR);
+                }
+                // 1回のトランザクション内の書き込みは同じ新しいレコードに対して行われる
+                if file.pending_records.is_none() {
+                    if !cyclic && file.records.len() >= max_records {
+                        return Err(STATUS_BOUNDARY_ERROR);
+                    }
+                    let mut records = file.records.clone();
+                    records.push(vec![0; record_size]);
+                    file.pending_records = Some(records);
+                }
+                let records = file.pending_records.as_mut().unwrap();
+                let record = records.last_mut().unwrap();
+                record[offset..offset + payload.len()].copy_from_slice(payload);
+                Ok(vec![])
+            }
+            (0xBB, [file_no, ..]) if data.len() == 7 => {
+                let (offset, count) = (read_u24(&data[1..4]), read_u24(&data[4..7]));
+                let file = self.file_access(*file_no, false)?;
+                let total = file.records.len() as u32;
+                if offset >= total {
+                    return Err(STATUS_BOUNDARY_ERROR);
+                }
+                let count = if count == 0 { total - offset } else { count };
+                if offset + count > total {
+                    return Err(STATUS_BOUNDARY_ERROR);
+                }
+                let end = (total - offset) as usize;
+                let start = end - count as usize;
+                Ok(file.records[start..end].concat())
+            }
+            (0xEB, [file_no]) => {
+                let file = self.file_access(*file_no, true)?;
+                file.pending_records = Some(Vec::new());
+                Ok(vec![])
+            }
+            (0xC7, []) => {
+                self.application()?
+                    .files
+                    .values_mut()
+                    .for_each(VirtualFile::commit);
+                Ok(vec![])
+            }
+            (0xA7, []) => {
+                self.application()?
+                    .files
+                    .values_mut()
+                    .for_each(VirtualFile::abort);
+                Ok(vec![])
+            }
+            _ => Err(STATUS_ILLEGAL_COMMAND),
+        }
+    }
+    /// 応答が長ければ分割し、残りを追加フレームで返す
+    fn respond_frames(&mut self, command: u8, data: Vec<u8>) -> Option<Vec<u8>> {
+        let mut frames = if command == 0x60 && data.len() > 14 {
+            // ハードウェア・ソフトウェア・製造情報の3フレームで返す
+            vec![
+                data[..7].to_vec(),
+                data[7..14].to_vec(),
+                data[14..].to_vec(),
+            ]
+            .into()
+        } else {
+            data.chunks(MAX_FRAME_SIZE)
+                .map(|frame| frame.to_vec())
+                .collect::<VecDeque<Vec<u8>>>()
+        };
+        let first = frames.pop_front().unwrap_or_default();
+        self.pending_response = frames;
+        self.respond_frame(first)
+    }
+    fn respond_frame(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+        let status = if self.pending_response.is_empty() {
+            STATUS_OK
+        } else {
+            STATUS_ADDITIONAL_FRAME
+        };
+        Self::respond(&data, status)
+    }
+    fn respond(data: &[u8], status: u8) -> Option<Vec<u8>> {
+        Some(data.iter().chain([SW1, status].iter()).cloned().collect())
+    }
+}
+
+impl VirtualCard for VirtualDesfire {
+    fn atr(&self) -> Vec<u8> {
+        // ISO14443-4 TypeAのATR(ATSの履歴バイトはDESFire EV1の80)
+        vec![0x3B, 0x81, 0x80, 0x01, 0x80, 0x80]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let (command, data) = match apdu {
+            [0x90, command, 0x00, 0x00, 0x00] => (*command, &[][..]),
+            [0x90, command, 0x00, 0x00, lc, rest @ ..] if rest.len() == *lc as usize + 1 => {
+                (*command, &rest[..*lc as usize])
+            }
+            _ => {
+                return Some(SW_INS_NOT_SUPPORTED.to_vec());
+            }
+        };
+        if command == 0xAF {
+            if let Some(auth) = self.pending_auth.take() {
+                return self.finish_authentication(auth, data);
+            }
+            if let Some((command, mut received, expected)) = self.pending_command.take() {
+                received.extend_from_slice(data);
+                if received.len() < expected {
+                    self.pending_command = Some((command, received, expected));
+                    return Self::respond(&[], STATUS_ADDITIONAL_FRAME);
+                }
+                return self.dispatch(command, &received);
+            }
+            return match self.pending_response.pop_front() {
+                Some(frame) => self.respond_frame(frame),
+                None => Self::respond(&[], STATUS_ILLEGAL_COMMAND),
+            };
+        }
+        self.pending_command = None;
+        self.pending_response.clear();
+        self.pending_auth = None;
+        let expected = self.expected_length(command, data);
+        if data.len() < expected {
+            self.pending_command = Some((command, data.to_vec(), expected));
+            return Self::respond(&[], STATUS_ADDITIONAL_FRAME);
+        }
+        self.dispatch(command, data)
+    }
+}
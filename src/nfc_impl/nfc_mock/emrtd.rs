@@ -0,0 +1,445 @@
+// ICAO 9303 のeMRTD(ICパスポート)の仮想カード
+// MFの下にEF.CardAccess(PACEを有効にしたとき)、LDS1アプリケーションの下にEF.COM・EF.SOD・データグループを置き、
+// BAC(GET CHALLENGE・EXTERNAL AUTHENTICATE)とPACE(MSE:Set AT・GENERAL AUTHENTICATE、ECDH-GM)に応答する。
+// 認証の後はSMのコマンドだけを受け付け、復号したコマンドをファイルシステムの仮想カードに渡して応答をSMで包む。
+// SMなしのコマンドが来たらセッションを終える。認証の前に使えるのはアプリケーションの選択とEF.CardAccessの読み出しだけ。
+// EF.COM・EF.SODを置かなければデータグループから作る（EF.SODのハッシュはSHA-256で、署名はない）。
+
+use super::iso7816::VirtualFileSystemCard;
+use super::VirtualCard;
+use crate::ber_tlv::{self, Tlv};
+use crate::crypto::random_bytes;
+use crate::crypto::sha::sha256;
+use crate::emrtd::bac::{BacKeys, CHALLENGE_SIZE, KEY_MATERIAL_SIZE};
+use crate::emrtd::lds::{data_group_number, TAG_COM, TAG_DG1, TAG_SOD};
+use crate::emrtd::mrz::{Mrz, MrzKey};
+use crate::emrtd::pace::{self, PaceInfo, Password};
+use crate::emrtd::{data_group_file_id, SmAlgorithm, EF_CARD_ACCESS, EF_CARD_ACCESS_SFI, EF_COM};
+use crate::emrtd::{EF_SOD, LDS1_AID};
+use crate::iso7816::secure_messaging::SmSession;
+use std::collections::BTreeMap;
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_AUTHENTICATION_FAILED: [u8; 2] = [0x63, 0x00];
+const SW_WRONG_LENGTH: [u8; 2] = [0x67, 0x00];
+const SW_SECURITY_STATUS_NOT_SATISFIED: [u8; 2] = [0x69, 0x82];
+const SW_CONDITIONS_NOT_SATISFIED: [u8; 2] = [0x69, 0x85];
+/// SMのデータオブジェクトが正しくない
+const SW_SM_DATA_INCORRECT: [u8; 2] = [0x69, 0x88];
+const SW_WRONG_DATA: [u8; 2] = [0x6A, 0x80];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const SW_NO_PRECISE_DIAGNOSIS: [u8; 2] = [0x6F, 0x00];
+const INS_MANAGE_SECURITY_ENVIRONMENT: u8 = 0x22;
+const INS_EXTERNAL_AUTHENTICATE: u8 = 0x82;
+const INS_GET_CHALLENGE: u8 = 0x84;
+const INS_GENERAL_AUTHENTICATE: u8 = 0x86;
+const INS_SELECT_FILE: u8 = 0xA4;
+const INS_READ_BINARY: u8 = 0xB0;
+/// LDS1アプリケーションのファイルID（仮想カードの中だけで使う）
+const LDS1_FILE_ID: u16 = 0x7F10;
+/// CLAのSMの指定(b4-b3)
+const SECURE_MESSAGING: u8 = 0x0C;
+
+/// PACEの途中の状態
+enum PaceStep {
+    /// MSE:Set ATの後。パスワードから導いた鍵 K_π
+    Started(Vec<u8>),
+    Nonce(Vec<u8>),
+    /// マッピングした生成元 G~
+    Mapped(Vec<u8>),
+    /// 共有秘密・カードの一時公開鍵・端末の一時公開鍵
+    Agreed(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+enum AuthState {
+    Idle,
+    /// GET CHALLENGEで返したRND.IC
+    Challenge(Vec<u8>),
+    Pace(PaceStep),
+    Secure(SmSession),
+}
+
+pub struct VirtualEmrtd {
+    mrz_key: MrzKey,
+    can: Option<String>,
+    /// LDS1アプリケーションのEF
+    files: BTreeMap<u16, Vec<u8>>,
+    pace: Option<PaceInfo>,
+    /// BACのRND.IC・K.IC（Noneなら乱数）
+    bac_randoms: Option<(Vec<u8>, Vec<u8>)>,
+    /// PACEのノンス・マッピング用の秘密鍵・一時秘密鍵（Noneなら乱数）
+    pace_keys: Option<(Vec<u8>, Vec<u8>, Vec<u8>)>,
+    /// 最初のコマンドでファイルを置く
+    card: Option<VirtualFileSystemCard>,
+    state: AuthState,
+}
+
+impl VirtualEmrtd {
+    /// DG1にMRZ(改行なし)を入れたBACだけのeMRTD
+    pub fn new(mrz: &str) -> Self {
+        let parsed = Mrz::parse(mrz).expect("invalid MRZ");
+        let dg1 = Tlv::constructed(TAG_DG1, vec![Tlv::primitive(0x5F1F, mrz.as_bytes())]);
+        let mut files = BTreeMap::new();
+        files.insert(data_group_file_id(1), dg1.to_bytes());
+        VirtualEmrtd {
+            mrz_key: parsed.key().expect("invalid MRZ"),
+            can: None,
+            files,
+            pace: None,
+            bac_randoms: None,
+            pace_keys: None,
+            card: None,
+            state: AuthState::Idle,
+        }
+    }
+    /// LDS1アプリケーションのEFを置く（EF.COM・EF.SODも置き換えられる）
+    pub fn set_file(&mut self, file_id: u16, data: &[u8]) -> &mut Self {
+        self.files.insert(file_id, data.to_vec());
+        self
+    }
+    pub fn set_data_group(&mut self, number: u8, data: &[u8]) -> &mut Self {
+        self.set_file(data_group_file_id(number), data)
+    }
+    /// PACEを有効にする（EF.CardAccessにPACEInfoを書く）
+    pub fn enable_pace(&mut self, info: PaceInfo) -> &mut Self {
+        self.pace = Some(info);
+        self
+    }
+    pub fn set_can(&mut self, can: &str) -> &mut Self {
+        self.can = Some(can.to_owned());
+        self
+    }
+    pub fn set_bac_randoms(&mut self, rnd_ic: &[u8], k_ic: &[u8]) -> &mut Self {
+        self.bac_randoms = Some((rnd_ic.to_vec(), k_ic.to_vec()));
+        self
+    }
+    pub fn set_pace_keys(&mut self, nonce: &[u8], map_key: &[u8], key: &[u8]) -> &mut Self {
+        self.pace_keys = Some((nonce.to_vec(), map_key.to_vec(), key.to_vec()));
+        self
+    }
+
+    fn file_system(&mut self) -> &mut VirtualFileSystemCard {
+        if self.card.is_none() {
+            let mut card = VirtualFileSystemCard::new();
+            if let Some(info) = &self.pace {
+                let card_access = Tlv::constructed(0x31, vec![info.to_tlv()]);
+                card.add_transparent_ef(
+                    &[EF_CARD_ACCESS],
+                    Some(EF_CARD_ACCESS_SFI),
+                    &card_access.to_bytes(),
+                );
+            }
+            card.add_df(&[LDS1_FILE_ID], Some(&LDS1_AID));
+            let data_groups = self
+                .files
+                .iter()
+                .filter(|(id, data)| {
+                    (data_group_file_id(1)..=data_group_file_id(16)).contains(*id)
+                        && !data.is_empty()
+                })
+                .map(|(id, data)| (*id as u8, data.clone()))
+                .collect::<Vec<(u8, Vec<u8>)>>();
+            self.files
+                .entry(EF_COM)
+                .or_insert_with(|| build_com(&data_groups));
+            self.files
+                .entry(EF_SOD)
+                .or_insert_with(|| build_sod(&data_groups));
+            for (id, data) in self.files.iter() {
+                card.add_transparent_ef(&[LDS1_FILE_ID, *id], None, data);
+            }
+            self.card = Some(card);
+        }
+        self.card.as_mut().unwrap()
+    }
+    /// SMのコマンドを開いてファイルシステムに渡し、応答を包む
+    fn process_secure(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let mut session = match std::mem::replace(&mut self.state, AuthState::Idle) {
+            AuthState::Secure(session) => session,
+            state => {
+                self.state = state;
+                return SW_CONDITIONS_NOT_SATISFIED.to_vec();
+            }
+        };
+        // 検証できなければセッションを終える
+        let command = match session.unwrap_command(apdu) {
+            Ok(command) => command,
+            Err(_) => return SW_SM_DATA_INCORRECT.to_vec(),
+        };
+        let res = self
+            .file_system()
+            .process_apdu(&command)
+            .unwrap_or_else(|| SW_WRONG_LENGTH.to_vec());
+        let (data, status_word) = res.split_at(res.len() - 2);
+        let mut wrapped = session.wrap_response(apdu[1], data, [status_word[0], status_word[1]]);
+        wrapped.extend_from_slice(status_word);
+        self.state = AuthState::Secure(session);
+        wrapped
+    }
+    fn process_plain(&mut self, apdu: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        let (ins, p1, p2) = (apdu[1], apdu[2], apdu[3]);
+        let data = command_data(apdu).ok_or(SW_WRONG_LENGTH)?;
+        match ins {
+            INS_SELECT_FILE | INS_READ_BINARY => Err(SW_SECURITY_STATUS_NOT_SATISFIED),
+            INS_GET_CHALLENGE if p1 == 0 && p2 == 0 => Ok(self.get_challenge()),
+            INS_EXTERNAL_AUTHENTICATE if p1 == 0 && p2 == 0 => self.external_authenticate(data),
+            INS_MANAGE_SECURITY_ENVIRONMENT if p1 == 0xC1 && p2 == 0xA4 => {
+                self.set_authentication_template(data)
+            }
+            INS_GENERAL_AUTHENTICATE if p1 == 0 && p2 == 0 => self.general_authenticate(data),
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        }
+    }
+    fn get_challenge(&mut self) -> Vec<u8> {
+        let rnd_ic = match &self.bac_randoms {
+            Some((rnd_ic, _)) => rnd_ic.clone(),
+            None => random_bytes(CHALLENGE_SIZE),
+        };
+        self.state = AuthState::Challenge(rnd_ic.clone());
+        rnd_ic
+    }
+    fn external_authenticate(&mut self, data: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        let rnd_ic = match std::mem::replace(&mut self.state, AuthState::Idle) {
+            AuthState::Challenge(rnd_ic) => rnd_ic,
+            _ => return Err(SW_CONDITIONS_NOT_SATISFIED),
+        };
+        let keys = BacKeys::new(&self.mrz_key);
+        // RND.IFD || RND.IC || K.IFD
+        let plain = keys.open(data).ok_or(SW_AUTHENTICATION_FAILED)?;
+        if plain.len() != CHALLENGE_SIZE * 2 + KEY_MATERIAL_SIZE
+            || plain[CHALLENGE_SIZE..CHALLENGE_SIZE * 2] != rnd_ic[..]
+        {
+            return Err(SW_AUTHENTICATION_FAILED);
+        }
+        let (rnd_ifd, k_ifd) = (&plain[..CHALLENGE_SIZE], &plain[CHALLENGE_SIZE * 2..]);
+        let k_ic = match &self.bac_randoms {
+            Some((_, k_ic)) => k_ic.clone(),
+            None => random_bytes(KEY_MATERIAL_SIZE),
+        };
+        let res = keys.seal(&[&rnd_ic[..], rnd_ifd, &k_ic[..]].concat());
+        let seed = k_ifd
+            .iter()
+            .zip(k_ic.iter())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<u8>>();
+        let ssc = [&rnd_ic[4..], &rnd_ifd[4..]].concat();
+        self.state = AuthState::Secure(SmAlgorithm::Tdes.session(&seed, &ssc));
+        Ok(res)
+    }
+    fn set_authentication_template(&mut self, data: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        self.state = AuthState::Idle;
+        let info = self.pace.clone().ok_or(SW_CONDITIONS_NOT_SATISFIED)?;
+        let objects = ber_tlv::read_objects(data).map_err(|_| SW_WRONG_DATA)?;
+        let value = |tag: u32| {
+            objects
+                .iter()
+                .find(|(t, _)| t.0 == tag)
+                .map(|(_, value)| *value)
+        };
+        if value(0x80) != Some(&info.protocol[..]) {
+            return Err(SW_WRONG_DATA);
+        }
+        let password = match value(0x83) {
+            Some([0x01]) => Password::Mrz(self.mrz_key.clone()),
+            Some([0x02]) => Password::Can(self.can.clone().ok_or(SW_WRONG_DATA)?),
+            _ => return Err(SW_WRONG_DATA),
+        };
+        let algorithm = info.algorithm().ok_or(SW_WRONG_DATA)?;
+        self.state = AuthState::Pace(PaceStep::Started(
+            algorithm.password_key(&password.secret()),
+        ));
+        Ok(Vec::new())
+    }
+    fn general_authenticate(&mut self, data: &[u8]) -> Result<Vec<u8>, [u8; 2]> {
+        let step = match std::mem::replace(&mut self.state, AuthState::Idle) {
+            AuthState::Pace(step) => step,
+            _ => return Err(SW_CONDITIONS_NOT_SATISFIED),
+        };
+        let info = self.pace.clone().ok_or(SW_CONDITIONS_NOT_SATISFIED)?;
+        let algorithm = info.algorithm().ok_or(SW_WRONG_DATA)?;
+        let curve = info.curve().ok_or(SW_WRONG_DATA)?;
+        let template = match Tlv::parse(data) {
+            Ok((template, _)) if template.tag.0 == 0x7C => template,
+            _ => return Err(SW_WRONG_DATA),
+        };
+        let object = |tag: u32| {
+            template
+                .children()
+                .iter()
+                .find(|child| child.tag.0 == tag)
+                .and_then(|child| child.value())
+                .ok_or(SW_WRONG_DATA)
+        };
+        let (next, response) = match step {
+            PaceStep::Started(password_key) => {
+                let nonce = match &self.pace_keys {
+                    Some((nonce, _, _)) => nonce.clone(),
+                    None => random_bytes(algorithm.block_size()),
+                };
+                let cipher = algorithm.cipher(&password_key).ok_or(SW_WRONG_DATA)?;
+                let encrypted = cipher.cbc_encrypt(&vec![0u8; algorithm.block_size()], &nonce);
+                (PaceStep::Nonce(nonce), Tlv::primitive(0x80, &encrypted))
+            }
+            PaceStep::Nonce(nonce) => {
+                let terminal = object(0x81)?;
+                let map_key = match &self.pace_keys {
+                    Some((_, map_key, _)) => map_key.clone(),
+                    None => curve
+                        .random_private_key()
+                        .map_err(|_| SW_NO_PRECISE_DIAGNOSIS)?,
+                };
+                let public = curve
+                    .multiply(&curve.generator(), &map_key)
+                    .ok_or(SW_WRONG_DATA)?;
+                let shared = curve.multiply(terminal, &map_key).ok_or(SW_WRONG_DATA)?;
+                let mapped = pace::map_generator(&curve, &nonce, &shared).ok_or(SW_WRONG_DATA)?;
+                (PaceStep::Mapped(mapped), Tlv::primitive(0x82, &public))
+            }
+            PaceStep::Mapped(generator) => {
+                let terminal = object(0x83)?;
+                let key = match &self.pace_keys {
+                    Some((_, _, key)) => key.clone(),
+                    None => curve
+                        .random_private_key()
+                        .map_err(|_| SW_NO_PRECISE_DIAGNOSIS)?,
+                };
+                let public = curve.multiply(&generator, &key).ok_or(SW_WRONG_DATA)?;
+                let secret = curve.multiply(terminal, &key).ok_or(SW_WRONG_DATA)?;
+                (
+                    PaceStep::Agreed(
+                        curve.x_coordinate(&secret).to_vec(),
+                        public.clone(),
+                        terminal.to_vec(),
+                    ),
+                    Tlv::primitive(0x84, &public),
+                )
+            }
+            PaceStep::Agreed(secret, public, terminal) => {
+                let expected =
+                    pace::authentication_token(algorithm, &secret, &info.protocol, &public);
+                if object(0x85)? != &expected[..] {
+                    return Err(SW_AUTHENTICATION_FAILED);
+                }
+                let token =
+                    pace::authentication_token(algorithm, &secret, &info.protocol, &terminal);
+                self.state = AuthState::Secure(algorithm.session(&secret, &[]));
+                return Ok(Tlv::constructed(0x7C, vec![Tlv::primitive(0x86, &token)]).to_bytes());
+            }
+        };
+        self.state = AuthState::Pace(next);
+        Ok(Tlv::constructed(0x7C, vec![response]).to_bytes())
+    }
+}
+
+/// コマンドAPDUのデータ（Lcがなければ空）
+fn command_data(apdu: &[u8]) -> Option<&[u8]> {
+    match apdu.get(4..) {
+        None | Some([]) | Some([_]) => Some(&[]),
+        Some([lc, rest @ ..]) if rest.len() >= *lc as usize && rest.len() <= *lc as usize + 1 => {
+            Some(&rest[..*lc as usize])
+        }
+        _ => None,
+    }
+}
+
+/// データグループの一覧からEF.COM(LDS 1.7、Unicode 4.0.0)を作る
+fn build_com(data_groups: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let tags = data_groups
+        .iter()
+        .filter_map(|(_, data)| data.first().cloned())
+        .filter(|tag| data_group_number(*tag).is_some())
+        .collect::<Vec<u8>>();
+    Tlv::constructed(
+        TAG_COM,
+        vec![
+            Tlv::primitive(0x5F01, b"0107"),
+            Tlv::primitive(0x5F36, b"040000"),
+            Tlv::primitive(0x5C, &tags),
+        ],
+    )
+    .to_bytes()
+}
+
+/// LDSSecurityObject(SHA-256)を署名なしのSignedDataに入れる
+fn build_sod(data_groups: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    // id-sha256・id-signedData・id-icao-mrtd-security-ldsSecurityObject
+    let sha256_oid = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+    let signed_data_oid = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+    let lds_object_oid = [0x67, 0x81, 0x08, 0x01, 0x01, 0x01];
+    let hashes = data_groups
+        .iter()
+        .map(|(number, data)| {
+            Tlv::constructed(
+                0x30,
+                vec![
+                    Tlv::primitive(0x02, &[*number]),
+                    Tlv::primitive(0x04, &sha256(data)),
+                ],
+            )
+        })
+        .collect();
+    let object = Tlv::constructed(
+        0x30,
+        vec![
+            Tlv::primitive(0x02, &[0x00]),
+            Tlv::constructed(0x30, vec![Tlv::primitive(0x06, &sha256_oid)]),
+            Tlv::constructed(0x30, hashes),
+        ],
+    );
+    let signed_data = Tlv::constructed(
+        0x30,
+        vec![
+            Tlv::primitive(0x02, &[0x03]),
+            Tlv::constructed(0x31, Vec::new()),
+            Tlv::constructed(
+                0x30,
+                vec![
+                    Tlv::primitive(0x06, &lds_object_oid),
+                    Tlv::constructed(0xA0, vec![Tlv::primitive(0x04, &object.to_bytes())]),
+                ],
+            ),
+            Tlv::constructed(0x31, Vec::new()),
+        ],
+    );
+    Tlv::constructed(
+        TAG_SOD,
+        vec![Tlv::constructed(
+            0x30,
+            vec![
+                Tlv::primitive(0x06, &signed_data_oid),
+                Tlv::constructed(0xA0, vec![signed_data]),
+            ],
+        )],
+    )
+    .to_bytes()
+}
+
+impl VirtualCard for VirtualEmrtd {
+    fn atr(&self) -> Vec<u8> {
+        // ISO14443-4 TypeAのATR（履歴バイトなし）
+        vec![0x3B, 0x80, 0x80, 0x01, 0x01]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        if apdu.len() < 4 {
+            return Some(SW_WRONG_LENGTH.to_vec());
+        }
+        if apdu[0] & SECURE_MESSAGING == SECURE_MESSAGING {
+            return Some(self.process_secure(apdu));
+        }
+        // SMなしのコマンドでセッションを終える
+        if let AuthState::Secure(_) = self.state {
+            self.state = AuthState::Idle;
+        }
+        // アプリケーションの選択とEF.CardAccessの読み出しはそのままファイルシステムに渡す
+        let (ins, p1) = (apdu[1], apdu[2]);
+        if (ins == INS_SELECT_FILE && p1 == 0x04)
+            || (ins == INS_READ_BINARY && p1 == 0x80 | EF_CARD_ACCESS_SFI)
+        {
+            return self.file_system().process_apdu(apdu);
+        }
+        Some(match self.process_plain(apdu) {
+            Ok(data) => [&data[..], &SW_SUCCESS[..]].concat(),
+            Err(status_word) => status_word.to_vec(),
+        })
+    }
+}
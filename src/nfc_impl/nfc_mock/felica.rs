@@ -0,0 +1,245 @@
+// PN53x系リーダーに載ったFeliCa Lite-Sを模した仮想カード
+// DIRECT TRANSMIT(FF 00 00 00)で渡された InListPassiveTarget(Polling) と
+// InDataExchange の Request Service・Read/Write Without Encryption に応答する
+// Search Service Code は enable_service_search で有効にしたときだけ応答する
+
+use super::VirtualCard;
+use crate::felica::command::{IDM_SIZE, PMM_SIZE, SYSTEM_CODE_WILDCARD};
+use crate::felica::ndef::{
+    LITE_S_SYSTEM_CODE, NDEF_READ_SERVICE, NDEF_SYSTEM_CODE, NDEF_WRITE_SERVICE,
+};
+use crate::felica::BLOCK_SIZE;
+use std::collections::BTreeMap;
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+/// PN53xのタイムアウト（カードが応答しない）
+const STATUS_TIMEOUT: u8 = 0x01;
+/// MC(メモリコンフィグレーション)ブロックとSYS_OPの位置
+const MC_BLOCK: u16 = 0x88;
+const MC_SYS_OP: usize = 3;
+// ステータスフラグ2
+const STATUS_ILLEGAL_SERVICE: u8 = 0xA6;
+const STATUS_ILLEGAL_BLOCK: u8 = 0xA8;
+/// 存在しないノード・列挙の終わり
+const NODE_NOT_FOUND: u16 = 0xFFFF;
+/// Search Service Codeで返すノード（エリア0000、NDEFのサービス、認証が要るサービス）
+const NODES: [(u16, Option<u16>); 4] = [
+    (0x0000, Some(0xFFFE)),
+    (NDEF_WRITE_SERVICE, None),
+    (NDEF_READ_SERVICE, None),
+    (0x1008, None),
+];
+
+pub struct VirtualFelicaLiteS {
+    idm: [u8; IDM_SIZE],
+    pmm: [u8; PMM_SIZE],
+    blocks: BTreeMap<u16, [u8; BLOCK_SIZE]>,
+    service_search: bool,
+}
+
+impl VirtualFelicaLiteS {
+    /// S_PADがすべて0で、NDEFに対応していない出荷状態のカード
+    pub fn new() -> Self {
+        let idm = [0x01, 0x2E, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+        let mut blocks = BTreeMap::new();
+        // S_PAD0～13, REG
+        for block in 0x00..=0x0E {
+            blocks.insert(block, [0u8; BLOCK_SIZE]);
+        }
+        let mut id = [0u8; BLOCK_SIZE];
+        id[..IDM_SIZE].copy_from_slice(&idm);
+        blocks.insert(0x82, id);
+        let mut sys_c = [0u8; BLOCK_SIZE];
+        sys_c[..2].copy_from_slice(&LITE_S_SYSTEM_CODE.to_be_bytes());
+        blocks.insert(0x85, sys_c);
+        let mut mc = [0u8; BLOCK_SIZE];
+        mc[..3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        blocks.insert(MC_BLOCK, mc);
+        VirtualFelicaLiteS {
+            idm,
+            pmm: [0x00, 0xF1, 0x00, 0x00, 0x00, 0x01, 0x43, 0x00],
+            blocks,
+            service_search: false,
+        }
+    }
+    /// Search Service Codeにも応答させる（FeliCa Lite-S自体は対応していない）
+    pub fn enable_service_search(&mut self) {
+        self.service_search = true;
+    }
+    pub fn block(&self, block: u16) -> [u8; BLOCK_SIZE] {
+        self.blocks[&block]
+    }
+    pub fn set_block(&mut self, block: u16, data: &[u8; BLOCK_SIZE]) {
+        self.blocks.insert(block, *data);
+    }
+    /// MCのSYS_OPが01ならNDEFのシステムコードにも応答する
+    fn has_system_code(&self, system_code: u16) -> bool {
+        system_code == SYSTEM_CODE_WILDCARD
+            || system_code == LITE_S_SYSTEM_CODE
+            || (system_code == NDEF_SYSTEM_CODE && self.blocks[&MC_BLOCK][MC_SYS_OP] == 0x01)
+    }
+    fn is_writable(block: u16) -> bool {
+        block <= 0x0E || block == MC_BLOCK
+    }
+    /// サービス数(1) || サービスコード || ブロック数 || ブロックリスト を読み、残りを返す
+    fn parse_block_list(command: &[u8]) -> Option<(u16, Vec<u16>, &[u8])> {
+        let (&services, rest) = command.split_first()?;
+        if services != 1 || rest.len() < 3 {
+            return None;
+        }
+        let service = u16::from_le_bytes([rest[0], rest[1]]);
+        let count = rest[2] as usize;
+        let mut rest = &rest[3..];
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            match rest {
+                [head, block, tail @ ..] if head & 0x80 != 0 => {
+                    blocks.push(*block as u16);
+                    rest = tail;
+                }
+                [_, low, high, tail @ ..] => {
+                    blocks.push(u16::from_le_bytes([*low, *high]));
+                    rest = tail;
+                }
+                _ => return None,
+            }
+        }
+        Some((service, blocks, rest))
+    }
+    /// ノード数 || ノードコードリスト に 鍵バージョン(0000)で応答する
+    fn request_service(command: &[u8]) -> Option<Vec<u8>> {
+        let (&count, list) = command.split_first()?;
+        if list.len() != count as usize * 2 {
+            return None;
+        }
+        let mut res = vec![count];
+        for node in list.chunks(2) {
+            let node = u16::from_le_bytes([node[0], node[1]]);
+            let version = if NODES.iter().any(|(code, _)| *code == node) {
+                0x0000
+            } else {
+                NODE_NOT_FOUND
+            };
+            res.extend_from_slice(&version.to_le_bytes());
+        }
+        Some(res)
+    }
+    /// インデックス番目のノードを返す
+    fn search_service_code(command: &[u8]) -> Option<Vec<u8>> {
+        let index = match command {
+            [low, high] => u16::from_le_bytes([*low, *high]) as usize,
+            _ => return None,
+        };
+        Some(match NODES.get(index) {
+            Some((code, Some(end))) => [code.to_le_bytes(), end.to_le_bytes()].concat(),
+            Some((code, None)) => code.to_le_bytes().to_vec(),
+            None => NODE_NOT_FOUND.to_le_bytes().to_vec(),
+        })
+    }
+    /// コマンドコードとIDmに続く部分を処理する。Errはステータスフラグ2
+    fn command(&mut self, code: u8, command: &[u8]) -> Result<Vec<u8>, u8> {
+        let (service, blocks, data) =
+            Self::parse_block_list(command).ok_or(STATUS_ILLEGAL_BLOCK)?;
+        match code {
+            // Read Without Encryption
+            0x06 => {
+                if service != NDEF_READ_SERVICE && service != NDEF_WRITE_SERVICE {
+                    return Err(STATUS_ILLEGAL_SERVICE);
+                }
+                let mut res = vec![blocks.len() as u8];
+                for block in blocks {
+                    res.extend_from_slice(self.blocks.get(&block).ok_or(STATUS_ILLEGAL_BLOCK)?);
+                }
+                Ok(res)
+            }
+            // Write Without Encryption
+            0x08 => {
+                if service != NDEF_WRITE_SERVICE {
+                    return Err(STATUS_ILLEGAL_SERVICE);
+                }
+                if data.len() != blocks.len() * BLOCK_SIZE
+                    || !blocks.iter().all(|block| Self::is_writable(*block))
+                {
+                    return Err(STATUS_ILLEGAL_BLOCK);
+                }
+                for (block, chunk) in blocks.iter().zip(data.chunks(BLOCK_SIZE)) {
+                    let mut value = [0u8; BLOCK_SIZE];
+                    value.copy_from_slice(chunk);
+                    self.blocks.insert(*block, value);
+                }
+                Ok(Vec::new())
+            }
+            _ => Err(STATUS_ILLEGAL_SERVICE),
+        }
+    }
+    fn respond(data: &[u8]) -> Option<Vec<u8>> {
+        Some([data, &SW_SUCCESS[..]].concat())
+    }
+}
+
+impl VirtualCard for VirtualFelicaLiteS {
+    fn atr(&self) -> Vec<u8> {
+        vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x11, 0x00,
+            0x3B, 0x00, 0x00, 0x00, 0x00, 0x42,
+        ]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let payload = match apdu {
+            [0xFF, 0x00, 0x00, 0x00, lc, payload @ ..] if payload.len() == *lc as usize => payload,
+            _ => return Some(SW_INS_NOT_SUPPORTED.to_vec()),
+        };
+        match payload {
+            // InListPassiveTarget 212kbps: 00 || システムコード || リクエストコード || タイムスロット
+            [0xD4, 0x4A, 0x01, 0x01, 0x00, high, low, request, _] => {
+                if !self.has_system_code(u16::from_be_bytes([*high, *low])) {
+                    return Self::respond(&[0xD5, 0x4B, 0x00]);
+                }
+                let mut res = vec![0xD5, 0x4B, 0x01, 0x01, 0x12, 0x01];
+                res.extend_from_slice(&self.idm);
+                res.extend_from_slice(&self.pmm);
+                if *request == 0x01 {
+                    res[4] = 0x14;
+                    let system_code = match u16::from_be_bytes([*high, *low]) {
+                        NDEF_SYSTEM_CODE => NDEF_SYSTEM_CODE,
+                        _ => LITE_S_SYSTEM_CODE,
+                    };
+                    res.extend_from_slice(&system_code.to_be_bytes());
+                }
+                Self::respond(&res)
+            }
+            // InDataExchange: 長さ || コマンドコード || IDm || ...
+            [0xD4, 0x40, 0x01, frame @ ..] => match frame {
+                [len, code, rest @ ..]
+                    if *len as usize == frame.len()
+                        && rest.len() >= IDM_SIZE
+                        && rest[..IDM_SIZE] == self.idm =>
+                {
+                    let command = &rest[IDM_SIZE..];
+                    // Request Service・Search Service Codeはステータスフラグを返さない
+                    let data = match code {
+                        0x02 => Self::request_service(command),
+                        0x0A if self.service_search => Self::search_service_code(command),
+                        0x0A => None,
+                        _ => Some(match self.command(*code, command) {
+                            Ok(data) => [&[0x00, 0x00][..], &data].concat(),
+                            Err(flag2) => vec![0x01, flag2],
+                        }),
+                    };
+                    let data = match data {
+                        Some(data) => data,
+                        None => return Self::respond(&[0xD5, 0x41, STATUS_TIMEOUT]),
+                    };
+                    let mut res = vec![0xD5, 0x41, 0x00, 0x00, code + 1];
+                    res.extend_from_slice(&self.idm);
+                    res.extend_from_slice(&data);
+                    res[3] = (res.len() - 3) as u8;
+                    Self::respond(&res)
+                }
+                _ => Self::respond(&[0xD5, 0x41, STATUS_TIMEOUT]),
+            },
+            _ => Some(SW_INS_NOT_SUPPORTED.to_vec()),
+        }
+    }
+}
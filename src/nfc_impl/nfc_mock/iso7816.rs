@@ -0,0 +1,587 @@
+// ISO/IEC 7816-4 のファイルシステムを持つ仮想カード
+// MFの下にDF・トランスペアレントEF・レコードEF(固定長/サイクリック)を置き、
+// SELECT FILE(FCI/FCP/FMD)・READ/UPDATE BINARY(偶数/奇数INS・SFI)・READ RECORDに応答する。
+// アクセス条件はセキュリティ属性のSCバイトで、00(常に許可)以外は満たされないものとして扱う。
+// MANAGE CHANNELで論理チャネル(1～19)を開くと、チャネルごとにカレントDF/EFを持つ。
+
+use super::VirtualCard;
+use crate::apdu_contactless::{channel_of_class, MAX_LOGICAL_CHANNEL};
+use crate::iso7816::MF_FILE_ID;
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+/// 読み出しの途中でファイルの終わりに達した
+const SW_END_OF_FILE: [u8; 2] = [0x62, 0x82];
+const SW_WRONG_LENGTH: [u8; 2] = [0x67, 0x00];
+/// 論理チャネルが開いていない
+const SW_CHANNEL_NOT_SUPPORTED: [u8; 2] = [0x68, 0x81];
+/// ファイルの構造とコマンドが合わない
+const SW_INCOMPATIBLE_FILE: [u8; 2] = [0x69, 0x81];
+const SW_SECURITY_STATUS_NOT_SATISFIED: [u8; 2] = [0x69, 0x82];
+/// EFが選択されていない
+const SW_NO_CURRENT_EF: [u8; 2] = [0x69, 0x86];
+const SW_WRONG_DATA: [u8; 2] = [0x6A, 0x80];
+/// 開けるチャネルがない・開いているチャネルを開こうとした
+const SW_FUNCTION_NOT_SUPPORTED: [u8; 2] = [0x6A, 0x81];
+const SW_FILE_NOT_FOUND: [u8; 2] = [0x6A, 0x82];
+const SW_RECORD_NOT_FOUND: [u8; 2] = [0x6A, 0x83];
+const SW_NOT_ENOUGH_MEMORY: [u8; 2] = [0x6A, 0x84];
+const SW_INCORRECT_P1P2: [u8; 2] = [0x6A, 0x86];
+const SW_WRONG_OFFSET: [u8; 2] = [0x6B, 0x00];
+const SW1_WRONG_LE: u8 = 0x6C;
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const SW_CLA_NOT_SUPPORTED: [u8; 2] = [0x6E, 0x00];
+
+const INS_SELECT_FILE: u8 = 0xA4;
+const INS_MANAGE_CHANNEL: u8 = 0x70;
+const INS_READ_BINARY: u8 = 0xB0;
+const INS_READ_BINARY_ODO: u8 = 0xB1;
+const INS_READ_RECORD: u8 = 0xB2;
+const INS_UPDATE_BINARY: u8 = 0xD6;
+const INS_UPDATE_BINARY_ODO: u8 = 0xD7;
+
+/// ライフサイクル: 運用中(活性)
+const LCS_ACTIVATED: u8 = 0x05;
+/// アクセス条件: 常に許可・常に禁止
+pub const ACCESS_ALWAYS: u8 = 0x00;
+pub const ACCESS_NEVER: u8 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq)]
+enum FileBody {
+    Df,
+    Transparent(Vec<u8>),
+    Records { cyclic: bool, records: Vec<Vec<u8>> },
+}
+
+#[derive(Debug, Clone)]
+struct VirtualFile {
+    fid: u16,
+    parent: Option<usize>,
+    name: Option<Vec<u8>>,
+    sfi: Option<u8>,
+    body: FileBody,
+    /// 読み出し・更新のSCバイト
+    read: u8,
+    update: u8,
+}
+
+impl VirtualFile {
+    fn is_df(&self) -> bool {
+        self.body == FileBody::Df
+    }
+    /// FCPテンプレート(62)の中身
+    fn fcp_objects(&self) -> Vec<u8> {
+        let mut objects = Vec::new();
+        match &self.body {
+            FileBody::Df => {
+                // DF: 共有可能なDF
+                push_object(&mut objects, 0x82, &[0x38]);
+            }
+            FileBody::Transparent(data) => {
+                push_object(&mut objects, 0x80, &(data.len() as u16).to_be_bytes());
+                push_object(&mut objects, 0x82, &[0x01]);
+            }
+            FileBody::Records { cyclic, records } => {
+                let size = records.iter().map(|r| r.len()).max().unwrap_or(0);
+                push_object(
+                    &mut objects,
+                    0x80,
+                    &((size * records.len()) as u16).to_be_bytes(),
+                );
+                let descriptor = if *cyclic { 0x06 } else { 0x02 };
+                // ファイル記述子 || データコーディング || 最大レコード長 || レコード数
+                push_object(
+                    &mut objects,
+                    0x82,
+                    &[descriptor, 0x21, size as u8, records.len() as u8],
+                );
+            }
+        }
+        push_object(&mut objects, 0x83, &self.fid.to_be_bytes());
+        if let Some(name) = &self.name {
+            push_object(&mut objects, 0x84, name);
+        }
+        if let Some(sfi) = self.sfi {
+            push_object(&mut objects, 0x88, &[sfi << 3]);
+        }
+        push_object(&mut objects, 0x8A, &[LCS_ACTIVATED]);
+        // コンパクト形式のセキュリティ属性: AM || SC...
+        if self.is_df() {
+            // DFの作成・EFの作成は不可
+            push_object(&mut objects, 0x8C, &[0x06, ACCESS_NEVER, ACCESS_NEVER]);
+        } else {
+            // 更新(b2) → 読み出し(b1)の順
+            push_object(&mut objects, 0x8C, &[0x03, self.update, self.read]);
+        }
+        objects
+    }
+    fn template(&self, p2: u8) -> Vec<u8> {
+        let mut template = Vec::new();
+        match p2 & 0x0C {
+            // FCI: FCPとFMDの中身をまとめる
+            0x00 => push_object(&mut template, 0x6F, &self.fcp_objects()),
+            0x04 => push_object(&mut template, 0x62, &self.fcp_objects()),
+            0x08 => {
+                let mut fmd = Vec::new();
+                if let Some(name) = &self.name {
+                    push_object(&mut fmd, 0x84, name);
+                }
+                push_object(&mut template, 0x64, &fmd);
+            }
+            _ => {}
+        }
+        template
+    }
+}
+
+/// BER-TLVの長さ(127バイトまでは1バイト、それ以上は 81/82 長さ)でデータオブジェクトを追加する
+fn push_object(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    out.push(tag);
+    match value.len() {
+        len @ 0..=0x7F => out.push(len as u8),
+        len @ 0x80..=0xFF => out.extend_from_slice(&[0x81, len as u8]),
+        len => {
+            out.push(0x82);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(value);
+}
+
+/// コマンドAPDUのボディ（Lc・データ・Le）
+struct Command<'a> {
+    data: &'a [u8],
+    le: Option<usize>,
+}
+
+impl<'a> Command<'a> {
+    fn parse(body: &'a [u8]) -> Option<Self> {
+        let ne = |le: u8| if le == 0 { 256 } else { le as usize };
+        match body {
+            [] => Some(Command {
+                data: &[],
+                le: None,
+            }),
+            [le] => Some(Command {
+                data: &[],
+                le: Some(ne(*le)),
+            }),
+            [lc, rest @ ..] if *lc != 0 && rest.len() == *lc as usize => Some(Command {
+                data: rest,
+                le: None,
+            }),
+            [lc, rest @ ..] if *lc != 0 && rest.len() == *lc as usize + 1 => Some(Command {
+                data: &rest[..*lc as usize],
+                le: Some(ne(rest[*lc as usize])),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// 論理チャネルのカレントDFとカレントEF
+type ChannelState = (usize, Option<usize>);
+
+pub struct VirtualFileSystemCard {
+    files: Vec<VirtualFile>,
+    /// 処理中のコマンドのチャネルの状態
+    current_df: usize,
+    current_ef: Option<usize>,
+    /// 開いていないチャネルはNone
+    channels: Vec<Option<ChannelState>>,
+}
+
+impl VirtualFileSystemCard {
+    /// MFだけを持つカード
+    pub fn new() -> Self {
+        VirtualFileSystemCard {
+            files: vec![VirtualFile {
+                fid: MF_FILE_ID,
+                parent: None,
+                name: None,
+                sfi: None,
+                body: FileBody::Df,
+                read: ACCESS_ALWAYS,
+                update: ACCESS_NEVER,
+            }],
+            current_df: 0,
+            current_ef: None,
+            channels: (0..=MAX_LOGICAL_CHANNEL)
+                .map(|channel| if channel == 0 { Some((0, None)) } else { None })
+                .collect(),
+        }
+    }
+    /// pathはMFからのファイルIDの並び（最後が追加するファイル）
+    pub fn add_df(&mut self, path: &[u16], name: Option<&[u8]>) -> &mut Self {
+        self.add(path, FileBody::Df, name, None)
+    }
+    pub fn add_transparent_ef(&mut self, path: &[u16], sfi: Option<u8>, data: &[u8]) -> &mut Self {
+        self.add(path, FileBody::Transparent(data.to_vec()), None, sfi)
+    }
+    pub fn add_record_ef(
+        &mut self,
+        path: &[u16],
+        sfi: Option<u8>,
+        cyclic: bool,
+        records: &[&[u8]],
+    ) -> &mut Self {
+        let records = records.iter().map(|r| r.to_vec()).collect();
+        self.add(path, FileBody::Records { cyclic, records }, None, sfi)
+    }
+    /// 読み出し・更新のアクセス条件(SCバイト)を変える
+    pub fn set_access(&mut self, path: &[u16], read: u8, update: u8) -> &mut Self {
+        let index = self.find_path(0, path).expect("file not found");
+        self.files[index].read = read;
+        self.files[index].update = update;
+        self
+    }
+
+    fn add(
+        &mut self,
+        path: &[u16],
+        body: FileBody,
+        name: Option<&[u8]>,
+        sfi: Option<u8>,
+    ) -> &mut Self {
+        let (fid, parent_path) = path.split_last().expect("empty path");
+        let parent = self.find_path(0, parent_path).expect("parent not found");
+        assert!(self.files[parent].is_df());
+        self.files.push(VirtualFile {
+            fid: *fid,
+            parent: Some(parent),
+            name: name.map(|name| name.to_vec()),
+            sfi,
+            body,
+            read: ACCESS_ALWAYS,
+            update: ACCESS_ALWAYS,
+        });
+        self
+    }
+    fn children(&self, parent: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.files.len()).filter(move |i| self.files[*i].parent == Some(parent))
+    }
+    fn find_child(&self, parent: usize, fid: u16) -> Option<usize> {
+        self.children(parent).find(|i| self.files[*i].fid == fid)
+    }
+    /// startのDFからファイルIDを順にたどる
+    fn find_path(&self, start: usize, path: &[u16]) -> Option<usize> {
+        path.iter()
+            .try_fold(start, |current, fid| self.find_child(current, *fid))
+    }
+    /// P1 = 00 のファイルIDでの選択: カレントDF・その子・親・親の子の順に探す
+    fn find_by_file_id(&self, fid: u16) -> Option<usize> {
+        if fid == MF_FILE_ID {
+            return Some(0);
+        }
+        let current = self.current_df;
+        if self.files[current].fid == fid {
+            return Some(current);
+        }
+        if let Some(index) = self.find_child(current, fid) {
+            return Some(index);
+        }
+        let parent = self.files[current].parent?;
+        if self.files[parent].fid == fid {
+            return Some(parent);
+        }
+        self.find_child(parent, fid)
+    }
+    fn parse_path(data: &[u8]) -> Option<Vec<u16>> {
+        if data.is_empty() || !data.len().is_multiple_of(2) {
+            return None;
+        }
+        Some(
+            data.chunks(2)
+                .map(|id| u16::from_be_bytes([id[0], id[1]]))
+                .collect(),
+        )
+    }
+    fn select(&mut self, p1: u8, p2: u8, command: &Command) -> Result<Vec<u8>, [u8; 2]> {
+        // P2の下位2ビットは最初の出現(00)だけに対応する
+        if p2 & 0xF3 != 0 {
+            return Err(SW_INCORRECT_P1P2);
+        }
+        let file_id = || match command.data {
+            [high, low] => Ok(u16::from_be_bytes([*high, *low])),
+            _ => Err(SW_WRONG_LENGTH),
+        };
+        let found = match p1 {
+            0x00 if command.data.is_empty() => Some(0),
+            0x00 => self.find_by_file_id(file_id()?),
+            0x01 => self
+                .find_child(self.current_df, file_id()?)
+                .filter(|i| self.files[*i].is_df()),
+            0x02 => self
+                .find_child(self.current_df, file_id()?)
+                .filter(|i| !self.files[*i].is_df()),
+            0x03 => self.files[self.current_df].parent,
+            0x04 => {
+                (0..self.files.len()).find(|i| self.files[*i].name.as_deref() == Some(command.data))
+            }
+            0x08 | 0x09 => {
+                let mut path = Self::parse_path(command.data).ok_or(SW_WRONG_LENGTH)?;
+                let start = if p1 == 0x08 { 0 } else { self.current_df };
+                // MFのIDから始まるパスも受け付ける
+                if p1 == 0x08 && path[0] == MF_FILE_ID {
+                    path.remove(0);
+                }
+                self.find_path(start, &path)
+            }
+            _ => return Err(SW_INCORRECT_P1P2),
+        };
+        let index = found.ok_or(SW_FILE_NOT_FOUND)?;
+        if self.files[index].is_df() {
+            self.current_df = index;
+            self.current_ef = None;
+        } else {
+            self.current_df = self.files[index].parent.unwrap_or(0);
+            self.current_ef = Some(index);
+        }
+        Ok(self.files[index].template(p2))
+    }
+    /// SFIが0でなければカレントDFの下からEFを選ぶ
+    fn select_ef(&mut self, sfi: u8) -> Result<usize, [u8; 2]> {
+        if sfi != 0 {
+            let index = self
+                .children(self.current_df)
+                .find(|i| self.files[*i].sfi == Some(sfi))
+                .ok_or(SW_FILE_NOT_FOUND)?;
+            self.current_ef = Some(index);
+        }
+        self.current_ef.ok_or(SW_NO_CURRENT_EF)
+    }
+    fn transparent(&self, index: usize, update: bool) -> Result<&Vec<u8>, [u8; 2]> {
+        let file = &self.files[index];
+        let condition = if update { file.update } else { file.read };
+        match &file.body {
+            FileBody::Transparent(_) if condition != ACCESS_ALWAYS => {
+                Err(SW_SECURITY_STATUS_NOT_SATISFIED)
+            }
+            FileBody::Transparent(data) => Ok(data),
+            _ => Err(SW_INCOMPATIBLE_FILE),
+        }
+    }
+    /// offsetから最大sizeバイト。足りなければ 62 82 を付けて返す
+    fn read_binary(
+        &self,
+        index: usize,
+        offset: usize,
+        size: usize,
+    ) -> Result<(Vec<u8>, bool), [u8; 2]> {
+        let data = self.transparent(index, false)?;
+        if offset >= data.len() {
+            return Err(SW_WRONG_OFFSET);
+        }
+        let end = (offset + size).min(data.len());
+        Ok((data[offset..end].to_vec(), end - offset < size))
+    }
+    fn update_binary(
+        &mut self,
+        index: usize,
+        offset: usize,
+        value: &[u8],
+    ) -> Result<Vec<u8>, [u8; 2]> {
+        let len = self.transparent(index, true)?.len();
+        if offset >= len {
+            return Err(SW_WRONG_OFFSET);
+        }
+        if offset + value.len() > len {
+            return Err(SW_NOT_ENOUGH_MEMORY);
+        }
+        if let FileBody::Transparent(data) = &mut self.files[index].body {
+            data[offset..offset + value.len()].copy_from_slice(value);
+        }
+        Ok(Vec::new())
+    }
+    fn read_record(&mut self, record: u8, p2: u8, le: usize) -> Result<Vec<u8>, [u8; 2]> {
+        // P1のレコード番号を読む指定(100)だけに対応する
+        if p2 & 0x07 != 0x04 || record == 0 {
+            return Err(SW_INCORRECT_P1P2);
+        }
+        let index = self.select_ef(p2 >> 3)?;
+        let file = &self.files[index];
+        let records = match &file.body {
+            FileBody::Records { .. } if file.read != ACCESS_ALWAYS => {
+                return Err(SW_SECURITY_STATUS_NOT_SATISFIED)
+            }
+            FileBody::Records { records, .. } => records,
+            _ => return Err(SW_INCOMPATIBLE_FILE),
+        };
+        let data = records
+            .get(record as usize - 1)
+            .ok_or(SW_RECORD_NOT_FOUND)?;
+        // Leが短ければ正しい長さを 6C xx で返す
+        if le < data.len() {
+            return Err([SW1_WRONG_LE, data.len() as u8]);
+        }
+        Ok(data.clone())
+    }
+    /// P1 = 00 で開く（P2 = 00 ならカードが番号を割り当てて返す）、P1 = 80 でP2のチャネルを閉じる
+    fn manage_channel(
+        &mut self,
+        channel: usize,
+        p1: u8,
+        p2: u8,
+        command: &Command,
+    ) -> Result<Vec<u8>, [u8; 2]> {
+        let max = MAX_LOGICAL_CHANNEL as usize;
+        let number = p2 as usize;
+        match p1 {
+            0x00 => {
+                let number = match (number, command.le) {
+                    (0, Some(_)) if command.data.is_empty() => (1..=max)
+                        .find(|n| self.channels[*n].is_none())
+                        .ok_or(SW_FUNCTION_NOT_SUPPORTED)?,
+                    (0, _) => return Err(SW_WRONG_LENGTH),
+                    (number, None) if command.data.is_empty() && number <= max => number,
+                    (_, None) if command.data.is_empty() => return Err(SW_FUNCTION_NOT_SUPPORTED),
+                    _ => return Err(SW_WRONG_LENGTH),
+                };
+                if self.channels[number].is_some() {
+                    return Err(SW_FUNCTION_NOT_SUPPORTED);
+                }
+                // 基本チャネルから開くとMF、それ以外は開いたチャネルのカレントDFから始まる
+                let df = if channel == 0 { 0 } else { self.current_df };
+                self.channels[number] = Some((df, None));
+                Ok(if p2 == 0 {
+                    vec![number as u8]
+                } else {
+                    Vec::new()
+                })
+            }
+            0x80 => {
+                // P2 = 00 ならコマンドを送ったチャネルを閉じる
+                let number = if number == 0 { channel } else { number };
+                if number == 0 || number > max || self.channels[number].is_none() {
+                    return Err(SW_INCORRECT_P1P2);
+                }
+                self.channels[number] = None;
+                Ok(Vec::new())
+            }
+            _ => Err(SW_INCORRECT_P1P2),
+        }
+    }
+    /// DO 54(3バイトのオフセット)と残りのデータ
+    fn parse_offset(data: &[u8]) -> Option<(usize, &[u8])> {
+        match data {
+            [0x54, 0x03, o1, o2, o3, rest @ ..] => {
+                Some((u32::from_be_bytes([0, *o1, *o2, *o3]) as usize, rest))
+            }
+            _ => None,
+        }
+    }
+    fn process(&mut self, ins: u8, p1: u8, p2: u8, command: &Command) -> Result<Vec<u8>, Vec<u8>> {
+        let result = match (ins, command.le) {
+            (INS_SELECT_FILE, _) => self.select(p1, p2, command),
+            (INS_READ_BINARY, Some(le)) if command.data.is_empty() => {
+                let (index, offset) = if p1 & 0x80 != 0 {
+                    (self.select_ef(p1 & 0x1F)?, p2 as usize)
+                } else {
+                    (self.select_ef(0)?, u16::from_be_bytes([p1, p2]) as usize)
+                };
+                let (data, short) = self.read_binary(index, offset, le)?;
+                return if short {
+                    Err([&data[..], &SW_END_OF_FILE[..]].concat())
+                } else {
+                    Ok(data)
+                };
+            }
+            (INS_READ_BINARY_ODO, Some(le)) if p1 == 0 && p2 == 0 => {
+                let offset = match Self::parse_offset(command.data) {
+                    Some((offset, [])) => offset,
+                    _ => return Err(SW_WRONG_DATA.to_vec()),
+                };
+                let index = self.select_ef(0)?;
+                // 応答は 53 長さ データ で、Leに収まるだけ返す
+                let size = if le > 0x82 {
+                    le - 3
+                } else {
+                    le.min(0x81).saturating_sub(2)
+                };
+                let (data, short) = self.read_binary(index, offset, size)?;
+                let mut res = Vec::new();
+                push_object(&mut res, 0x53, &data);
+                return if short {
+                    Err([&res[..], &SW_END_OF_FILE[..]].concat())
+                } else {
+                    Ok(res)
+                };
+            }
+            (INS_READ_RECORD, Some(le)) if command.data.is_empty() => self.read_record(p1, p2, le),
+            (INS_UPDATE_BINARY, None) if !command.data.is_empty() => {
+                let (index, offset) = if p1 & 0x80 != 0 {
+                    (self.select_ef(p1 & 0x1F)?, p2 as usize)
+                } else {
+                    (self.select_ef(0)?, u16::from_be_bytes([p1, p2]) as usize)
+                };
+                self.update_binary(index, offset, command.data)
+            }
+            (INS_UPDATE_BINARY_ODO, None) if p1 == 0 && p2 == 0 => {
+                let (offset, value) = match Self::parse_offset(command.data) {
+                    Some((offset, [0x53, 0x81, len, value @ ..]))
+                        if *len >= 0x80 && value.len() == *len as usize =>
+                    {
+                        (offset, value)
+                    }
+                    Some((offset, [0x53, len, value @ ..]))
+                        if *len < 0x80 && value.len() == *len as usize =>
+                    {
+                        (offset, value)
+                    }
+                    _ => return Err(SW_WRONG_DATA.to_vec()),
+                };
+                let index = self.select_ef(0)?;
+                self.update_binary(index, offset, value)
+            }
+            (INS_READ_BINARY, _)
+            | (INS_READ_BINARY_ODO, _)
+            | (INS_READ_RECORD, _)
+            | (INS_UPDATE_BINARY, _)
+            | (INS_UPDATE_BINARY_ODO, _) => Err(SW_WRONG_LENGTH),
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        };
+        result.map_err(|sw| sw.to_vec())
+    }
+}
+
+impl VirtualCard for VirtualFileSystemCard {
+    fn atr(&self) -> Vec<u8> {
+        // ISO14443-4 TypeAのATR（履歴バイトなし）
+        vec![0x3B, 0x80, 0x80, 0x01, 0x01]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let (cla, ins, p1, p2, body) = match apdu {
+            [cla, ins, p1, p2, body @ ..] => (*cla, *ins, *p1, *p2, body),
+            _ => return Some(SW_WRONG_LENGTH.to_vec()),
+        };
+        // セキュアメッセージング・コマンドチェインなしの業界間クラスだけ
+        if cla > 0x03 && cla & 0xF0 != 0x40 {
+            return Some(SW_CLA_NOT_SUPPORTED.to_vec());
+        }
+        let channel = channel_of_class(cla) as usize;
+        let (current_df, current_ef) = match self.channels[channel] {
+            Some(state) => state,
+            None => return Some(SW_CHANNEL_NOT_SUPPORTED.to_vec()),
+        };
+        self.current_df = current_df;
+        self.current_ef = current_ef;
+        let command = match Command::parse(body) {
+            Some(command) => command,
+            None => return Some(SW_WRONG_LENGTH.to_vec()),
+        };
+        let res = if ins == INS_MANAGE_CHANNEL {
+            self.manage_channel(channel, p1, p2, &command)
+                .map_err(|sw| sw.to_vec())
+        } else {
+            self.process(ins, p1, p2, &command)
+        };
+        // 閉じたチャネルの状態は残さない
+        if let Some(state) = self.channels[channel].as_mut() {
+            *state = (self.current_df, self.current_ef);
+        }
+        Some(match res {
+            Ok(data) => [&data[..], &SW_SUCCESS[..]].concat(),
+            // エラーのSW（62 82 はデータの後ろに付く）
+            Err(res) => res,
+        })
+    }
+}
@@ -0,0 +1,230 @@
+// PC/SCリーダーに載ったMIFARE Classicを模した仮想カード
+// LOAD KEYS / GENERAL AUTHENTICATE / READ BINARY / UPDATE BINARY と、
+// ACS系リーダーの値ブロック拡張(FF D7/FF B1)に応答する
+
+use super::VirtualCard;
+use crate::mifare_classic::value_block::ValueBlock;
+use crate::mifare_classic::{MifareClassicKind, BLOCK_SIZE, KEY_SIZE};
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_AUTH_FAILED: [u8; 2] = [0x63, 0x00];
+const SW_OPERATION_FAILED: [u8; 2] = [0x63, 0x00];
+const SW_NOT_AUTHENTICATED: [u8; 2] = [0x69, 0x82];
+const SW_WRONG_PARAMETER: [u8; 2] = [0x6A, 0x86];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+
+pub struct VirtualMifareClassic {
+    kind: MifareClassicKind,
+    blocks: Vec<[u8; BLOCK_SIZE]>,
+    key_slots: [Option<[u8; KEY_SIZE]>; 2],
+    authenticated_sector: Option<u8>,
+    /// 指定回数のAPDUを処理した時点で一度だけ取り外されたように振る舞う
+    remove_at: Option<usize>,
+    processed: usize,
+}
+
+impl VirtualMifareClassic {
+    /// 全セクタが出荷時のキー(FF..FF)とアクセスビットで初期化されたカード
+    pub fn new(kind: MifareClassicKind) -> Self {
+        let mut blocks = vec![[0u8; BLOCK_SIZE]; kind.block_count()];
+        blocks[0] = [
+            0x01, 0x02, 0x03, 0x04, 0x04, 0x08, 0x04, 0x00, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+            0x68, 0x69,
+        ];
+        let mut card = VirtualMifareClassic {
+            kind,
+            blocks,
+            key_slots: [None; 2],
+            authenticated_sector: None,
+            remove_at: None,
+            processed: 0,
+        };
+        for sector in 0..kind.sector_count() {
+            card.set_keys(sector, &[0xFF; KEY_SIZE], &[0xFF; KEY_SIZE]);
+        }
+        card
+    }
+    pub fn set_keys(&mut self, sector: u8, key_a: &[u8; KEY_SIZE], key_b: &[u8; KEY_SIZE]) {
+        let trailer = &mut self.blocks[MifareClassicKind::trailer_block(sector) as usize];
+        trailer[0..6].copy_from_slice(key_a);
+        trailer[6..10].copy_from_slice(&[0xFF, 0x07, 0x80, 0x69]);
+        trailer[10..16].copy_from_slice(key_b);
+    }
+    pub fn set_block(&mut self, block: u8, data: &[u8; BLOCK_SIZE]) {
+        self.blocks[block as usize] = *data;
+    }
+    pub fn block(&self, block: u8) -> [u8; BLOCK_SIZE] {
+        self.blocks[block as usize]
+    }
+    pub fn remove_at(mut self, apdu_count: usize) -> Self {
+        self.remove_at = Some(apdu_count);
+        self
+    }
+    fn respond(data: &[u8], sw: [u8; 2]) -> Option<Vec<u8>> {
+        Some(data.iter().chain(sw.iter()).cloned().collect())
+    }
+    fn authenticate(&mut self, block: u8, key_type: u8, key_no: u8) -> [u8; 2] {
+        self.authenticated_sector = None;
+        if block as usize >= self.blocks.len() {
+            return SW_WRONG_PARAMETER;
+        }
+        let key = match self.key_slots.get(key_no as usize) {
+            Some(Some(key)) => *key,
+            _ => return SW_AUTH_FAILED,
+        };
+        let sector = MifareClassicKind::sector_of_block(block);
+        let trailer = &self.blocks[MifareClassicKind::trailer_block(sector) as usize];
+        let expected = match key_type {
+            0x60 => &trailer[0..6],
+            0x61 => &trailer[10..16],
+            _ => return SW_WRONG_PARAMETER,
+        };
+        if expected == key {
+            self.authenticated_sector = Some(sector);
+            SW_SUCCESS
+        } else {
+            SW_AUTH_FAILED
+        }
+    }
+    fn is_authenticated(&self, block: u8) -> bool {
+        (block as usize) < self.blocks.len()
+            && self.authenticated_sector == Some(MifareClassicKind::sector_of_block(block))
+    }
+    fn value_block(&self, block: u8) -> Option<ValueBlock> {
+        ValueBlock::from_bytes(&self.blocks[block as usize], block).ok()
+    }
+    /// VALUE BLOCK OPERATION: 格納・加減算の結果を同じブロックへ転送する
+    fn value_operation(&mut self, block: u8, operation: u8, operand: &[u8]) -> [u8; 2] {
+        if MifareClassicKind::is_trailer_block(block) || block == 0 {
+            return SW_OPERATION_FAILED;
+        }
+        let operand = i32::from_be_bytes([operand[0], operand[1], operand[2], operand[3]]);
+        let current = self.value_block(block);
+        let result = match (operation, current) {
+            (0x00, _) => Some(ValueBlock::new(operand, block)),
+            (0x01, Some(current)) => current
+                .value
+                .checked_add(operand)
+                .map(|value| ValueBlock::new(value, current.address)),
+            (0x02, Some(current)) => current
+                .value
+                .checked_sub(operand)
+                .map(|value| ValueBlock::new(value, current.address)),
+            _ => None,
+        };
+        match result {
+            Some(value) => {
+                self.blocks[block as usize] = value.to_bytes();
+                SW_SUCCESS
+            }
+            None => SW_OPERATION_FAILED,
+        }
+    }
+    /// RESTORE VALUE BLOCK: 同じセクタ内の値ブロックを複写する
+    fn restore(&mut self, source: u8, target: u8) -> [u8; 2] {
+        if (target as usize) >= self.blocks.len()
+            || MifareClassicKind::sector_of_block(source)
+                != MifareClassicKind::sector_of_block(target)
+            || MifareClassicKind::is_trailer_block(target)
+            || target == 0
+        {
+            return SW_OPERATION_FAILED;
+        }
+        match self.value_block(source) {
+            Some(_) => {
+                self.blocks[target as usize] = self.blocks[source as usize];
+                SW_SUCCESS
+            }
+            None => SW_OPERATION_FAILED,
+        }
+    }
+}
+
+impl VirtualCard for VirtualMifareClassic {
+    fn atr(&self) -> Vec<u8> {
+        let name = match self.kind {
+            MifareClassicKind::Mini => 0x26,
+            MifareClassicKind::Classic1k => 0x01,
+            MifareClassicKind::Classic2k => 0x36,
+            MifareClassicKind::Classic4k => 0x02,
+        };
+        vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00,
+            name, 0x00, 0x00, 0x00, 0x00, 0x6A,
+        ]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        self.processed += 1;
+        if self.remove_at == Some(self.processed) {
+            self.remove_at = None;
+            self.authenticated_sector = None;
+            return None;
+        }
+        if apdu.len() < 5 || apdu[0] != 0xFF {
+            return Self::respond(&[], SW_INS_NOT_SUPPORTED);
+        }
+        let block = apdu[3];
+        match apdu[1] {
+            // GET DATA (UID)
+            0xCA if apdu[2] == 0x00 => {
+                let uid = self.blocks[0][0..4].to_vec();
+                Self::respond(&uid, SW_SUCCESS)
+            }
+            0x82 if apdu.len() == 11 => match self.key_slots.get_mut(apdu[3] as usize) {
+                Some(slot) => {
+                    let mut key = [0u8; KEY_SIZE];
+                    key.copy_from_slice(&apdu[5..11]);
+                    *slot = Some(key);
+                    Self::respond(&[], SW_SUCCESS)
+                }
+                None => Self::respond(&[], SW_WRONG_PARAMETER),
+            },
+            0x86 if apdu.len() == 10 => {
+                let sw = self.authenticate(apdu[7], apdu[8], apdu[9]);
+                Self::respond(&[], sw)
+            }
+            0xB0 => {
+                if !self.is_authenticated(block) {
+                    return Self::respond(&[], SW_NOT_AUTHENTICATED);
+                }
+                let mut data = self.blocks[block as usize];
+                if MifareClassicKind::is_trailer_block(block) {
+                    // KeyAは読み出せない
+                    data[0..6].copy_from_slice(&[0u8; KEY_SIZE]);
+                }
+                Self::respond(&data, SW_SUCCESS)
+            }
+            0xD6 if apdu.len() == 5 + BLOCK_SIZE => {
+                if !self.is_authenticated(block) {
+                    return Self::respond(&[], SW_NOT_AUTHENTICATED);
+                }
+                self.blocks[block as usize].copy_from_slice(&apdu[5..]);
+                Self::respond(&[], SW_SUCCESS)
+            }
+            0xD7 if apdu.len() == 10 || apdu.len() == 7 => {
+                if !self.is_authenticated(block) {
+                    return Self::respond(&[], SW_NOT_AUTHENTICATED);
+                }
+                let sw = match (apdu[4], apdu[5]) {
+                    (5, operation) if apdu.len() == 10 => {
+                        self.value_operation(block, operation, &apdu[6..10])
+                    }
+                    (2, 0x03) if apdu.len() == 7 => self.restore(block, apdu[6]),
+                    _ => SW_WRONG_PARAMETER,
+                };
+                Self::respond(&[], sw)
+            }
+            0xB1 => {
+                if !self.is_authenticated(block) {
+                    return Self::respond(&[], SW_NOT_AUTHENTICATED);
+                }
+                // 値はビッグエンディアンで返す
+                match self.value_block(block) {
+                    Some(value) => Self::respond(&value.value.to_be_bytes(), SW_SUCCESS),
+                    None => Self::respond(&[], SW_OPERATION_FAILED),
+                }
+            }
+            _ => Self::respond(&[], SW_INS_NOT_SUPPORTED),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// PN53x系リーダーに載ったMIFARE Plusを模した仮想カード
+// DIRECT TRANSMIT(FF 00 00 00)で渡された InDataExchange のネイティブコマンドに応答し、
+// SL1ではそれ以外のAPDU(LOAD KEYS / GENERAL AUTHENTICATE など)をVirtualMifareClassicに任せる
+
+use super::mifare_classic::VirtualMifareClassic;
+use super::VirtualCard;
+use crate::crypto::{random_bytes, rotate_left, Cipher, AES_BLOCK_SIZE};
+use crate::mifare_classic::{MifareClassicKind, BLOCK_SIZE};
+use crate::mifare_plus::auth::{AuthMethod, CMD_AUTHENTICATE_CONTINUE, RANDOM_SIZE};
+use crate::mifare_plus::session::{Session, MAC_SIZE, TI_SIZE};
+use crate::mifare_plus::{
+    sector_key, MifarePlusSize, SecurityLevel, KEY_SIZE, MASTER_KEY, MAX_BLOCKS, SL1_CARD_AUTH_KEY,
+    SL2_SWITCH_KEY, SL3_SWITCH_KEY, STATUS_OK,
+};
+use crate::pc_sc_standard::MifareKeyType;
+use std::collections::HashMap;
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const PN53X_IN_DATA_EXCHANGE_RESPONSE: [u8; 3] = [0xD5, 0x41, 0x00];
+
+// カードのステータス
+const STATUS_AUTHENTICATION_ERROR: u8 = 0x06;
+const STATUS_INVALID_MAC: u8 = 0x08;
+const STATUS_INVALID_BLOCK: u8 = 0x09;
+const STATUS_CONDITIONS_NOT_SATISFIED: u8 = 0x0B;
+const STATUS_LENGTH_ERROR: u8 = 0x0C;
+
+/// 認証途中の状態
+struct PendingAuth {
+    method: AuthMethod,
+    key_no: u16,
+    rnd_b: Vec<u8>,
+}
+
+pub struct VirtualMifarePlus {
+    size: MifarePlusSize,
+    level: SecurityLevel,
+    /// データブロックとSL1のCrypto1の鍵
+    classic: VirtualMifareClassic,
+    /// AESの鍵（セクタの鍵とカードの鍵）
+    keys: HashMap<u16, [u8; KEY_SIZE]>,
+    pending_auth: Option<PendingAuth>,
+    session: Option<Session>,
+}
+
+impl VirtualMifarePlus {
+    /// SL0の出荷状態のカード（AESの鍵はすべてFF..FF）
+    pub fn new(size: MifarePlusSize) -> Self {
+        let mut keys = HashMap::new();
+        for sector in 0..size.sector_count() {
+            keys.insert(sector_key(sector, MifareKeyType::KeyA), [0xFF; KEY_SIZE]);
+            keys.insert(sector_key(sector, MifareKeyType::KeyB), [0xFF; KEY_SIZE]);
+        }
+        for key_no in MASTER_KEY..=SL1_CARD_AUTH_KEY {
+            keys.insert(key_no, [0xFF; KEY_SIZE]);
+        }
+        VirtualMifarePlus {
+            size,
+            level: SecurityLevel::Sl0,
+            classic: VirtualMifareClassic::new(size.classic_kind()),
+            keys,
+            pending_auth: None,
+            session: None,
+        }
+    }
+    pub fn set_security_level(&mut self, level: SecurityLevel) {
+        self.level = level;
+    }
+    pub fn set_key(&mut self, key_no: u16, key: &[u8; KEY_SIZE]) {
+        self.keys.insert(key_no, *key);
+    }
+    pub fn set_block(&mut self, block: u8, data: &[u8; BLOCK_SIZE]) {
+        self.classic.set_block(block, data);
+    }
+    /// ネイティブコマンドを処理し、ステータスと応答のデータを返す
+    fn native(&mut self, command: u8, data: &[u8]) -> Vec<u8> {
+        // 認証の続き以外のコマンドが来たら認証途中の状態は捨てる
+        let pending = self.pending_auth.take();
+        let response = match (self.level, command) {
+            (_, CMD_AUTHENTICATE_CONTINUE) => match pending {
+                Some(pending) => self.finish_authentication(pending, data),
+                None => vec![STATUS_AUTHENTICATION_ERROR],
+            },
+            (SecurityLevel::Sl0, 0xA8) => self.write_perso(data),
+            (SecurityLevel::Sl0, 0xAA) => {
+                self.level = SecurityLevel::Sl1;
+                vec![STATUS_OK]
+            }
+            (SecurityLevel::Sl0, _) => vec![STATUS_CONDITIONS_NOT_SATISFIED],
+            (_, 0x70) | (SecurityLevel::Sl3, 0x76) => {
+                self.start_authentication(AuthMethod::from_command(command).unwrap(), data)
+            }
+            (SecurityLevel::Sl3, 0x78) => {
+                self.session = None;
+                vec![STATUS_OK]
+            }
+            (SecurityLevel::Sl3, 0x30..=0x37) => self.read(command, data),
+            (SecurityLevel::Sl3, 0xA0..=0xA3) => self.write(command, data),
+            _ => vec![STATUS_CONDITIONS_NOT_SATISFIED],
+        };
+        // エラーになると認証を解除する
+        if response[0] != STATUS_OK {
+            self.session = None;
+        }
+        response
+    }
+    fn write_perso(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.len() != 2 + BLOCK_SIZE {
+            return vec![STATUS_LENGTH_ERROR];
+        }
+        let address = u16::from_le_bytes([data[0], data[1]]);
+        let mut value = [0u8; BLOCK_SIZE];
+        value.copy_from_slice(&data[2..]);
+        if (address as usize) < self.size.block_count() {
+            self.classic.set_block(address as u8, &value);
+        } else if let Some(key) = self.keys.get_mut(&address) {
+            *key = value;
+        } else {
+            return vec![STATUS_INVALID_BLOCK];
+        }
+        vec![STATUS_OK]
+    }
+    fn start_authentication(&mut self, method: AuthMethod, data: &[u8]) -> Vec<u8> {
+        let key_no = match (method, data) {
+            (AuthMethod::First, [low, high, len_cap, cap @ ..])
+                if cap.len() == *len_cap as usize =>
+            {
+                u16::from_le_bytes([*low, *high])
+            }
+            (AuthMethod::Following, [low, high]) if self.session.is_some() => {
+                u16::from_le_bytes([*low, *high])
+            }
+            _ => return vec![STATUS_AUTHENTICATION_ERROR],
+        };
+        // SL1・SL2ではセキュリティレベルの切り替えにだけ使える
+        let allowed = match self.level {
+            SecurityLevel::Sl1 => key_no == SL2_SWITCH_KEY || key_no == SL3_SWITCH_KEY,
+            SecurityLevel::Sl2 => key_no == SL3_SWITCH_KEY,
+            _ => key_no != SL2_SWITCH_KEY && key_no != SL3_SWITCH_KEY,
+        };
+        let key = match self.keys.get(&key_no) {
+            Some(key) if allowed => *key,
+            _ => return vec![STATUS_INVALID_BLOCK],
+        };
+        if method == AuthMethod::First {
+            self.session = None;
+        }
+        let rnd_b = random_bytes(RANDOM_SIZE);
+        let encrypted = Cipher::aes128(&key).cbc_encrypt(&[0u8; AES_BLOCK_SIZE], &rnd_b);
+        self.pending_auth = Some(PendingAuth {
+            method,
+            key_no,
+            rnd_b,
+        });
+        [&[STATUS_OK][..], &encrypted].concat()
+    }
+    fn finish_authentication(&mut self, pending: PendingAuth, data: &[u8]) -> Vec<u8> {
+        if data.len() != RANDOM_SIZE * 2 {
+            return vec![STATUS_LENGTH_ERROR];
+        }
+        let key = self.keys[&pending.key_no];
+        let cipher = Cipher::aes128(&key);
+        let zero = [0u8; AES_BLOCK_SIZE];
+        let plain = cipher.cbc_decrypt(&zero, data);
+        let (rnd_a, rnd_b) = plain.split_at(RANDOM_SIZE);
+        if rnd_b != &rotate_left(&pending.rnd_b)[..] {
+            return vec![STATUS_AUTHENTICATION_ERROR];
+        }
+        match pending.key_no {
+            SL2_SWITCH_KEY => {
+                self.level = SecurityLevel::Sl2;
+                return vec![STATUS_OK];
+            }
+            SL3_SWITCH_KEY => {
+                self.level = SecurityLevel::Sl3;
+                return vec![STATUS_OK];
+            }
+            _ => {}
+        }
+        let response = match (pending.method, self.session.as_mut()) {
+            (AuthMethod::Following, Some(session)) => {
+                session.follow(pending.key_no, &key, rnd_a, &pending.rnd_b);
+                rotate_left(rnd_a)
+            }
+            (AuthMethod::Following, None) => return vec![STATUS_AUTHENTICATION_ERROR],
+            (AuthMethod::First, _) => {
+                let mut ti = [0u8; TI_SIZE];
+                ti.copy_from_slice(&random_bytes(TI_SIZE));
+                self.session = Some(Session::new(
+                    pending.key_no,
+                    &key,
+                    rnd_a,
+                    &pending.rnd_b,
+                    ti,
+                ));
+                // TI || RndA' || PICCcap2 || PCDcap2
+                [&ti[..], &rotate_left(rnd_a), &[0u8; 12]].concat()
+            }
+        };
+        [&[STATUS_OK][..], &cipher.cbc_encrypt(&zero, &response)].concat()
+    }
+    /// blockからcount個のブロックが認証中の鍵のセクタに収まっているか
+    fn is_accessible(&self, block: usize, count: usize) -> bool {
+        let key_no = match &self.session {
+            Some(session) => session.key_no(),
+            None => return false,
+        };
+        (block..block + count).all(|block| {
+            let sector = MifareClassicKind::sector_of_block(block as u8);
+            key_no == sector_key(sector, MifareKeyType::KeyA)
+                || key_no == sector_key(sector, MifareKeyType::KeyB)
+        })
+    }
+    fn read(&mut self, command: u8, data: &[u8]) -> Vec<u8> {
+        let maced_command = command & 0x04 == 0;
+        let header_len = 3;
+        let expected = if maced_command {
+            header_len + MAC_SIZE
+        } else {
+            header_len
+        };
+        if data.len() != expected {
+            return vec![STATUS_LENGTH_ERROR];
+        }
+        let (header, mac) = data.split_at(header_len);
+        let block = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let count = header[2] as usize;
+        if count == 0 || count > MAX_BLOCKS || block + count > self.size.block_count() {
+            return vec![STATUS_INVALID_BLOCK];
+        }
+        let blocks = (block..block + count)
+            .map(|block| self.classic.block(block as u8))
+            .collect::<Vec<_>>()
+            .concat();
+        // 製造者ブロックだけは認証なしで読み出せる
+        if self.session.is_none() && command == 0x36 && block == 0 && count == 1 {
+            return [&[STATUS_OK][..], &blocks].concat();
+        }
+        if !self.is_accessible(block, count) {
+            return vec![STATUS_CONDITIONS_NOT_SATISFIED];
+        }
+        let session = self.session.as_mut().unwrap();
+        if maced_command && session.command_mac(command, header, &[]) != mac {
+            return vec![STATUS_INVALID_MAC];
+        }
+        let blocks = if command & 0x02 == 0 {
+            session.encrypt_response(command, &blocks)
+        } else {
+            blocks
+        };
+        let mac = if command & 0x01 != 0 {
+            session
+                .response_mac(command, STATUS_OK, header, &blocks)
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        session.advance(command);
+        [&[STATUS_OK][..], &blocks, &mac].concat()
+    }
+    fn write(&mut self, command: u8, data: &[u8]) -> Vec<u8> {
+        let header_len = 2;
+        if data.len() < header_len + BLOCK_SIZE + MAC_SIZE
+            || !data[header_len..data.len() - MAC_SIZE]
+                .chunks_exact(BLOCK_SIZE)
+                .remainder()
+                .is_empty()
+        {
+            return vec![STATUS_LENGTH_ERROR];
+        }
+        let (header, rest) = data.split_at(header_len);
+        let (body, mac) = rest.split_at(rest.len() - MAC_SIZE);
+        let address = u16::from_le_bytes([header[0], header[1]]);
+        let encrypted = command & 0x02 == 0;
+        let session = match self.session.as_ref() {
+            Some(session) => session,
+            None => return vec![STATUS_CONDITIONS_NOT_SATISFIED],
+        };
+        if session.command_mac(command, header, body) != mac {
+            return vec![STATUS_INVALID_MAC];
+        }
+        let plain = if encrypted {
+            session.decrypt_command(body)
+        } else {
+            body.to_vec()
+        };
+        let key_no = session.key_no();
+        let count = plain.len() / BLOCK_SIZE;
+        if (address as usize) < self.size.block_count() {
+            if address == 0
+                || count > MAX_BLOCKS
+                || address as usize + count > self.size.block_count()
+            {
+                return vec![STATUS_INVALID_BLOCK];
+            }
+            if !self.is_accessible(address as usize, count) {
+                return vec![STATUS_CONDITIONS_NOT_SATISFIED];
+            }
+            for (i, chunk) in plain.chunks_exact(BLOCK_SIZE).enumerate() {
+                let mut value = [0u8; BLOCK_SIZE];
+                value.copy_from_slice(chunk);
+                self.classic.set_block(address as u8 + i as u8, &value);
+            }
+        } else if let Some(key) = self.keys.get_mut(&address) {
+            // 鍵は暗号化して1ブロックだけ書き込める
+            // カードの鍵はカードマスター鍵、セクタの鍵はそのセクタのKeyBで認証している必要がある
+            let allowed = if address >= MASTER_KEY {
+                key_no == MASTER_KEY
+            } else {
+                let sector = ((address - sector_key(0, MifareKeyType::KeyA)) / 2) as u8;
+                key_no == sector_key(sector, MifareKeyType::KeyB)
+            };
+            if !encrypted || count != 1 || !allowed {
+                return vec![STATUS_CONDITIONS_NOT_SATISFIED];
+            }
+            key.copy_from_slice(&plain);
+        } else {
+            return vec![STATUS_INVALID_BLOCK];
+        }
+        let session = self.session.as_mut().unwrap();
+        let mac = if command & 0x01 != 0 {
+            session.response_mac(command, STATUS_OK, &[], &[]).to_vec()
+        } else {
+            Vec::new()
+        };
+        session.advance(command);
+        [&[STATUS_OK][..], &mac].concat()
+    }
+}
+
+impl VirtualCard for VirtualMifarePlus {
+    fn atr(&self) -> Vec<u8> {
+        let name = match (self.level, self.size) {
+            (SecurityLevel::Sl1, MifarePlusSize::Plus2k) => 0x36,
+            (SecurityLevel::Sl1, MifarePlusSize::Plus4k) => 0x37,
+            (SecurityLevel::Sl2, MifarePlusSize::Plus2k) => 0x38,
+            (SecurityLevel::Sl2, MifarePlusSize::Plus4k) => 0x39,
+            // SL0・SL3はISO14443-4 TypeAのATR(履歴バイトはATSのもの)
+            _ => {
+                return vec![
+                    0x3B, 0x87, 0x80, 0x01, 0xC1, 0x05, 0x2F, 0x2F, 0x01, 0xBC, 0xD6, 0xA9,
+                ]
+            }
+        };
+        vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00,
+            name, 0x00, 0x00, 0x00, 0x00, 0x6A,
+        ]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        match apdu {
+            [0xFF, 0x00, 0x00, 0x00, lc, payload @ ..] if payload.len() == *lc as usize => {
+                match payload {
+                    [0xD4, 0x40, 0x01, command, data @ ..] => {
+                        let response = self.native(*command, data);
+                        Some(
+                            [&PN53X_IN_DATA_EXCHANGE_RESPONSE[..], &response, &SW_SUCCESS].concat(),
+                        )
+                    }
+                    _ => Some(SW_INS_NOT_SUPPORTED.to_vec()),
+                }
+            }
+            // GET DATAはどのセキュリティレベルでも使える
+            [0xFF, 0xCA, ..] => self.classic.process_apdu(apdu),
+            _ if self.level == SecurityLevel::Sl1 => self.classic.process_apdu(apdu),
+            _ => Some(SW_INS_NOT_SUPPORTED.to_vec()),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// PN53x系リーダーに載ったTopaz(NFC Forum Type 1 Tag)を模した仮想カード
+// DIRECT TRANSMIT(FF 00 00 00)で渡された InDataExchange のコマンドに応答する
+
+use super::VirtualCard;
+use crate::topaz::{
+    CapabilityContainer, TopazModel, BLOCK_SIZE, CC_OFFSET, CC_SIZE, DATA_AREA_START, LOCK_OFFSET,
+    SEGMENT_SIZE, STATIC_MEMORY_SIZE,
+};
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const PN53X_IN_DATA_EXCHANGE_RESPONSE: [u8; 2] = [0xD5, 0x41];
+/// PN53xのタイムアウト（カードが応答しない）
+const STATUS_TIMEOUT: u8 = 0x01;
+
+pub struct VirtualTopaz {
+    model: TopazModel,
+    header_rom: [u8; 2],
+    memory: Vec<u8>,
+}
+
+impl VirtualTopaz {
+    /// UID 01 02 03 04 05 06 07、空のNDEFメッセージを格納した出荷状態のタグ
+    pub fn new(model: TopazModel) -> Self {
+        let mut memory = vec![0u8; model.memory_size()];
+        memory[0..7].copy_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]);
+        let cc = CapabilityContainer::new(model.memory_size());
+        memory[CC_OFFSET..CC_OFFSET + CC_SIZE].copy_from_slice(&cc.to_bytes());
+        // 動的メモリはブロックFのロックバイト・予約領域を指すLock Control・Memory Control TLVを置く
+        let control: &[u8] = match model {
+            TopazModel::Topaz96 => &[],
+            TopazModel::Topaz512 => &[0x01, 0x03, 0xF2, 0x30, 0x33, 0x02, 0x03, 0xF0, 0x02, 0x03],
+        };
+        // NDEF Message TLV(空) と Terminator TLV
+        let tlvs = [control, &[0x03, 0x00, 0xFE]].concat();
+        memory[DATA_AREA_START..DATA_AREA_START + tlvs.len()].copy_from_slice(&tlvs);
+        let hr0 = match model {
+            TopazModel::Topaz96 => 0x11,
+            TopazModel::Topaz512 => 0x12,
+        };
+        VirtualTopaz {
+            model,
+            header_rom: [hr0, 0x48],
+            memory,
+        }
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn set_memory(&mut self, offset: usize, data: &[u8]) {
+        self.memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+    /// ブロック0(UID)は常に、ブロック1～Eは静的ロックバイトのビットが立っていれば書き込めない
+    fn is_locked(&self, block: usize) -> bool {
+        let lock = u16::from_le_bytes([self.memory[LOCK_OFFSET], self.memory[LOCK_OFFSET + 1]]);
+        match block {
+            0 => true,
+            1..=0x0E => lock & (1 << block) != 0,
+            _ => false,
+        }
+    }
+    fn process_command(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+        let is_dynamic = self.model.is_dynamic();
+        match command {
+            // RID
+            [0x78, 0x00, 0x00] => Some([&self.header_rom[..], &self.memory[0..4]].concat()),
+            // RALL
+            [0x00, 0x00, 0x00] => {
+                Some([&self.header_rom[..], &self.memory[..STATIC_MEMORY_SIZE]].concat())
+            }
+            // READ
+            [0x01, address, 0x00] if (*address as usize) < STATIC_MEMORY_SIZE => {
+                Some(vec![*address, self.memory[*address as usize]])
+            }
+            // WRITE-E / WRITE-NE
+            [command @ 0x53, address, data] | [command @ 0x1A, address, data]
+                if (*address as usize) < STATIC_MEMORY_SIZE =>
+            {
+                let address = *address as usize;
+                if self.is_locked(address / BLOCK_SIZE) {
+                    return None;
+                }
+                if *command == 0x53 {
+                    self.memory[address] = *data;
+                } else {
+                    self.memory[address] |= *data;
+                }
+                Some(vec![address as u8, self.memory[address]])
+            }
+            // RSEG
+            [0x10, adds, rest @ ..] if is_dynamic && rest.len() == BLOCK_SIZE => {
+                let start = (*adds >> 4) as usize * SEGMENT_SIZE;
+                let segment = self.memory.get(start..start + SEGMENT_SIZE)?;
+                Some([&[*adds][..], segment].concat())
+            }
+            // READ8
+            [0x02, block, rest @ ..] if is_dynamic && rest.len() == BLOCK_SIZE => {
+                let start = *block as usize * BLOCK_SIZE;
+                let data = self.memory.get(start..start + BLOCK_SIZE)?;
+                Some([&[*block][..], data].concat())
+            }
+            // WRITE-E8 / WRITE-NE8
+            [command @ 0x54, block, data @ ..] | [command @ 0x1B, block, data @ ..]
+                if is_dynamic && data.len() == BLOCK_SIZE =>
+            {
+                let start = *block as usize * BLOCK_SIZE;
+                if start + BLOCK_SIZE > self.memory.len() || self.is_locked(*block as usize) {
+                    return None;
+                }
+                for (i, byte) in data.iter().enumerate() {
+                    if *command == 0x54 {
+                        self.memory[start + i] = *byte;
+                    } else {
+                        self.memory[start + i] |= *byte;
+                    }
+                }
+                Some([&[*block][..], &self.memory[start..start + BLOCK_SIZE]].concat())
+            }
+            // 対応しないコマンドには応答しない
+            _ => None,
+        }
+    }
+}
+
+impl VirtualCard for VirtualTopaz {
+    fn atr(&self) -> Vec<u8> {
+        vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00,
+            0x30, 0x00, 0x00, 0x00, 0x00, 0x6A,
+        ]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        match apdu {
+            [0xFF, 0x00, 0x00, 0x00, lc, payload @ ..] if payload.len() == *lc as usize => {
+                match payload {
+                    [0xD4, 0x40, 0x01, command @ ..] => {
+                        let response = match self.process_command(command) {
+                            Some(data) => [&[0x00][..], &data].concat(),
+                            None => vec![STATUS_TIMEOUT],
+                        };
+                        Some(
+                            [&PN53X_IN_DATA_EXCHANGE_RESPONSE[..], &response, &SW_SUCCESS].concat(),
+                        )
+                    }
+                    _ => Some(SW_INS_NOT_SUPPORTED.to_vec()),
+                }
+            }
+            _ => Some(SW_INS_NOT_SUPPORTED.to_vec()),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// NFC Forum Type 4 Tagの仮想カード
+// NDEFアプリケーション・CCファイル・NDEFファイルだけを持ち、SELECT FILE・READ BINARY・UPDATE BINARYに応答する。
+// CCのMLe/MLcや書き込み権限を越えるコマンドは、実際のタグと同じようにエラーのSWを返す。
+// マッピング3.0のときは奇数INS(B1/D7)のREAD/UPDATE BINARYにも応答する。
+
+use super::VirtualCard;
+use crate::ndef::NdefMessage;
+use crate::type4_tag::{
+    CapabilityContainer, NdefFileControl, ACCESS_GRANTED, CC_FILE_ID, MAPPING_VERSION_2,
+    NDEF_APPLICATION_AID,
+};
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_WRONG_LENGTH: [u8; 2] = [0x67, 0x00];
+const SW_SECURITY_STATUS_NOT_SATISFIED: [u8; 2] = [0x69, 0x82];
+/// EFが選択されていない
+const SW_COMMAND_NOT_ALLOWED: [u8; 2] = [0x69, 0x86];
+const SW_WRONG_DATA: [u8; 2] = [0x6A, 0x80];
+const SW_FILE_NOT_FOUND: [u8; 2] = [0x6A, 0x82];
+const SW_NOT_ENOUGH_MEMORY: [u8; 2] = [0x6A, 0x84];
+const SW_INCORRECT_P1P2: [u8; 2] = [0x6A, 0x86];
+/// オフセットがEFの外
+const SW_WRONG_OFFSET: [u8; 2] = [0x6B, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const SW_CLA_NOT_SUPPORTED: [u8; 2] = [0x6E, 0x00];
+
+const INS_SELECT_FILE: u8 = 0xA4;
+const INS_READ_BINARY: u8 = 0xB0;
+const INS_READ_BINARY_ODO: u8 = 0xB1;
+const INS_UPDATE_BINARY: u8 = 0xD6;
+const INS_UPDATE_BINARY_ODO: u8 = 0xD7;
+
+/// 選択中のEF
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SelectedFile {
+    None,
+    CapabilityContainer,
+    Ndef,
+}
+
+/// コマンドAPDUのボディ（Lc・データ・Le）
+struct Command<'a> {
+    data: &'a [u8],
+    /// Leが00なら256
+    le: Option<usize>,
+}
+
+impl<'a> Command<'a> {
+    /// 短い形式のケース1～4を分ける
+    fn parse(body: &'a [u8]) -> Option<Self> {
+        match body {
+            [] => Some(Command {
+                data: &[],
+                le: None,
+            }),
+            [le] => Some(Command {
+                data: &[],
+                le: Some(Self::ne(*le)),
+            }),
+            [lc, rest @ ..] if *lc != 0 && rest.len() == *lc as usize => Some(Command {
+                data: rest,
+                le: None,
+            }),
+            [lc, rest @ ..] if *lc != 0 && rest.len() == *lc as usize + 1 => Some(Command {
+                data: &rest[..*lc as usize],
+                le: Some(Self::ne(rest[*lc as usize])),
+            }),
+            _ => None,
+        }
+    }
+    fn ne(le: u8) -> usize {
+        match le {
+            0 => 256,
+            le => le as usize,
+        }
+    }
+}
+
+pub struct VirtualType4Tag {
+    cc: CapabilityContainer,
+    ndef_file: Vec<u8>,
+    application_selected: bool,
+    selected: SelectedFile,
+}
+
+impl VirtualType4Tag {
+    /// マッピング2.0、NDEFファイル(E104)は2048バイトで空のメッセージ
+    pub fn new() -> Self {
+        Self::with_capability_container(CapabilityContainer {
+            version: MAPPING_VERSION_2,
+            mle: 0x003B,
+            mlc: 0x0034,
+            ndef_file: NdefFileControl {
+                file_id: 0xE104,
+                max_size: 0x0800,
+                read_access: ACCESS_GRANTED,
+                write_access: ACCESS_GRANTED,
+                extended: false,
+            },
+        })
+    }
+    /// CCの内容(マッピングバージョン・MLe/MLc・NDEFファイル)を指定する
+    pub fn with_capability_container(cc: CapabilityContainer) -> Self {
+        VirtualType4Tag {
+            ndef_file: vec![0; cc.ndef_file.max_size as usize],
+            cc,
+            application_selected: false,
+            selected: SelectedFile::None,
+        }
+    }
+    /// NDEFファイルにメッセージを書き込んでおく
+    pub fn set_ndef_message(&mut self, message: &NdefMessage) {
+        let data = message.to_bytes();
+        let nlen_size = self.cc.ndef_file.nlen_size();
+        let nlen = (data.len() as u32).to_be_bytes();
+        self.ndef_file[..nlen_size].copy_from_slice(&nlen[4 - nlen_size..]);
+        self.ndef_file[nlen_size..nlen_size + data.len()].copy_from_slice(&data);
+    }
+    /// CCの書き込み権限を変える（FFで読み出し専用）
+    pub fn set_write_access(&mut self, access: u8) {
+        self.cc.ndef_file.write_access = access;
+    }
+
+    fn select(&mut self, p1: u8, p2: u8, command: &Command) -> Vec<u8> {
+        match (p1, p2) {
+            // DF名で選択
+            (0x04, 0x00) => {
+                if command.data != NDEF_APPLICATION_AID {
+                    return SW_FILE_NOT_FOUND.to_vec();
+                }
+                self.application_selected = true;
+                self.selected = SelectedFile::None;
+                SW_SUCCESS.to_vec()
+            }
+            // ファイルIDで選択（FCIは返さない）
+            (0x00, 0x0C) => {
+                let file_id = match command.data {
+                    [high, low] if self.application_selected => u16::from_be_bytes([*high, *low]),
+                    [_, _] => return SW_FILE_NOT_FOUND.to_vec(),
+                    _ => return SW_WRONG_LENGTH.to_vec(),
+                };
+                self.selected = if file_id == CC_FILE_ID {
+                    SelectedFile::CapabilityContainer
+                } else if file_id == self.cc.ndef_file.file_id {
+                    SelectedFile::Ndef
+                } else {
+                    return SW_FILE_NOT_FOUND.to_vec();
+                };
+                SW_SUCCESS.to_vec()
+            }
+            _ => SW_INCORRECT_P1P2.to_vec(),
+        }
+    }
+    /// 選択中のEFのoffsetからsizeバイト（ファイルの終わりまで）
+    fn read(&self, offset: usize, size: usize, le: usize) -> Result<Vec<u8>, [u8; 2]> {
+        if le > self.cc.mle as usize {
+            return Err(SW_WRONG_LENGTH);
+        }
+        let file = match self.selected {
+            SelectedFile::CapabilityContainer => self.cc.to_bytes(),
+            SelectedFile::Ndef if self.cc.ndef_file.is_readable() => self.ndef_file.clone(),
+            SelectedFile::Ndef => return Err(SW_SECURITY_STATUS_NOT_SATISFIED),
+            SelectedFile::None => return Err(SW_COMMAND_NOT_ALLOWED),
+        };
+        if offset >= file.len() {
+            return Err(SW_WRONG_OFFSET);
+        }
+        Ok(file[offset..(offset + size).min(file.len())].to_vec())
+    }
+    fn update(&mut self, offset: usize, data: &[u8], lc: usize) -> Result<(), [u8; 2]> {
+        if lc > self.cc.mlc as usize {
+            return Err(SW_WRONG_LENGTH);
+        }
+        match self.selected {
+            SelectedFile::Ndef if self.cc.ndef_file.is_writable() => {}
+            SelectedFile::None => return Err(SW_COMMAND_NOT_ALLOWED),
+            _ => return Err(SW_SECURITY_STATUS_NOT_SATISFIED),
+        }
+        if offset >= self.ndef_file.len() {
+            return Err(SW_WRONG_OFFSET);
+        }
+        if offset + data.len() > self.ndef_file.len() {
+            return Err(SW_NOT_ENOUGH_MEMORY);
+        }
+        self.ndef_file[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+    /// 奇数INSはマッピング3.0から
+    fn supports_odo(&self) -> bool {
+        self.cc.major_version() >= 3
+    }
+    /// DO 54(3バイトのオフセット)と残りのデータ
+    fn parse_offset(data: &[u8]) -> Option<(usize, &[u8])> {
+        match data {
+            [0x54, 0x03, o1, o2, o3, rest @ ..] => {
+                Some((u32::from_be_bytes([0, *o1, *o2, *o3]) as usize, rest))
+            }
+            _ => None,
+        }
+    }
+    fn respond(result: Result<Vec<u8>, [u8; 2]>) -> Vec<u8> {
+        match result {
+            Ok(data) => [&data[..], &SW_SUCCESS[..]].concat(),
+            Err(sw) => sw.to_vec(),
+        }
+    }
+}
+
+impl VirtualCard for VirtualType4Tag {
+    fn atr(&self) -> Vec<u8> {
+        // ISO14443-4 TypeAのATR（履歴バイトなし）
+        vec![0x3B, 0x80, 0x80, 0x01, 0x01]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let (cla, ins, p1, p2, body) = match apdu {
+            [cla, ins, p1, p2, body @ ..] => (*cla, *ins, *p1, *p2, body),
+            _ => return Some(SW_WRONG_LENGTH.to_vec()),
+        };
+        if cla != 0x00 {
+            return Some(SW_CLA_NOT_SUPPORTED.to_vec());
+        }
+        let command = match Command::parse(body) {
+            Some(command) => command,
+            None => return Some(SW_WRONG_LENGTH.to_vec()),
+        };
+        let res = match ins {
+            INS_SELECT_FILE => self.select(p1, p2, &command),
+            // P1の最上位ビットはSFIの指定
+            INS_READ_BINARY if p1 & 0x80 != 0 => SW_INCORRECT_P1P2.to_vec(),
+            INS_READ_BINARY => match command.le {
+                Some(le) if command.data.is_empty() => {
+                    let offset = u16::from_be_bytes([p1, p2]) as usize;
+                    Self::respond(self.read(offset, le, le))
+                }
+                _ => SW_WRONG_LENGTH.to_vec(),
+            },
+            INS_UPDATE_BINARY if p1 & 0x80 != 0 => SW_INCORRECT_P1P2.to_vec(),
+            INS_UPDATE_BINARY => match command.le {
+                None if !command.data.is_empty() => {
+                    let offset = u16::from_be_bytes([p1, p2]) as usize;
+                    let lc = command.data.len();
+                    Self::respond(self.update(offset, command.data, lc).map(|_| Vec::new()))
+                }
+                _ => SW_WRONG_LENGTH.to_vec(),
+            },
+            INS_READ_BINARY_ODO if self.supports_odo() => {
+                match (Self::parse_offset(command.data), command.le) {
+                    (Some((offset, [])), Some(le)) if p1 == 0 && p2 == 0 => {
+                        // 応答は 53 長さ データ で、Leに収まるだけ返す
+                        let size = if le > 0x82 {
+                            le - 3
+                        } else {
+                            le.min(0x81).saturating_sub(2)
+                        };
+                        Self::respond(self.read(offset, size, le).map(|data| {
+                            let mut res = vec![0x53];
+                            if data.len() >= 0x80 {
+                                res.push(0x81);
+                            }
+                            res.push(data.len() as u8);
+                            res.extend(data);
+                            res
+                        }))
+                    }
+                    (None, _) => SW_WRONG_DATA.to_vec(),
+                    _ => SW_WRONG_LENGTH.to_vec(),
+                }
+            }
+            INS_UPDATE_BINARY_ODO if self.supports_odo() => {
+                let lc = command.data.len();
+                match Self::parse_offset(command.data) {
+                    Some((offset, [0x53, 0x81, len, data @ ..]))
+                        if *len >= 0x80 && data.len() == *len as usize =>
+                    {
+                        Self::respond(self.update(offset, data, lc).map(|_| Vec::new()))
+                    }
+                    Some((offset, [0x53, len, data @ ..]))
+                        if *len < 0x80 && data.len() == *len as usize =>
+                    {
+                        Self::respond(self.update(offset, data, lc).map(|_| Vec::new()))
+                    }
+                    _ => SW_WRONG_DATA.to_vec(),
+                }
+            }
+            _ => SW_INS_NOT_SUPPORTED.to_vec(),
+        };
+        Some(res)
+    }
+}
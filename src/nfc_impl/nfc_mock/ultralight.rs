@@ -0,0 +1,358 @@
+// PN53x系リーダーに載ったUltralight/NTAG21xを模した仮想カード
+// DIRECT TRANSMIT(FF 00 00 00)で渡された InCommunicateThru / InListPassiveTarget に応答する
+
+use super::VirtualCard;
+use crate::crypto::{rotate_left, Cipher, DES_BLOCK_SIZE};
+use crate::ultralight::lock_bytes::{self, LockBytes, OTP_PAGE, STATIC_LOCK_PAGE};
+use crate::ultralight::ntag::{MirrorConf, NtagConfig, AUTH0_DISABLED, SIGNATURE_SIZE};
+use crate::ultralight::ultralight_c::{self, AUTH0_PAGE, AUTH1_PAGE, KEY_PAGE, KEY_SIZE};
+use crate::ultralight::version::UltralightModel;
+use crate::ultralight::PAGE_SIZE;
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+/// PN53xのタイムアウト（カードが応答しない）
+const STATUS_TIMEOUT: u8 = 0x01;
+const ACK: u8 = 0x0A;
+const NAK_INVALID_ARGUMENT: u8 = 0x00;
+const NAK_NOT_SUPPORTED: u8 = 0x04;
+
+pub struct VirtualUltralight {
+    model: UltralightModel,
+    pages: Vec<[u8; PAGE_SIZE]>,
+    version: Option<Vec<u8>>,
+    signature: [u8; SIGNATURE_SIZE],
+    /// NAKを返した後は選択し直すまで応答しない
+    halted: bool,
+    /// PWD_AUTH・3DES認証に成功している（選択し直すと解除される）
+    authenticated: bool,
+    /// Ultralight Cの認証途中のRndBとIV
+    auth_challenge: Option<([u8; DES_BLOCK_SIZE], Vec<u8>)>,
+    nfc_counter: u32,
+    /// 選択後にNFCカウンタを加算済み
+    counter_incremented: bool,
+}
+
+impl VirtualUltralight {
+    /// UID 04 11 22 33 44 55 66 の出荷状態のカード
+    pub fn new(model: UltralightModel) -> Self {
+        let page_count = model.page_count().unwrap_or(16) as usize;
+        let mut pages = vec![[0u8; PAGE_SIZE]; page_count];
+        // BCC0 = 0x88 ^ UID0 ^ UID1 ^ UID2, BCC1 = UID3 ^ UID4 ^ UID5 ^ UID6
+        pages[0] = [0x04, 0x11, 0x22, 0x88 ^ 0x04 ^ 0x11 ^ 0x22];
+        pages[1] = [0x33, 0x44, 0x55, 0x66];
+        pages[2] = [0x33 ^ 0x44 ^ 0x55 ^ 0x66, 0x48, 0x00, 0x00];
+        // NTAGは出荷時にNDEF用のCapability Containerが書き込まれている
+        pages[OTP_PAGE as usize] = match model {
+            UltralightModel::Ntag213 => [0xE1, 0x10, 0x12, 0x00],
+            UltralightModel::Ntag215 => [0xE1, 0x10, 0x3E, 0x00],
+            UltralightModel::Ntag216 => [0xE1, 0x10, 0x6D, 0x00],
+            _ => [0x00; PAGE_SIZE],
+        };
+        if let Some(page) = lock_bytes::dynamic_lock_page(model) {
+            pages[page as usize] = [0x00, 0x00, 0x00, 0xBD];
+        }
+        // Ultralight Cは保護なし・出荷時の鍵
+        if model == UltralightModel::UltralightC {
+            pages[AUTH0_PAGE as usize] = [ultralight_c::AUTH0_DISABLED, 0x00, 0x00, 0x00];
+            let key_pages = ultralight_c::key_pages(&ultralight_c::DEFAULT_KEY);
+            pages[KEY_PAGE as usize..KEY_PAGE as usize + 4].copy_from_slice(&key_pages);
+        }
+        // CFG0, CFG1, PWD, PACK の出荷時の値
+        if let Some(page) = model.config_page() {
+            let page = page as usize;
+            pages[page] = [0x04, 0x00, 0x00, AUTH0_DISABLED];
+            pages[page + 1] = [0x00, 0x05, 0x00, 0x00];
+            pages[page + 2] = [0xFF; PAGE_SIZE];
+            pages[page + 3] = [0x00; PAGE_SIZE];
+        }
+        let version = match model {
+            UltralightModel::UltralightEv1Mf0ul11 => Some([0x03, 0x01, 0x0B]),
+            UltralightModel::UltralightEv1Mf0ul21 => Some([0x03, 0x01, 0x0E]),
+            UltralightModel::Ntag210 => Some([0x04, 0x01, 0x0B]),
+            UltralightModel::Ntag212 => Some([0x04, 0x01, 0x0E]),
+            UltralightModel::Ntag213 => Some([0x04, 0x02, 0x0F]),
+            UltralightModel::Ntag215 => Some([0x04, 0x02, 0x11]),
+            UltralightModel::Ntag216 => Some([0x04, 0x02, 0x13]),
+            _ => None,
+        }
+        .map(|[product_type, subtype, storage]| {
+            vec![0x00, 0x04, product_type, subtype, 0x01, 0x00, storage, 0x03]
+        });
+        VirtualUltralight {
+            model,
+            pages,
+            version,
+            signature: [0u8; SIGNATURE_SIZE],
+            halted: false,
+            authenticated: false,
+            auth_challenge: None,
+            nfc_counter: 0,
+            counter_incremented: false,
+        }
+    }
+    pub fn set_signature(&mut self, signature: &[u8; SIGNATURE_SIZE]) {
+        self.signature = *signature;
+    }
+    /// GET_VERSIONの応答を差し替える
+    pub fn set_version(&mut self, version: &[u8]) {
+        self.version = Some(version.to_vec());
+    }
+    pub fn set_page(&mut self, page: u8, data: &[u8; PAGE_SIZE]) {
+        self.pages[page as usize] = *data;
+    }
+    pub fn page(&self, page: u8) -> [u8; PAGE_SIZE] {
+        self.pages[page as usize]
+    }
+    fn lock_bytes(&self) -> LockBytes {
+        let static_lock = &self.pages[STATIC_LOCK_PAGE as usize];
+        LockBytes {
+            static_lock: [static_lock[2], static_lock[3]],
+            dynamic_lock: lock_bytes::dynamic_lock_page(self.model).map(|page| {
+                let data = self.pages[page as usize];
+                [data[0], data[1], data[2]]
+            }),
+        }
+    }
+    fn config(&self) -> Option<(usize, NtagConfig)> {
+        self.model.config_page().map(|page| {
+            let page = page as usize;
+            (
+                page,
+                NtagConfig::from_pages(&self.pages[page], &self.pages[page + 1]),
+            )
+        })
+    }
+    /// Ultralight Cの3DES鍵（ページには逆順で格納されている）
+    fn ultralight_c_key(&self) -> [u8; KEY_SIZE] {
+        let mut key = [0u8; KEY_SIZE];
+        for (i, start) in [4, 0, 12, 8].iter().enumerate() {
+            for j in 0..PAGE_SIZE {
+                key[start + PAGE_SIZE - 1 - j] = self.pages[KEY_PAGE as usize + i][j];
+            }
+        }
+        key
+    }
+    /// Ultralight Cの保護範囲(AUTH0と、読み出しも保護するか)
+    fn ultralight_c_access(&self) -> Option<(usize, bool)> {
+        if self.model != UltralightModel::UltralightC {
+            return None;
+        }
+        Some((
+            self.pages[AUTH0_PAGE as usize][0] as usize,
+            self.pages[AUTH1_PAGE as usize][0] & 0x01 == 0,
+        ))
+    }
+    fn is_read_protected(&self, page: usize) -> bool {
+        if let Some((auth0, read_protected)) = self.ultralight_c_access() {
+            return !self.authenticated && read_protected && page >= auth0;
+        }
+        match self.config() {
+            Some((_, config)) => {
+                !self.authenticated && config.read_protected && page >= config.auth0 as usize
+            }
+            None => false,
+        }
+    }
+    fn is_write_protected(&self, page: usize) -> bool {
+        if let Some((auth0, _)) = self.ultralight_c_access() {
+            return !self.authenticated && page >= auth0;
+        }
+        match self.config() {
+            Some((config_page, config)) => {
+                (!self.authenticated && page >= config.auth0 as usize)
+                    || (config.config_locked && (page == config_page || page == config_page + 1))
+            }
+            None => false,
+        }
+    }
+    /// READで見えるページの内容（PWD/PACKと保護されたページは00になる）
+    fn visible_page(&self, page: usize) -> [u8; PAGE_SIZE] {
+        match self.config() {
+            Some((config_page, _)) if page == config_page + 2 || page == config_page + 3 => {
+                [0x00; PAGE_SIZE]
+            }
+            _ if self.model == UltralightModel::UltralightC
+                && page >= KEY_PAGE as usize
+                && page < KEY_PAGE as usize + 4 =>
+            {
+                [0x00; PAGE_SIZE]
+            }
+            _ if self.is_read_protected(page) => [0x00; PAGE_SIZE],
+            _ => self.pages[page],
+        }
+    }
+    /// ミラーで書き込まれて見える文字列と開始位置(バイト)
+    fn mirror(&self) -> Option<(usize, Vec<u8>)> {
+        let (_, config) = self.config()?;
+        let uid = [&self.pages[0][0..3], &self.pages[1][..]]
+            .concat()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        let counter = format!("{:06X}", self.nfc_counter);
+        let text = match config.mirror_conf {
+            MirrorConf::None => return None,
+            MirrorConf::Uid => uid,
+            MirrorConf::Counter => counter,
+            MirrorConf::UidAndCounter => format!("{}x{}", uid, counter),
+        };
+        let start = config.mirror_page as usize * PAGE_SIZE + config.mirror_byte as usize;
+        Some((start, text.into_bytes()))
+    }
+    /// カードのコマンドを処理する。Errは4ビットのNAK
+    fn command(&mut self, command: &[u8]) -> Result<Vec<u8>, u8> {
+        match command {
+            [0x60] => self.version.clone().ok_or(NAK_NOT_SUPPORTED),
+            [0x30, page] => {
+                let page = *page as usize;
+                if page >= self.pages.len() || self.is_read_protected(page) {
+                    return Err(NAK_INVALID_ARGUMENT);
+                }
+                if let Some((_, config)) = self.config() {
+                    if config.counter_enabled && !self.counter_incremented {
+                        self.nfc_counter += 1;
+                        self.counter_incremented = true;
+                    }
+                }
+                // 最終ページを越えた分は先頭へ折り返す
+                let pages = (0..4)
+                    .map(|i| (page + i) % self.pages.len())
+                    .collect::<Vec<usize>>();
+                let mut data = pages
+                    .iter()
+                    .flat_map(|p| self.visible_page(*p).to_vec())
+                    .collect::<Vec<u8>>();
+                if let Some((start, text)) = self.mirror() {
+                    for (i, p) in pages.iter().enumerate() {
+                        for j in 0..PAGE_SIZE {
+                            let position = p * PAGE_SIZE + j;
+                            if position >= start && position < start + text.len() {
+                                data[i * PAGE_SIZE + j] = text[position - start];
+                            }
+                        }
+                    }
+                }
+                Ok(data)
+            }
+            [0xA2, page, data @ ..] if data.len() == PAGE_SIZE => {
+                let page = *page;
+                if page as usize >= self.pages.len()
+                    || page < STATIC_LOCK_PAGE
+                    || self.lock_bytes().is_page_locked(self.model, page as u16)
+                    || self.is_write_protected(page as usize)
+                {
+                    return Err(NAK_INVALID_ARGUMENT);
+                }
+                let current = &mut self.pages[page as usize];
+                if page == STATIC_LOCK_PAGE {
+                    current[2] |= data[2];
+                    current[3] |= data[3];
+                } else if page == OTP_PAGE
+                    || Some(page) == lock_bytes::dynamic_lock_page(self.model)
+                {
+                    for (dst, src) in current.iter_mut().zip(data.iter()) {
+                        *dst |= *src;
+                    }
+                } else {
+                    current.copy_from_slice(data);
+                }
+                Ok(vec![ACK])
+            }
+            // PWD_AUTH
+            [0x1B, password @ ..] if password.len() == PAGE_SIZE => {
+                let (config_page, _) = self.config().ok_or(NAK_NOT_SUPPORTED)?;
+                if self.pages[config_page + 2][..] != *password {
+                    return Err(NAK_INVALID_ARGUMENT);
+                }
+                self.authenticated = true;
+                Ok(self.pages[config_page + 3][0..2].to_vec())
+            }
+            // READ_CNT
+            [0x39, 0x02] if self.model.has_nfc_counter() => {
+                let (_, config) = self.config().ok_or(NAK_NOT_SUPPORTED)?;
+                if config.counter_password_protected && !self.authenticated {
+                    return Err(NAK_INVALID_ARGUMENT);
+                }
+                Ok(self.nfc_counter.to_le_bytes()[0..3].to_vec())
+            }
+            // Ultralight CのAUTHENTICATE（RndBは01～08で固定）
+            [0x1A, 0x00] if self.model == UltralightModel::UltralightC => {
+                let rnd_b = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+                let cipher = Cipher::tdes2(&self.ultralight_c_key());
+                let encrypted = cipher.cbc_encrypt(&[0; DES_BLOCK_SIZE], &rnd_b);
+                self.authenticated = false;
+                self.auth_challenge = Some((rnd_b, encrypted.clone()));
+                Ok([&[0xAF][..], &encrypted].concat())
+            }
+            [0xAF, token @ ..] if token.len() == DES_BLOCK_SIZE * 2 => {
+                let (rnd_b, iv) = self.auth_challenge.take().ok_or(NAK_INVALID_ARGUMENT)?;
+                let cipher = Cipher::tdes2(&self.ultralight_c_key());
+                let plain = cipher.cbc_decrypt(&iv, token);
+                if plain[DES_BLOCK_SIZE..] != rotate_left(&rnd_b)[..] {
+                    return Err(NAK_INVALID_ARGUMENT);
+                }
+                self.authenticated = true;
+                let rnd_a = rotate_left(&plain[..DES_BLOCK_SIZE]);
+                let encrypted = cipher.cbc_encrypt(&token[DES_BLOCK_SIZE..], &rnd_a);
+                Ok([&[0x00][..], &encrypted].concat())
+            }
+            // READ_SIG
+            [0x3C, 0x00] if self.version.is_some() => Ok(self.signature.to_vec()),
+            _ => Err(NAK_NOT_SUPPORTED),
+        }
+    }
+    fn respond(data: &[u8], sw: [u8; 2]) -> Option<Vec<u8>> {
+        Some(data.iter().chain(sw.iter()).cloned().collect())
+    }
+}
+
+impl VirtualCard for VirtualUltralight {
+    fn atr(&self) -> Vec<u8> {
+        let name = match self.model {
+            UltralightModel::UltralightC => 0x3A,
+            _ => 0x03,
+        };
+        vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00,
+            name, 0x00, 0x00, 0x00, 0x00, 0x68,
+        ]
+    }
+    fn process_apdu(&mut self, apdu: &[u8]) -> Option<Vec<u8>> {
+        let uid = [&self.pages[0][0..3], &self.pages[1][..]].concat();
+        match apdu {
+            // GET DATA (UID)
+            [0xFF, 0xCA, 0x00, 0x00, ..] => Self::respond(&uid, SW_SUCCESS),
+            // DIRECT TRANSMIT InListPassiveTarget
+            [0xFF, 0x00, 0x00, 0x00, _, 0xD4, 0x4A, ..] => {
+                self.halted = false;
+                self.authenticated = false;
+                self.auth_challenge = None;
+                self.counter_incremented = false;
+                let mut res = vec![0xD5, 0x4B, 0x01, 0x01, 0x00, 0x44, 0x00, uid.len() as u8];
+                res.extend_from_slice(&uid);
+                Self::respond(&res, SW_SUCCESS)
+            }
+            // DIRECT TRANSMIT InCommunicateThru
+            [0xFF, 0x00, 0x00, 0x00, _, 0xD4, 0x42, command @ ..] => {
+                if self.halted {
+                    return Self::respond(&[0xD5, 0x43, STATUS_TIMEOUT], SW_SUCCESS);
+                }
+                let data = match self.command(command) {
+                    Ok(data) => data,
+                    Err(nak) => {
+                        self.halted = true;
+                        vec![nak]
+                    }
+                };
+                let res = [0xD5, 0x43, 0x00]
+                    .iter()
+                    .chain(data.iter())
+                    .cloned()
+                    .collect::<Vec<u8>>();
+                Self::respond(&res, SW_SUCCESS)
+            }
+            _ => Self::respond(&[], SW_INS_NOT_SUPPORTED),
+        }
+    }
+}
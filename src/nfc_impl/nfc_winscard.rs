@@ -214,29 +214,6 @@ fn u16_ptr_to_strarray(ptr: *const u16) -> Vec<String> {
     s
 }
 
-#[derive(Debug, PartialEq)]
-enum TransmitErrorKind {
-    Success(u8, u8),
-    Warn(u8, u8),
-    Error(u8, u8),
-    ApiError(HRESULT),
-}
-#[derive(Debug)]
-struct TransmitError {
-    code: TransmitErrorKind,
-}
-impl TransmitError {
-    fn new(code: TransmitErrorKind) -> Self {
-        TransmitError { code }
-    }
-}
-impl std::error::Error for TransmitError {}
-impl std::fmt::Display for TransmitError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "")
-    }
-}
-
 impl smart_card::Smartcard for WinScardNFC {
     fn get_atr(&self) -> &AnswerToReset {
         &self.atr
@@ -353,27 +330,19 @@ impl smart_card::Smartcard for WinScardNFC {
                 res.as_mut_ptr(),
                 &mut size,
             );
+            if state == SCARD_W_REMOVED_CARD || state == SCARD_E_NO_SMARTCARD {
+                return Err(Box::new(SmartcardError::new(
+                    SmartcardErrorKind::ConnectionLost,
+                )));
+            }
             if state != SCARD_S_SUCCESS {
                 return Err(Box::new(TransmitError::new(TransmitErrorKind::ApiError(
                     state,
                 ))));
             }
         }
-        let code_start = size as usize - 2;
-        let code_end = size as usize;
-        let code = &res[code_start..code_end];
-        if code == &[0x90, 0x00] {
-            Ok(res[0..code_start].to_vec())
-        } else {
-            match code[0] {
-                0x62 => Err(Box::new(TransmitError::new(TransmitErrorKind::Error(
-                    code[0], code[1],
-                )))),
-                _ => Err(Box::new(TransmitError::new(TransmitErrorKind::Warn(
-                    code[0], code[1],
-                )))),
-            }
-        }
+        res.truncate(size as usize);
+        TransmitError::check_response(res)
         // SCardGetStatusChange 的なやつを呼び出してReadyになるまで待機させたりすればいいけど
         // とりあえず今はなんだかよくわからんがとにかくコマンド叩く　よし！
         // 実際にStateChangeを呼ぶ場合には、カードリーダーの数分のステート管理領域を生成する必要がある。
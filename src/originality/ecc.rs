@@ -0,0 +1,285 @@
+// 素体上の短いWeierstrass曲線 y^2 = x^3 + ax + b とECDSA検証
+// 署名の検証しか行わないので、定数時間処理などの秘密鍵向けの対策はしていない
+// ただしemrtd::curveがbrainpoolP256r1・secp224r1のPACEの一時鍵の演算に使っている（サイドチャネルに弱い）
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+
+/// ヤコビアン座標の点(x = X/Z^2, y = Y/Z^3)。Z=0は無限遠点
+#[derive(Debug, Clone, PartialEq)]
+struct JacobianPoint {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+impl JacobianPoint {
+    fn infinity() -> Self {
+        JacobianPoint {
+            x: BigUint::one(),
+            y: BigUint::one(),
+            z: BigUint::zero(),
+        }
+    }
+    fn is_infinity(&self) -> bool {
+        self.z.is_zero()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EllipticCurve {
+    p: BigUint,
+    a: BigUint,
+    b: BigUint,
+    gx: BigUint,
+    gy: BigUint,
+    n: BigUint,
+    /// 座標1つ分のバイト数
+    size: usize,
+}
+
+fn hex(value: &str) -> BigUint {
+    BigUint::parse_bytes(value.as_bytes(), 16).unwrap()
+}
+
+impl EllipticCurve {
+    pub fn secp128r1() -> Self {
+        EllipticCurve {
+            p: hex("FFFFFFFDFFFFFFFFFFFFFFFFFFFFFFFF"),
+            a: hex("FFFFFFFDFFFFFFFFFFFFFFFFFFFFFFFC"),
+            b: hex("E87579C11079F43DD824993C2CEE5ED3"),
+            gx: hex("161FF7528B899B2D0C28607CA52C5B86"),
+            gy: hex("CF5AC8395BAFEB13C02DA292DDED7A83"),
+            n: hex("FFFFFFFE0000000075A30D1B9038A115"),
+            size: 16,
+        }
+    }
+    pub fn secp224r1() -> Self {
+        EllipticCurve {
+            p: hex("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF000000000000000000000001"),
+            a: hex("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFFFFFFFFFFFFFFFFFFFE"),
+            b: hex("B4050A850C04B3ABF54132565044B0B7D7BFD8BA270B39432355FFB4"),
+            gx: hex("B70E0CBD6BB4BF7F321390B94A03C1D356C21122343280D6115C1D21"),
+            gy: hex("BD376388B5F723FB4C22DFE6CD4375A05A07476444D5819985007E34"),
+            n: hex("FFFFFFFFFFFFFFFFFFFFFFFFFFFF16A2E0B8F03E13DD29455C5C2A3D"),
+            size: 28,
+        }
+    }
+    /// RFC 5639
+    pub fn brainpool_p256r1() -> Self {
+        EllipticCurve {
+            p: hex("A9FB57DBA1EEA9BC3E660A909D838D726E3BF623D52620282013481D1F6E5377"),
+            a: hex("7D5A0975FC2C3057EEF67530417AFFE7FB8055C126DC5C6CE94A4B44F330B5D9"),
+            b: hex("26DC5C6CE94A4B44F330B5D9BBD77CBF958416295CF7E1CE6BCCDC18FF8C07B6"),
+            gx: hex("8BD2AEB9CB7E57CB2C4B482FFC81B7AFB9DE27E1E3BD23C23A4453BD9ACE3262"),
+            gy: hex("547EF835C3DAC4FD97F8461A14611DC9C27745132DED8E545C1D54C72F046997"),
+            n: hex("A9FB57DBA1EEA9BC3E660A909D838D718C397AA3B561A6F7901E0E82974856A7"),
+            size: 32,
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn generator(&self) -> (BigUint, BigUint) {
+        (self.gx.clone(), self.gy.clone())
+    }
+    /// 生成元の位数
+    pub fn order(&self) -> &BigUint {
+        &self.n
+    }
+    /// 座標を曲線のバイト数の固定長にする
+    pub fn encode_coordinate(&self, value: &BigUint) -> Vec<u8> {
+        let bytes = value.to_bytes_be();
+        let mut out = vec![0u8; self.size.saturating_sub(bytes.len())];
+        out.extend_from_slice(&bytes);
+        out
+    }
+    /// 非圧縮形式(04 || X || Y)にする
+    pub fn encode_point(&self, point: &(BigUint, BigUint)) -> Vec<u8> {
+        let mut out = vec![0x04];
+        out.extend(self.encode_coordinate(&point.0));
+        out.extend(self.encode_coordinate(&point.1));
+        out
+    }
+    /// 非圧縮形式(04 || X || Y)の公開鍵を読み込み、曲線上の点か確かめる
+    pub fn decode_point(&self, data: &[u8]) -> Option<(BigUint, BigUint)> {
+        if data.len() != 1 + self.size * 2 || data[0] != 0x04 {
+            return None;
+        }
+        let x = BigUint::from_bytes_be(&data[1..=self.size]);
+        let y = BigUint::from_bytes_be(&data[1 + self.size..]);
+        if x >= self.p || y >= self.p || !self.is_on_curve(&x, &y) {
+            return None;
+        }
+        Some((x, y))
+    }
+    pub fn is_on_curve(&self, x: &BigUint, y: &BigUint) -> bool {
+        let rhs = (x * x * x + &self.a * x + &self.b) % &self.p;
+        (y * y) % &self.p == rhs
+    }
+    /// ECDSA署名(r || s)を検証する
+    /// messageはハッシュ済みの値として扱い、nのビット長を超える分は下位ビットを切り捨てる
+    pub fn verify(
+        &self,
+        public_key: &(BigUint, BigUint),
+        message: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        if signature.len() != self.size * 2 {
+            return false;
+        }
+        let r = BigUint::from_bytes_be(&signature[..self.size]);
+        let s = BigUint::from_bytes_be(&signature[self.size..]);
+        if r.is_zero() || s.is_zero() || r >= self.n || s >= self.n {
+            return false;
+        }
+        let e = self.truncate_message(message);
+        let w = self.inverse(&s, &self.n);
+        let u1 = (e * &w) % &self.n;
+        let u2 = (&r * &w) % &self.n;
+        let g = self.to_jacobian(&self.gx, &self.gy);
+        let q = self.to_jacobian(&public_key.0, &public_key.1);
+        let point = self.add(&self.multiply(&g, &u1), &self.multiply(&q, &u2));
+        match self.to_affine(&point) {
+            Some((x, _)) => x % &self.n == r,
+            None => false,
+        }
+    }
+    /// スカラー倍。結果が無限遠点ならNone
+    pub fn multiply_point(
+        &self,
+        point: &(BigUint, BigUint),
+        scalar: &BigUint,
+    ) -> Option<(BigUint, BigUint)> {
+        let point = self.to_jacobian(&point.0, &point.1);
+        self.to_affine(&self.multiply(&point, scalar))
+    }
+    /// 2点の和。結果が無限遠点ならNone
+    pub fn add_points(
+        &self,
+        lhs: &(BigUint, BigUint),
+        rhs: &(BigUint, BigUint),
+    ) -> Option<(BigUint, BigUint)> {
+        let lhs = self.to_jacobian(&lhs.0, &lhs.1);
+        let rhs = self.to_jacobian(&rhs.0, &rhs.1);
+        self.to_affine(&self.add(&lhs, &rhs))
+    }
+    fn truncate_message(&self, message: &[u8]) -> BigUint {
+        let e = BigUint::from_bytes_be(message);
+        let message_bits = message.len() as u64 * 8;
+        let order_bits = self.n.bits();
+        if message_bits > order_bits {
+            e >> (message_bits - order_bits) as usize
+        } else {
+            e
+        }
+    }
+    /// 法が素数なのでフェルマーの小定理で逆元を求める
+    fn inverse(&self, value: &BigUint, modulus: &BigUint) -> BigUint {
+        value.modpow(&(modulus - 2u32), modulus)
+    }
+    fn sub_mod(&self, lhs: &BigUint, rhs: &BigUint) -> BigUint {
+        (lhs + &self.p - rhs) % &self.p
+    }
+    fn to_jacobian(&self, x: &BigUint, y: &BigUint) -> JacobianPoint {
+        JacobianPoint {
+            x: x.clone(),
+            y: y.clone(),
+            z: BigUint::one(),
+        }
+    }
+    fn to_affine(&self, point: &JacobianPoint) -> Option<(BigUint, BigUint)> {
+        if point.is_infinity() {
+            return None;
+        }
+        let z_inv = self.inverse(&point.z, &self.p);
+        let z_inv2 = (&z_inv * &z_inv) % &self.p;
+        let x = (&point.x * &z_inv2) % &self.p;
+        let y = (&point.y * z_inv2 * z_inv) % &self.p;
+        Some((x, y))
+    }
+    fn double(&self, point: &JacobianPoint) -> JacobianPoint {
+        if point.is_infinity() || point.y.is_zero() {
+            return JacobianPoint::infinity();
+        }
+        let p = &self.p;
+        let y2 = (&point.y * &point.y) % p;
+        let s = (4u32 * &point.x * &y2) % p;
+        let z2 = (&point.z * &point.z) % p;
+        let m = (3u32 * &point.x * &point.x + &self.a * &z2 * &z2) % p;
+        let x = self.sub_mod(&((&m * &m) % p), &((2u32 * &s) % p));
+        let y = self.sub_mod(
+            &((&m * self.sub_mod(&s, &x)) % p),
+            &((8u32 * &y2 * &y2) % p),
+        );
+        let z = (2u32 * &point.y * &point.z) % p;
+        JacobianPoint { x, y, z }
+    }
+    fn add(&self, lhs: &JacobianPoint, rhs: &JacobianPoint) -> JacobianPoint {
+        if lhs.is_infinity() {
+            return rhs.clone();
+        }
+        if rhs.is_infinity() {
+            return lhs.clone();
+        }
+        let p = &self.p;
+        let z1z1 = (&lhs.z * &lhs.z) % p;
+        let z2z2 = (&rhs.z * &rhs.z) % p;
+        let u1 = (&lhs.x * &z2z2) % p;
+        let u2 = (&rhs.x * &z1z1) % p;
+        let s1 = (&lhs.y * &z2z2 * &rhs.z) % p;
+        let s2 = (&rhs.y * &z1z1 * &lhs.z) % p;
+        if u1 == u2 {
+            return if s1 == s2 {
+                self.double(lhs)
+            } else {
+                JacobianPoint::infinity()
+            };
+        }
+        let h = self.sub_mod(&u2, &u1);
+        let r = self.sub_mod(&s2, &s1);
+        let h2 = (&h * &h) % p;
+        let h3 = (&h2 * &h) % p;
+        let u1h2 = (&u1 * &h2) % p;
+        let x = self.sub_mod(&self.sub_mod(&((&r * &r) % p), &h3), &((2u32 * &u1h2) % p));
+        let y = self.sub_mod(&((&r * self.sub_mod(&u1h2, &x)) % p), &((&s1 * &h3) % p));
+        let z = (&h * &lhs.z * &rhs.z) % p;
+        JacobianPoint { x, y, z }
+    }
+    fn multiply(&self, point: &JacobianPoint, scalar: &BigUint) -> JacobianPoint {
+        let mut result = JacobianPoint::infinity();
+        for i in (0..scalar.bits()).rev() {
+            result = self.double(&result);
+            if scalar.bit(i) {
+                result = self.add(&result, point);
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn ecc_generator_order() {
+    for curve in [
+        EllipticCurve::secp128r1(),
+        EllipticCurve::secp224r1(),
+        EllipticCurve::brainpool_p256r1(),
+    ]
+    .iter()
+    {
+        assert!(curve.is_on_curve(&curve.gx, &curve.gy));
+        let g = curve.to_jacobian(&curve.gx, &curve.gy);
+        assert!(curve.multiply(&g, &curve.n).is_infinity());
+        let (x, y) = curve
+            .to_affine(&curve.multiply(&g, &(&curve.n + 1u32)))
+            .unwrap();
+        assert_eq!((x, y), (curve.gx.clone(), curve.gy.clone()));
+        // 2G = G + G
+        let g = curve.generator();
+        assert_eq!(
+            curve.add_points(&g, &g),
+            curve.multiply_point(&g, &BigUint::from(2u32))
+        );
+        assert_eq!(curve.decode_point(&curve.encode_point(&g)), Some(g));
+    }
+}
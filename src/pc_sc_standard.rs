@@ -172,8 +172,8 @@ impl AnswerToReset {
                 0x26 => ("MIFARE Mini".to_owned(), CardName::MifareMini),
                 0x36 => ("MIFARE Plus SL1 2K".to_owned(), CardName::MifarePlusSl12k),
                 0x37 => ("MIFARE Plus SL1 4K".to_owned(), CardName::MifarePlusSl14k),
-                0x38 => ("MIFARE Plus SL2 2K".to_owned(), CardName::MifarePlusSl12k),
-                0x39 => ("MIFARE Plus SL2 4K".to_owned(), CardName::MifarePlusSl14k),
+                0x38 => ("MIFARE Plus SL2 2K".to_owned(), CardName::MifarePlusSl22k),
+                0x39 => ("MIFARE Plus SL2 4K".to_owned(), CardName::MifarePlusSl24k),
                 0x3A => (
                     "MIFARE Ultralight C".to_owned(),
                     CardName::MifareUltralightC,
@@ -268,6 +268,10 @@ impl std::fmt::Display for ATRParseError {
 pub trait ApduBuilderExtWithPcsc3V2 {
     fn get_serial(&mut self) -> &mut Self;
     fn get_ats(&mut self) -> &mut Self;
+    /// READ BINARY (FF B0) ストレージカードのaddressからleバイト読み出す
+    fn read_binary(&mut self, address: u16, le: u8) -> &mut Self;
+    /// UPDATE BINARY (FF D6) ストレージカードのaddressへ書き込む
+    fn update_binary(&mut self, address: u16, data: &[u8]) -> &mut Self;
 }
 
 pub trait ApduBuilderExtWithFelica {
@@ -275,18 +279,112 @@ pub trait ApduBuilderExtWithFelica {
     fn get_card_kind(&mut self) -> &mut Self;
     fn get_card_kind_name(&mut self) -> &mut Self;
     fn get_card_name(&mut self) -> &mut Self;
+    /// SELECT FILE (FF A4) 以降のREAD/UPDATE BINARYで使うサービスコードを選択する
+    fn select_service(&mut self, service_code: u16) -> &mut Self;
 }
 
 // マイナンバーカード拡張
 pub trait JpkiExt {}
 
+// ACS系リーダーの拡張
+pub trait ApduBuilderExtWithDirectTransmit {
+    /// DIRECT TRANSMIT (FF 00 00 00) payloadをそのままリーダーのNFCコントローラへ渡す
+    /// PC/SC Part3で扱えない独自コマンド(Ultralight/NTAG等)を送るのに使う
+    fn direct_transmit(&mut self, payload: &[u8]) -> &mut Self;
+}
+
+// MIFARE DESFire
+pub trait ApduBuilderExtWithDesfire {
+    /// ネイティブコマンドをISO7816-4のAPDUで包む (90 cmd 00 00 [Lc data] 00)
+    /// 応答のSW1は91、SW2がDESFireのステータスになる
+    fn desfire_command(&mut self, command: u8, data: &[u8]) -> &mut Self;
+}
+
+// ISO/IEC 7816-4 (CLA 00) のファイル操作
+pub trait ApduBuilderExtWithIso7816 {
+    /// SELECT FILE (00 A4) P1で選択方法、P2で応答(FCI/FCP/FMD/なし)を指定する
+    /// 応答を返させるときだけLe(00)を付ける
+    fn select_file(&mut self, p1: u8, p2: u8, data: &[u8]) -> &mut Self;
+    /// SELECT FILE (00 A4 04 00) DF名(AID)で選択し、FCIを返させる
+    fn select_by_name(&mut self, name: &[u8]) -> &mut Self;
+    /// SELECT FILE (00 A4 00 0C) ファイルIDで選択する（応答データなし）
+    fn select_by_file_id(&mut self, file_id: u16) -> &mut Self;
+    /// READ BINARY (00 B0) 選択中のEFのoffset(0～7FFF)からleバイト読み出す
+    fn iso_read_binary(&mut self, offset: u16, le: u8) -> &mut Self;
+    /// READ BINARY (00 B0) 短いEF識別子(SFI)のEFを選択し、offset(0～FF)から読み出す
+    fn read_binary_sfi(&mut self, sfi: u8, offset: u8, le: u8) -> &mut Self;
+    /// UPDATE BINARY (00 D6) 選択中のEFのoffset(0～7FFF)へ書き込む
+    fn iso_update_binary(&mut self, offset: u16, data: &[u8]) -> &mut Self;
+    /// READ BINARY (00 B1) オフセットをDO 54で指定する。応答はDO 53で包まれる
+    fn iso_read_binary_odo(&mut self, offset: u32, le: u8) -> &mut Self;
+    /// UPDATE BINARY (00 D7) オフセットをDO 54、データをDO 53で指定する
+    fn iso_update_binary_odo(&mut self, offset: u32, data: &[u8]) -> &mut Self;
+    /// READ RECORD (00 B2) P2の上位5ビットがSFI(0で選択中のEF)、下位3ビットがレコードの指定方法
+    fn read_record(&mut self, record: u8, p2: u8, le: u8) -> &mut Self;
+    /// MANAGE CHANNEL (00 70 00) 論理チャネルを開く
+    /// Noneならカードが番号を割り当て、応答の1バイトで返す
+    fn manage_channel_open(&mut self, channel: Option<u8>) -> &mut Self;
+    /// MANAGE CHANNEL (00 70 80) 論理チャネルを閉じる
+    fn manage_channel_close(&mut self, channel: u8) -> &mut Self;
+    /// GET CHALLENGE (00 84) カードの乱数をleバイト受け取る
+    fn get_challenge(&mut self, le: u8) -> &mut Self;
+    /// EXTERNAL AUTHENTICATE (00 82 00 00) 認証データを送り、カードの認証データをleバイト受け取る
+    fn external_authenticate(&mut self, data: &[u8], le: u8) -> &mut Self;
+    /// MANAGE SECURITY ENVIRONMENT (00 22) P1で操作、P2で制御参照テンプレートを指定する
+    fn manage_security_environment(&mut self, p1: u8, p2: u8, data: &[u8]) -> &mut Self;
+    /// GENERAL AUTHENTICATE (00 86 00 00) 動的認証データ(7C)を送る。Leは00
+    /// chainingならCLAにコマンドチェインを立てる（最後のコマンド以外）
+    fn iso_general_authenticate(&mut self, chaining: bool, data: &[u8]) -> &mut Self;
+}
+
+/// MIFARE Classicの認証に使うキーの種別
+/// 値はGENERAL AUTHENTICATEで指定するキータイプそのもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MifareKeyType {
+    KeyA = 0x60,
+    KeyB = 0x61,
+}
+
+// PC/SC Part3 LOAD KEYSのキー構造体バイト
+/// カードキー、平文転送、リーダーの揮発性メモリへ格納
+pub const KEY_STRUCTURE_VOLATILE: u8 = 0x00;
+/// カードキー、平文転送、リーダーの不揮発性メモリへ格納
+pub const KEY_STRUCTURE_NON_VOLATILE: u8 = 0x20;
+
 pub trait MifareExt {
-    // MIFARE 1K/4K PICC
-    fn load_auth_keys(
-        reserved_key_struct: u8,
-        key_no: u8,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    // MIFARE 1K/4K/Mini PICC
+    /// LOAD KEYS (FF 82)
+    /// リーダーのキー格納領域(key_no)に6バイトのキーを書き込む
+    fn load_auth_keys(&mut self, key_structure: u8, key_no: u8, key: &[u8; 6]) -> &mut Self;
     // MIFARE 1K/4K への認証
+    /// GENERAL AUTHENTICATE (FF 86)
+    /// key_noに格納済みのキーでblockを含むセクタへ認証する
+    fn general_authenticate(&mut self, block: u8, key_type: MifareKeyType, key_no: u8)
+        -> &mut Self;
+    /// READ BINARY (FF B0) 16バイトのブロックを読み出す
+    fn read_block(&mut self, block: u8) -> &mut Self;
+    /// UPDATE BINARY (FF D6) 16バイトのブロックを書き込む
+    fn update_block(&mut self, block: u8, data: &[u8; 16]) -> &mut Self;
+    // 以下はACS系リーダーの値ブロック拡張（PC/SC Part3には値ブロックの命令がない）
+    /// VALUE BLOCK OPERATION (FF D7) 値の格納・加算・減算を行い同じブロックへ転送する
+    fn value_block_operation(
+        &mut self,
+        block: u8,
+        operation: ValueBlockOperation,
+        value: i32,
+    ) -> &mut Self;
+    /// READ VALUE BLOCK (FF B1) 値ブロックの値を読み出す
+    fn read_value_block(&mut self, block: u8) -> &mut Self;
+    /// RESTORE VALUE BLOCK (FF D7) sourceの値ブロックをtargetへ複写する
+    fn restore_value_block(&mut self, source: u8, target: u8) -> &mut Self;
+}
+
+/// VALUE BLOCK OPERATIONの操作種別
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueBlockOperation {
+    Store = 0x00,
+    Increment = 0x01,
+    Decrement = 0x02,
 }
 
 #[test]
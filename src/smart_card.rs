@@ -120,6 +120,8 @@ pub enum SmartcardErrorKind {
     ProtocolMismatch,
     /// スマートカードとの接続が途中で切れたときのエラー
     ConnectionLost,
+    /// このプラットフォーム・ビルドでは使えないリーダの実装
+    NotSupported,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -137,6 +139,16 @@ impl SmartcardError {
             kind: kind,
         }
     }
+    pub fn kind(&self) -> SmartcardErrorKind {
+        self.kind
+    }
+    /// transmitなどが返したエラーがカードの取り外し(接続断)によるものかどうか
+    pub fn is_connection_lost(err: &(dyn std::error::Error + 'static)) -> bool {
+        match err.downcast_ref::<SmartcardError>() {
+            Some(e) => e.kind == SmartcardErrorKind::ConnectionLost,
+            None => false,
+        }
+    }
     fn kind2msg(kind: SmartcardErrorKind) -> String {
         let msg = match kind {
             SmartcardErrorKind::Success => "Success",
@@ -150,6 +162,7 @@ impl SmartcardError {
             SmartcardErrorKind::CardDetectionFailed => "Smart card detection failed.",
             SmartcardErrorKind::ProtocolMismatch => "Smart card Protocol mismatch.",
             SmartcardErrorKind::NotReady => "Smart card not ready.",
+            SmartcardErrorKind::NotSupported => "Smart card reader implementation not supported.",
         };
         msg.to_owned()
     }
@@ -162,3 +175,71 @@ impl std::fmt::Display for SmartcardError {
 }
 
 impl std::error::Error for SmartcardError {}
+
+#[derive(Debug, PartialEq)]
+pub enum TransmitErrorKind {
+    Success(u8, u8),
+    Warn(u8, u8),
+    Error(u8, u8),
+    /// 実装依存のAPIエラーコード（WinSCardならHRESULT）
+    ApiError(i32),
+}
+#[derive(Debug)]
+pub struct TransmitError {
+    code: TransmitErrorKind,
+    data: Vec<u8>,
+}
+impl TransmitError {
+    pub fn new(code: TransmitErrorKind) -> Self {
+        TransmitError {
+            code,
+            data: Vec::new(),
+        }
+    }
+    pub fn with_data(code: TransmitErrorKind, data: Vec<u8>) -> Self {
+        TransmitError { code, data }
+    }
+    pub fn kind(&self) -> &TransmitErrorKind {
+        &self.code
+    }
+    /// SW1SW2の前にカードが返したデータ（DESFireの91 AFなど、90 00以外でもデータを返すカードがある）
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// カードが返したステータスワード(SW1, SW2)
+    pub fn status_word(&self) -> Option<(u8, u8)> {
+        match self.code {
+            TransmitErrorKind::Success(sw1, sw2)
+            | TransmitErrorKind::Warn(sw1, sw2)
+            | TransmitErrorKind::Error(sw1, sw2) => Some((sw1, sw2)),
+            TransmitErrorKind::ApiError(_) => None,
+        }
+    }
+    /// レスポンス(データ+SW1SW2)を検査し、90 00ならデータ部を返す
+    pub fn check_response(mut res: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if res.len() < 2 {
+            return Err(Box::new(TransmitError::new(TransmitErrorKind::ApiError(0))));
+        }
+        let code = res.split_off(res.len() - 2);
+        if code == [0x90, 0x00] {
+            Ok(res)
+        } else {
+            match code[0] {
+                0x62 => Err(Box::new(TransmitError::with_data(
+                    TransmitErrorKind::Error(code[0], code[1]),
+                    res,
+                ))),
+                _ => Err(Box::new(TransmitError::with_data(
+                    TransmitErrorKind::Warn(code[0], code[1]),
+                    res,
+                ))),
+            }
+        }
+    }
+}
+impl std::error::Error for TransmitError {}
+impl std::fmt::Display for TransmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.code)
+    }
+}
@@ -0,0 +1,514 @@
+// Topaz / NFC Forum Type 1 Tag の読み書き
+// カードのコマンドはDIRECT TRANSMIT(FF 00 00 00)でPN53xの InDataExchange に載せて送る
+// （コマンドに続くUIDとCRCはPN53xが付ける）。
+// メモリは8バイトのブロックに分かれ、1バイト単位の命令のアドレスは ブロック番号 << 3 | ブロック内の位置 になる。
+// 静的メモリ(Topaz 96)はRALL/READ/WRITE、動的メモリ(Topaz 512)はセグメント・8バイト単位の命令も使う。
+
+pub mod ndef;
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::{ApduBuilderExtWithDirectTransmit, CardName};
+use crate::smart_card::Smartcard;
+
+pub const BLOCK_SIZE: usize = 8;
+pub const SEGMENT_SIZE: usize = 128;
+/// RALLで読み出せる範囲（ブロック0～E）
+pub const STATIC_MEMORY_SIZE: usize = 120;
+/// Capability Containerの位置（ブロック1の先頭）
+pub const CC_OFFSET: usize = 8;
+pub const CC_SIZE: usize = 4;
+/// データ領域の先頭（CCの直後）
+pub const DATA_AREA_START: usize = CC_OFFSET + CC_SIZE;
+/// 静的ロックバイト(LOCK0, LOCK1)の位置
+pub const LOCK_OFFSET: usize = 0x70;
+/// CCのマジックナンバー（NDEFを格納している）
+pub const NDEF_MAGIC: u8 = 0xE1;
+
+// カードのコマンド
+const CMD_RID: u8 = 0x78;
+const CMD_RALL: u8 = 0x00;
+const CMD_READ: u8 = 0x01;
+const CMD_WRITE_E: u8 = 0x53;
+const CMD_WRITE_NE: u8 = 0x1A;
+const CMD_RSEG: u8 = 0x10;
+const CMD_READ8: u8 = 0x02;
+const CMD_WRITE_E8: u8 = 0x54;
+const CMD_WRITE_NE8: u8 = 0x1B;
+
+// PN53xのコマンド
+const PN53X_IN_DATA_EXCHANGE: [u8; 3] = [0xD4, 0x40, 0x01];
+const PN53X_IN_DATA_EXCHANGE_RESPONSE: [u8; 2] = [0xD5, 0x41];
+
+/// ブロック0(UID)・D(予約)・E(ロック・OTP)・F(予約)は書き込み用のAPIから除く
+const RESERVED_BLOCKS: [usize; 4] = [0x00, 0x0D, 0x0E, 0x0F];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopazModel {
+    /// 静的メモリ 120バイト(HR0 = 11)
+    Topaz96,
+    /// 動的メモリ 512バイト(HR0 = 12)
+    Topaz512,
+}
+
+impl TopazModel {
+    /// ヘッダROMの1バイト目(HR0)の下位4ビットで判定する
+    pub fn from_header_rom(hr0: u8) -> Option<Self> {
+        match hr0 & 0x0F {
+            0x01 => Some(TopazModel::Topaz96),
+            0x02 => Some(TopazModel::Topaz512),
+            _ => None,
+        }
+    }
+    pub fn memory_size(&self) -> usize {
+        match self {
+            TopazModel::Topaz96 => STATIC_MEMORY_SIZE,
+            TopazModel::Topaz512 => 512,
+        }
+    }
+    pub fn is_dynamic(&self) -> bool {
+        *self == TopazModel::Topaz512
+    }
+}
+
+/// Type 1 TagのCapability Container（ブロック1の先頭4バイト）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilityContainer {
+    /// 上位4ビットがメジャー、下位4ビットがマイナーバージョン
+    pub version: u8,
+    /// タグのメモリサイズ。CCには (サイズ / 8) - 1 で格納する
+    pub memory_size: usize,
+    /// 上位4ビットが読み出し、下位4ビットが書き込みの権限（0で許可、Fで禁止）
+    pub access: u8,
+}
+
+impl CapabilityContainer {
+    pub fn new(memory_size: usize) -> Self {
+        CapabilityContainer {
+            version: 0x10,
+            memory_size,
+            access: 0x00,
+        }
+    }
+    /// NDEFのマジックナンバーがなければNone
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        match data {
+            [NDEF_MAGIC, version, tms, access, ..] => Some(CapabilityContainer {
+                version: *version,
+                memory_size: (*tms as usize + 1) * BLOCK_SIZE,
+                access: *access,
+            }),
+            _ => None,
+        }
+    }
+    pub fn to_bytes(self) -> [u8; CC_SIZE] {
+        [
+            NDEF_MAGIC,
+            self.version,
+            (self.memory_size / BLOCK_SIZE - 1) as u8,
+            self.access,
+        ]
+    }
+    pub fn major_version(&self) -> u8 {
+        self.version >> 4
+    }
+    pub fn is_readable(&self) -> bool {
+        self.access & 0xF0 == 0x00
+    }
+    pub fn is_writable(&self) -> bool {
+        self.access & 0x0F == 0x00
+    }
+}
+
+pub struct Topaz<'a> {
+    nfc: &'a dyn Smartcard,
+    model: TopazModel,
+    header_rom: [u8; 2],
+    uid: [u8; 4],
+}
+
+impl<'a> Topaz<'a> {
+    /// ATRでTopazかを確かめ、RIDでヘッダROMとUIDを読み出す
+    pub fn identify(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        match nfc.get_atr().card_name.as_ref().map(|(_, name)| name) {
+            Some(CardName::TopazJewel) => {}
+            _ => return Err(Box::new(TopazError::new(TopazErrorKind::NotTopaz))),
+        }
+        let res = Self::exchange(nfc, &[CMD_RID, 0x00, 0x00])?;
+        // HR0 HR1 UID0～3
+        if res.len() != 6 {
+            return Err(Box::new(TopazError::new(
+                TopazErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let model = TopazModel::from_header_rom(res[0])
+            .ok_or_else(|| TopazError::new(TopazErrorKind::NotTopaz))?;
+        let mut uid = [0u8; 4];
+        uid.copy_from_slice(&res[2..6]);
+        Ok(Topaz {
+            nfc,
+            model,
+            header_rom: [res[0], res[1]],
+            uid,
+        })
+    }
+    pub fn model(&self) -> TopazModel {
+        self.model
+    }
+    pub fn header_rom(&self) -> [u8; 2] {
+        self.header_rom
+    }
+    /// RIDで読み出したUIDの先頭4バイト（7バイトのUIDはブロック0にある）
+    pub fn uid(&self) -> [u8; 4] {
+        self.uid
+    }
+    /// カードのコマンドを送り、カードの応答を返す
+    pub fn transceive(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Self::exchange(self.nfc, command)
+    }
+    fn exchange(
+        nfc: &dyn Smartcard,
+        command: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let payload = PN53X_IN_DATA_EXCHANGE
+            .iter()
+            .chain(command.iter())
+            .cloned()
+            .collect::<Vec<u8>>();
+        let apdu = ApduBuilder::new().direct_transmit(&payload).build();
+        let res = nfc.transmit(Box::new(apdu))?;
+        if res.len() < 3 || res[0..2] != PN53X_IN_DATA_EXCHANGE_RESPONSE {
+            return Err(Box::new(TopazError::new(
+                TopazErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        // 下位6ビットがエラーコード（書き込み禁止のブロックへの書き込みなどはタイムアウトになる）
+        let status = res[2] & 0x3F;
+        if status != 0 {
+            return Err(Box::new(TopazError::new(TopazErrorKind::ReaderStatus(
+                status,
+            ))));
+        }
+        Ok(res[3..].to_vec())
+    }
+
+    /// RALL ブロック0～Eの120バイトを読み出す
+    pub fn read_all(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let res = self.transceive(&[CMD_RALL, 0x00, 0x00])?;
+        // HR0 HR1に続いてメモリの内容が返る
+        Self::check_length(&res, 2 + STATIC_MEMORY_SIZE)?;
+        Ok(res[2..].to_vec())
+    }
+    /// READ 1バイトを読み出す
+    pub fn read_byte(&self, address: u8) -> Result<u8, Box<dyn std::error::Error>> {
+        Self::check_static_address(address)?;
+        let res = self.transceive(&[CMD_READ, address, 0x00])?;
+        Self::check_echo(&res, address, 1)?;
+        Ok(res[1])
+    }
+    /// WRITE-E(消去してから書く) / WRITE-NE(今の値とORを取る) で1バイトを書き込む
+    pub fn write_byte(
+        &self,
+        address: u8,
+        data: u8,
+        erase: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Self::check_static_address(address)?;
+        let command = if erase { CMD_WRITE_E } else { CMD_WRITE_NE };
+        let res = self.transceive(&[command, address, data])?;
+        Self::check_echo(&res, address, 1)?;
+        Ok(())
+    }
+    /// RSEG 128バイトのセグメントを読み出す（動的メモリのみ）
+    pub fn read_segment(
+        &self,
+        segment: u8,
+    ) -> Result<[u8; SEGMENT_SIZE], Box<dyn std::error::Error>> {
+        self.check_dynamic()?;
+        self.check_range(segment as usize * SEGMENT_SIZE, SEGMENT_SIZE)?;
+        let adds = segment << 4;
+        let res = self.transceive(&[&[CMD_RSEG, adds][..], &[0u8; BLOCK_SIZE]].concat())?;
+        Self::check_echo(&res, adds, SEGMENT_SIZE)?;
+        let mut data = [0u8; SEGMENT_SIZE];
+        data.copy_from_slice(&res[1..]);
+        Ok(data)
+    }
+    /// READ8 8バイトのブロックを読み出す（動的メモリのみ）
+    pub fn read_block(&self, block: u8) -> Result<[u8; BLOCK_SIZE], Box<dyn std::error::Error>> {
+        self.check_dynamic()?;
+        self.check_range(block as usize * BLOCK_SIZE, BLOCK_SIZE)?;
+        let res = self.transceive(&[&[CMD_READ8, block][..], &[0u8; BLOCK_SIZE]].concat())?;
+        Self::check_echo(&res, block, BLOCK_SIZE)?;
+        let mut data = [0u8; BLOCK_SIZE];
+        data.copy_from_slice(&res[1..]);
+        Ok(data)
+    }
+    /// WRITE-E8 / WRITE-NE8 で8バイトのブロックを書き込む（動的メモリのみ）
+    pub fn write_block(
+        &self,
+        block: u8,
+        data: &[u8; BLOCK_SIZE],
+        erase: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_dynamic()?;
+        self.check_range(block as usize * BLOCK_SIZE, BLOCK_SIZE)?;
+        let command = if erase { CMD_WRITE_E8 } else { CMD_WRITE_NE8 };
+        let mut frame = vec![command, block];
+        frame.extend_from_slice(data);
+        let res = self.transceive(&frame)?;
+        Self::check_echo(&res, block, BLOCK_SIZE)?;
+        Ok(())
+    }
+
+    /// Capability Containerを読み出す。NDEFでフォーマットされていなければNone
+    pub fn capability_container(
+        &self,
+    ) -> Result<Option<CapabilityContainer>, Box<dyn std::error::Error>> {
+        let memory = self.read_all()?;
+        Ok(CapabilityContainer::from_bytes(
+            &memory[CC_OFFSET..CC_OFFSET + CC_SIZE],
+        ))
+    }
+    /// Capability Containerを書き込む
+    pub fn write_capability_container(
+        &self,
+        cc: &CapabilityContainer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_bytes(CC_OFFSET, &cc.to_bytes())
+    }
+    /// offsetからlenバイトを読み出す
+    /// 静的メモリはRALL、動的メモリは必要なセグメントをまとめて読む
+    pub fn read_memory(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.check_range(offset, len)?;
+        if !self.model.is_dynamic() {
+            let memory = self.read_all()?;
+            return Ok(memory[offset..offset + len].to_vec());
+        }
+        let mut memory = Vec::new();
+        let first = offset / SEGMENT_SIZE;
+        let last = (offset + len).saturating_sub(1) / SEGMENT_SIZE;
+        for segment in first..=last {
+            memory.extend_from_slice(&self.read_segment(segment as u8)?);
+        }
+        let start = offset - first * SEGMENT_SIZE;
+        Ok(memory[start..start + len].to_vec())
+    }
+    /// offsetからdataを書き込む
+    /// UID・ロックバイトなどの予約ブロックに掛かる場合はエラーになる
+    pub fn write_memory(
+        &self,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_range(offset, data.len())?;
+        if let Some(address) = (offset..offset + data.len())
+            .find(|address| RESERVED_BLOCKS.contains(&(address / BLOCK_SIZE)))
+        {
+            return Err(Box::new(TopazError::new(TopazErrorKind::ReservedArea(
+                address,
+            ))));
+        }
+        self.write_bytes(offset, data)
+    }
+    /// CCを読み出し専用にし、静的ロックバイトを全て立てる（元に戻せない）
+    pub fn make_read_only(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cc = self
+            .capability_container()?
+            .ok_or_else(|| TopazError::new(TopazErrorKind::InvalidCapabilityContainer))?;
+        cc.access = 0x0F;
+        self.write_capability_container(&cc)?;
+        self.write_byte(LOCK_OFFSET as u8, 0xFF, false)?;
+        self.write_byte(LOCK_OFFSET as u8 + 1, 0xFF, false)?;
+        Ok(())
+    }
+
+    /// 予約ブロックを確かめずに書き込む
+    /// 動的メモリでは8バイト単位で読んで書き戻し、静的メモリでは1バイトずつ書く
+    fn write_bytes(&self, offset: usize, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.model.is_dynamic() {
+            for (i, byte) in data.iter().enumerate() {
+                self.write_byte((offset + i) as u8, *byte, true)?;
+            }
+            return Ok(());
+        }
+        let end = offset + data.len();
+        let mut address = offset;
+        while address < end {
+            let block = address / BLOCK_SIZE;
+            let block_start = block * BLOCK_SIZE;
+            let len = (block_start + BLOCK_SIZE).min(end) - address;
+            let mut value = if len == BLOCK_SIZE {
+                [0u8; BLOCK_SIZE]
+            } else {
+                self.read_block(block as u8)?
+            };
+            let start = address - block_start;
+            value[start..start + len]
+                .copy_from_slice(&data[address - offset..address - offset + len]);
+            self.write_block(block as u8, &value, true)?;
+            address += len;
+        }
+        Ok(())
+    }
+    fn check_dynamic(&self) -> Result<(), TopazError> {
+        if self.model.is_dynamic() {
+            Ok(())
+        } else {
+            Err(TopazError::new(TopazErrorKind::NotDynamicMemory))
+        }
+    }
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), TopazError> {
+        if offset + len <= self.model.memory_size() {
+            Ok(())
+        } else {
+            Err(TopazError::new(TopazErrorKind::AddressOutOfRange(
+                offset + len,
+            )))
+        }
+    }
+    /// 1バイト単位の命令はブロックEまで
+    fn check_static_address(address: u8) -> Result<(), TopazError> {
+        if (address as usize) < STATIC_MEMORY_SIZE {
+            Ok(())
+        } else {
+            Err(TopazError::new(TopazErrorKind::AddressOutOfRange(
+                address as usize,
+            )))
+        }
+    }
+    fn check_length(res: &[u8], expected: usize) -> Result<(), TopazError> {
+        if res.len() == expected {
+            Ok(())
+        } else {
+            Err(TopazError::new(TopazErrorKind::InvalidResponseLength(
+                res.len(),
+            )))
+        }
+    }
+    /// 応答の先頭にはコマンドのアドレスが返る
+    fn check_echo(res: &[u8], address: u8, len: usize) -> Result<(), TopazError> {
+        Self::check_length(res, 1 + len)?;
+        if res[0] == address {
+            Ok(())
+        } else {
+            Err(TopazError::new(TopazErrorKind::InvalidResponseLength(
+                res.len(),
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopazErrorKind {
+    /// 接続中のカードがTopaz(Type 1 Tag)ではない
+    NotTopaz,
+    /// PN53xのエラーコード
+    ReaderStatus(u8),
+    InvalidResponseLength(usize),
+    AddressOutOfRange(usize),
+    /// UID・ロックバイトなどの予約された領域への書き込み
+    ReservedArea(usize),
+    /// 動的メモリ(Topaz 512)専用の命令
+    NotDynamicMemory,
+    /// NDEFのCapability Containerがない
+    InvalidCapabilityContainer,
+    /// CCで読み出し・書き込みが禁止されている
+    NdefAccessDenied,
+    /// データ領域にNDEF Message TLVがない
+    NdefNotFound,
+}
+
+#[derive(Debug)]
+pub struct TopazError {
+    code: TopazErrorKind,
+}
+
+impl TopazError {
+    pub fn new(code: TopazErrorKind) -> Self {
+        TopazError { code }
+    }
+    pub fn kind(&self) -> &TopazErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for TopazError {}
+impl std::fmt::Display for TopazError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn topaz_static_memory() {
+    use crate::nfc_impl::nfc_mock::topaz::VirtualTopaz;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let nfc = MockSmartcard::new(Box::new(VirtualTopaz::new(TopazModel::Topaz96)));
+    let tag = Topaz::identify(&nfc).unwrap();
+    assert_eq!(tag.model(), TopazModel::Topaz96);
+    assert_eq!(tag.header_rom(), [0x11, 0x48]);
+    assert_eq!(tag.uid(), [0x01, 0x02, 0x03, 0x04]);
+    let cc = tag.capability_container().unwrap().unwrap();
+    assert_eq!(cc.memory_size, STATIC_MEMORY_SIZE);
+    assert_eq!(cc.major_version(), 1);
+    assert!(cc.is_writable());
+
+    let message = [0x03, 0x05, 0xD1, 0x01, 0x01, 0x54, 0x41, 0xFE];
+    tag.write_memory(DATA_AREA_START, &message).unwrap();
+    assert_eq!(
+        tag.read_memory(DATA_AREA_START, message.len()).unwrap(),
+        message
+    );
+    assert_eq!(tag.read_byte(DATA_AREA_START as u8 + 2).unwrap(), 0xD1);
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<TopazError>().unwrap().kind().clone();
+    assert_eq!(
+        kind(tag.write_memory(0x66, &[0x00; 4]).unwrap_err()),
+        TopazErrorKind::ReservedArea(0x68)
+    );
+    assert_eq!(
+        kind(tag.read_segment(0).unwrap_err()),
+        TopazErrorKind::NotDynamicMemory
+    );
+
+    tag.make_read_only().unwrap();
+    let cc = tag.capability_container().unwrap().unwrap();
+    assert!(!cc.is_writable());
+    // ロックされたブロックへの書き込みにはタグが応答しない
+    assert_eq!(
+        kind(tag.write_memory(DATA_AREA_START, &[0x00]).unwrap_err()),
+        TopazErrorKind::ReaderStatus(0x01)
+    );
+}
+
+#[test]
+fn topaz_dynamic_memory() {
+    use crate::nfc_impl::nfc_mock::topaz::VirtualTopaz;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    let nfc = MockSmartcard::new(Box::new(VirtualTopaz::new(TopazModel::Topaz512)));
+    let tag = Topaz::identify(&nfc).unwrap();
+    assert_eq!(tag.model(), TopazModel::Topaz512);
+    let cc = tag.capability_container().unwrap().unwrap();
+    assert_eq!(cc.to_bytes(), [0xE1, 0x10, 0x3F, 0x00]);
+
+    // ブロックの途中から書き込み、セグメント0と1の境界をまたいで読み出す
+    let data = (0..20).collect::<Vec<u8>>();
+    tag.write_memory(0x8C, &data).unwrap();
+    let memory = tag.read_memory(0x70, 0x30).unwrap();
+    assert_eq!(memory[0x1C..], data[..]);
+    assert_eq!(tag.read_segment(1).unwrap()[0x0C..0x20], data[..]);
+    assert_eq!(
+        tag.read_block(0x12).unwrap(),
+        [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B]
+    );
+    tag.write_block(0x12, &[0x80; BLOCK_SIZE], false).unwrap();
+    assert_eq!(
+        tag.read_block(0x12).unwrap(),
+        [0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x8B]
+    );
+    assert!(tag.read_memory(500, 20).is_err());
+}
@@ -0,0 +1,176 @@
+// NFC Forum Type 1 TagとしてのNDEFの読み書き
+// ブロック1の先頭4バイトがCapability Container、その直後からがデータ領域で、TLVの並びにNDEFメッセージを格納する。
+// ブロックD～F（予約・静的ロックバイト・OTP）はデータ領域から除き、
+// 動的メモリのロックバイトや予約領域はLock Control・Memory Control TLVが指す範囲を読み飛ばす。
+// 書き込みはType 2 Tagと同じく、長さを0にする → メッセージを書く → 長さを書く の順で行う。
+
+use super::{CapabilityContainer, Topaz, TopazError, TopazErrorKind, BLOCK_SIZE, DATA_AREA_START};
+use crate::ndef::tlv::TlvLayout;
+use crate::ndef::NdefMessage;
+use std::ops::Range;
+
+/// ブロックD～Fはデータ領域に含めない
+const STATIC_RESERVED_AREA: Range<usize> = 0x0D * BLOCK_SIZE..0x10 * BLOCK_SIZE;
+
+/// データ領域を読み出した内容（ブロック0からの通しのアドレス）
+struct DataArea {
+    memory: Vec<u8>,
+    layout: TlvLayout,
+}
+
+impl<'a> Topaz<'a> {
+    /// NDEFメッセージを読み出す。NDEF Message TLVの長さが0なら空のメッセージを返す
+    pub fn read_ndef(&self) -> Result<NdefMessage, Box<dyn std::error::Error>> {
+        let cc = self.ndef_capability_container()?;
+        if !cc.is_readable() {
+            return Err(Box::new(TopazError::new(TopazErrorKind::NdefAccessDenied)));
+        }
+        let area = self.read_data_area(&cc)?;
+        let ndef = area
+            .layout
+            .ndef
+            .as_ref()
+            .ok_or_else(|| TopazError::new(TopazErrorKind::NdefNotFound))?;
+        if ndef.value.is_empty() {
+            return Ok(NdefMessage::default());
+        }
+        let data = ndef
+            .value
+            .iter()
+            .map(|position| area.memory[*position])
+            .collect::<Vec<u8>>();
+        Ok(NdefMessage::from_bytes(&data)?)
+    }
+    /// NDEFメッセージを書き込む（既存のNDEF Message TLVを置き換える）
+    pub fn write_ndef(&self, message: &NdefMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let cc = self.ndef_capability_container()?;
+        if !cc.is_readable() || !cc.is_writable() {
+            return Err(Box::new(TopazError::new(TopazErrorKind::NdefAccessDenied)));
+        }
+        let area = self.read_data_area(&cc)?;
+        let (empty, image) = area.layout.place(&area.memory, &message.to_bytes())?;
+        // 長さを0にして、メッセージ、長さの順に書く
+        self.write_changed_blocks(&area.memory, &empty)?;
+        self.write_changed_blocks(&empty, &image)?;
+        Ok(())
+    }
+
+    /// 対応するのはメジャーバージョン1
+    fn ndef_capability_container(&self) -> Result<CapabilityContainer, Box<dyn std::error::Error>> {
+        match self.capability_container()? {
+            Some(cc) if cc.major_version() == 1 => Ok(cc),
+            _ => Err(Box::new(TopazError::new(
+                TopazErrorKind::InvalidCapabilityContainer,
+            ))),
+        }
+    }
+    fn read_data_area(
+        &self,
+        cc: &CapabilityContainer,
+    ) -> Result<DataArea, Box<dyn std::error::Error>> {
+        // CCのサイズがタグのメモリを越えていても読める範囲に留める
+        let end = cc.memory_size.min(self.model.memory_size());
+        let memory = self.read_memory(0, end)?;
+        let layout =
+            TlvLayout::parse_with_reserved(&memory, DATA_AREA_START, end, &[STATIC_RESERVED_AREA])?;
+        Ok(DataArea { memory, layout })
+    }
+    /// 変わったブロックだけを書く（静的メモリは変わったバイトだけ）
+    fn write_changed_blocks(
+        &self,
+        current: &[u8],
+        next: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (i, (before, after)) in current
+            .chunks(BLOCK_SIZE)
+            .zip(next.chunks(BLOCK_SIZE))
+            .enumerate()
+        {
+            if before == after {
+                continue;
+            }
+            let offset = i * BLOCK_SIZE;
+            if self.model.is_dynamic() {
+                self.write_memory(offset, after)?;
+                continue;
+            }
+            for (j, (old, new)) in before.iter().zip(after.iter()).enumerate() {
+                if old != new {
+                    self.write_memory(offset + j, &[*new])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn topaz_ndef_read_write() {
+    use super::TopazModel;
+    use crate::ndef::NdefRecord;
+    use crate::nfc_impl::nfc_mock::topaz::VirtualTopaz;
+    use crate::nfc_impl::nfc_mock::MockSmartcard;
+
+    // Topaz 96: データ領域は 0x0C..0x68
+    let nfc = MockSmartcard::new(Box::new(VirtualTopaz::new(TopazModel::Topaz96)));
+    let tag = Topaz::identify(&nfc).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), NdefMessage::default());
+    let message = NdefMessage::new(vec![NdefRecord::uri("https://www.nfc.com")]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(
+        tag.read_memory(DATA_AREA_START, 4).unwrap(),
+        [0x03, 0x0C, 0xD1, 0x01]
+    );
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    // 0x68を越えるメッセージは静的ロックバイトに掛かるので書き込めない
+    let too_large = NdefMessage::new(vec![NdefRecord::mime("text/plain", &[0x41; 80])]);
+    assert!(tag.write_ndef(&too_large).is_err());
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    tag.make_read_only().unwrap();
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<TopazError>().unwrap().kind().clone();
+    assert_eq!(
+        kind(tag.write_ndef(&NdefMessage::default()).unwrap_err()),
+        TopazErrorKind::NdefAccessDenied
+    );
+    assert_eq!(tag.read_ndef().unwrap(), message);
+
+    // Topaz 512: ブロックD～Fと、Lock Control TLVが指す動的ロックバイト(0x100..0x102)を避ける
+    let mut card = VirtualTopaz::new(TopazModel::Topaz512);
+    card.set_memory(
+        DATA_AREA_START,
+        &[0x01, 0x03, 0x80, 0x10, 0x45, 0x03, 0x00, 0xFE],
+    );
+    card.set_memory(0x100, &[0x0F, 0xF0]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let tag = Topaz::identify(&nfc).unwrap();
+    let message = NdefMessage::new(vec![
+        NdefRecord::text("en", "Hello"),
+        NdefRecord::mime("application/octet-stream", &[0xA5; 300]),
+    ]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_memory(0x11, 2).unwrap(), [0x03, 0xFF]);
+    assert_eq!(tag.read_memory(0x68, 0x18).unwrap(), [0x00; 0x18]);
+    assert_eq!(tag.read_memory(0x100, 2).unwrap(), [0x0F, 0xF0]);
+    assert_eq!(tag.read_ndef().unwrap(), message);
+
+    // 出荷状態のLock Control・Memory Control TLVは残したまま書き込む
+    let nfc = MockSmartcard::new(Box::new(VirtualTopaz::new(TopazModel::Topaz512)));
+    let tag = Topaz::identify(&nfc).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), NdefMessage::default());
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(
+        tag.read_memory(DATA_AREA_START, 12).unwrap(),
+        [0x01, 0x03, 0xF2, 0x30, 0x33, 0x02, 0x03, 0xF0, 0x02, 0x03, 0x03, 0xFF]
+    );
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    // NDEF Message TLVがない
+    let mut card = VirtualTopaz::new(TopazModel::Topaz512);
+    card.set_memory(DATA_AREA_START, &[0xFE]);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let tag = Topaz::identify(&nfc).unwrap();
+    assert_eq!(
+        kind(tag.read_ndef().unwrap_err()),
+        TopazErrorKind::NdefNotFound
+    );
+}
@@ -0,0 +1,515 @@
+// NFC Forum Type 4 Tag（ISO 14443-4 のカード）としてのNDEFの読み書き
+// NDEFアプリケーション(D2760000850101)を選択し、CCファイル(E103)からNDEFファイルのIDと
+// 1回のREAD BINARYで読める長さ(MLe)・UPDATE BINARYで書ける長さ(MLc)を読み出す。
+// NDEFファイルの先頭はメッセージの長さ(NLEN 2バイト、マッピング3.0のENDEFファイルはENLEN 4バイト)。
+// 書き込みは NLENを0にする → メッセージを書く → NLENを書く の順で行う。
+// APDUは短い形式だけを使うので、MLe/MLcが255を超えても1回のデータは255バイトまでにする。
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::ndef::NdefMessage;
+use crate::pc_sc_standard::ApduBuilderExtWithIso7816;
+use crate::smart_card::{Smartcard, TransmitError};
+
+/// NDEFタグアプリケーションのAID（マッピング2.0以降）
+pub const NDEF_APPLICATION_AID: [u8; 7] = [0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01];
+/// Capability ContainerファイルのID
+pub const CC_FILE_ID: u16 = 0xE103;
+pub const MAPPING_VERSION_2: u8 = 0x20;
+pub const MAPPING_VERSION_3: u8 = 0x30;
+/// NDEF File Control TLV（NLEN 2バイト）
+pub const TLV_NDEF_FILE_CONTROL: u8 = 0x04;
+/// Extended NDEF File Control TLV（マッピング3.0、ENLEN 4バイト）
+pub const TLV_EXTENDED_NDEF_FILE_CONTROL: u8 = 0x06;
+pub const ACCESS_GRANTED: u8 = 0x00;
+pub const ACCESS_DENIED: u8 = 0xFF;
+/// CCLEN・バージョン・MLe・MLcの7バイト
+const CC_HEADER_SIZE: usize = 7;
+/// 偶数INSのREAD/UPDATE BINARYで指定できる最大のオフセット
+const MAX_EVEN_INS_OFFSET: usize = 0x7FFF;
+/// 短いAPDUで扱えるデータの長さ
+const MAX_SHORT_APDU_DATA: usize = 0xFF;
+/// 奇数INSのDO 54(5バイト)とDO 53のタグ・長さ(最大3バイト)
+const ODO_OVERHEAD: usize = 8;
+/// DO 53のタグ・長さ(最大3バイト)
+const DISCRETIONARY_DATA_OVERHEAD: usize = 3;
+/// 選択するファイルが見つからない
+const SW_FILE_NOT_FOUND: (u8, u8) = (0x6A, 0x82);
+
+/// CCのNDEF File Control TLV / Extended NDEF File Control TLV
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NdefFileControl {
+    pub file_id: u16,
+    /// NLEN(ENLEN)を含むNDEFファイルの最大のバイト数
+    pub max_size: u32,
+    /// 00で許可、FFで禁止（80～FEは独自の条件）
+    pub read_access: u8,
+    pub write_access: u8,
+    /// Extended NDEF File Control TLVで指定されている
+    pub extended: bool,
+}
+
+impl NdefFileControl {
+    /// ファイル先頭の長さのバイト数
+    pub fn nlen_size(&self) -> usize {
+        if self.extended {
+            4
+        } else {
+            2
+        }
+    }
+    /// 格納できるNDEFメッセージの最大のバイト数
+    pub fn capacity(&self) -> usize {
+        (self.max_size as usize).saturating_sub(self.nlen_size())
+    }
+    pub fn is_readable(&self) -> bool {
+        self.read_access == ACCESS_GRANTED
+    }
+    pub fn is_writable(&self) -> bool {
+        self.write_access == ACCESS_GRANTED
+    }
+}
+
+/// Type 4 TagのCapability Container
+/// CCLEN(2) || マッピングバージョン || MLe(2) || MLc(2) || NDEF File Control TLV || ...
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilityContainer {
+    /// 上位4ビットがメジャー、下位4ビットがマイナーバージョン
+    pub version: u8,
+    /// 1回のREAD BINARYで読み出せる最大のバイト数
+    pub mle: u16,
+    /// 1回のUPDATE BINARYで書き込める最大のバイト数
+    pub mlc: u16,
+    pub ndef_file: NdefFileControl,
+}
+
+impl CapabilityContainer {
+    /// NDEFファイルの制御TLVがなければNone
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < CC_HEADER_SIZE {
+            return None;
+        }
+        let cclen = u16::from_be_bytes([data[0], data[1]]) as usize;
+        let data = &data[..cclen.min(data.len())];
+        let mut tlvs = data.get(CC_HEADER_SIZE..)?;
+        // 最初のNDEF File Control TLVを使う（Proprietary File Control TLVなどは読み飛ばす）
+        let ndef_file = loop {
+            match tlvs {
+                [TLV_NDEF_FILE_CONTROL, 0x06, id1, id2, size1, size2, read, write, ..] => {
+                    break NdefFileControl {
+                        file_id: u16::from_be_bytes([*id1, *id2]),
+                        max_size: u16::from_be_bytes([*size1, *size2]) as u32,
+                        read_access: *read,
+                        write_access: *write,
+                        extended: false,
+                    }
+                }
+                [TLV_EXTENDED_NDEF_FILE_CONTROL, 0x08, id1, id2, s1, s2, s3, s4, read, write, ..] => {
+                    break NdefFileControl {
+                        file_id: u16::from_be_bytes([*id1, *id2]),
+                        max_size: u32::from_be_bytes([*s1, *s2, *s3, *s4]),
+                        read_access: *read,
+                        write_access: *write,
+                        extended: true,
+                    }
+                }
+                [_, len, rest @ ..] if rest.len() >= *len as usize => {
+                    tlvs = &rest[*len as usize..];
+                }
+                _ => return None,
+            }
+        };
+        Some(CapabilityContainer {
+            version: data[2],
+            mle: u16::from_be_bytes([data[3], data[4]]),
+            mlc: u16::from_be_bytes([data[5], data[6]]),
+            ndef_file,
+        })
+    }
+    pub fn to_bytes(self) -> Vec<u8> {
+        let file = self.ndef_file;
+        let mut tlv = if file.extended {
+            let mut tlv = vec![TLV_EXTENDED_NDEF_FILE_CONTROL, 0x08];
+            tlv.extend_from_slice(&file.file_id.to_be_bytes());
+            tlv.extend_from_slice(&file.max_size.to_be_bytes());
+            tlv
+        } else {
+            let mut tlv = vec![TLV_NDEF_FILE_CONTROL, 0x06];
+            tlv.extend_from_slice(&file.file_id.to_be_bytes());
+            tlv.extend_from_slice(&(file.max_size as u16).to_be_bytes());
+            tlv
+        };
+        tlv.extend_from_slice(&[file.read_access, file.write_access]);
+        let mut data = ((CC_HEADER_SIZE + tlv.len()) as u16).to_be_bytes().to_vec();
+        data.push(self.version);
+        data.extend_from_slice(&self.mle.to_be_bytes());
+        data.extend_from_slice(&self.mlc.to_be_bytes());
+        data.extend(tlv);
+        data
+    }
+    pub fn major_version(&self) -> u8 {
+        self.version >> 4
+    }
+    /// 1回に読み出すバイト数
+    fn read_size(&self) -> usize {
+        (self.mle as usize).min(MAX_SHORT_APDU_DATA)
+    }
+    /// 1回に書き込むバイト数
+    fn write_size(&self) -> usize {
+        (self.mlc as usize).min(MAX_SHORT_APDU_DATA)
+    }
+}
+
+pub struct Type4Tag<'a> {
+    nfc: &'a dyn Smartcard,
+    cc: CapabilityContainer,
+}
+
+impl<'a> Type4Tag<'a> {
+    /// NDEFアプリケーションを選択し、CCファイルを読み出す
+    pub fn select(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .select_by_name(&NDEF_APPLICATION_AID)
+            .build();
+        if let Err(e) = nfc.transmit(Box::new(apdu)) {
+            return match e.downcast_ref::<TransmitError>() {
+                Some(error) if error.status_word() == Some(SW_FILE_NOT_FOUND) => {
+                    Err(Box::new(Type4TagError::new(Type4TagErrorKind::NotType4Tag)))
+                }
+                _ => Err(e),
+            };
+        }
+        Self::select_file(nfc, CC_FILE_ID)?;
+        let cclen = match Self::read_even(nfc, 0, 2)?.as_slice() {
+            [high, low] => u16::from_be_bytes([*high, *low]) as usize,
+            res => {
+                return Err(Box::new(Type4TagError::new(
+                    Type4TagErrorKind::InvalidResponseLength(res.len()),
+                )))
+            }
+        };
+        let data = Self::read_even(nfc, 0, cclen.min(MAX_SHORT_APDU_DATA))?;
+        let cc = CapabilityContainer::from_bytes(&data)
+            .ok_or_else(|| Type4TagError::new(Type4TagErrorKind::InvalidCapabilityContainer))?;
+        // ENDEFファイルはマッピング3.0から
+        match cc.major_version() {
+            2 if !cc.ndef_file.extended => {}
+            3 => {}
+            _ => {
+                return Err(Box::new(Type4TagError::new(
+                    Type4TagErrorKind::UnsupportedVersion(cc.version),
+                )))
+            }
+        }
+        if cc.mle == 0 || cc.mlc == 0 || cc.ndef_file.max_size as usize <= cc.ndef_file.nlen_size()
+        {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::InvalidCapabilityContainer,
+            )));
+        }
+        Ok(Type4Tag { nfc, cc })
+    }
+    pub fn capability_container(&self) -> &CapabilityContainer {
+        &self.cc
+    }
+    /// 書き込めるNDEFメッセージの最大のバイト数
+    pub fn ndef_capacity(&self) -> usize {
+        self.cc.ndef_file.capacity()
+    }
+    /// NDEFメッセージを読み出す。NLENが0なら空のメッセージを返す
+    pub fn read_ndef(&self) -> Result<NdefMessage, Box<dyn std::error::Error>> {
+        let file = self.cc.ndef_file;
+        if !file.is_readable() {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::NdefAccessDenied,
+            )));
+        }
+        Self::select_file(self.nfc, file.file_id)?;
+        let nlen = self.read_binary(0, file.nlen_size())?;
+        let len = nlen
+            .iter()
+            .fold(0usize, |len, byte| len << 8 | *byte as usize);
+        if len > file.capacity() {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::InvalidNlen(len),
+            )));
+        }
+        if len == 0 {
+            return Ok(NdefMessage::default());
+        }
+        let data = self.read_binary(file.nlen_size(), len)?;
+        Ok(NdefMessage::from_bytes(&data)?)
+    }
+    /// NDEFメッセージを書き込む
+    pub fn write_ndef(&self, message: &NdefMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let file = self.cc.ndef_file;
+        if !file.is_writable() {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::NdefAccessDenied,
+            )));
+        }
+        let data = message.to_bytes();
+        if data.len() > file.capacity() {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::NdefTooLarge(file.capacity()),
+            )));
+        }
+        Self::select_file(self.nfc, file.file_id)?;
+        let nlen = (data.len() as u32).to_be_bytes();
+        let nlen = &nlen[4 - file.nlen_size()..];
+        // 長さを0にして、メッセージ、長さの順に書く
+        self.update_binary(0, &vec![0x00; nlen.len()])?;
+        self.update_binary(file.nlen_size(), &data)?;
+        self.update_binary(0, nlen)?;
+        Ok(())
+    }
+
+    fn select_file(nfc: &dyn Smartcard, file_id: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().select_by_file_id(file_id).build();
+        nfc.transmit(Box::new(apdu))?;
+        Ok(())
+    }
+    /// 選択中のEFのoffsetからlenバイト読み出す
+    fn read_binary(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let position = offset + data.len();
+            let remain = len - data.len();
+            let chunk = if position <= MAX_EVEN_INS_OFFSET {
+                Self::read_even(self.nfc, position, remain.min(self.cc.read_size()))?
+            } else {
+                // 応答のDO 53のタグと長さの分だけ短くする
+                let size = self
+                    .cc
+                    .read_size()
+                    .saturating_sub(DISCRETIONARY_DATA_OVERHEAD)
+                    .max(1);
+                self.read_odd(position, remain.min(size))?
+            };
+            if chunk.is_empty() {
+                return Err(Box::new(Type4TagError::new(
+                    Type4TagErrorKind::InvalidResponseLength(0),
+                )));
+            }
+            data.extend(chunk);
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+    fn read_even(
+        nfc: &dyn Smartcard,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .iso_read_binary(offset as u16, len as u8)
+            .build();
+        let res = nfc.transmit(Box::new(apdu))?;
+        if res.len() > len {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        Ok(res)
+    }
+    /// 奇数INSのREAD BINARY。応答は 53 長さ データ
+    fn read_odd(&self, offset: usize, len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        // 53 長さ(128バイト以上は 81 長さ) データ
+        let le = if len >= 0x80 { len + 3 } else { len + 2 };
+        let apdu = ApduBuilder::new()
+            .iso_read_binary_odo(offset as u32, le as u8)
+            .build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        let data = match res.as_slice() {
+            [0x53, 0x81, size, data @ ..] if *size >= 0x80 && data.len() == *size as usize => data,
+            [0x53, size, data @ ..] if *size < 0x80 && data.len() == *size as usize => data,
+            _ => {
+                return Err(Box::new(Type4TagError::new(
+                    Type4TagErrorKind::InvalidResponseLength(res.len()),
+                )))
+            }
+        };
+        if data.len() > len {
+            return Err(Box::new(Type4TagError::new(
+                Type4TagErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        Ok(data.to_vec())
+    }
+    /// 選択中のEFのoffsetへMLcごとに分けて書き込む
+    fn update_binary(&self, offset: usize, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written;
+            let remain = &data[written..];
+            let apdu = if position <= MAX_EVEN_INS_OFFSET {
+                let size = remain.len().min(self.cc.write_size());
+                written += size;
+                ApduBuilder::new()
+                    .iso_update_binary(position as u16, &remain[..size])
+                    .build()
+            } else {
+                // DO 54・DO 53の分もMLcに含まれる
+                let size = remain
+                    .len()
+                    .min(self.cc.write_size().saturating_sub(ODO_OVERHEAD).max(1));
+                written += size;
+                ApduBuilder::new()
+                    .iso_update_binary_odo(position as u32, &remain[..size])
+                    .build()
+            };
+            self.nfc.transmit(Box::new(apdu))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type4TagErrorKind {
+    /// NDEFアプリケーションがない
+    NotType4Tag,
+    /// CCの形式が正しくない、またはNDEFファイルの制御TLVがない
+    InvalidCapabilityContainer,
+    /// 対応していないマッピングバージョン
+    UnsupportedVersion(u8),
+    /// NDEFファイルの読み出し・書き込みが許可されていない
+    NdefAccessDenied,
+    /// NLENがNDEFファイルの大きさを越えている
+    InvalidNlen(usize),
+    /// NDEFメッセージがNDEFファイルに収まらない（値は格納できる最大のバイト数）
+    NdefTooLarge(usize),
+    InvalidResponseLength(usize),
+}
+
+#[derive(Debug)]
+pub struct Type4TagError {
+    code: Type4TagErrorKind,
+}
+
+impl Type4TagError {
+    pub fn new(code: Type4TagErrorKind) -> Self {
+        Type4TagError { code }
+    }
+    pub fn kind(&self) -> &Type4TagErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for Type4TagError {}
+impl std::fmt::Display for Type4TagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn type4_capability_container() {
+    // マッピング2.0: MLe 003B、MLc 0034、NDEFファイル E104 最大0800バイト
+    let data = [
+        0x00, 0x0F, 0x20, 0x00, 0x3B, 0x00, 0x34, 0x04, 0x06, 0xE1, 0x04, 0x08, 0x00, 0x00, 0x00,
+    ];
+    let cc = CapabilityContainer::from_bytes(&data).unwrap();
+    assert_eq!(cc.major_version(), 2);
+    assert_eq!((cc.mle, cc.mlc), (0x3B, 0x34));
+    assert_eq!(cc.ndef_file.file_id, 0xE104);
+    assert_eq!(cc.ndef_file.capacity(), 0x7FE);
+    assert!(cc.ndef_file.is_readable() && cc.ndef_file.is_writable());
+    assert_eq!(cc.to_bytes(), data);
+
+    // マッピング3.0: Proprietary File Control TLVの後ろにENDEFファイル、書き込み禁止
+    let data = [
+        0x00, 0x19, 0x30, 0x01, 0x00, 0x00, 0xFF, 0x05, 0x06, 0xE1, 0x05, 0x00, 0x80, 0x00, 0x00,
+        0x06, 0x08, 0xE1, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0xFF,
+    ];
+    let cc = CapabilityContainer::from_bytes(&data).unwrap();
+    assert!(cc.ndef_file.extended);
+    assert_eq!(cc.ndef_file.nlen_size(), 4);
+    assert_eq!(cc.ndef_file.capacity(), 0x10000 - 4);
+    assert!(!cc.ndef_file.is_writable());
+    assert_eq!((cc.read_size(), cc.write_size()), (0xFF, 0xFF));
+
+    // CCLENより後ろのTLVは使わない
+    let mut data = data.to_vec();
+    data[1] = 0x0F;
+    assert!(CapabilityContainer::from_bytes(&data).is_none());
+    assert!(CapabilityContainer::from_bytes(&[0x00, 0x0F, 0x20]).is_none());
+}
+
+#[test]
+fn type4_tag_ndef_read_write() {
+    use crate::ndef::smart_poster::SmartPoster;
+    use crate::ndef::NdefRecord;
+    use crate::nfc_impl::nfc_mock::{type4_tag::VirtualType4Tag, MockSmartcard};
+
+    let nfc = MockSmartcard::new(Box::new(VirtualType4Tag::new()));
+    let tag = Type4Tag::select(&nfc).unwrap();
+    assert_eq!(tag.capability_container().mle, 0x3B);
+    assert_eq!(tag.ndef_capacity(), 0x7FE);
+    assert_eq!(tag.read_ndef().unwrap(), NdefMessage::default());
+
+    // MLe・MLcより長いメッセージを分けて読み書きする
+    let poster = SmartPoster::new("https://www.nfc-forum.org").with_title("en", "NFC Forum");
+    let message = NdefMessage::new(vec![
+        poster.to_record(),
+        NdefRecord::mime("application/octet-stream", &[0xA5; 300]),
+    ]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    let too_large = NdefMessage::new(vec![NdefRecord::mime("text/plain", &[0x41; 0x800])]);
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<Type4TagError>().unwrap().kind().clone();
+    assert_eq!(
+        kind(tag.write_ndef(&too_large).unwrap_err()),
+        Type4TagErrorKind::NdefTooLarge(0x7FE)
+    );
+    assert_eq!(tag.read_ndef().unwrap(), message);
+
+    // 書き込み済みの読み出し専用タグ
+    let mut card = VirtualType4Tag::new();
+    card.set_ndef_message(&message);
+    card.set_write_access(ACCESS_DENIED);
+    let nfc = MockSmartcard::new(Box::new(card));
+    let tag = Type4Tag::select(&nfc).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    assert_eq!(
+        kind(tag.write_ndef(&NdefMessage::default()).unwrap_err()),
+        Type4TagErrorKind::NdefAccessDenied
+    );
+}
+
+#[test]
+fn type4_tag_extended_ndef_file() {
+    use crate::ndef::NdefRecord;
+    use crate::nfc_impl::nfc_mock::{type4_tag::VirtualType4Tag, MockSmartcard};
+
+    // マッピング3.0のENDEFファイル。0x7FFFを越える位置は奇数INSで読み書きする
+    let cc = CapabilityContainer {
+        version: MAPPING_VERSION_3,
+        mle: 0x00FF,
+        mlc: 0x00FF,
+        ndef_file: NdefFileControl {
+            file_id: 0xE104,
+            max_size: 0x10000,
+            read_access: ACCESS_GRANTED,
+            write_access: ACCESS_GRANTED,
+            extended: true,
+        },
+    };
+    let nfc = MockSmartcard::new(Box::new(VirtualType4Tag::with_capability_container(cc)));
+    let tag = Type4Tag::select(&nfc).unwrap();
+    assert_eq!(tag.ndef_capacity(), 0x10000 - 4);
+    let payload = (0..40000).map(|i| i as u8).collect::<Vec<u8>>();
+    let message = NdefMessage::new(vec![NdefRecord::mime("application/octet-stream", &payload)]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+
+    // マッピング2.0のタグはENDEFファイルを持てない
+    let cc = CapabilityContainer {
+        version: MAPPING_VERSION_2,
+        ..cc
+    };
+    let nfc = MockSmartcard::new(Box::new(VirtualType4Tag::with_capability_container(cc)));
+    assert!(Type4Tag::select(&nfc).is_err());
+}
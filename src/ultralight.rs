@@ -0,0 +1,366 @@
+// MIFARE Ultralight / NTAG21x の読み書き
+// PC/SC Part3にはUltralight向けの命令がないため、DIRECT TRANSMIT(FF 00 00 00)で
+// リーダーのNFCコントローラ(PN53x)の InCommunicateThru にカードのコマンドを載せて送る
+
+use crate::apdu_contactless::ApduBuilder;
+use crate::pc_sc_standard::{
+    ApduBuilderExtWithDirectTransmit, ApduBuilderExtWithPcsc3V2, CardName,
+};
+use crate::smart_card::{Smartcard, SmartcardError, SmartcardErrorKind};
+
+pub mod lock_bytes;
+pub mod ndef;
+pub mod ntag;
+pub mod ultralight_c;
+pub mod version;
+use version::{UltralightModel, UltralightVersion};
+
+pub const PAGE_SIZE: usize = 4;
+/// READは指定ページから4ページ分をまとめて返す
+pub const READ_SIZE: usize = PAGE_SIZE * 4;
+
+// カードのコマンド
+const CMD_GET_VERSION: u8 = 0x60;
+const CMD_READ: u8 = 0x30;
+const CMD_WRITE: u8 = 0xA2;
+/// 4ビットのACK（それ以外の4ビット応答はNAK）
+const ACK: u8 = 0x0A;
+
+// PN53xのコマンド
+const PN53X_IN_COMMUNICATE_THRU: [u8; 2] = [0xD4, 0x42];
+const PN53X_IN_COMMUNICATE_THRU_RESPONSE: [u8; 2] = [0xD5, 0x43];
+/// 106kbps TypeAのターゲットを1枚だけ選択し直す
+const PN53X_IN_LIST_PASSIVE_TARGET: [u8; 4] = [0xD4, 0x4A, 0x01, 0x00];
+const PN53X_IN_LIST_PASSIVE_TARGET_RESPONSE: [u8; 2] = [0xD5, 0x4B];
+
+pub struct Ultralight<'a> {
+    nfc: &'a dyn Smartcard,
+    model: UltralightModel,
+    version: Option<UltralightVersion>,
+    page_count: u16,
+}
+
+impl<'a> Ultralight<'a> {
+    /// 種別が分かっている場合に使う
+    pub fn new(nfc: &'a dyn Smartcard, model: UltralightModel) -> Self {
+        Ultralight {
+            nfc,
+            model,
+            version: None,
+            page_count: model.page_count().unwrap_or(0),
+        }
+    }
+    /// ATRとGET_VERSIONで種別とメモリサイズを判定する
+    /// GET_VERSIONに応答しない旧来のUltralight/Ultralight CはATRで判別する
+    pub fn identify(nfc: &'a dyn Smartcard) -> Result<Self, Box<dyn std::error::Error>> {
+        let atr_model = match nfc.get_atr().card_name.as_ref().map(|(_, name)| name) {
+            Some(CardName::MifareUltralight) => UltralightModel::Ultralight,
+            Some(CardName::MifareUltralightC) => UltralightModel::UltralightC,
+            _ => {
+                return Err(Box::new(UltralightError::new(
+                    UltralightErrorKind::NotUltralight,
+                )))
+            }
+        };
+        let mut card = Self::new(nfc, atr_model);
+        match card.get_version() {
+            Ok(version) => {
+                card.model = version.model();
+                card.version = Some(version);
+            }
+            Err(e) => {
+                if SmartcardError::is_connection_lost(e.as_ref()) {
+                    return Err(e);
+                }
+                // NAKでカードが休止状態になるので選択し直す
+                card.reactivate()?;
+            }
+        }
+        card.page_count = match card.model.page_count() {
+            Some(page_count) => page_count,
+            None => card.probe_page_count()?,
+        };
+        Ok(card)
+    }
+    pub fn model(&self) -> UltralightModel {
+        self.model
+    }
+    /// GET_VERSIONの応答（identifyで取得できた場合のみ）
+    pub fn version(&self) -> Option<&UltralightVersion> {
+        self.version.as_ref()
+    }
+    /// 全ページ数（UID・ロック・設定ページを含む）
+    pub fn page_count(&self) -> u16 {
+        self.page_count
+    }
+    pub fn uid(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new().get_serial().build();
+        self.nfc.transmit(Box::new(apdu))
+    }
+    /// カードのコマンドを送り、カードの応答を返す
+    /// ACKは空の応答、NAKはエラーになる
+    pub fn transceive(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let payload = PN53X_IN_COMMUNICATE_THRU
+            .iter()
+            .chain(command.iter())
+            .cloned()
+            .collect::<Vec<u8>>();
+        let apdu = ApduBuilder::new().direct_transmit(&payload).build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        if res.len() < 3 || res[0..2] != PN53X_IN_COMMUNICATE_THRU_RESPONSE {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        // 下位6ビットがエラーコード
+        let status = res[2] & 0x3F;
+        if status != 0 {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::ReaderStatus(status),
+            )));
+        }
+        let data = &res[3..];
+        match data {
+            [ACK] => Ok(Vec::new()),
+            [nak] if *nak <= 0x0F => Err(Box::new(UltralightError::new(UltralightErrorKind::Nak(
+                *nak,
+            )))),
+            _ => Ok(data.to_vec()),
+        }
+    }
+    /// NAKやタイムアウトで休止状態になったカードを選択し直す
+    pub fn reactivate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let apdu = ApduBuilder::new()
+            .direct_transmit(&PN53X_IN_LIST_PASSIVE_TARGET)
+            .build();
+        let res = self.nfc.transmit(Box::new(apdu))?;
+        // D5 4B NbTg ...
+        if res.len() < 3 || res[0..2] != PN53X_IN_LIST_PASSIVE_TARGET_RESPONSE || res[2] == 0 {
+            return Err(Box::new(SmartcardError::new(
+                SmartcardErrorKind::ConnectionLost,
+            )));
+        }
+        Ok(())
+    }
+    pub fn get_version(&self) -> Result<UltralightVersion, Box<dyn std::error::Error>> {
+        let res = self.transceive(&[CMD_GET_VERSION])?;
+        UltralightVersion::from_bytes(&res).ok_or_else(|| {
+            Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )) as Box<dyn std::error::Error>
+        })
+    }
+    /// pageから4ページ(16バイト)を読み出す
+    /// 最終ページを越えた分は先頭ページに折り返す
+    pub fn read(&self, page: u8) -> Result<[u8; READ_SIZE], Box<dyn std::error::Error>> {
+        self.check_page(page)?;
+        let res = self.transceive(&[CMD_READ, page])?;
+        if res.len() != READ_SIZE {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let mut data = [0u8; READ_SIZE];
+        data.copy_from_slice(&res);
+        Ok(data)
+    }
+    pub fn read_page(&self, page: u8) -> Result<[u8; PAGE_SIZE], Box<dyn std::error::Error>> {
+        let data = self.read(page)?;
+        let mut result = [0u8; PAGE_SIZE];
+        result.copy_from_slice(&data[..PAGE_SIZE]);
+        Ok(result)
+    }
+    /// startからcountページを読み出す
+    pub fn read_pages(
+        &self,
+        start: u8,
+        count: u16,
+    ) -> Result<Vec<[u8; PAGE_SIZE]>, Box<dyn std::error::Error>> {
+        if start as u16 + count > self.page_count {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::PageOutOfRange(start as u16 + count),
+            )));
+        }
+        let mut pages = Vec::new();
+        let end = start as u16 + count;
+        let mut page = start as u16;
+        while page < end {
+            let data = self.read(page as u8)?;
+            for chunk in data.chunks(PAGE_SIZE).take((end - page) as usize) {
+                let mut result = [0u8; PAGE_SIZE];
+                result.copy_from_slice(chunk);
+                pages.push(result);
+            }
+            page += 4;
+        }
+        Ok(pages)
+    }
+    /// 1ページ(4バイト)を書き込む
+    /// UID・ロックバイト・OTPのページ(0～3)はここでは書き込めない（lock_bytesを使う）
+    pub fn write_page(
+        &self,
+        page: u8,
+        data: &[u8; PAGE_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_page(page)?;
+        if page < 4 {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::ProtectedPage(page),
+            )));
+        }
+        self.write_page_unchecked(page, data)
+    }
+    fn write_page_unchecked(
+        &self,
+        page: u8,
+        data: &[u8; PAGE_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let command = [CMD_WRITE, page, data[0], data[1], data[2], data[3]];
+        self.transceive(&command)?;
+        Ok(())
+    }
+    /// ユーザーメモリのページ範囲
+    pub fn user_pages(&self) -> std::ops::Range<u16> {
+        match self.model.user_memory_end() {
+            Some(end) => 4..end,
+            None => 4..self.page_count,
+        }
+    }
+    /// 読み出しが成功する最後のページを二分探索してページ数を求める
+    /// 範囲外のREADはNAKになりカードが休止するので、失敗するたびに選択し直す
+    fn probe_page_count(&self) -> Result<u16, Box<dyn std::error::Error>> {
+        // [low, high) の間にページ数がある（ページ0～3は必ず存在する）
+        let mut low = 4u16;
+        let mut high = 256u16;
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            let command = [CMD_READ, (mid - 1) as u8];
+            match self.transceive(&command) {
+                Ok(_) => low = mid,
+                Err(e) => {
+                    if SmartcardError::is_connection_lost(e.as_ref()) {
+                        return Err(e);
+                    }
+                    self.reactivate()?;
+                    high = mid;
+                }
+            }
+        }
+        Ok(low)
+    }
+    fn check_page(&self, page: u8) -> Result<(), UltralightError> {
+        if (page as u16) < self.page_count {
+            Ok(())
+        } else {
+            Err(UltralightError::new(UltralightErrorKind::PageOutOfRange(
+                page as u16,
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UltralightErrorKind {
+    /// 接続中のカードがUltralight/NTAGではない
+    NotUltralight,
+    PageOutOfRange(u16),
+    /// UID・ロックバイト・OTPのページ
+    ProtectedPage(u8),
+    /// カードが4ビットのNAKを返した
+    Nak(u8),
+    /// リーダー(PN53x)のエラーステータス
+    ReaderStatus(u8),
+    InvalidResponseLength(usize),
+    /// この種別では使えない機能
+    Unsupported,
+    /// PWD_AUTHの応答が期待したPACKと一致しない
+    PackMismatch,
+    /// ミラーがユーザーメモリに収まらない
+    InvalidMirrorPosition,
+    /// 3DES認証でカードの応答が期待値と一致しない
+    AuthenticationFailed,
+    /// CCがない、または対応しないバージョン
+    InvalidCapabilityContainer,
+    /// データ領域にNDEF Message TLVがない
+    NdefNotFound,
+    /// CCのアクセス条件で読み出し・書き込みが禁止されている
+    NdefAccessDenied,
+    /// CCのページが0でもNDEFのCCでもないため初期化できない
+    NotBlank,
+}
+
+#[derive(Debug)]
+pub struct UltralightError {
+    code: UltralightErrorKind,
+}
+
+impl UltralightError {
+    pub fn new(code: UltralightErrorKind) -> Self {
+        UltralightError { code }
+    }
+    pub fn kind(&self) -> &UltralightErrorKind {
+        &self.code
+    }
+}
+impl std::error::Error for UltralightError {}
+impl std::fmt::Display for UltralightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn ultralight_identify_and_read_write() {
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    let nfc = MockSmartcard::new(Box::new(VirtualUltralight::new(UltralightModel::Ntag213)));
+    let kind = |e: Box<dyn std::error::Error>| {
+        e.downcast_ref::<UltralightError>().unwrap().kind().clone()
+    };
+    let card = Ultralight::identify(&nfc).unwrap();
+    assert_eq!(card.model(), UltralightModel::Ntag213);
+    assert_eq!(card.page_count(), 45);
+    assert_eq!(card.user_pages(), 4..0x28);
+
+    card.write_page(4, &[0x01, 0x02, 0x03, 0x04]).unwrap();
+    let pages = card.read_pages(0, 45).unwrap();
+    assert_eq!(pages.len(), 45);
+    assert_eq!(pages[4], [0x01, 0x02, 0x03, 0x04]);
+    assert_eq!(
+        kind(card.write_page(2, &[0; PAGE_SIZE]).unwrap_err()),
+        UltralightErrorKind::ProtectedPage(2)
+    );
+    assert_eq!(
+        kind(card.read(45).unwrap_err()),
+        UltralightErrorKind::PageOutOfRange(45)
+    );
+    assert_eq!(
+        kind(card.read_pages(40, 6).unwrap_err()),
+        UltralightErrorKind::PageOutOfRange(46)
+    );
+    // カードのNAKはそのままエラーの種類になる
+    assert_eq!(
+        kind(card.transceive(&[0x30, 0x2D]).unwrap_err()),
+        UltralightErrorKind::Nak(0x00)
+    );
+}
+
+#[test]
+fn ultralight_identify_without_get_version() {
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    // GET_VERSIONに応答しない旧来のUltralight
+    let nfc = MockSmartcard::new(Box::new(VirtualUltralight::new(
+        UltralightModel::Ultralight,
+    )));
+    let card = Ultralight::identify(&nfc).unwrap();
+    assert_eq!(card.model(), UltralightModel::Ultralight);
+    assert!(card.version().is_none());
+    assert_eq!(card.read_page(0).unwrap()[0], 0x04);
+
+    // 未知の種別はREADの可否でページ数を調べる
+    let mut virtual_card = VirtualUltralight::new(UltralightModel::Ntag215);
+    virtual_card.set_version(&[0x00, 0x04, 0x04, 0x05, 0x02, 0x00, 0x11, 0x03]);
+    let nfc = MockSmartcard::new(Box::new(virtual_card));
+    let card = Ultralight::identify(&nfc).unwrap();
+    assert_eq!(card.model(), UltralightModel::Unknown);
+    assert_eq!(card.page_count(), 135);
+}
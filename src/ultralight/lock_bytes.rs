@@ -0,0 +1,188 @@
+// ロックバイトとOTP
+// ロックビット・OTPビットは一度立てると元に戻せない（書き込みは既存の値とのORになる）
+
+use super::version::UltralightModel;
+use super::{Ultralight, UltralightError, UltralightErrorKind, PAGE_SIZE};
+
+/// ページ2のバイト2,3（静的ロックバイト）
+pub const STATIC_LOCK_PAGE: u8 = 2;
+/// OTP(One Time Programmable)ページ
+pub const OTP_PAGE: u8 = 3;
+/// 静的ロックバイトで保護される最後のページ
+const STATIC_LOCK_LAST_PAGE: u16 = 15;
+/// 静的ロックバイト0のOTPページのロックビット
+const LOCK_OTP_BIT: u8 = 0x08;
+
+/// 動的ロックバイトの配置（ページ16以降をロックする）
+struct DynamicLockLayout {
+    page: u8,
+    /// ロックビット1ビットで保護するページ数
+    pages_per_bit: u16,
+}
+
+fn dynamic_lock_layout(model: UltralightModel) -> Option<DynamicLockLayout> {
+    let (page, pages_per_bit) = match model {
+        UltralightModel::UltralightC => (0x28, 4),
+        UltralightModel::UltralightEv1Mf0ul21 | UltralightModel::Ntag212 => (0x24, 2),
+        UltralightModel::Ntag213 => (0x28, 2),
+        UltralightModel::Ntag215 => (0x82, 16),
+        UltralightModel::Ntag216 => (0xE2, 16),
+        _ => return None,
+    };
+    Some(DynamicLockLayout {
+        page,
+        pages_per_bit,
+    })
+}
+
+/// 動的ロックバイトのページ（持たない種別はNone）
+pub fn dynamic_lock_page(model: UltralightModel) -> Option<u8> {
+    dynamic_lock_layout(model).map(|layout| layout.page)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LockBytes {
+    pub static_lock: [u8; 2],
+    /// 動的ロックバイトを持つ種別のみ
+    pub dynamic_lock: Option<[u8; 3]>,
+}
+
+impl LockBytes {
+    /// pageが書き込み禁止になっているか
+    pub fn is_page_locked(&self, model: UltralightModel, page: u16) -> bool {
+        match page {
+            3 => self.static_lock[0] & LOCK_OTP_BIT != 0,
+            4..=7 => self.static_lock[0] >> page & 1 == 1,
+            8..=15 => self.static_lock[1] >> (page - 8) & 1 == 1,
+            _ => match (dynamic_lock_layout(model), self.dynamic_lock) {
+                (Some(layout), Some(dynamic_lock)) => {
+                    match Self::dynamic_lock_bit(model, &layout, page) {
+                        Some(bit) => {
+                            let bits = u16::from_le_bytes([dynamic_lock[0], dynamic_lock[1]]);
+                            bits >> bit & 1 == 1
+                        }
+                        None => false,
+                    }
+                }
+                _ => false,
+            },
+        }
+    }
+    /// 指定したページ（OTPページ3を含む）をロックするためのロックビットを求める
+    pub fn for_pages(model: UltralightModel, pages: &[u16]) -> Result<Self, UltralightError> {
+        let layout = dynamic_lock_layout(model);
+        let mut lock = LockBytes {
+            static_lock: [0; 2],
+            dynamic_lock: layout.as_ref().map(|_| [0; 3]),
+        };
+        for page in pages.iter().cloned() {
+            match page {
+                3 => lock.static_lock[0] |= LOCK_OTP_BIT,
+                4..=7 => lock.static_lock[0] |= 1 << page,
+                8..=STATIC_LOCK_LAST_PAGE => lock.static_lock[1] |= 1 << (page - 8),
+                _ => {
+                    let bit = layout
+                        .as_ref()
+                        .and_then(|layout| Self::dynamic_lock_bit(model, layout, page))
+                        .ok_or_else(|| {
+                            UltralightError::new(UltralightErrorKind::PageOutOfRange(page))
+                        })?;
+                    let dynamic_lock = lock.dynamic_lock.as_mut().unwrap();
+                    dynamic_lock[(bit / 8) as usize] |= 1 << (bit % 8);
+                }
+            }
+        }
+        Ok(lock)
+    }
+    fn dynamic_lock_bit(
+        model: UltralightModel,
+        layout: &DynamicLockLayout,
+        page: u16,
+    ) -> Option<u16> {
+        let end = model.user_memory_end()?;
+        if page <= STATIC_LOCK_LAST_PAGE || page >= end {
+            return None;
+        }
+        let bit = (page - (STATIC_LOCK_LAST_PAGE + 1)) / layout.pages_per_bit;
+        // 動的ロックバイトの3バイト目はロックビット自体のロック(ブロックロック)
+        if bit < 16 {
+            Some(bit)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Ultralight<'a> {
+    pub fn read_lock_bytes(&self) -> Result<LockBytes, Box<dyn std::error::Error>> {
+        let page = self.read_page(STATIC_LOCK_PAGE)?;
+        let dynamic_lock = match dynamic_lock_layout(self.model) {
+            Some(layout) => {
+                let data = self.read_page(layout.page)?;
+                Some([data[0], data[1], data[2]])
+            }
+            None => None,
+        };
+        Ok(LockBytes {
+            static_lock: [page[2], page[3]],
+            dynamic_lock,
+        })
+    }
+    /// 指定したページを永久に書き込み禁止にする
+    pub fn lock_pages(&self, pages: &[u16]) -> Result<LockBytes, Box<dyn std::error::Error>> {
+        let lock = LockBytes::for_pages(self.model, pages)?;
+        if lock.static_lock != [0; 2] {
+            // ページ2のバイト0,1(シリアル番号・内部データ)は書き込んでも無視される
+            let data = [0, 0, lock.static_lock[0], lock.static_lock[1]];
+            self.write_page_unchecked(STATIC_LOCK_PAGE, &data)?;
+        }
+        if let (Some(layout), Some(dynamic_lock)) =
+            (dynamic_lock_layout(self.model), lock.dynamic_lock)
+        {
+            if dynamic_lock != [0; 3] {
+                let data = [dynamic_lock[0], dynamic_lock[1], dynamic_lock[2], 0];
+                self.write_page_unchecked(layout.page, &data)?;
+            }
+        }
+        self.read_lock_bytes()
+    }
+    pub fn read_otp(&self) -> Result<[u8; PAGE_SIZE], Box<dyn std::error::Error>> {
+        self.read_page(OTP_PAGE)
+    }
+    /// OTPビットを立てる（既存のビットとORされ、戻せない）
+    pub fn write_otp(&self, bits: &[u8; PAGE_SIZE]) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_page_unchecked(OTP_PAGE, bits)
+    }
+}
+
+#[test]
+fn ultralight_lock_bytes_mapping() {
+    let lock = LockBytes::for_pages(UltralightModel::Ntag213, &[3, 4, 15, 16, 17, 39]).unwrap();
+    assert_eq!(lock.static_lock, [0x18, 0x80]);
+    // ページ16,17がビット0、ページ38,39がビット11
+    assert_eq!(lock.dynamic_lock, Some([0x01, 0x08, 0x00]));
+    assert!(lock.is_page_locked(UltralightModel::Ntag213, 17));
+    assert!(!lock.is_page_locked(UltralightModel::Ntag213, 18));
+    assert!(!lock.is_page_locked(UltralightModel::Ntag213, 5));
+    assert!(LockBytes::for_pages(UltralightModel::Ntag213, &[0x28]).is_err());
+    assert!(LockBytes::for_pages(UltralightModel::Ultralight, &[16]).is_err());
+}
+
+#[test]
+fn ultralight_lock_pages_on_card() {
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    let nfc = MockSmartcard::new(Box::new(VirtualUltralight::new(
+        UltralightModel::UltralightEv1Mf0ul21,
+    )));
+    let card = Ultralight::identify(&nfc).unwrap();
+    card.write_otp(&[0x01, 0x00, 0x00, 0x80]).unwrap();
+    card.write_otp(&[0x02, 0x00, 0x00, 0x00]).unwrap();
+    assert_eq!(card.read_otp().unwrap(), [0x03, 0x00, 0x00, 0x80]);
+
+    let lock = card.lock_pages(&[5, 20]).unwrap();
+    assert!(lock.is_page_locked(card.model(), 5));
+    assert!(lock.is_page_locked(card.model(), 21));
+    assert!(card.write_page(5, &[0xAA; 4]).is_err());
+    card.reactivate().unwrap();
+    card.write_page(6, &[0xAA; 4]).unwrap();
+}
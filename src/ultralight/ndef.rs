@@ -0,0 +1,274 @@
+// NFC Forum Type 2 TagとしてのNDEFの読み書き
+// ページ3がCapability Container、ページ4からがデータ領域で、TLVの並びにNDEFメッセージを格納する。
+// 書き込みは NDEF Message TLVの長さを0にする → メッセージを書く → 長さを書く の順で行い、
+// 途中で離されても壊れたメッセージが読まれないようにする。
+
+use super::lock_bytes::OTP_PAGE;
+use super::version::UltralightModel;
+use super::{Ultralight, UltralightError, UltralightErrorKind, PAGE_SIZE};
+use crate::ndef::tlv::{TlvLayout, TLV_NDEF_MESSAGE, TLV_TERMINATOR};
+use crate::ndef::NdefMessage;
+
+/// CCのマジックナンバー（NDEFを格納している）
+pub const NDEF_MAGIC: u8 = 0xE1;
+/// データ領域の最初のページ
+pub const DATA_AREA_PAGE: u8 = 4;
+const DATA_AREA_START: usize = DATA_AREA_PAGE as usize * PAGE_SIZE;
+/// CCのデータ領域のサイズは8バイト単位
+const DATA_AREA_UNIT: usize = 8;
+
+/// Type 2 TagのCapability Container（ページ3）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapabilityContainer {
+    /// 上位4ビットがメジャー、下位4ビットがマイナーバージョン
+    pub version: u8,
+    /// データ領域のバイト数。CCには / 8 で格納する
+    pub data_area_size: usize,
+    /// 上位4ビットが読み出し、下位4ビットが書き込みの権限（0で許可、Fで禁止）
+    pub access: u8,
+}
+
+impl CapabilityContainer {
+    pub fn new(data_area_size: usize) -> Self {
+        CapabilityContainer {
+            version: 0x10,
+            data_area_size,
+            access: 0x00,
+        }
+    }
+    /// 種別ごとの出荷時のCC（NTAG215/216はデータシートの値がユーザーメモリより小さい）
+    pub fn for_model(model: UltralightModel, user_pages: std::ops::Range<u16>) -> Self {
+        let data_area_size = match model {
+            UltralightModel::Ntag215 => 496,
+            UltralightModel::Ntag216 => 872,
+            _ => user_pages.len() * PAGE_SIZE,
+        };
+        Self::new(data_area_size.min(0xFF * DATA_AREA_UNIT))
+    }
+    /// NDEFのマジックナンバーがなければNone
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        match data {
+            [NDEF_MAGIC, version, size, access, ..] => Some(CapabilityContainer {
+                version: *version,
+                data_area_size: *size as usize * DATA_AREA_UNIT,
+                access: *access,
+            }),
+            _ => None,
+        }
+    }
+    pub fn to_bytes(self) -> [u8; PAGE_SIZE] {
+        [
+            NDEF_MAGIC,
+            self.version,
+            (self.data_area_size / DATA_AREA_UNIT) as u8,
+            self.access,
+        ]
+    }
+    pub fn major_version(&self) -> u8 {
+        self.version >> 4
+    }
+    pub fn is_readable(&self) -> bool {
+        self.access & 0xF0 == 0x00
+    }
+    pub fn is_writable(&self) -> bool {
+        self.access & 0x0F == 0x00
+    }
+}
+
+/// データ領域を読み出した内容（ページ0からの通しのアドレス）
+struct DataArea {
+    memory: Vec<u8>,
+    layout: TlvLayout,
+}
+
+impl<'a> Ultralight<'a> {
+    /// CCを読み出す。NDEF用に初期化されていなければエラー
+    pub fn read_capability_container(
+        &self,
+    ) -> Result<CapabilityContainer, Box<dyn std::error::Error>> {
+        let page = self.read_page(OTP_PAGE)?;
+        match CapabilityContainer::from_bytes(&page) {
+            // 対応するのはメジャーバージョン1
+            Some(cc) if cc.major_version() == 1 => Ok(cc),
+            _ => Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidCapabilityContainer,
+            ))),
+        }
+    }
+    /// NDEFメッセージを読み出す。NDEF Message TLVの長さが0なら空のメッセージを返す
+    pub fn read_ndef(&self) -> Result<NdefMessage, Box<dyn std::error::Error>> {
+        let cc = self.read_capability_container()?;
+        if !cc.is_readable() {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::NdefAccessDenied,
+            )));
+        }
+        let area = self.read_data_area(&cc)?;
+        let ndef = area
+            .layout
+            .ndef
+            .as_ref()
+            .ok_or_else(|| UltralightError::new(UltralightErrorKind::NdefNotFound))?;
+        if ndef.value.is_empty() {
+            return Ok(NdefMessage::default());
+        }
+        let data = ndef
+            .value
+            .iter()
+            .map(|position| area.memory[*position])
+            .collect::<Vec<u8>>();
+        Ok(NdefMessage::from_bytes(&data)?)
+    }
+    /// 書き込めるNDEFメッセージの最大のバイト数
+    pub fn ndef_capacity(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let cc = self.read_capability_container()?;
+        Ok(self.read_data_area(&cc)?.layout.capacity())
+    }
+    /// NDEFメッセージを書き込む（既存のNDEF Message TLVを置き換える）
+    pub fn write_ndef(&self, message: &NdefMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let cc = self.read_capability_container()?;
+        if !cc.is_readable() || !cc.is_writable() {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::NdefAccessDenied,
+            )));
+        }
+        let area = self.read_data_area(&cc)?;
+        let (empty, image) = area.layout.place(&area.memory, &message.to_bytes())?;
+        // 長さを0にして、メッセージ、長さの順に書く
+        self.write_changed_pages(&area.memory, &empty)?;
+        self.write_changed_pages(&empty, &image)?;
+        Ok(())
+    }
+    /// 未使用のタグにCCと空のNDEF Message TLVを書き込む
+    /// CCがすでにあれば、NDEFメッセージだけを空にする
+    pub fn format_ndef(&self) -> Result<CapabilityContainer, Box<dyn std::error::Error>> {
+        let page = self.read_page(OTP_PAGE)?;
+        let cc = match CapabilityContainer::from_bytes(&page) {
+            Some(cc) if cc.is_writable() => cc,
+            Some(_) => {
+                return Err(Box::new(UltralightError::new(
+                    UltralightErrorKind::NdefAccessDenied,
+                )))
+            }
+            // OTPページはビットを立てることしかできないので、0のときだけ書き込める
+            None if page == [0x00; PAGE_SIZE] => {
+                let cc = CapabilityContainer::for_model(self.model, self.user_pages());
+                self.write_otp(&cc.to_bytes())?;
+                cc
+            }
+            None => {
+                return Err(Box::new(UltralightError::new(
+                    UltralightErrorKind::NotBlank,
+                )))
+            }
+        };
+        self.write_page(
+            DATA_AREA_PAGE,
+            &[TLV_NDEF_MESSAGE, 0x00, TLV_TERMINATOR, 0x00],
+        )?;
+        Ok(cc)
+    }
+    /// CCを読み出し専用にして、CCとデータ領域のページをロックする（元に戻せない）
+    pub fn make_read_only(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let cc = self.read_capability_container()?;
+        // OTPページへの書き込みはORになるので、書き込み権限のビットだけを立てる
+        self.write_otp(&[0x00, 0x00, 0x00, 0x0F])?;
+        let last = DATA_AREA_PAGE as usize + cc.data_area_size.div_ceil(PAGE_SIZE);
+        let end = (last as u16).min(self.user_pages().end);
+        let pages = (OTP_PAGE as u16..end).collect::<Vec<u16>>();
+        self.lock_pages(&pages)?;
+        Ok(())
+    }
+
+    fn read_data_area(
+        &self,
+        cc: &CapabilityContainer,
+    ) -> Result<DataArea, Box<dyn std::error::Error>> {
+        // CCのサイズがカードのユーザーメモリを越えていても読める範囲に留める
+        let end =
+            (DATA_AREA_START + cc.data_area_size).min(self.user_pages().end as usize * PAGE_SIZE);
+        let count = end.saturating_sub(DATA_AREA_START).div_ceil(PAGE_SIZE);
+        let mut memory = vec![0u8; DATA_AREA_START];
+        for page in self.read_pages(DATA_AREA_PAGE, count as u16)? {
+            memory.extend_from_slice(&page);
+        }
+        let layout = TlvLayout::parse(&memory, DATA_AREA_START, end)?;
+        Ok(DataArea { memory, layout })
+    }
+    fn write_changed_pages(
+        &self,
+        current: &[u8],
+        next: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (i, (before, after)) in current
+            .chunks(PAGE_SIZE)
+            .zip(next.chunks(PAGE_SIZE))
+            .enumerate()
+        {
+            if before != after {
+                let mut data = [0u8; PAGE_SIZE];
+                data.copy_from_slice(after);
+                self.write_page(i as u8, &data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn ultralight_ndef_read_write() {
+    use crate::ndef::NdefRecord;
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    let kind =
+        |e: Box<dyn std::error::Error>| e.downcast_ref::<UltralightError>().unwrap().kind().clone();
+
+    // 未使用のUltralight
+    let nfc = MockSmartcard::new(Box::new(VirtualUltralight::new(
+        UltralightModel::Ultralight,
+    )));
+    let card = Ultralight::identify(&nfc).unwrap();
+    assert_eq!(
+        kind(card.read_ndef().unwrap_err()),
+        UltralightErrorKind::InvalidCapabilityContainer
+    );
+    let cc = card.format_ndef().unwrap();
+    assert_eq!(cc.to_bytes(), [0xE1, 0x10, 0x06, 0x00]);
+    assert_eq!(card.read_ndef().unwrap(), NdefMessage::default());
+    assert_eq!(card.ndef_capacity().unwrap(), 46);
+
+    let message = NdefMessage::new(vec![NdefRecord::uri("https://www.nfc.com")]);
+    card.write_ndef(&message).unwrap();
+    assert_eq!(card.read_page(4).unwrap(), [0x03, 0x0C, 0xD1, 0x01]);
+    assert_eq!(card.read_ndef().unwrap(), message);
+    let too_large = NdefMessage::new(vec![NdefRecord::mime("text/plain", &[0x41; 64])]);
+    assert!(card.write_ndef(&too_large).is_err());
+    assert_eq!(card.read_ndef().unwrap(), message);
+
+    // NTAG216に3バイト長のTLVで書き込んで読み出し専用にする
+    let nfc = MockSmartcard::new(Box::new(VirtualUltralight::new(UltralightModel::Ntag216)));
+    let card = Ultralight::identify(&nfc).unwrap();
+    assert_eq!(card.format_ndef().unwrap().data_area_size, 872);
+    assert_eq!(card.ndef_capacity().unwrap(), 868);
+    let message = NdefMessage::new(vec![
+        NdefRecord::text("en", "Hello"),
+        NdefRecord::mime("application/octet-stream", &[0xA5; 600]),
+    ]);
+    card.write_ndef(&message).unwrap();
+    assert_eq!(card.read_page(4).unwrap()[0..2], [0x03, 0xFF]);
+    assert_eq!(card.read_ndef().unwrap(), message);
+
+    card.make_read_only().unwrap();
+    assert_eq!(card.read_capability_container().unwrap().access, 0x0F);
+    assert_eq!(
+        kind(card.write_ndef(&NdefMessage::default()).unwrap_err()),
+        UltralightErrorKind::NdefAccessDenied
+    );
+    assert_eq!(
+        kind(card.format_ndef().unwrap_err()),
+        UltralightErrorKind::NdefAccessDenied
+    );
+    assert_eq!(card.read_ndef().unwrap(), message);
+    let lock = card.read_lock_bytes().unwrap();
+    assert!(lock.is_page_locked(card.model(), 3));
+    assert!(lock.is_page_locked(card.model(), 0xDD));
+}
@@ -0,0 +1,404 @@
+// NTAG21x / Ultralight EV1 のパスワード保護・カウンタ・署名
+// PWD_AUTHでカードが返すPACKを照合すれば、パスワードを知っている正規のタグか確認できる。
+// 設定ページの配置はNTAG213/215/216に合わせている（ミラーとNFCカウンタはこの3種のみ）
+
+use crate::originality::{verify_originality, OriginalityCheck};
+use super::{Ultralight, UltralightError, UltralightErrorKind, PAGE_SIZE};
+
+const CMD_PWD_AUTH: u8 = 0x1B;
+const CMD_READ_CNT: u8 = 0x39;
+const CMD_READ_SIG: u8 = 0x3C;
+/// NTAG21xのNFCカウンタのアドレス
+const NFC_COUNTER_ADDRESS: u8 = 0x02;
+
+pub const PWD_SIZE: usize = 4;
+pub const PACK_SIZE: usize = 2;
+pub const SIGNATURE_SIZE: usize = 32;
+/// AUTH0をこの値にするとパスワード保護が無効になる
+pub const AUTH0_DISABLED: u8 = 0xFF;
+/// カウンタミラーはカウンタ値を6文字の16進ASCIIで書き込む
+const COUNTER_MIRROR_SIZE: u16 = 6;
+
+// 設定ページ内の位置
+const CFG1_OFFSET: u8 = 1;
+const PWD_OFFSET: u8 = 2;
+const PACK_OFFSET: u8 = 3;
+
+/// ミラー(UID・カウンタをASCIIでユーザーメモリ上に見せる機能)の対象
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MirrorConf {
+    None = 0,
+    Uid = 1,
+    Counter = 2,
+    UidAndCounter = 3,
+}
+
+impl From<u8> for MirrorConf {
+    fn from(value: u8) -> Self {
+        match value & 0x03 {
+            1 => MirrorConf::Uid,
+            2 => MirrorConf::Counter,
+            3 => MirrorConf::UidAndCounter,
+            _ => MirrorConf::None,
+        }
+    }
+}
+
+/// CFG0/CFG1ページの内容
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtagConfig {
+    pub mirror_conf: MirrorConf,
+    /// ミラー開始位置のページ内バイト位置(0～3)
+    pub mirror_byte: u8,
+    pub mirror_page: u8,
+    pub strong_modulation: bool,
+    /// このページ以降がパスワード保護される
+    pub auth0: u8,
+    /// 書き込みだけでなく読み出しも保護する(PROT)
+    pub read_protected: bool,
+    /// 設定ページを永久に書き込み禁止にする(CFGLCK)
+    pub config_locked: bool,
+    pub counter_enabled: bool,
+    /// READ_CNTにパスワード認証を要求する
+    pub counter_password_protected: bool,
+    /// 認証失敗の上限。0は無制限、nは2^n回
+    pub auth_limit: u8,
+}
+
+impl NtagConfig {
+    pub fn from_pages(cfg0: &[u8; PAGE_SIZE], cfg1: &[u8; PAGE_SIZE]) -> Self {
+        NtagConfig {
+            mirror_conf: MirrorConf::from(cfg0[0] >> 6),
+            mirror_byte: (cfg0[0] >> 4) & 0x03,
+            mirror_page: cfg0[2],
+            strong_modulation: cfg0[0] & 0x04 != 0,
+            auth0: cfg0[3],
+            read_protected: cfg1[0] & 0x80 != 0,
+            config_locked: cfg1[0] & 0x40 != 0,
+            counter_enabled: cfg1[0] & 0x10 != 0,
+            counter_password_protected: cfg1[0] & 0x08 != 0,
+            auth_limit: cfg1[0] & 0x07,
+        }
+    }
+    /// 現在のページ内容のRFUビットを保ったまま設定を反映する
+    pub fn to_pages(
+        self,
+        cfg0: &[u8; PAGE_SIZE],
+        cfg1: &[u8; PAGE_SIZE],
+    ) -> ([u8; PAGE_SIZE], [u8; PAGE_SIZE]) {
+        let mut cfg0 = *cfg0;
+        let mut cfg1 = *cfg1;
+        cfg0[0] = (cfg0[0] & 0x0B)
+            | (self.mirror_conf as u8) << 6
+            | (self.mirror_byte & 0x03) << 4
+            | if self.strong_modulation { 0x04 } else { 0 };
+        cfg0[2] = self.mirror_page;
+        cfg0[3] = self.auth0;
+        cfg1[0] = (cfg1[0] & 0x20)
+            | if self.read_protected { 0x80 } else { 0 }
+            | if self.config_locked { 0x40 } else { 0 }
+            | if self.counter_enabled { 0x10 } else { 0 }
+            | if self.counter_password_protected {
+                0x08
+            } else {
+                0
+            }
+            | (self.auth_limit & 0x07);
+        (cfg0, cfg1)
+    }
+}
+
+/// 偽造防止タグとして書き込む設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtagProtection {
+    pub password: [u8; PWD_SIZE],
+    pub pack: [u8; PACK_SIZE],
+    /// このページ以降を保護する（AUTH0_DISABLEDで保護しない）
+    pub auth0: u8,
+    pub read_protected: bool,
+    pub auth_limit: u8,
+    /// NFCカウンタを有効にし、指定した(ページ, バイト位置)へカウンタをミラーする
+    pub counter_mirror: Option<(u8, u8)>,
+    pub counter_password_protected: bool,
+}
+
+/// verifyで確認したタグの状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtagStatus {
+    pub config: NtagConfig,
+    /// NFCカウンタが無効、または持たない種別ではNone
+    pub nfc_counter: Option<u32>,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl<'a> Ultralight<'a> {
+    fn config_page(&self) -> Result<u8, UltralightError> {
+        self.model
+            .config_page()
+            .ok_or_else(|| UltralightError::new(UltralightErrorKind::Unsupported))
+    }
+    /// CFG0/CFG1を読み出す（読み出し保護されている場合は先に認証する）
+    pub fn read_config(&self) -> Result<NtagConfig, Box<dyn std::error::Error>> {
+        let (cfg0, cfg1) = self.read_config_pages()?;
+        Ok(NtagConfig::from_pages(&cfg0, &cfg1))
+    }
+    fn read_config_pages(
+        &self,
+    ) -> Result<([u8; PAGE_SIZE], [u8; PAGE_SIZE]), Box<dyn std::error::Error>> {
+        let data = self.read(self.config_page()?)?;
+        let mut cfg0 = [0u8; PAGE_SIZE];
+        let mut cfg1 = [0u8; PAGE_SIZE];
+        cfg0.copy_from_slice(&data[0..PAGE_SIZE]);
+        cfg1.copy_from_slice(&data[PAGE_SIZE..PAGE_SIZE * 2]);
+        Ok((cfg0, cfg1))
+    }
+    /// CFG1, CFG0の順に書き込む
+    /// AUTH0を下げると以降の設定ページの書き込みに認証が必要になるため、CFG0を最後にしている
+    pub fn write_config(&self, config: &NtagConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let page = self.config_page()?;
+        if config.mirror_conf != MirrorConf::None {
+            self.check_mirror(config)?;
+        }
+        let (current_cfg0, current_cfg1) = self.read_config_pages()?;
+        let (cfg0, cfg1) = config.to_pages(&current_cfg0, &current_cfg1);
+        if cfg1 != current_cfg1 {
+            self.write_page(page + CFG1_OFFSET, &cfg1)?;
+        }
+        if cfg0 != current_cfg0 {
+            self.write_page(page, &cfg0)?;
+        }
+        Ok(())
+    }
+    /// ミラーがユーザーメモリに収まるか確認する
+    fn check_mirror(&self, config: &NtagConfig) -> Result<(), UltralightError> {
+        let invalid = || UltralightError::new(UltralightErrorKind::InvalidMirrorPosition);
+        if !self.model.has_nfc_counter() {
+            return Err(UltralightError::new(UltralightErrorKind::Unsupported));
+        }
+        let size = match config.mirror_conf {
+            MirrorConf::None => 0,
+            MirrorConf::Uid => 14,
+            MirrorConf::Counter => COUNTER_MIRROR_SIZE,
+            // UIDとカウンタの間に区切り文字'x'が入る
+            MirrorConf::UidAndCounter => 14 + 1 + COUNTER_MIRROR_SIZE,
+        };
+        let end = self.model.user_memory_end().ok_or_else(invalid)?;
+        let start = config.mirror_page as u16 * PAGE_SIZE as u16 + config.mirror_byte as u16;
+        if config.mirror_page < 4 || config.mirror_byte > 3 || start + size > end * 4 {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+    /// PWDとPACKを書き込む（どちらも読み出すと00になる）
+    pub fn set_password(
+        &self,
+        password: &[u8; PWD_SIZE],
+        pack: &[u8; PACK_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let page = self.config_page()?;
+        self.write_page(page + PWD_OFFSET, password)?;
+        self.write_page(page + PACK_OFFSET, &[pack[0], pack[1], 0, 0])
+    }
+    /// PWD_AUTH カードが返したPACKを返す
+    pub fn pwd_auth(
+        &self,
+        password: &[u8; PWD_SIZE],
+    ) -> Result<[u8; PACK_SIZE], Box<dyn std::error::Error>> {
+        let command = [
+            CMD_PWD_AUTH,
+            password[0],
+            password[1],
+            password[2],
+            password[3],
+        ];
+        let res = self.transceive(&command)?;
+        if res.len() != PACK_SIZE {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        Ok([res[0], res[1]])
+    }
+    /// パスワードで認証し、カードのPACKが期待値と一致するか確かめる
+    pub fn authenticate_with_pack(
+        &self,
+        password: &[u8; PWD_SIZE],
+        expected_pack: &[u8; PACK_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if &self.pwd_auth(password)? != expected_pack {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::PackMismatch,
+            )));
+        }
+        Ok(())
+    }
+    /// READ_CNT 24ビットのカウンタを読み出す
+    pub fn read_counter(&self, counter: u8) -> Result<u32, Box<dyn std::error::Error>> {
+        let res = self.transceive(&[CMD_READ_CNT, counter])?;
+        if res.len() != 3 {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        // 下位バイトが先
+        Ok(u32::from_le_bytes([res[0], res[1], res[2], 0]))
+    }
+    /// NTAG213/215/216のNFCカウンタ（電源投入後最初のREADで1増える）
+    pub fn read_nfc_counter(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        if !self.model.has_nfc_counter() {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::Unsupported,
+            )));
+        }
+        self.read_counter(NFC_COUNTER_ADDRESS)
+    }
+    /// READ_SIG NXPの製造時署名(ECC)を読み出す
+    pub fn read_signature(&self) -> Result<[u8; SIGNATURE_SIZE], Box<dyn std::error::Error>> {
+        let res = self.transceive(&[CMD_READ_SIG, 0x00])?;
+        if res.len() != SIGNATURE_SIZE {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::InvalidResponseLength(res.len()),
+            )));
+        }
+        let mut signature = [0u8; SIGNATURE_SIZE];
+        signature.copy_from_slice(&res);
+        Ok(signature)
+    }
+    /// READ_SIGの署名をUIDに対してNXPの公開鍵で検証する
+    pub fn check_originality(&self) -> Result<OriginalityCheck, Box<dyn std::error::Error>> {
+        let uid = self.uid()?;
+        let signature = self.read_signature()?;
+        Ok(verify_originality(&uid, &signature)?)
+    }
+    /// パスワード・PACK・保護範囲・カウンタミラーを書き込む
+    /// 既に保護されているタグは、先に現在のパスワードで認証しておくこと
+    pub fn provision(&self, protection: &NtagProtection) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = self.read_config()?;
+        if config.config_locked {
+            return Err(Box::new(UltralightError::new(
+                UltralightErrorKind::ProtectedPage(self.config_page()?),
+            )));
+        }
+        config.auth0 = protection.auth0;
+        config.read_protected = protection.read_protected;
+        config.auth_limit = protection.auth_limit;
+        config.counter_password_protected = protection.counter_password_protected;
+        match protection.counter_mirror {
+            Some((page, byte)) => {
+                config.counter_enabled = true;
+                config.mirror_conf = MirrorConf::Counter;
+                config.mirror_page = page;
+                config.mirror_byte = byte;
+            }
+            None => {
+                config.mirror_conf = MirrorConf::None;
+                config.mirror_page = 0;
+                config.mirror_byte = 0;
+            }
+        }
+        if config.mirror_conf != MirrorConf::None {
+            self.check_mirror(&config)?;
+        }
+        self.set_password(&protection.password, &protection.pack)?;
+        self.write_config(&config)
+    }
+    /// PACKを照合して認証し、設定・NFCカウンタ・署名を読み出す
+    pub fn verify(
+        &self,
+        password: &[u8; PWD_SIZE],
+        expected_pack: &[u8; PACK_SIZE],
+    ) -> Result<NtagStatus, Box<dyn std::error::Error>> {
+        self.authenticate_with_pack(password, expected_pack)?;
+        let config = self.read_config()?;
+        let nfc_counter = if self.model.has_nfc_counter() && config.counter_enabled {
+            Some(self.read_nfc_counter()?)
+        } else {
+            None
+        };
+        Ok(NtagStatus {
+            config,
+            nfc_counter,
+            signature: self.read_signature()?,
+        })
+    }
+}
+
+#[test]
+fn ntag_config_pages() {
+    // NTAG213の出荷時の値
+    let cfg0 = [0x04, 0x00, 0x00, 0xFF];
+    let cfg1 = [0x00, 0x05, 0x00, 0x00];
+    let mut config = NtagConfig::from_pages(&cfg0, &cfg1);
+    assert!(config.strong_modulation);
+    assert_eq!(config.auth0, AUTH0_DISABLED);
+    assert_eq!(config.to_pages(&cfg0, &cfg1), (cfg0, cfg1));
+
+    config.mirror_conf = MirrorConf::Counter;
+    config.mirror_byte = 2;
+    config.mirror_page = 0x10;
+    config.auth0 = 0x04;
+    config.read_protected = true;
+    config.counter_enabled = true;
+    config.auth_limit = 3;
+    assert_eq!(
+        config.to_pages(&cfg0, &cfg1),
+        ([0xA4, 0x00, 0x10, 0x04], [0x93, 0x05, 0x00, 0x00])
+    );
+    let (cfg0, cfg1) = config.to_pages(&cfg0, &cfg1);
+    assert_eq!(NtagConfig::from_pages(&cfg0, &cfg1), config);
+}
+
+#[test]
+fn ntag_provision_and_verify() {
+    use super::version::UltralightModel;
+    use crate::nfc_impl::nfc_mock::{ultralight::VirtualUltralight, MockSmartcard};
+    let mut virtual_card = VirtualUltralight::new(UltralightModel::Ntag213);
+    virtual_card.set_signature(&[0x5A; SIGNATURE_SIZE]);
+    let nfc = MockSmartcard::new(Box::new(virtual_card));
+    let kind = |e: Box<dyn std::error::Error>| {
+        e.downcast_ref::<UltralightError>().unwrap().kind().clone()
+    };
+    let card = Ultralight::identify(&nfc).unwrap();
+    let password = [0x12, 0x34, 0x56, 0x78];
+    let pack = [0xAB, 0xCD];
+    card.provision(&NtagProtection {
+        password,
+        pack,
+        auth0: 0x04,
+        read_protected: true,
+        auth_limit: 0,
+        counter_mirror: Some((0x10, 0)),
+        counter_password_protected: false,
+    })
+    .unwrap();
+
+    // 選択し直すと認証状態が解除され、保護されたページは読めない
+    card.reactivate().unwrap();
+    assert_eq!(kind(card.read(4).unwrap_err()), UltralightErrorKind::Nak(0x00));
+    card.reactivate().unwrap();
+    assert_eq!(
+        kind(card.authenticate_with_pack(&[0; PWD_SIZE], &pack).unwrap_err()),
+        UltralightErrorKind::Nak(0x00)
+    );
+    card.reactivate().unwrap();
+    assert_eq!(
+        kind(card.authenticate_with_pack(&password, &[0; PACK_SIZE]).unwrap_err()),
+        UltralightErrorKind::PackMismatch
+    );
+    card.reactivate().unwrap();
+
+    let status = card.verify(&password, &pack).unwrap();
+    assert_eq!(status.config.auth0, 0x04);
+    assert_eq!(status.config.mirror_conf, MirrorConf::Counter);
+    assert_eq!(status.signature, [0x5A; SIGNATURE_SIZE]);
+    assert_eq!(status.nfc_counter, Some(1));
+    // カウンタはASCIIでミラーされる
+    assert_eq!(&card.read(0x10).unwrap()[0..6], b"000001");
+    // PWDとPACKは読み出せない
+    let config_page = UltralightModel::Ntag213.config_page().unwrap();
+    assert_eq!(card.read(config_page).unwrap()[8..16], [0u8; 8]);
+    // 模擬カードの署名はNXPの鍵では検証できない
+    let originality = card.check_originality().unwrap();
+    assert!(!originality.verdicts.is_empty());
+    assert!(!originality.is_genuine());
+}
@@ -0,0 +1,187 @@
+// GET_VERSIONの応答による種別判定
+
+/// Ultralight/NTAGの種別
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UltralightModel {
+    /// MF0ICU1（GET_VERSION非対応）
+    Ultralight,
+    /// MF0ICU2（GET_VERSION非対応）
+    UltralightC,
+    UltralightEv1Mf0ul11,
+    UltralightEv1Mf0ul21,
+    Ntag210,
+    Ntag212,
+    Ntag213,
+    Ntag215,
+    Ntag216,
+    /// GET_VERSIONには応答したが表にない種別
+    Unknown,
+}
+
+impl UltralightModel {
+    /// 全ページ数
+    pub fn page_count(&self) -> Option<u16> {
+        match self {
+            UltralightModel::Ultralight => Some(16),
+            UltralightModel::UltralightC => Some(48),
+            UltralightModel::UltralightEv1Mf0ul11 => Some(20),
+            UltralightModel::UltralightEv1Mf0ul21 => Some(41),
+            UltralightModel::Ntag210 => Some(20),
+            UltralightModel::Ntag212 => Some(41),
+            UltralightModel::Ntag213 => Some(45),
+            UltralightModel::Ntag215 => Some(135),
+            UltralightModel::Ntag216 => Some(231),
+            UltralightModel::Unknown => None,
+        }
+    }
+    /// ユーザーメモリの次のページ（以降はロックバイト・設定ページ）
+    pub fn user_memory_end(&self) -> Option<u16> {
+        match self {
+            UltralightModel::Ultralight => Some(0x10),
+            UltralightModel::UltralightC => Some(0x28),
+            UltralightModel::UltralightEv1Mf0ul11 => Some(0x10),
+            UltralightModel::UltralightEv1Mf0ul21 => Some(0x24),
+            UltralightModel::Ntag210 => Some(0x10),
+            UltralightModel::Ntag212 => Some(0x24),
+            UltralightModel::Ntag213 => Some(0x28),
+            UltralightModel::Ntag215 => Some(0x82),
+            UltralightModel::Ntag216 => Some(0xE2),
+            UltralightModel::Unknown => None,
+        }
+    }
+    /// 設定ページ(CFG0)。続くページがCFG1, PWD, PACK
+    /// 設定ページを持たない種別はNone
+    pub fn config_page(&self) -> Option<u8> {
+        match self {
+            UltralightModel::UltralightEv1Mf0ul11 | UltralightModel::Ntag210 => Some(0x10),
+            UltralightModel::UltralightEv1Mf0ul21 | UltralightModel::Ntag212 => Some(0x25),
+            UltralightModel::Ntag213 => Some(0x29),
+            UltralightModel::Ntag215 => Some(0x83),
+            UltralightModel::Ntag216 => Some(0xE3),
+            _ => None,
+        }
+    }
+    /// NFCカウンタとカウンタミラーを持つか
+    pub fn has_nfc_counter(&self) -> bool {
+        matches!(
+            self,
+            UltralightModel::Ntag213 | UltralightModel::Ntag215 | UltralightModel::Ntag216
+        )
+    }
+}
+
+/// GET_VERSIONの応答（8バイト）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UltralightVersion {
+    pub vendor_id: u8,
+    pub product_type: u8,
+    pub product_subtype: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub storage_size: u8,
+    pub protocol_type: u8,
+}
+
+/// NXPのベンダーID
+const VENDOR_NXP: u8 = 0x04;
+const PRODUCT_TYPE_ULTRALIGHT: u8 = 0x03;
+const PRODUCT_TYPE_NTAG: u8 = 0x04;
+
+impl UltralightVersion {
+    /// 先頭の固定ヘッダ(00)を含む8バイトから生成する
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != 8 {
+            return None;
+        }
+        Some(UltralightVersion {
+            vendor_id: data[1],
+            product_type: data[2],
+            product_subtype: data[3],
+            major_version: data[4],
+            minor_version: data[5],
+            storage_size: data[6],
+            protocol_type: data[7],
+        })
+    }
+    pub fn to_bytes(self) -> [u8; 8] {
+        [
+            0x00,
+            self.vendor_id,
+            self.product_type,
+            self.product_subtype,
+            self.major_version,
+            self.minor_version,
+            self.storage_size,
+            self.protocol_type,
+        ]
+    }
+    pub fn model(&self) -> UltralightModel {
+        if self.vendor_id != VENDOR_NXP {
+            return UltralightModel::Unknown;
+        }
+        match (self.product_type, self.product_subtype, self.storage_size) {
+            // EV1のサブタイプは容量(17pF/50pF)の違いなので区別しない
+            (PRODUCT_TYPE_ULTRALIGHT, _, 0x0B) => UltralightModel::UltralightEv1Mf0ul11,
+            (PRODUCT_TYPE_ULTRALIGHT, _, 0x0E) => UltralightModel::UltralightEv1Mf0ul21,
+            (PRODUCT_TYPE_NTAG, 0x01, 0x0B) => UltralightModel::Ntag210,
+            (PRODUCT_TYPE_NTAG, 0x01, 0x0E) => UltralightModel::Ntag212,
+            (PRODUCT_TYPE_NTAG, 0x02, 0x0F) => UltralightModel::Ntag213,
+            (PRODUCT_TYPE_NTAG, 0x02, 0x11) => UltralightModel::Ntag215,
+            (PRODUCT_TYPE_NTAG, 0x02, 0x13) => UltralightModel::Ntag216,
+            _ => UltralightModel::Unknown,
+        }
+    }
+    /// storage_sizeが示すユーザーメモリのバイト数
+    /// 上位7ビットがnのとき、最下位ビットが0なら2^n、1なら2^nより大きく2^(n+1)より小さい
+    pub fn user_memory_size(&self) -> (usize, bool) {
+        let n = (self.storage_size >> 1) as u32;
+        (1usize << n.min(31), self.storage_size & 1 == 1)
+    }
+}
+
+#[test]
+fn ultralight_version_model() {
+    let table = [
+        (
+            [0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0B, 0x03],
+            UltralightModel::UltralightEv1Mf0ul11,
+        ),
+        (
+            [0x00, 0x04, 0x03, 0x02, 0x01, 0x00, 0x0E, 0x03],
+            UltralightModel::UltralightEv1Mf0ul21,
+        ),
+        (
+            [0x00, 0x04, 0x04, 0x01, 0x01, 0x00, 0x0B, 0x03],
+            UltralightModel::Ntag210,
+        ),
+        (
+            [0x00, 0x04, 0x04, 0x01, 0x01, 0x00, 0x0E, 0x03],
+            UltralightModel::Ntag212,
+        ),
+        (
+            [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03],
+            UltralightModel::Ntag213,
+        ),
+        (
+            [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03],
+            UltralightModel::Ntag215,
+        ),
+        (
+            [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03],
+            UltralightModel::Ntag216,
+        ),
+        (
+            [0x00, 0x53, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03],
+            UltralightModel::Unknown,
+        ),
+    ];
+    for (bytes, model) in table.iter() {
+        let version = UltralightVersion::from_bytes(bytes).unwrap();
+        assert_eq!(version.model(), *model);
+        assert_eq!(&version.to_bytes(), bytes);
+    }
+    // NTAG213: 144バイト(2^7 < 144 < 2^8)
+    let version = UltralightVersion::from_bytes(&table[4].0).unwrap();
+    assert_eq!(version.user_memory_size(), (128, true));
+    assert!(UltralightVersion::from_bytes(&[0x00]).is_none());
+}